@@ -0,0 +1,55 @@
+//! Compares writing a large compressible blob with compression and IO overlapped
+//! against doing both serially, the way blobs used to be written.
+
+use std::io::Write;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+
+const SIZE: usize = 32 * 1024 * 1024;
+
+fn contents() -> Vec<u8> {
+    let mut result = Vec::with_capacity(SIZE);
+    let mut i = 0u64;
+    while result.len() < SIZE {
+        writeln!(result, "line {i} of a large and fairly compressible upload").unwrap();
+        i += 1;
+    }
+    result.truncate(SIZE);
+    result
+}
+
+fn blob_write(c: &mut Criterion) {
+    let data = contents();
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("blob");
+
+    let mut group = c.benchmark_group("blob_write");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(SIZE as u64));
+    let encoder = || flate2::read::GzEncoder::new(&data[..], flate2::Compression::new(9));
+
+    group.bench_function("serial", |b| {
+        b.iter_batched(
+            encoder,
+            |mut reader| {
+                let mut file = std::fs::File::create(&path).unwrap();
+                std::io::copy(&mut reader, &mut file).unwrap()
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.bench_function("pipelined", |b| {
+        b.iter_batched(
+            encoder,
+            |mut reader| {
+                let mut file = std::fs::File::create(&path).unwrap();
+                filetracker_rs::util::pipelined_copy(&mut reader, &mut file).unwrap()
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, blob_write);
+criterion_main!(benches);
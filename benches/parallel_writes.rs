@@ -0,0 +1,69 @@
+//! Measures writing many distinct small blobs at once, which all land in one of
+//! the shard directories and used to race on creating it.
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use clap::Parser;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use filetracker_rs::storage::{Compression, Content, LocalStorage, Storage, StorageConfig, Upload};
+
+const WRITES: usize = 256;
+
+#[derive(Parser)]
+struct Opts {
+    #[clap(flatten)]
+    storage: StorageConfig,
+}
+
+fn parallel_writes(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let opts = Opts::parse_from(["filetracker", "--blob-compression-min-ratio", "0"]);
+    let storage =
+        Arc::new(runtime.block_on(async { LocalStorage::new(dir.path(), opts.storage).unwrap() }));
+    let next = Arc::new(AtomicUsize::new(0));
+
+    let mut group = c.benchmark_group("parallel_writes");
+    group.throughput(Throughput::Elements(WRITES as u64));
+    group.bench_function("unique_blobs", |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                let writes: Vec<_> = (0..WRITES)
+                    .map(|_| {
+                        let storage = storage.clone();
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        tokio::spawn(async move {
+                            let content = format!("unique blob {i}");
+                            let upload = Upload {
+                                content: Content::Memory(content.as_bytes()),
+                                content_encoding: Compression::None,
+                                checksum: None,
+                                logical_size: None,
+                                filename: None,
+                                compression: None,
+                                if_match: None,
+                            };
+                            storage
+                                .put(&format!("file{i}"), chrono::Utc::now(), upload)
+                                .await
+                                .unwrap();
+                        })
+                    })
+                    .collect();
+                for write in writes {
+                    write.await.unwrap();
+                }
+            })
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parallel_writes);
+criterion_main!(benches);
@@ -0,0 +1,180 @@
+//! Per-token access control, restricting each bearer token to some path prefixes.
+//!
+//! Tokens are configured in a file with one scope per line:
+//!
+//! ```text
+//! # token   prefix   permissions
+//! s3cr3t    teamA/   rw
+//! r34d3r    /        r
+//! ```
+//!
+//! A token may appear on several lines to be granted several scopes. A line with
+//! just a token grants it read-write access to everything.
+
+use std::{collections::HashMap, path::PathBuf};
+
+#[derive(clap::Args)]
+pub struct AccessConfig {
+    /// File mapping bearer tokens to the path prefixes they may read or write.
+    ///
+    /// Without one every request is allowed.
+    #[clap(long, alias = "auth-token-file", env = "FILETRACKER_ACCESS_FILE")]
+    pub access_file: Option<PathBuf>,
+    /// Allow reading without a token, only writes are checked against the access file.
+    #[clap(long, env = "FILETRACKER_PUBLIC_READ")]
+    pub public_read: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Permission {
+    Read,
+    Write,
+}
+
+struct Scope {
+    prefix: Vec<String>,
+    read: bool,
+    write: bool,
+}
+
+impl Scope {
+    fn allows(&self, components: &[&str], permission: Permission) -> bool {
+        let permitted = match permission {
+            Permission::Read => self.read,
+            Permission::Write => self.write,
+        };
+        permitted
+            && components.len() >= self.prefix.len()
+            && self.prefix.iter().zip(components).all(|(a, b)| a == b)
+    }
+}
+
+/// Identifies the token a request was allowed with, without revealing the token itself.
+#[derive(Clone, Debug)]
+pub struct TokenFingerprint(pub String);
+
+impl TokenFingerprint {
+    pub fn of(token: &str) -> Self {
+        use sha2::Digest;
+        Self(crate::util::bytes_to_hex(&sha2::Sha256::digest(token)[..8]))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Denied {
+    /// No token was given, or one that isn't configured.
+    Unauthenticated,
+    /// The token is valid, but not for this path.
+    OutOfScope,
+    /// The path can't be checked, e.g. because it contains `..`.
+    InvalidPath(String),
+}
+
+pub struct AccessControl {
+    tokens: HashMap<String, Vec<Scope>>,
+    public_read: bool,
+}
+
+impl AccessControl {
+    /// Loads the access file named in `config`, `None` if there isn't one.
+    pub fn load(config: &AccessConfig) -> std::io::Result<Option<Self>> {
+        let Some(file) = &config.access_file else {
+            return Ok(None);
+        };
+        let contents = std::fs::read_to_string(file)?;
+        let mut access = Self::parse(&contents).map_err(|message| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{}: {message}", file.display()),
+            )
+        })?;
+        access.public_read = config.public_read;
+        Ok(Some(access))
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut tokens: HashMap<String, Vec<Scope>> = HashMap::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let (token, prefix, permissions) = match fields[..] {
+                [token] => (token, "/", "rw"),
+                [token, prefix, permissions] => (token, prefix, permissions),
+                _ => {
+                    return Err(format!(
+                        "line {}: expected '<token> [<prefix> <permissions>]'",
+                        number + 1
+                    ))
+                }
+            };
+            let (read, write) = match permissions {
+                "r" => (true, false),
+                "w" => (false, true),
+                "rw" => (true, true),
+                other => {
+                    return Err(format!(
+                        "line {}: permissions must be r, w or rw, got '{other}'",
+                        number + 1
+                    ))
+                }
+            };
+            let prefix = crate::path::components(prefix)
+                .map_err(|e| format!("line {}: {e}", number + 1))?
+                .into_iter()
+                .map(String::from)
+                .collect();
+
+            tokens.entry(token.to_string()).or_default().push(Scope {
+                prefix,
+                read,
+                write,
+            });
+        }
+        Ok(Self {
+            tokens,
+            public_read: false,
+        })
+    }
+
+    /// Checks whether `token` may access `path`, normalized the same way storage does.
+    pub fn check(
+        &self,
+        token: Option<&str>,
+        path: &str,
+        permission: Permission,
+    ) -> Result<(), Denied> {
+        let components =
+            crate::path::components(path).map_err(|e| Denied::InvalidPath(e.to_string()))?;
+        if self.public_read && permission == Permission::Read {
+            return Ok(());
+        }
+        let scopes = self.scopes(token)?;
+        match scopes
+            .iter()
+            .any(|scope| scope.allows(&components, permission))
+        {
+            true => Ok(()),
+            false => Err(Denied::OutOfScope),
+        }
+    }
+
+    pub fn public_read(&self) -> bool {
+        self.public_read
+    }
+
+    /// Checks that `token` is configured at all, for requests not tied to a path.
+    pub fn authenticate(&self, token: Option<&str>) -> Result<(), Denied> {
+        self.scopes(token).map(|_| ())
+    }
+
+    fn scopes(&self, token: Option<&str>) -> Result<&[Scope], Denied> {
+        token
+            .and_then(|token| self.tokens.get(token))
+            .map(Vec::as_slice)
+            .ok_or(Denied::Unauthenticated)
+    }
+}
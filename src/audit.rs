@@ -0,0 +1,147 @@
+//! Append-only log of the effects of every mutation, one JSON object per line.
+//!
+//! Unlike an access log this records what a request actually did, e.g. that a PUT
+//! was superseded by a newer version and didn't change anything.
+
+use std::{io::Write, net::SocketAddr, path::PathBuf};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+#[derive(clap::Args)]
+pub struct AuditConfig {
+    /// Append a record of every PUT, DELETE, COPY, MOVE and promotion to this file.
+    #[clap(long)]
+    pub audit_log: Option<PathBuf>,
+    /// Rotate the audit log once it grows past this many bytes, by renaming it with
+    /// the time of rotation appended.
+    #[clap(long, default_value_t = 128 << 20)]
+    pub audit_log_max_size: u64,
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Put,
+    Delete,
+    Copy,
+    Move,
+    Promote,
+}
+
+#[derive(Serialize)]
+pub struct Event {
+    pub action: Action,
+    pub path: String,
+    /// Where the files were copied or moved to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+    /// What happened, e.g. `stored` or `superseded`.
+    pub outcome: &'static str,
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    event: Event,
+    /// Fingerprint of the access token the request was made with.
+    token: Option<&'a str>,
+    client: Option<SocketAddr>,
+}
+
+/// Hands records over to a writer thread, so that requests never wait for the disk.
+pub struct AuditLog {
+    sender: Option<std::sync::mpsc::Sender<String>>,
+    writer: Option<std::thread::JoinHandle<()>>,
+}
+
+impl AuditLog {
+    /// Opens the audit log named in `config`, `None` if there isn't one.
+    pub fn open(config: &AuditConfig) -> std::io::Result<Option<Self>> {
+        let Some(path) = config.audit_log.clone() else {
+            return Ok(None);
+        };
+        let file = open_append(&path)?;
+        let max_size = config.audit_log_max_size;
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let writer = std::thread::Builder::new()
+            .name("audit-log".into())
+            .spawn(move || write_records(path, file, max_size, receiver))?;
+        Ok(Some(Self {
+            sender: Some(sender),
+            writer: Some(writer),
+        }))
+    }
+
+    pub fn record(&self, event: Event, token: Option<&str>, client: Option<SocketAddr>) {
+        let record = Record {
+            timestamp: Utc::now(),
+            event,
+            token,
+            client,
+        };
+        let mut line = serde_json::to_string(&record).unwrap();
+        line.push('\n');
+        // The writer only stops once every sender is gone.
+        _ = self.sender.as_ref().unwrap().send(line);
+    }
+}
+
+impl Drop for AuditLog {
+    /// Waits for the queued records to be written out, e.g. when shutting down.
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        if let Some(writer) = self.writer.take() {
+            _ = writer.join();
+        }
+    }
+}
+
+fn open_append(path: &std::path::Path) -> std::io::Result<std::fs::File> {
+    std::fs::File::options()
+        .create(true)
+        .append(true)
+        .open(path)
+}
+
+fn rotate(path: &std::path::Path) -> std::io::Result<std::fs::File> {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(Utc::now().format(".%Y%m%dT%H%M%S%.3f").to_string());
+    std::fs::rename(path, rotated)?;
+    open_append(path)
+}
+
+fn log_failure(message: &str, path: &std::path::Path, error: std::io::Error) {
+    tracing::error!(path = %path.display(), %error, "{message}");
+}
+
+fn write_records(
+    path: PathBuf,
+    mut file: std::fs::File,
+    max_size: u64,
+    receiver: std::sync::mpsc::Receiver<String>,
+) {
+    let mut size = file.metadata().map_or(0, |metadata| metadata.len());
+    for line in receiver {
+        if size >= max_size {
+            match rotate(&path) {
+                Ok(new_file) => {
+                    file = new_file;
+                    size = 0;
+                }
+                // Keep appending to the old file rather than losing records.
+                Err(e) => log_failure("failed to rotate audit log", &path, e),
+            }
+        }
+
+        match file.write_all(line.as_bytes()) {
+            Ok(()) => size += line.len() as u64,
+            Err(e) => log_failure("failed to write audit log", &path, e),
+        }
+    }
+}
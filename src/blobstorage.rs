@@ -1,10 +1,26 @@
 use std::{
+    collections::{HashMap, VecDeque},
     fs::Metadata,
     io::Read,
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicI64, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock, Weak,
+    },
+    time::{Duration, Instant},
 };
 
-use crate::{lockmap::LockMap, util::bytes_to_hex};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    lockmap::LockMap,
+    storage::{
+        zstd_dictionary_of, Compression, Content, StorageConfig, StorageError, ZstdDictionary,
+    },
+    util::{blocking, bytes_to_hex, hex_to_byte_array, pipelined_copy, FsyncPolicy},
+};
 
 fn read_usize(path: &Path) -> std::io::Result<usize> {
     std::fs::read_to_string(path)?
@@ -12,64 +28,1090 @@ fn read_usize(path: &Path) -> std::io::Result<usize> {
         .map_err(|x| std::io::Error::new(std::io::ErrorKind::InvalidData, x))
 }
 
+/// Like [`Path::metadata`], but refuses anything that isn't a regular file, most
+/// importantly symlinks planted in the blob directory.
+fn blob_metadata(path: &Path) -> std::io::Result<Metadata> {
+    let metadata = path.symlink_metadata()?;
+    if !metadata.is_file() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("{} is not a regular file", path.display()),
+        ));
+    }
+    Ok(metadata)
+}
+
+/// Runs `write` and removes the partially written `path` if it fails,
+/// e.g. because the disk is full.
+fn remove_on_error(
+    path: &Path,
+    write: impl FnOnce() -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    write().inspect_err(|_| _ = std::fs::remove_file(path))
+}
+
+pub struct BlobInfo {
+    pub size: u64,
+    pub created: DateTime<Utc>,
+    pub accessed: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct BlobTotals {
+    pub blobs: u64,
+    /// Bytes taken up by the blob files, i.e. after compression.
+    pub bytes: u64,
+}
+
+#[derive(Default)]
+pub struct BlobCounters {
+    pub created: AtomicU64,
+    /// Writes of content that was already stored.
+    pub deduplicated: AtomicU64,
+    pub removed: AtomicU64,
+    /// Passes compressing uploaded content, to store it or to decide whether to.
+    pub compressions: AtomicU64,
+    /// Bytes of blobs created minus bytes of blobs removed since startup.
+    bytes_delta: AtomicI64,
+    /// Blobs present at startup, known once they have been counted in the background.
+    pub initial: OnceLock<BlobTotals>,
+}
+
+impl BlobCounters {
+    pub(crate) fn record_created(&self, size: u64) {
+        self.created.fetch_add(1, Ordering::Relaxed);
+        self.bytes_delta.fetch_add(size as i64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_removed(&self, size: u64) {
+        self.removed.fetch_add(1, Ordering::Relaxed);
+        self.bytes_delta.fetch_sub(size as i64, Ordering::Relaxed);
+    }
+
+    /// The blobs currently stored, once known.
+    ///
+    /// NOTE: Blobs created while the initial count is running may be counted twice.
+    pub fn current(&self) -> Option<BlobTotals> {
+        let initial = *self.initial.get()?;
+        Some(BlobTotals {
+            blobs: (initial.blobs + self.created.load(Ordering::Relaxed))
+                .saturating_sub(self.removed.load(Ordering::Relaxed)),
+            bytes: initial
+                .bytes
+                .saturating_add_signed(self.bytes_delta.load(Ordering::Relaxed)),
+        })
+    }
+}
+
+/// Blobs written moments ago, so that uploads of the same content arriving right
+/// after can reference them without looking for them on disk or preparing their data.
+///
+/// Bounded both in size and in how long blobs are remembered for, the oldest are
+/// forgotten first.
+struct RecentBlobs {
+    capacity: usize,
+    ttl: Duration,
+    inner: Mutex<RecentInner>,
+}
+
+#[derive(Default)]
+struct RecentInner {
+    blobs: HashMap<[u8; 32], (Compression, Instant)>,
+    /// In the order the blobs were remembered, including ones remembered again since.
+    order: VecDeque<([u8; 32], Instant)>,
+}
+
+impl RecentInner {
+    fn forget_oldest(&mut self) {
+        if let Some((sha256, added)) = self.order.pop_front() {
+            // Only if it wasn't remembered again since.
+            if self.blobs.get(&sha256).is_some_and(|&(_, at)| at == added) {
+                self.blobs.remove(&sha256);
+            }
+        }
+    }
+}
+
+impl RecentBlobs {
+    fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            inner: Mutex::default(),
+        }
+    }
+
+    fn get(&self, sha256: &[u8; 32]) -> Option<Compression> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .blobs
+            .get(sha256)
+            .filter(|(_, added)| added.elapsed() < self.ttl)
+            .map(|&(compression, _)| compression)
+    }
+
+    fn insert(&self, sha256: &[u8; 32], compression: Compression) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        while inner
+            .order
+            .front()
+            .is_some_and(|&(_, added)| added.elapsed() >= self.ttl)
+            || inner.order.len() >= self.capacity
+        {
+            inner.forget_oldest();
+        }
+        inner.blobs.insert(*sha256, (compression, now));
+        inner.order.push_back((*sha256, now));
+    }
+
+    fn remove(&self, sha256: &[u8; 32]) {
+        // Left in `order` until it's the oldest.
+        self.inner.lock().unwrap().blobs.remove(sha256);
+    }
+}
+
+/// What garbage collection found, and fixed unless it was a dry run.
+#[derive(Debug, Default)]
+pub struct GcReport {
+    pub files: usize,
+    /// Blobs referenced by metadata, but missing. These can't be fixed.
+    pub missing_blobs: usize,
+    /// Metadata that couldn't be parsed, the blobs it refers to count as unreferenced.
+    pub corrupt_metadata: usize,
+    pub removed_blobs: usize,
+    pub freed_bytes: u64,
+    pub fixed_refcounts: usize,
+    /// Unfinished writes and refcounts without a blob.
+    pub removed_leftovers: usize,
+}
+
+/// What fsck found, each problem is also printed as it's found.
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    pub checked_blobs: usize,
+    pub corrupt_blobs: usize,
+    pub quarantined_blobs: usize,
+    pub files: usize,
+    pub missing_blobs: usize,
+    pub corrupt_metadata: usize,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.corrupt_blobs == 0 && self.missing_blobs == 0 && self.corrupt_metadata == 0
+    }
+}
+
+/// How many of the corrupt blobs it found the scrubber's report lists.
+const MAX_REPORTED_CORRUPT: usize = 100;
+
+/// Passes of the scrubber over the blobs take at least this long, so that it doesn't
+/// spin on a store with few blobs.
+const MIN_SCRUB_PASS: Duration = Duration::from_secs(60);
+
+/// What the background scrubber has found since the server started.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ScrubReport {
+    /// Completed passes over all blobs.
+    pub passes: u64,
+    pub pass_started: Option<DateTime<Utc>>,
+    pub last_pass_finished: Option<DateTime<Utc>>,
+    pub checked_blobs: u64,
+    pub checked_bytes: u64,
+    /// Distinct blobs found corrupt.
+    pub corrupt_blobs: u64,
+    pub quarantined_blobs: u64,
+    /// The corrupt blobs found most recently, oldest first.
+    pub corrupt: Vec<CorruptBlob>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CorruptBlob {
+    pub checksum: String,
+    pub compression: String,
+    /// When the blob was last found corrupt, it's found again on every pass unless
+    /// it was quarantined.
+    pub found: DateTime<Utc>,
+    pub error: String,
+    pub quarantined: bool,
+}
+
+/// Where the scrubber is in its pass over the blobs.
+struct ScrubCursor {
+    /// Index of the next shard to list.
+    shard: usize,
+    compression: Compression,
+    /// Blobs of the current shard that are yet to be verified.
+    pending: Vec<PathBuf>,
+    pass_started: Instant,
+}
+
+/// Verifies blobs over and over, reading no more than `rate` bytes per second,
+/// until the storage is dropped.
+async fn scrubber(blobs: Weak<BlobStorage>, rate: u64, quarantine: Option<PathBuf>) {
+    let mut cursor = ScrubCursor {
+        shard: 0,
+        compression: Compression::None,
+        pending: Vec::new(),
+        pass_started: Instant::now(),
+    };
+    // The storage is only held while working, so that dropping it ends the scrubber.
+    while let Some(storage) = blobs.upgrade() {
+        let read = storage.scrub_next(&mut cursor, quarantine.as_deref()).await;
+        drop(storage);
+        let pause = match cursor.shard {
+            // A pass just ended.
+            0 => MIN_SCRUB_PASS.saturating_sub(cursor.pass_started.elapsed()),
+            _ => Duration::from_secs_f64(read as f64 / rate as f64),
+        };
+        tokio::time::sleep(pause).await;
+        if cursor.shard == 0 {
+            cursor.pass_started = Instant::now();
+        }
+    }
+}
+
+/// The blob files in a shard, skipping refcounts and unfinished writes.
+fn list_blobs(shard: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut blobs = Vec::new();
+    for entry in shard.read_dir()? {
+        let path = entry?.path();
+        if path.extension().is_none() {
+            blobs.push(path);
+        }
+    }
+    Ok(blobs)
+}
+
+fn compression_name(compression: Compression) -> String {
+    clap::ValueEnum::to_possible_value(&compression)
+        .unwrap()
+        .get_name()
+        .to_string()
+}
+
+/// Counts the blob files under `directory`, skipping refcounts and unfinished writes.
+fn count_blobs(directory: &Path) -> std::io::Result<BlobTotals> {
+    let mut totals = BlobTotals::default();
+    for entry in directory.read_dir()? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            let nested = count_blobs(&entry.path())?;
+            totals.blobs += nested.blobs;
+            totals.bytes += nested.bytes;
+        } else if file_type.is_file() && entry.path().extension().is_none() {
+            totals.blobs += 1;
+            totals.bytes += entry.metadata()?.len();
+        }
+    }
+    Ok(totals)
+}
+
+/// The checksum a blob file (or its refcount, etc.) is named after.
+fn checksum_of(path: &Path) -> Option<[u8; 32]> {
+    let shard = path.parent()?.file_name()?.to_str()?;
+    let stem = path.file_stem()?.to_str()?;
+    hex_to_byte_array(&format!("{shard}{stem}"))
+}
+
+/// Checks that a blob decompresses to content with the checksum it's named after.
+fn verify_blob(
+    path: &Path,
+    checksum: &[u8; 32],
+    compression: Compression,
+    dictionary: Option<&ZstdDictionary>,
+) -> std::io::Result<()> {
+    let file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(
+        &mut compression.blob_decoder(file, dictionary)?,
+        &mut hasher,
+    )?;
+    let actual: [u8; 32] = hasher.finalize().into();
+    match &actual == checksum {
+        true => Ok(()),
+        false => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("content has checksum {}", bytes_to_hex(&actual)),
+        )),
+    }
+}
+
 pub struct BlobStorage {
     locks: LockMap<[u8; 32]>,
+    counters: Arc<BlobCounters>,
     blobs: PathBuf,
+    staging: Option<PathBuf>,
+    fsync: FsyncPolicy,
+    track_access: bool,
+    /// Only present while the scrubber is running.
+    scrub: Mutex<Option<ScrubReport>>,
+    recent: RecentBlobs,
+    zstd_dict: Option<Arc<ZstdDictionary>>,
 }
 
 impl BlobStorage {
-    pub fn create(directory: PathBuf) -> std::io::Result<Self> {
+    pub fn create(directory: PathBuf, config: &StorageConfig) -> std::io::Result<Self> {
         std::fs::create_dir_all(&directory)?;
-        Ok(Self {
-            locks: LockMap::new(),
-            blobs: directory,
-        })
+        if let Some(staging) = &config.staging_dir {
+            std::fs::create_dir_all(staging)?;
+        }
+        let counters = Arc::<BlobCounters>::default();
+        let result = Self {
+            locks: LockMap::new(config.lock_timeout),
+            counters: counters.clone(),
+            blobs: directory.clone(),
+            staging: config.staging_dir.clone(),
+            fsync: config.fsync,
+            track_access: config.track_blob_access,
+            scrub: Mutex::new(None),
+            recent: RecentBlobs::new(config.recent_blobs, config.recent_blobs_ttl),
+            zstd_dict: config.zstd_dict.clone(),
+        };
+        result.create_shards()?;
+        std::thread::Builder::new()
+            .name("count-blobs".into())
+            .spawn(move || match count_blobs(&directory) {
+                Ok(totals) => _ = counters.initial.set(totals),
+                Err(e) => tracing::error!(error = %e, "failed to count blobs"),
+            })?;
+        Ok(result)
     }
 
-    fn path_to_blob(&self, sha256: &[u8; 32]) -> PathBuf {
+    /// Creates all the shard directories up front, so that writes never have to.
+    fn create_shards(&self) -> std::io::Result<()> {
+        for &compression in <Compression as clap::ValueEnum>::value_variants() {
+            let first_shard = self.path_to_blob(&[0; 32], compression);
+            let directory = first_shard.parent().unwrap().parent().unwrap();
+            for shard in 0..=u8::MAX {
+                std::fs::create_dir_all(directory.join(bytes_to_hex(&[shard])))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Blobs are keyed by the checksum of their decompressed contents, so each
+    /// compression gets a separate directory to keep differently encoded copies apart.
+    fn path_to_blob(&self, sha256: &[u8; 32], compression: Compression) -> PathBuf {
         let hex = bytes_to_hex(sha256);
+        let directory = match compression {
+            Compression::None => self.blobs.join("none"),
+            // Gzip blobs predate the others and thus live directly in the blob directory.
+            Compression::Gzip => self.blobs.clone(),
+            Compression::Zstd => self.blobs.join("zstd"),
+        };
 
-        self.blobs.join(&hex[0..2]).join(&hex[2..])
+        directory.join(&hex[0..2]).join(&hex[2..])
     }
 
-    pub async fn write(&self, sha256: &[u8; 32], data: &mut impl Read) -> std::io::Result<bool> {
-        let _guard = self.locks.lock_ref(sha256).await;
-        let path = self.path_to_blob(sha256);
-        let count_path = path.with_extension("count");
+    /// The shard directories of every compression.
+    fn shards(&self) -> impl Iterator<Item = (Compression, PathBuf)> + '_ {
+        <Compression as clap::ValueEnum>::value_variants()
+            .iter()
+            .flat_map(move |&compression| {
+                let first_shard = self.path_to_blob(&[0; 32], compression);
+                let directory = first_shard.parent().unwrap().parent().unwrap().to_owned();
+                (0..=u8::MAX)
+                    .map(move |shard| (compression, directory.join(bytes_to_hex(&[shard]))))
+            })
+    }
+
+    /// Stores a blob unless it already exists, in which case its refcount is bumped
+    /// without reading its data at all. Returns the compression it's stored with and
+    /// whether it was newly created.
+    ///
+    /// `prepare` picks the compression and provides the data, given which compressions
+    /// the blob is already stored with. It's called while holding the blob's lock, so
+    /// writers of the same content queue up behind the first one, then find its blob
+    /// stored and skip any compression `prepare` would otherwise have to do to decide.
+    pub async fn write<R: Read + Send>(
+        &self,
+        sha256: &[u8; 32],
+        prepare: impl FnOnce(&dyn Fn(Compression) -> bool) -> std::io::Result<(Compression, R)> + Send,
+    ) -> Result<(Compression, bool), StorageError> {
+        let _guard = self.locks.write_ref(sha256).await?;
+        Ok(blocking(|| {
+            let (compression, mut data) =
+                prepare(&|compression| self.path_to_blob(sha256, compression).exists())?;
+            let created = self.write_locked(sha256, compression, &mut data)?;
+            self.recent.insert(sha256, compression);
+            std::io::Result::Ok((compression, created))
+        })?)
+    }
+
+    /// Adds a reference to a blob written moments ago, returning its compression, or
+    /// `None` if there's no such blob with `compression` (any if it's `None`). Unlike
+    /// [`Self::write`] this doesn't need to look for the blob nor its data.
+    pub async fn reuse_recent(
+        &self,
+        sha256: &[u8; 32],
+        compression: Option<Compression>,
+    ) -> Result<Option<Compression>, StorageError> {
+        let Some(recent) = self
+            .recent
+            .get(sha256)
+            .filter(|&recent| compression.is_none_or(|compression| compression == recent))
+        else {
+            return Ok(None);
+        };
+
+        let _guard = self.locks.write_ref(sha256).await?;
+        Ok(blocking(|| match self.incref_locked(sha256, recent) {
+            Ok(()) => {
+                self.counters.deduplicated.fetch_add(1, Ordering::Relaxed);
+                Ok(Some(recent))
+            }
+            // Removed behind the server's back, e.g. by gc.
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                self.recent.remove(sha256);
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        })?)
+    }
+
+    fn write_locked(
+        &self,
+        sha256: &[u8; 32],
+        compression: Compression,
+        data: &mut (impl Read + Send),
+    ) -> std::io::Result<bool> {
+        let path = self.path_to_blob(sha256, compression);
         if !path.exists() {
             let tmp_path = path.with_extension("tmp");
-            std::fs::create_dir_all(path.parent().unwrap())?;
-            std::io::copy(data, &mut std::fs::File::create(&tmp_path)?)?;
-            std::fs::rename(tmp_path, path)?;
-            std::fs::write(count_path, b"1").map(|_| true)
+            match &self.staging {
+                Some(staging) => {
+                    let staging_path = staging.join(tmp_path.file_name().unwrap());
+                    remove_on_error(&staging_path, || {
+                        let mut file = std::fs::File::create(&staging_path)?;
+                        pipelined_copy(data, &mut file)?;
+                        self.fsync.sync_file(&file)
+                    })?;
+                    self.move_from_staging(&staging_path, &tmp_path, &path)?;
+                }
+                None => remove_on_error(&tmp_path, || {
+                    let mut file = std::fs::File::create(&tmp_path)?;
+                    pipelined_copy(data, &mut file)?;
+                    self.fsync.sync_file(&file)?;
+                    std::fs::rename(&tmp_path, &path)
+                })?,
+            }
+            self.fsync.sync_parent(&path)?;
+            self.write_count(&path, 1)?;
+            self.counters
+                .record_created(blob_metadata(&path).map_or(0, |metadata| metadata.len()));
+            Ok(true)
         } else {
-            std::fs::write(
-                &count_path,
-                (read_usize(&count_path)? + 1).to_string(),
-            )
-            .map(|_| false)
+            self.incref_locked(sha256, compression)?;
+            self.counters.deduplicated.fetch_add(1, Ordering::Relaxed);
+            Ok(false)
         }
     }
 
-    pub fn read(&self, sha256: &[u8; 32]) -> std::io::Result<Vec<u8>> {
-        std::fs::read(self.path_to_blob(sha256))
+    /// Adds a reference to a blob for a file linked to it without uploading its content,
+    /// returning the compression it's stored with. Only a blob with `compression` will
+    /// do if it's given. Fails with `NotFound` if there's no such blob or with the error
+    /// of `check`, which is given the blob to check against what the client declared.
+    pub async fn link(
+        &self,
+        sha256: &[u8; 32],
+        compression: Option<Compression>,
+        check: impl FnOnce(Content, Compression) -> Result<(), StorageError> + Send,
+    ) -> Result<Compression, StorageError> {
+        use clap::ValueEnum;
+
+        let _guard = self.locks.write_ref(sha256).await?;
+        blocking(|| {
+            let compression = match compression {
+                Some(compression) => Some(compression).filter(|&c| self.exists(sha256, c)),
+                None => Compression::value_variants()
+                    .iter()
+                    .copied()
+                    .find(|&c| self.exists(sha256, c)),
+            }
+            .ok_or_else(|| {
+                StorageError::NotFound(format!(
+                    "Content with checksum {} is not stored",
+                    bytes_to_hex(sha256)
+                ))
+            })?;
+            let file = std::fs::File::open(self.path_to_blob(sha256, compression))?;
+            let len = file.metadata()?.len() as usize;
+            check(Content::File { file: &file, len }, compression)?;
+            self.incref_locked(sha256, compression)?;
+            self.counters.deduplicated.fetch_add(1, Ordering::Relaxed);
+            Ok(compression)
+        })
+    }
+
+    /// Adds a reference to a blob that is already stored, failing with `NotFound` if it
+    /// isn't, e.g. for a file copied without its content.
+    pub async fn incref(
+        &self,
+        sha256: &[u8; 32],
+        compression: Compression,
+    ) -> Result<(), StorageError> {
+        let _guard = self.locks.write_ref(sha256).await?;
+        Ok(blocking(|| self.incref_locked(sha256, compression))?)
+    }
+
+    fn incref_locked(&self, sha256: &[u8; 32], compression: Compression) -> std::io::Result<()> {
+        let path = self.path_to_blob(sha256, compression);
+        self.write_count(&path, read_usize(&path.with_extension("count"))? + 1)
+    }
+
+    /// Replaces the refcount of the blob at `path`. It's written through the blob's
+    /// temporary file, which is free since blobs are only written under the same lock.
+    fn write_count(&self, path: &Path, count: usize) -> std::io::Result<()> {
+        self.fsync.write(
+            &path.with_extension("tmp"),
+            &path.with_extension("count"),
+            count.to_string(),
+        )
+    }
+
+    /// Moves a staged blob into place, copying it over if the staging directory is
+    /// on a different filesystem.
+    fn move_from_staging(
+        &self,
+        staging_path: &Path,
+        tmp_path: &Path,
+        path: &Path,
+    ) -> std::io::Result<()> {
+        move_staged(self.fsync, staging_path, tmp_path, path, |from, to| {
+            std::fs::rename(from, to)
+        })
+    }
+
+    pub fn counters(&self) -> &BlobCounters {
+        &self.counters
     }
 
-    pub fn metadata(&self, sha256: &[u8; 32]) -> std::io::Result<Metadata> {
-        self.path_to_blob(sha256).metadata()
+    /// Number of blobs currently holding a lock entry.
+    pub fn lock_count(&self) -> usize {
+        self.locks.len()
     }
 
-    pub async fn decref(&self, sha256: &[u8; 32]) -> std::io::Result<()> {
-        let _guard = self.locks.lock_ref(sha256).await;
-        let path = self.path_to_blob(sha256);
+    /// The id of the dictionary a blob is compressed with, if it's a zstd blob using one.
+    pub fn zstd_dictionary(
+        &self,
+        sha256: &[u8; 32],
+        compression: Compression,
+    ) -> std::io::Result<Option<u32>> {
+        match compression {
+            Compression::Zstd => {
+                zstd_dictionary_of(std::fs::File::open(self.path_to_blob(sha256, compression))?)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    pub fn exists(&self, sha256: &[u8; 32], compression: Compression) -> bool {
+        self.path_to_blob(sha256, compression).exists()
+    }
+
+    /// The path of a blob some file refers to, which the store is corrupt without.
+    fn referenced_blob(
+        &self,
+        sha256: &[u8; 32],
+        compression: Compression,
+    ) -> Result<PathBuf, StorageError> {
+        let path = self.path_to_blob(sha256, compression);
+        match blob_metadata(&path) {
+            Ok(_) => Ok(path),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(StorageError::Corrupt(
+                format!("blob {} is missing", bytes_to_hex(sha256)),
+            )),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Opens a blob for reading, the open file stays readable even if the blob is
+    /// removed in the meantime.
+    pub fn open(
+        &self,
+        sha256: &[u8; 32],
+        compression: Compression,
+    ) -> Result<std::fs::File, StorageError> {
+        let path = self.referenced_blob(sha256, compression)?;
+        let file = std::fs::File::open(path)?;
+        if self.track_access {
+            file.set_times(std::fs::FileTimes::new().set_accessed(std::time::SystemTime::now()))?;
+        }
+        Ok(file)
+    }
+
+    /// Returns the size of a blob, when it was created and, if access tracking
+    /// is enabled, when it was last read.
+    ///
+    /// Blobs are never modified after being written, so their mtime is their creation time.
+    pub fn info(
+        &self,
+        sha256: &[u8; 32],
+        compression: Compression,
+    ) -> Result<BlobInfo, StorageError> {
+        let metadata = blob_metadata(&self.referenced_blob(sha256, compression)?)?;
+        Ok(BlobInfo {
+            size: metadata.len(),
+            created: metadata.modified()?.into(),
+            accessed: match self.track_access {
+                true => Some(metadata.accessed()?.into()),
+                false => None,
+            },
+        })
+    }
+
+    /// Removes the blobs missing from `refs`, corrects refcounts that disagree with it
+    /// and removes whatever interrupted writes left behind.
+    ///
+    /// Must not run concurrently with anything that writes blobs.
+    pub fn collect_garbage(
+        &self,
+        refs: &HashMap<([u8; 32], Compression), usize>,
+        dry_run: bool,
+        report: &mut GcReport,
+    ) -> std::io::Result<()> {
+        let remove = |path: &Path| match dry_run {
+            true => Ok(()),
+            false => std::fs::remove_file(path),
+        };
+
+        for (compression, shard) in self.shards() {
+            for entry in shard.read_dir()? {
+                let path = entry?.path();
+                let Some(checksum) = checksum_of(&path) else {
+                    continue;
+                };
+                let blob_exists = || self.path_to_blob(&checksum, compression).exists();
+
+                match path.extension().and_then(|extension| extension.to_str()) {
+                    None => {
+                        let count_path = path.with_extension("count");
+                        match refs.get(&(checksum, compression)) {
+                            None => {
+                                let size = blob_metadata(&path)?.len();
+                                report.freed_bytes += size;
+                                remove(&path)?;
+                                match remove(&count_path) {
+                                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                                        return Err(e)
+                                    }
+                                    _ => (),
+                                }
+                                report.removed_blobs += 1;
+                                if !dry_run {
+                                    self.counters.record_removed(size);
+                                }
+                            }
+                            Some(&count) => {
+                                if read_usize(&count_path).ok() != Some(count) {
+                                    if !dry_run {
+                                        self.write_count(&path, count)?;
+                                    }
+                                    report.fixed_refcounts += 1;
+                                }
+                            }
+                        }
+                    }
+                    Some("tmp") => {
+                        remove(&path)?;
+                        report.removed_leftovers += 1;
+                    }
+                    Some("count") if !blob_exists() => {
+                        remove(&path)?;
+                        report.removed_leftovers += 1;
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        if let Some(staging) = &self.staging {
+            for entry in staging.read_dir()? {
+                let path = entry?.path();
+                if path.extension().is_some_and(|extension| extension == "tmp") {
+                    remove(&path)?;
+                    report.removed_leftovers += 1;
+                }
+            }
+        }
+
+        report.missing_blobs = refs
+            .keys()
+            .filter(|(checksum, compression)| !self.exists(checksum, *compression))
+            .count();
+        Ok(())
+    }
+
+    /// Re-hashes every blob, reporting the ones that don't match their checksum and,
+    /// with `quarantine`, moving them (and their refcounts) there.
+    pub fn verify(&self, quarantine: Option<&Path>) -> std::io::Result<FsckReport> {
+        if let Some(quarantine) = quarantine {
+            std::fs::create_dir_all(quarantine)?;
+        }
+        let shards = self.shards().collect::<Vec<_>>();
+        let next_shard = AtomicUsize::new(0);
+        let report = Mutex::new(FsckReport::default());
+
+        let verify_shard = |compression: Compression, shard: &Path| -> std::io::Result<()> {
+            for entry in shard.read_dir()? {
+                let path = entry?.path();
+                if path.extension().is_some() {
+                    continue;
+                }
+                let Some(checksum) = checksum_of(&path) else {
+                    continue;
+                };
+
+                let result = verify_blob(&path, &checksum, compression, self.zstd_dict.as_deref());
+                report.lock().unwrap().checked_blobs += 1;
+                let Err(e) = result else {
+                    continue;
+                };
+                let info = self.info(&checksum, compression).ok();
+                let created = info
+                    .as_ref()
+                    .map(|info| info.created.to_rfc3339())
+                    .unwrap_or_else(|| "unknown".into());
+                tracing::error!(path = %path.display(), created, error = %e, "corrupt blob");
+                report.lock().unwrap().corrupt_blobs += 1;
+
+                if let Some(quarantine) = quarantine {
+                    self.quarantine(&path, &checksum, compression, quarantine)?;
+                    report.lock().unwrap().quarantined_blobs += 1;
+                }
+            }
+            Ok(())
+        };
+
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        std::thread::scope(|scope| {
+            let workers = (0..threads)
+                .map(|_| {
+                    scope.spawn(|| -> std::io::Result<()> {
+                        loop {
+                            let Some((compression, shard)) =
+                                shards.get(next_shard.fetch_add(1, Ordering::Relaxed))
+                            else {
+                                return Ok(());
+                            };
+                            verify_shard(*compression, shard)?;
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            workers
+                .into_iter()
+                .try_for_each(|worker| worker.join().unwrap())
+        })?;
+        Ok(report.into_inner().unwrap())
+    }
+
+    /// Moves a corrupt blob and its refcount to `quarantine`, files using it fail to be
+    /// read from then on instead of serving corrupt content.
+    fn quarantine(
+        &self,
+        path: &Path,
+        checksum: &[u8; 32],
+        compression: Compression,
+        quarantine: &Path,
+    ) -> std::io::Result<()> {
+        let size = blob_metadata(path)?.len();
+        let dest = quarantine.join(format!(
+            "{}-{}",
+            compression_name(compression),
+            bytes_to_hex(checksum)
+        ));
+        std::fs::rename(path, &dest)?;
+        match std::fs::rename(path.with_extension("count"), dest.with_extension("count")) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+        self.counters.record_removed(size);
+        Ok(())
+    }
+
+    /// Starts re-verifying all blobs in the background, reading no more than `rate`
+    /// bytes per second. Corrupt ones are moved to `quarantine` if given, and are
+    /// reported by [`BlobStorage::scrub_report`] either way.
+    pub fn start_scrubber(
+        self: &Arc<Self>,
+        rate: u64,
+        quarantine: Option<PathBuf>,
+    ) -> std::io::Result<()> {
+        if let Some(quarantine) = &quarantine {
+            std::fs::create_dir_all(quarantine)?;
+        }
+        *self.scrub.lock().unwrap() = Some(ScrubReport::default());
+        tokio::spawn(scrubber(Arc::downgrade(self), rate, quarantine));
+        Ok(())
+    }
+
+    /// What the scrubber has found so far, `None` if it isn't running.
+    pub fn scrub_report(&self) -> Option<ScrubReport> {
+        self.scrub.lock().unwrap().clone()
+    }
+
+    fn update_scrub_report(&self, update: impl FnOnce(&mut ScrubReport)) {
+        if let Some(report) = self.scrub.lock().unwrap().as_mut() {
+            update(report);
+        }
+    }
+
+    /// Takes the next step of the scrubber's pass, which is verifying a blob or listing
+    /// the next shard. Returns how many bytes of blobs were read.
+    async fn scrub_next(&self, cursor: &mut ScrubCursor, quarantine: Option<&Path>) -> u64 {
+        let Some(path) = cursor.pending.pop() else {
+            match self.shards().nth(cursor.shard) {
+                Some((compression, shard)) => {
+                    if cursor.shard == 0 {
+                        self.update_scrub_report(|report| report.pass_started = Some(Utc::now()));
+                    }
+                    cursor.shard += 1;
+                    cursor.compression = compression;
+                    cursor.pending = blocking(|| list_blobs(&shard)).unwrap_or_else(|e| {
+                        tracing::error!(
+                            shard = %shard.display(),
+                            error = %e,
+                            "scrubber failed to list blobs"
+                        );
+                        Vec::new()
+                    });
+                }
+                None => {
+                    cursor.shard = 0;
+                    self.update_scrub_report(|report| {
+                        report.passes += 1;
+                        report.last_pass_finished = Some(Utc::now());
+                    });
+                }
+            }
+            return 0;
+        };
+        let Some(checksum) = checksum_of(&path) else {
+            return 0;
+        };
+        let compression = cursor.compression;
+
+        let size = blob_metadata(&path).map_or(0, |metadata| metadata.len());
+        match blocking(|| verify_blob(&path, &checksum, compression, self.zstd_dict.as_deref())) {
+            // Removed in the meantime.
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return 0,
+            Ok(()) => (),
+            Err(_) => {
+                self.scrub_corrupt(&path, &checksum, compression, quarantine)
+                    .await
+            }
+        }
+        self.update_scrub_report(|report| {
+            report.checked_blobs += 1;
+            report.checked_bytes += size;
+        });
+        size
+    }
+
+    /// Records a blob the scrubber found corrupt and quarantines it if asked to. It's
+    /// verified again while holding its lock first, since it may have been removed
+    /// and written anew in the meantime.
+    async fn scrub_corrupt(
+        &self,
+        path: &Path,
+        checksum: &[u8; 32],
+        compression: Compression,
+        quarantine: Option<&Path>,
+    ) {
+        // Should the lock time out, the blob is looked at again on the next pass.
+        let Ok(_guard) = self.locks.write_ref(checksum).await else {
+            return;
+        };
+        let error = match blocking(|| {
+            verify_blob(path, checksum, compression, self.zstd_dict.as_deref())
+        }) {
+            Ok(()) => return,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+            Err(e) => e,
+        };
+        let quarantined = quarantine.is_some_and(|quarantine| {
+            blocking(|| self.quarantine(path, checksum, compression, quarantine))
+                .inspect_err(|e| {
+                    tracing::error!(
+                        checksum = bytes_to_hex(checksum),
+                        error = %e,
+                        "failed to quarantine blob"
+                    )
+                })
+                .is_ok()
+        });
+        tracing::error!(
+            checksum = bytes_to_hex(checksum),
+            compression = compression_name(compression),
+            %error,
+            quarantined,
+            "corrupt blob"
+        );
+
+        let corrupt = CorruptBlob {
+            checksum: bytes_to_hex(checksum),
+            compression: compression_name(compression),
+            found: Utc::now(),
+            error: error.to_string(),
+            quarantined,
+        };
+        self.update_scrub_report(|report| {
+            report.quarantined_blobs += quarantined as u64;
+            match report.corrupt.iter().position(|known| {
+                known.checksum == corrupt.checksum && known.compression == corrupt.compression
+            }) {
+                Some(index) => _ = report.corrupt.remove(index),
+                None => report.corrupt_blobs += 1,
+            }
+            report.corrupt.push(corrupt);
+            if report.corrupt.len() > MAX_REPORTED_CORRUPT {
+                report.corrupt.remove(0);
+            }
+        });
+    }
+
+    pub async fn decref(
+        &self,
+        sha256: &[u8; 32],
+        compression: Compression,
+    ) -> Result<(), StorageError> {
+        let _guard = self.locks.write_ref(sha256).await?;
+        Ok(blocking(|| self.decref_locked(sha256, compression))?)
+    }
+
+    fn decref_locked(&self, sha256: &[u8; 32], compression: Compression) -> std::io::Result<()> {
+        let path = self.path_to_blob(sha256, compression);
         let count_path = path.with_extension("count");
-        let refs = read_usize(&count_path)?;
+        let refs = match read_usize(&count_path) {
+            // Quarantined by fsck, there's nothing left to release.
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !path.exists() => return Ok(()),
+            result => result?,
+        };
 
         if refs == 1 {
+            self.recent.remove(sha256);
+            let size = blob_metadata(&path)?.len();
             std::fs::remove_file(count_path)?;
-            std::fs::remove_file(path)
+            std::fs::remove_file(path)?;
+            self.counters.record_removed(size);
+            Ok(())
         } else {
-            std::fs::write(count_path, (refs - 1).to_string())
+            self.write_count(&path, refs - 1)
         }
     }
 }
+
+/// Implements [`BlobStorage::move_from_staging`], with the rename out of the staging
+/// directory replaceable so that tests can pretend it's on another filesystem.
+fn move_staged(
+    fsync: FsyncPolicy,
+    staging_path: &Path,
+    tmp_path: &Path,
+    path: &Path,
+    rename: impl FnOnce(&Path, &Path) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    match rename(staging_path, path) {
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+            let result = remove_on_error(tmp_path, || {
+                std::fs::copy(staging_path, tmp_path)?;
+                fsync.sync_file(&std::fs::File::open(tmp_path)?)?;
+                std::fs::rename(tmp_path, path)
+            });
+            std::fs::remove_file(staging_path)?;
+            result
+        }
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recent_blobs_are_bounded() {
+        let recent = RecentBlobs::new(2, Duration::from_secs(60));
+        recent.insert(&[1; 32], Compression::Gzip);
+        recent.insert(&[2; 32], Compression::None);
+        recent.insert(&[3; 32], Compression::Gzip);
+        assert_eq!(recent.get(&[1; 32]), None);
+        assert_eq!(recent.get(&[2; 32]), Some(Compression::None));
+        assert_eq!(recent.get(&[3; 32]), Some(Compression::Gzip));
+
+        // Remembering a blob again doesn't let it take up two places.
+        recent.insert(&[3; 32], Compression::Gzip);
+        recent.insert(&[3; 32], Compression::Gzip);
+        assert_eq!(recent.get(&[3; 32]), Some(Compression::Gzip));
+        assert!(recent.inner.lock().unwrap().order.len() <= 2);
+
+        recent.remove(&[3; 32]);
+        assert_eq!(recent.get(&[3; 32]), None);
+    }
+
+    #[test]
+    fn recent_blobs_expire() {
+        let recent = RecentBlobs::new(10, Duration::from_millis(50));
+        recent.insert(&[1; 32], Compression::Gzip);
+        assert_eq!(recent.get(&[1; 32]), Some(Compression::Gzip));
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(recent.get(&[1; 32]), None);
+
+        recent.insert(&[2; 32], Compression::Gzip);
+        assert!(!recent.inner.lock().unwrap().blobs.contains_key(&[1; 32]));
+    }
+
+    #[test]
+    fn disabled_recent_blobs_remember_nothing() {
+        let recent = RecentBlobs::new(0, Duration::from_secs(60));
+        recent.insert(&[1; 32], Compression::Gzip);
+        assert_eq!(recent.get(&[1; 32]), None);
+    }
+
+    #[test]
+    fn staged_blobs_are_copied_across_filesystems() {
+        let dir = tempfile::tempdir().unwrap();
+        let staging_path = dir.path().join("staged.tmp");
+        let tmp_path = dir.path().join("blob.tmp");
+        let path = dir.path().join("blob");
+        std::fs::write(&staging_path, "data").unwrap();
+
+        move_staged(
+            FsyncPolicy::Data,
+            &staging_path,
+            &tmp_path,
+            &path,
+            |_, _| Err(std::io::ErrorKind::CrossesDevices.into()),
+        )
+        .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"data");
+        assert!(!staging_path.exists());
+        assert!(!tmp_path.exists());
+    }
+
+    #[test]
+    fn failed_copies_leave_nothing_behind() {
+        let dir = tempfile::tempdir().unwrap();
+        let staging_path = dir.path().join("staged.tmp");
+        let tmp_path = dir.path().join("missing/blob.tmp");
+        let path = dir.path().join("missing/blob");
+        std::fs::write(&staging_path, "data").unwrap();
+
+        move_staged(
+            FsyncPolicy::None,
+            &staging_path,
+            &tmp_path,
+            &path,
+            |_, _| Err(std::io::ErrorKind::CrossesDevices.into()),
+        )
+        .unwrap_err();
+        assert!(!staging_path.exists());
+        assert!(!path.exists());
+    }
+}
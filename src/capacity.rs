@@ -0,0 +1,139 @@
+//! Watching the free space of the data directory's filesystem, so that uploads can be
+//! refused up front instead of failing halfway through once it runs out.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::Serialize;
+
+use crate::util::blocking;
+
+/// How often the free space is looked at, uploads arriving in between may use up more.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(clap::Args)]
+pub struct CapacityConfig {
+    /// Answer uploads with 507 Insufficient Storage while less than this many bytes
+    /// are free on the data directory's filesystem.
+    #[clap(long)]
+    pub min_free_space: Option<u64>,
+    /// Like `--min-free-space`, for the number of free inodes, each file taking up one.
+    #[clap(long)]
+    pub min_free_inodes: Option<u64>,
+}
+
+/// Space on a filesystem, counting only what unprivileged users can use as free.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct DiskSpace {
+    pub free_bytes: u64,
+    pub total_bytes: u64,
+    pub free_inodes: u64,
+    pub total_inodes: u64,
+}
+
+// The field types of `statvfs` differ between platforms, some are `u64` already.
+#[cfg(target_family = "unix")]
+#[allow(clippy::unnecessary_cast)]
+fn disk_space(path: &Path) -> std::io::Result<DiskSpace> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `path` is NUL-terminated and `stat` is only read once `statvfs` has
+    //         filled it in.
+    let stat = unsafe {
+        if libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        stat.assume_init()
+    };
+    Ok(DiskSpace {
+        free_bytes: stat.f_bavail as u64 * stat.f_frsize as u64,
+        total_bytes: stat.f_blocks as u64 * stat.f_frsize as u64,
+        free_inodes: stat.f_favail as u64,
+        total_inodes: stat.f_files as u64,
+    })
+}
+
+#[cfg(not(target_family = "unix"))]
+fn disk_space(_path: &Path) -> std::io::Result<DiskSpace> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+async fn watcher(directory: PathBuf, current: Arc<Mutex<Option<DiskSpace>>>) {
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+    loop {
+        interval.tick().await;
+
+        let space = blocking(|| disk_space(&directory));
+        if let Err(e) = &space {
+            tracing::warn!(error = %e, "failed to check free space");
+        }
+        *current.lock().unwrap() = space.ok();
+    }
+}
+
+/// Keeps track of the free space of the filesystem holding the data directory.
+pub struct CapacityWatcher {
+    min_free_space: Option<u64>,
+    min_free_inodes: Option<u64>,
+    /// `None` if the last check failed, uploads aren't refused then.
+    current: Arc<Mutex<Option<DiskSpace>>>,
+    worker: tokio::task::AbortHandle,
+}
+
+impl Drop for CapacityWatcher {
+    fn drop(&mut self) {
+        self.worker.abort();
+    }
+}
+
+impl CapacityWatcher {
+    /// Starts watching `directory`, failing if its free space can't be determined
+    /// even though a minimum is configured.
+    pub fn start(directory: &Path, config: &CapacityConfig) -> std::io::Result<Self> {
+        let current = match disk_space(directory) {
+            Ok(space) => Some(space),
+            Err(e) if config.min_free_space.is_some() || config.min_free_inodes.is_some() => {
+                return Err(e)
+            }
+            Err(_) => None,
+        };
+        let current = Arc::new(Mutex::new(current));
+        let worker = tokio::spawn(watcher(directory.to_owned(), current.clone())).abort_handle();
+        Ok(Self {
+            min_free_space: config.min_free_space,
+            min_free_inodes: config.min_free_inodes,
+            current,
+            worker,
+        })
+    }
+
+    /// The space as of the last check, `None` if it failed.
+    pub fn current(&self) -> Option<DiskSpace> {
+        *self.current.lock().unwrap()
+    }
+
+    /// Why uploads should be refused at the moment, if they should.
+    pub fn shortage(&self) -> Option<String> {
+        let space = self.current()?;
+        if let Some(min) = self.min_free_space.filter(|&min| space.free_bytes < min) {
+            return Some(format!(
+                "Insufficient storage space: {} bytes free, below the minimum of {min}",
+                space.free_bytes
+            ));
+        }
+        if let Some(min) = self.min_free_inodes.filter(|&min| space.free_inodes < min) {
+            return Some(format!(
+                "Insufficient storage space: {} inodes free, below the minimum of {min}",
+                space.free_inodes
+            ));
+        }
+        None
+    }
+}
@@ -0,0 +1,286 @@
+//! An async client for the filetracker protocol as spoken by this server.
+
+use std::io::Read;
+
+use axum::http::{HeaderMap, Method, Request, StatusCode};
+use base64::prelude::*;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use http_body_util::{BodyExt, Full};
+use hyper_util::{
+    client::legacy::{connect::HttpConnector, Client},
+    rt::TokioExecutor,
+};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    headers,
+    storage::{Compression, ExtraDigests, FileMetadata},
+    util::{
+        bytes_to_hex, hex_to_byte_array, is_gzip_coding, is_zstd_coding, percent_encode_component,
+    },
+};
+
+#[derive(Debug)]
+pub enum ClientError {
+    Http(hyper_util::client::legacy::Error),
+    Body(Box<dyn std::error::Error + Send + Sync>),
+    Io(std::io::Error),
+    /// The server responded with a non-success status code.
+    Status(StatusCode, String),
+    /// The server's response was missing a header or had a malformed one.
+    InvalidResponse(&'static str),
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::Http(e) => write!(f, "http error: {e}"),
+            ClientError::Body(e) => write!(f, "error while reading response body: {e}"),
+            ClientError::Io(e) => write!(f, "io error: {e}"),
+            ClientError::Status(status, message) => {
+                write!(f, "server returned {status}: {message}")
+            }
+            ClientError::InvalidResponse(what) => write!(f, "invalid response from server: {what}"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<std::io::Error> for ClientError {
+    fn from(value: std::io::Error) -> Self {
+        ClientError::Io(value)
+    }
+}
+
+/// An entry returned by [`FiletrackerClient::list`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListEntry {
+    pub path: String,
+    pub version: DateTime<Utc>,
+    pub decompressed_size: usize,
+}
+
+pub struct FiletrackerClient {
+    base_url: String,
+    token: Option<String>,
+    client: Client<HttpConnector, Full<Bytes>>,
+}
+
+fn version_query(version: Option<DateTime<Utc>>) -> String {
+    match version {
+        Some(version) => format!(
+            "?last_modified={}",
+            percent_encode_component(&version.to_rfc2822(), false)
+        ),
+        None => String::new(),
+    }
+}
+
+fn parse_header<T>(
+    headers: &HeaderMap,
+    name: &'static str,
+    parse: impl FnOnce(&str) -> Option<T>,
+) -> Result<T, ClientError> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse)
+        .ok_or(ClientError::InvalidResponse(name))
+}
+
+/// Like [`parse_header`], but a missing header isn't an error.
+fn parse_optional_header<T>(
+    headers: &HeaderMap,
+    name: &'static str,
+    parse: impl FnOnce(&str) -> Option<T>,
+) -> Result<Option<T>, ClientError> {
+    headers
+        .contains_key(name)
+        .then(|| parse_header(headers, name, parse))
+        .transpose()
+}
+
+/// Reconstructs file metadata from the headers of a GET or HEAD response.
+///
+/// The server always sends a `Content-Disposition`, even if no filename was declared,
+/// so `filename` is left unset.
+fn parse_metadata(headers: &HeaderMap) -> Result<FileMetadata, ClientError> {
+    Ok(FileMetadata {
+        version: parse_header(headers, headers::LAST_MODIFIED, |value| {
+            DateTime::parse_from_rfc2822(value).ok().map(|x| x.to_utc())
+        })?,
+        checksum: parse_header(headers, headers::SHA256_CHECKSUM, hex_to_byte_array)?,
+        compression: match headers.get("Content-Encoding") {
+            Some(value) if is_gzip_coding(value.as_bytes()) => Compression::Gzip,
+            Some(value) if is_zstd_coding(value.as_bytes()) => Compression::Zstd,
+            Some(_) => return Err(ClientError::InvalidResponse("Content-Encoding")),
+            None => Compression::None,
+        },
+        decompressed_size: parse_header(headers, headers::LOGICAL_SIZE, |value| {
+            value.parse().ok()
+        })?,
+        filename: None,
+        extra_digests: ExtraDigests {
+            md5: parse_optional_header(headers, headers::CONTENT_MD5, |value| {
+                BASE64_STANDARD.decode(value).ok()?.try_into().ok()
+            })?,
+            crc32: parse_optional_header(headers, headers::X_CRC32, |value| {
+                u32::from_str_radix(value, 16).ok()
+            })?,
+        },
+        // The server decompresses blobs using its dictionary before sending them.
+        zstd_dictionary: None,
+    })
+}
+
+impl FiletrackerClient {
+    /// Creates a client talking to the server at `base_url`, e.g. `http://127.0.0.1:9999`.
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self {
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            token: None,
+            client: Client::builder(TokioExecutor::new()).build_http(),
+        }
+    }
+
+    /// Sends `token` as a bearer token with every request, for servers with access control.
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    async fn send(
+        &self,
+        request: axum::http::request::Builder,
+        body: impl Into<Bytes>,
+    ) -> Result<(HeaderMap, Bytes), ClientError> {
+        let response = self
+            .client
+            .request(request.body(Full::new(body.into())).unwrap())
+            .await
+            .map_err(ClientError::Http)?;
+        let (parts, body) = response.into_parts();
+        let body = body
+            .collect()
+            .await
+            .map_err(|e| ClientError::Body(e.into()))?
+            .to_bytes();
+        if !parts.status.is_success() {
+            return Err(ClientError::Status(
+                parts.status,
+                String::from_utf8_lossy(&body).into_owned(),
+            ));
+        }
+        Ok((parts.headers, body))
+    }
+
+    fn request(
+        &self,
+        method: Method,
+        endpoint: &str,
+        path: &str,
+        query: &str,
+    ) -> axum::http::request::Builder {
+        let request = Request::builder().method(method).uri(format!(
+            "{}/{endpoint}/{}{query}",
+            self.base_url,
+            percent_encode_component(path.trim_start_matches('/'), true)
+        ));
+        match &self.token {
+            Some(token) => request.header("Authorization", format!("Bearer {token}")),
+            None => request,
+        }
+    }
+
+    /// Downloads a file, returning its metadata and decompressed contents.
+    pub async fn get(&self, path: &str) -> Result<(FileMetadata, Vec<u8>), ClientError> {
+        // Compressed files are smaller on the wire, and decompressed here anyway.
+        let request = self
+            .request(Method::GET, "files", path, "")
+            .header("Accept-Encoding", "zstd, gzip");
+        let (headers, body) = self.send(request, Bytes::new()).await?;
+        let metadata = parse_metadata(&headers)?;
+        let mut content = Vec::with_capacity(metadata.decompressed_size);
+        metadata
+            .compression
+            .decoder(&body[..])?
+            .read_to_end(&mut content)?;
+        Ok((metadata, content))
+    }
+
+    pub async fn head(&self, path: &str) -> Result<FileMetadata, ClientError> {
+        let request = self
+            .request(Method::HEAD, "files", path, "")
+            .header("Accept-Encoding", "zstd, gzip");
+        let (headers, _) = self.send(request, Bytes::new()).await?;
+        parse_metadata(&headers)
+    }
+
+    /// Uploads a file, compressing it locally so the server can store it as is.
+    /// Returns the version the server assigned to the file.
+    pub async fn put(
+        &self,
+        path: &str,
+        content: &[u8],
+        version: Option<DateTime<Utc>>,
+    ) -> Result<DateTime<Utc>, ClientError> {
+        let checksum: [u8; 32] = Sha256::new().chain_update(content).finalize().into();
+        let mut compressed = Vec::new();
+        flate2::read::GzEncoder::new(content, flate2::Compression::default())
+            .read_to_end(&mut compressed)?;
+
+        let request = self
+            .request(Method::PUT, "files", path, &version_query(version))
+            .header("Content-Encoding", "gzip")
+            .header(headers::SHA256_CHECKSUM, bytes_to_hex(&checksum))
+            .header(headers::LOGICAL_SIZE, content.len());
+        let (headers, _) = self.send(request, compressed).await?;
+        parse_header(&headers, headers::LAST_MODIFIED, |value| {
+            DateTime::parse_from_rfc2822(value).ok().map(|x| x.to_utc())
+        })
+    }
+
+    pub async fn delete(
+        &self,
+        path: &str,
+        version: Option<DateTime<Utc>>,
+    ) -> Result<(), ClientError> {
+        let request = self.request(Method::DELETE, "files", path, &version_query(version));
+        self.send(request, Bytes::new()).await.map(|_| ())
+    }
+
+    /// Lists all files under `path` with a version not newer than `version`.
+    pub async fn list(
+        &self,
+        path: &str,
+        version: Option<DateTime<Utc>>,
+    ) -> Result<Vec<ListEntry>, ClientError> {
+        let request = self.request(Method::GET, "list", path, &version_query(version));
+        let (_, body) = self.send(request, Bytes::new()).await?;
+        let body = std::str::from_utf8(&body)
+            .map_err(|_| ClientError::InvalidResponse("listing is not valid UTF-8"))?;
+
+        let mut lines = body.lines();
+        let mut entries = Vec::new();
+        while let Some(path) = lines.next() {
+            let invalid = || ClientError::InvalidResponse("malformed listing");
+            let version = lines
+                .next()
+                .and_then(|x| x.parse().ok())
+                .and_then(|x| DateTime::from_timestamp(x, 0))
+                .ok_or_else(invalid)?;
+            let decompressed_size = lines
+                .next()
+                .and_then(|x| x.parse().ok())
+                .ok_or_else(invalid)?;
+            entries.push(ListEntry {
+                path: path.to_string(),
+                version,
+                decompressed_size,
+            });
+        }
+        Ok(entries)
+    }
+}
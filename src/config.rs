@@ -0,0 +1,237 @@
+//! Defaults for command line options read from a configuration file.
+//!
+//! The file is a subset of TOML where every key names a long option, with either
+//! dashes or underscores:
+//!
+//! ```toml
+//! listen = "0.0.0.0:9999"
+//! directory = "/var/lib/filetracker"
+//!
+//! [storage]
+//! fsync = "data+dir"
+//! extra_digests = ["md5", "crc32"]
+//! ```
+//!
+//! Tables only group options and don't affect their names. Options given on the
+//! command line take precedence over the file.
+
+use std::{ffi::OsString, path::Path};
+
+enum Value {
+    Bool(bool),
+    /// Strings and numbers, passed on to clap as they are.
+    Scalar(String),
+    Array(Vec<String>),
+}
+
+fn parse_string(text: &str) -> Option<(String, &str)> {
+    if let Some(rest) = text.strip_prefix('\'') {
+        let end = rest.find('\'')?;
+        return Some((rest[..end].to_string(), &rest[end + 1..]));
+    }
+
+    let mut chars = text.strip_prefix('"')?.char_indices();
+    let mut string = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((string, &text[i + 2..])),
+            '\\' => string.push(match chars.next()?.1 {
+                'n' => '\n',
+                't' => '\t',
+                '"' => '"',
+                '\\' => '\\',
+                _ => return None,
+            }),
+            c => string.push(c),
+        }
+    }
+    None
+}
+
+/// Parses a single scalar, returning it along with the rest of the text.
+fn parse_scalar(text: &str) -> Option<(String, &str)> {
+    if text.starts_with(['"', '\'']) {
+        return parse_string(text);
+    }
+    let end = text
+        .find(|c: char| c == ',' || c == ']' || c == '#' || c.is_whitespace())
+        .unwrap_or(text.len());
+    let scalar = &text[..end];
+    let valid = !scalar.is_empty()
+        && scalar
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '+' | '-'));
+    valid.then(|| (scalar.replace('_', ""), &text[end..]))
+}
+
+fn parse_value(text: &str) -> Option<(Value, &str)> {
+    let Some(mut rest) = text.strip_prefix('[') else {
+        let (scalar, rest) = parse_scalar(text)?;
+        let value = match scalar.as_str() {
+            "true" if !text.starts_with(['"', '\'']) => Value::Bool(true),
+            "false" if !text.starts_with(['"', '\'']) => Value::Bool(false),
+            _ => Value::Scalar(scalar),
+        };
+        return Some((value, rest));
+    };
+
+    let mut items = Vec::new();
+    loop {
+        rest = rest.trim_start();
+        if let Some(rest) = rest.strip_prefix(']') {
+            return Some((Value::Array(items), rest));
+        }
+        let (item, after) = parse_scalar(rest)?;
+        items.push(item);
+        rest = after.trim_start();
+        rest = rest.strip_prefix(',').unwrap_or(rest);
+    }
+}
+
+/// Turns one `key = value` into the command line arguments it stands for.
+fn to_args(command: &clap::Command, key: &str, value: Value) -> Result<Vec<String>, String> {
+    let long = key.replace('_', "-");
+    let arg = command
+        .get_arguments()
+        .find(|arg| {
+            arg.get_long() == Some(long.as_str())
+                || arg
+                    .get_all_aliases()
+                    .is_some_and(|aliases| aliases.contains(&long.as_str()))
+        })
+        .filter(|arg| !matches!(arg.get_id().as_str(), "config" | "help" | "version"))
+        .ok_or_else(|| format!("unknown option '{key}'"))?;
+    let long = arg.get_long().unwrap();
+
+    Ok(match (arg.get_action(), value) {
+        (clap::ArgAction::SetTrue, Value::Bool(true)) => vec![format!("--{long}")],
+        (clap::ArgAction::SetTrue, Value::Bool(false)) => vec![],
+        (clap::ArgAction::SetTrue, _) => {
+            return Err(format!("'{key}' must be true or false"));
+        }
+        (_, Value::Bool(value)) => vec![format!("--{long}={value}")],
+        (_, Value::Scalar(value)) => vec![format!("--{long}={value}")],
+        (clap::ArgAction::Append, Value::Array(items)) => items
+            .into_iter()
+            .map(|item| format!("--{long}={item}"))
+            .collect(),
+        (_, Value::Array(_)) => return Err(format!("'{key}' takes a single value")),
+    })
+}
+
+/// Converts the contents of a configuration file into command line arguments.
+pub fn parse(command: &clap::Command, contents: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let at = |message: String| format!("line {}: {message}", number + 1);
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') {
+            if !line.ends_with(']') && !line.contains("] #") && !line.contains("]#") {
+                return Err(at("malformed table header".into()));
+            }
+            continue;
+        }
+
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(at("expected 'key = value'".into()));
+        };
+        let key = key.trim();
+        if key.is_empty()
+            || !key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(at(format!("invalid key '{key}'")));
+        }
+        let (value, rest) =
+            parse_value(value.trim()).ok_or_else(|| at(format!("invalid value for '{key}'")))?;
+        let rest = rest.trim();
+        if !rest.is_empty() && !rest.starts_with('#') {
+            return Err(at(format!(
+                "unexpected '{rest}' after the value of '{key}'"
+            )));
+        }
+        args.extend(to_args(command, key, value).map_err(at)?);
+    }
+    Ok(args)
+}
+
+/// Finds the configuration file named by `--config` (or the environment variable)
+/// in `args`, without parsing the rest of them.
+fn find_config(args: &[OsString], env: &str) -> Option<OsString> {
+    let mut found = std::env::var_os(env);
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        } else if arg == "--config" {
+            found = args.next().cloned();
+        } else if let Some(path) = arg.to_str().and_then(|arg| arg.strip_prefix("--config=")) {
+            found = Some(path.into());
+        }
+    }
+    found
+}
+
+/// Whether `arg` appears among the command line arguments `args`.
+fn given(args: &[OsString], arg: &clap::Arg) -> bool {
+    let long = arg.get_long().map(|long| format!("--{long}"));
+    let short = arg.get_short().map(|short| format!("-{short}"));
+    args.iter()
+        .skip(1)
+        .map_while(|arg| arg.to_str().filter(|&arg| arg != "--"))
+        .any(|given| {
+            long.as_deref().is_some_and(|long| {
+                given == long
+                    || given
+                        .strip_prefix(long)
+                        .is_some_and(|rest| rest.starts_with('='))
+            }) || short
+                .as_deref()
+                .is_some_and(|short| given.starts_with(short))
+        })
+}
+
+/// Inserts the options from the configuration file, if there is one, in front of the
+/// ones given on the command line, so that the latter override them.
+pub fn with_config_file(
+    args: Vec<OsString>,
+    command: &clap::Command,
+    env: &str,
+) -> Result<Vec<OsString>, String> {
+    let Some(path) = find_config(&args, env) else {
+        return Ok(args);
+    };
+    let path = Path::new(&path);
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+    let from_file = parse(command, &contents).map_err(|e| format!("{}: {e}", path.display()))?;
+
+    // Later values override earlier ones, except for options taking several values
+    // which accumulate, so those from the file have to be left out.
+    let appended_on_command_line = |option: &str| {
+        let long = option.trim_start_matches('-');
+        let long = long.split_once('=').map_or(long, |(long, _)| long);
+        command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(long))
+            .is_some_and(|arg| {
+                matches!(arg.get_action(), clap::ArgAction::Append) && given(&args, arg)
+            })
+    };
+    let from_file: Vec<_> = from_file
+        .into_iter()
+        .filter(|option| !appended_on_command_line(option))
+        .collect();
+
+    let mut args = args.into_iter();
+    Ok(args
+        .next()
+        .into_iter()
+        .chain(from_file.into_iter().map(OsString::from))
+        .chain(args)
+        .collect())
+}
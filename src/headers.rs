@@ -0,0 +1,74 @@
+//! Names of the headers the filetracker protocol and its extensions give a meaning to,
+//! shared by the server and the client.
+
+/// The version of a file.
+pub const LAST_MODIFIED: &str = "Last-Modified";
+/// Size of a file's contents after decompression.
+pub const LOGICAL_SIZE: &str = "Logical-Size";
+/// Hex encoded SHA-256 of a file's decompressed contents.
+pub const SHA256_CHECKSUM: &str = "SHA256-Checksum";
+/// Filename declared by an uploader that doesn't send a `Content-Disposition`.
+pub const X_FILENAME: &str = "X-Filename";
+
+/// Extra digests of a file's decompressed contents, see `--extra-digests`.
+pub const CONTENT_MD5: &str = "Content-MD5";
+pub const X_CRC32: &str = "X-CRC32";
+
+/// When a file's blob was written and last read.
+pub const BLOB_CREATED: &str = "Blob-Created";
+pub const BLOB_ACCESSED: &str = "Blob-Accessed";
+
+/// Whether a DELETE actually deleted the file.
+pub const X_DELETED: &str = "X-Deleted";
+/// Whether a PUT found its content already stored.
+pub const X_DEDUPLICATED: &str = "X-Deduplicated";
+/// The version and checksum of the stored file when a PUT was ignored.
+pub const X_CURRENT_VERSION: &str = "X-Current-Version";
+pub const X_CURRENT_CHECKSUM: &str = "X-Current-Checksum";
+
+/// Where a COPY or MOVE puts the file, as `/files/<path>` or a URL with that path.
+pub const DESTINATION: &str = "Destination";
+
+/// How many corrupt files a lenient listing left out, a trailer for streamed listings.
+pub const X_SKIPPED_ENTRIES: &str = "X-Skipped-Entries";
+
+/// Set on listings cut short by `--list-max-entries`.
+pub const X_TRUNCATED: &str = "X-Truncated";
+
+/// How much of a resumable upload has been received.
+pub const UPLOAD_OFFSET: &str = "Upload-Offset";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_names_render_as_expected() {
+        for (name, expected) in [
+            (LAST_MODIFIED, "last-modified"),
+            (LOGICAL_SIZE, "logical-size"),
+            (SHA256_CHECKSUM, "sha256-checksum"),
+            (X_FILENAME, "x-filename"),
+            (CONTENT_MD5, "content-md5"),
+            (X_CRC32, "x-crc32"),
+            (BLOB_CREATED, "blob-created"),
+            (BLOB_ACCESSED, "blob-accessed"),
+            (X_DELETED, "x-deleted"),
+            (X_DEDUPLICATED, "x-deduplicated"),
+            (X_CURRENT_VERSION, "x-current-version"),
+            (X_CURRENT_CHECKSUM, "x-current-checksum"),
+            (DESTINATION, "destination"),
+            (X_SKIPPED_ENTRIES, "x-skipped-entries"),
+            (X_TRUNCATED, "x-truncated"),
+            (UPLOAD_OFFSET, "upload-offset"),
+        ] {
+            let header = axum::http::HeaderName::from_static(expected);
+            assert_eq!(axum::http::HeaderName::try_from(name).unwrap(), header);
+            assert!(header.as_str().eq_ignore_ascii_case(name));
+        }
+        // The spelling used by the filetracker protocol.
+        assert_eq!(SHA256_CHECKSUM, "SHA256-Checksum");
+        assert_eq!(LOGICAL_SIZE, "Logical-Size");
+        assert_eq!(LAST_MODIFIED, "Last-Modified");
+    }
+}
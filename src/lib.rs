@@ -0,0 +1,23 @@
+pub mod util;
+
+pub mod access;
+pub mod audit;
+mod blobstorage;
+pub mod capacity;
+pub mod config;
+pub mod headers;
+pub mod logging;
+pub mod storage;
+
+mod lockmap;
+pub mod metrics;
+pub mod migrate;
+pub mod path;
+pub mod server;
+pub mod spool;
+pub mod stats;
+pub mod tls;
+pub mod uploads;
+
+#[cfg(feature = "client")]
+pub mod client;
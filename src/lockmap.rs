@@ -1,10 +1,34 @@
-use std::{borrow::Borrow, collections::HashMap, future::Future, hash::Hash, sync::Arc};
+use std::{
+    borrow::Borrow, collections::HashMap, future::Future, hash::Hash, sync::Arc, time::Duration,
+};
 
-type LocksArc<K> = Arc<std::sync::Mutex<HashMap<K, Arc<tokio::sync::Mutex<()>>>>>;
+/// Number of locks past which the map is swept right away instead of waiting for the timer.
+const SWEEP_THRESHOLD: usize = 4096;
+
+struct Locks<K> {
+    map: HashMap<K, Arc<tokio::sync::RwLock<()>>>,
+    /// Grows along with the locks that survive a sweep, so that a map full of held
+    /// locks isn't swept again on every insertion.
+    sweep_threshold: usize,
+}
+
+impl<K: Hash + Eq> Locks<K> {
+    fn sweep(&mut self) {
+        self.map.retain(|_, v| Arc::strong_count(v) > 1);
+        self.sweep_threshold = SWEEP_THRESHOLD.max(self.map.len() * 2);
+    }
+}
+
+struct Shared<K> {
+    locks: std::sync::Mutex<Locks<K>>,
+    /// Wakes up the cleanup worker once the map has grown past the sweep threshold.
+    grown: tokio::sync::Notify,
+}
 
 pub struct LockMap<K: Hash + Eq + Send + 'static> {
-    locks: LocksArc<K>,
+    shared: Arc<Shared<K>>,
     cleanup_worker: tokio::task::AbortHandle,
+    timeout: Option<Duration>,
 }
 
 impl<K: Hash + Eq + Send + 'static> Drop for LockMap<K> {
@@ -13,51 +37,181 @@ impl<K: Hash + Eq + Send + 'static> Drop for LockMap<K> {
     }
 }
 
-async fn cleanup_worker<K: Hash + Eq + Send>(map: LocksArc<K>) {
+async fn cleanup_worker<K: Hash + Eq + Send>(shared: Arc<Shared<K>>) {
     let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(60));
     interval.tick().await;
     interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
 
     loop {
-        interval.tick().await;
+        tokio::select! {
+            _ = interval.tick() => (),
+            _ = shared.grown.notified() => interval.reset(),
+        }
+
+        shared.locks.lock().unwrap().sweep();
+    }
+}
 
-        map.lock().unwrap().retain(|_, v| Arc::strong_count(v) > 1);
+pub(crate) async fn acquire<G>(
+    locking: impl Future<Output = G>,
+    timeout: Option<Duration>,
+) -> std::io::Result<G> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, locking).await.map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "Timed out waiting for a lock held by another request",
+            )
+        }),
+        None => Ok(locking.await),
     }
 }
 
 impl<K: Hash + Eq + Send + 'static> LockMap<K> {
-    pub fn new() -> Self {
-        let locks = LocksArc::<K>::default();
-        let cleanup_worker = tokio::spawn(cleanup_worker(locks.clone())).abort_handle();
+    /// Creates a new lock map, locking will fail if it takes longer than `timeout`.
+    pub fn new(timeout: Option<Duration>) -> Self {
+        let shared = Arc::new(Shared {
+            locks: std::sync::Mutex::new(Locks {
+                map: HashMap::new(),
+                sweep_threshold: SWEEP_THRESHOLD,
+            }),
+            grown: tokio::sync::Notify::new(),
+        });
+        let cleanup_worker = tokio::spawn(cleanup_worker(shared.clone())).abort_handle();
         Self {
-            locks,
+            shared,
             cleanup_worker,
+            timeout,
         }
     }
 
-    pub fn lock_ref<Q>(&self, key: &Q) -> impl Future<Output = tokio::sync::OwnedMutexGuard<()>>
+    fn inserted(&self, locks: &Locks<K>) {
+        if locks.map.len() > locks.sweep_threshold {
+            self.shared.grown.notify_one();
+        }
+    }
+
+    fn get<Q>(&self, key: &Q) -> Arc<tokio::sync::RwLock<()>>
+    where
+        Q: Hash + Eq + ?Sized + ToOwned<Owned = K>,
+        K: Borrow<Q>,
+    {
+        let mut locks = self.shared.locks.lock().unwrap();
+        match locks.map.get(key) {
+            Some(lock) => lock.clone(),
+            None => {
+                let new_lock: Arc<tokio::sync::RwLock<()>> = Arc::default();
+                locks.map.insert(key.to_owned(), new_lock.clone());
+                self.inserted(&locks);
+                new_lock
+            }
+        }
+    }
+
+    /// Locks `key` for reading, which can be held by any number of readers at once.
+    pub fn read_ref<Q>(
+        &self,
+        key: &Q,
+    ) -> impl Future<Output = std::io::Result<tokio::sync::OwnedRwLockReadGuard<()>>>
+    where
+        Q: Hash + Eq + ?Sized + ToOwned<Owned = K>,
+        K: Borrow<Q>,
+    {
+        acquire(self.get(key).read_owned(), self.timeout)
+    }
+
+    /// Locks `key` exclusively.
+    pub fn write_ref<Q>(
+        &self,
+        key: &Q,
+    ) -> impl Future<Output = std::io::Result<tokio::sync::OwnedRwLockWriteGuard<()>>>
     where
         Q: Hash + Eq + ?Sized + ToOwned<Owned = K>,
         K: Borrow<Q>,
     {
-        let mut locks = self.locks.lock().unwrap();
-        locks
-            .get(key)
-            .map(|lock| lock.clone().lock_owned())
-            .unwrap_or_else(|| {
-                let new_lock: Arc<tokio::sync::Mutex<()>> = Arc::default();
-                locks.insert(key.to_owned(), new_lock.clone());
-                new_lock.lock_owned()
-            })
-    }
-
-    pub fn lock_owned(&self, key: K) -> impl Future<Output = tokio::sync::OwnedMutexGuard<()>> {
-        self.locks
-            .lock()
-            .unwrap()
-            .entry(key)
-            .or_default()
-            .clone()
-            .lock_owned()
+        acquire(self.get(key).write_owned(), self.timeout)
+    }
+
+    /// Number of keys in the map, including ones no longer locked but not swept yet.
+    pub fn len(&self) -> usize {
+        self.shared.locks.lock().unwrap().map.len()
+    }
+
+    #[allow(dead_code)]
+    pub fn write_owned(
+        &self,
+        key: K,
+    ) -> impl Future<Output = std::io::Result<tokio::sync::OwnedRwLockWriteGuard<()>>> {
+        let mut locks = self.shared.locks.lock().unwrap();
+        let lock = locks.map.entry(key).or_default().clone();
+        self.inserted(&locks);
+        acquire(lock.write_owned(), self.timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn locking_times_out_while_another_holder_keeps_the_lock() {
+        let locks = LockMap::<String>::new(Some(Duration::from_millis(50)));
+        let held = locks.write_ref("key").await.unwrap();
+
+        let error = locks.write_ref("key").await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+        let error = locks.read_ref("key").await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+        // Other keys are unaffected.
+        locks.write_ref("other").await.unwrap();
+
+        drop(held);
+        locks.write_ref("key").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn locking_waits_for_the_holder_without_a_timeout() {
+        let locks = LockMap::<String>::new(None);
+        let held = locks.write_ref("key").await.unwrap();
+
+        let waiting = locks.write_ref("key");
+        let release = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            drop(held);
+        };
+        let (acquired, ()) = tokio::join!(waiting, release);
+        acquired.unwrap();
+    }
+
+    /// Gives the cleanup worker a chance to run, without letting paused time advance.
+    async fn let_worker_run() {
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn large_maps_are_swept_before_the_timer() {
+        let start = tokio::time::Instant::now();
+        let locks = LockMap::<String>::new(None);
+        let held = locks.write_ref("held").await.unwrap();
+
+        // Small maps wait for the timer.
+        for i in 0..10 {
+            drop(locks.write_ref(&i.to_string()).await.unwrap());
+        }
+        let_worker_run().await;
+        assert_eq!(locks.len(), 11);
+
+        for i in 0..SWEEP_THRESHOLD {
+            drop(locks.write_ref(&i.to_string()).await.unwrap());
+        }
+        let_worker_run().await;
+        assert_eq!(locks.len(), 1);
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        drop(held);
+        tokio::time::sleep(Duration::from_secs(61)).await;
+        assert_eq!(locks.len(), 0);
     }
 }
@@ -0,0 +1,51 @@
+//! Sets up `tracing` to log to stderr, as plain text or one JSON object per line.
+
+use tracing_subscriber::filter::LevelFilter;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl From<Level> for LevelFilter {
+    fn from(level: Level) -> Self {
+        match level {
+            Level::Error => LevelFilter::ERROR,
+            Level::Warn => LevelFilter::WARN,
+            Level::Info => LevelFilter::INFO,
+            Level::Debug => LevelFilter::DEBUG,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// `key=value` pairs after the message, preceded by the request span.
+    Text,
+    /// One JSON object per line, with the request span's fields under `span`.
+    Json,
+}
+
+#[derive(Clone, Copy, clap::Args)]
+pub struct LogConfig {
+    /// Least severe messages to log, requests are logged at info.
+    #[clap(long, value_enum, default_value = "info")]
+    pub log_level: Level,
+    #[clap(long, value_enum, default_value = "text")]
+    pub log_format: LogFormat,
+}
+
+/// Sets up logging for the whole process, only the first call has any effect.
+pub fn init(config: LogConfig) {
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(LevelFilter::from(config.log_level))
+        .with_writer(std::io::stderr);
+    // Fails if a subscriber has already been set, e.g. by an earlier call.
+    _ = match config.log_format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().flatten_event(true).try_init(),
+    };
+}
@@ -0,0 +1,142 @@
+//! Cumulative counters exported at `/metrics` in the Prometheus text format.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+/// Upper bounds of the request duration histogram buckets, in seconds.
+const DURATION_BUCKETS: [f64; 12] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0,
+];
+
+#[derive(Default)]
+struct Histogram {
+    /// Non-cumulative counts, `DURATION_BUCKETS.len()` is for everything slower.
+    counts: [u64; DURATION_BUCKETS.len() + 1],
+    sum: f64,
+}
+
+impl Histogram {
+    fn record(&mut self, seconds: f64) {
+        let bucket = DURATION_BUCKETS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(DURATION_BUCKETS.len());
+        self.counts[bucket] += 1;
+        self.sum += seconds;
+    }
+}
+
+#[derive(Default)]
+struct Requests {
+    /// Keyed by route, method and status code. Ordered to keep the output stable.
+    counts: BTreeMap<(&'static str, &'static str, u16), u64>,
+    durations: BTreeMap<&'static str, Histogram>,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    requests: Mutex<Requests>,
+    pub bytes_received: AtomicU64,
+    pub bytes_sent: AtomicU64,
+}
+
+impl Metrics {
+    pub fn record_request(
+        &self,
+        route: &'static str,
+        method: &'static str,
+        status: u16,
+        duration: Duration,
+    ) {
+        let mut requests = self.requests.lock().unwrap();
+        *requests.counts.entry((route, method, status)).or_default() += 1;
+        requests
+            .durations
+            .entry(route)
+            .or_default()
+            .record(duration.as_secs_f64());
+    }
+
+    /// Appends the request metrics to `out`.
+    pub fn render(&self, out: &mut String) {
+        let requests = self.requests.lock().unwrap();
+
+        write_header(
+            out,
+            "filetracker_requests_total",
+            "counter",
+            "Requests handled.",
+        );
+        for ((route, method, status), count) in &requests.counts {
+            _ = writeln!(
+                out,
+                "filetracker_requests_total{{route=\"{route}\",method=\"{method}\",code=\"{status}\"}} {count}"
+            );
+        }
+
+        write_header(
+            out,
+            "filetracker_request_duration_seconds",
+            "histogram",
+            "Time until the response headers were ready.",
+        );
+        for (route, histogram) in &requests.durations {
+            let name = "filetracker_request_duration_seconds";
+            let mut cumulative = 0;
+            for (bound, count) in DURATION_BUCKETS.iter().zip(&histogram.counts) {
+                cumulative += count;
+                _ = writeln!(
+                    out,
+                    "{name}_bucket{{route=\"{route}\",le=\"{bound}\"}} {cumulative}"
+                );
+            }
+            cumulative += histogram.counts[DURATION_BUCKETS.len()];
+            _ = writeln!(
+                out,
+                "{name}_bucket{{route=\"{route}\",le=\"+Inf\"}} {cumulative}"
+            );
+            _ = writeln!(out, "{name}_sum{{route=\"{route}\"}} {}", histogram.sum);
+            _ = writeln!(out, "{name}_count{{route=\"{route}\"}} {cumulative}");
+        }
+        drop(requests);
+
+        write_value(
+            out,
+            "filetracker_received_bytes_total",
+            "counter",
+            "Bytes of request bodies received.",
+            self.bytes_received.load(Ordering::Relaxed),
+        );
+        write_value(
+            out,
+            "filetracker_sent_bytes_total",
+            "counter",
+            "Bytes of response bodies sent.",
+            self.bytes_sent.load(Ordering::Relaxed),
+        );
+    }
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    _ = writeln!(out, "# HELP {name} {help}");
+    _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// Appends a metric with a single unlabeled value.
+pub fn write_value(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    value: impl std::fmt::Display,
+) {
+    write_header(out, name, kind, help);
+    _ = writeln!(out, "{name} {value}");
+}
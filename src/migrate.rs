@@ -0,0 +1,180 @@
+//! Migration from the store layout of the original Python filetracker.
+//!
+//! The original keeps gzipped blobs in `blobs/<xx>/<sha256>` and every file as a
+//! symlink `links/<path>` to its blob, with the version of the file being the mtime
+//! of the link itself. Refcounts live in a Berkeley DB which we don't need, since
+//! they can be recomputed from the links.
+
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    storage::{Compression, Content, LocalStorage, PutOutcome, Storage, StorageError, Upload},
+    util::hex_to_byte_array,
+};
+
+/// How often progress is reported, in files.
+const PROGRESS_INTERVAL: usize = 1000;
+
+#[derive(Default)]
+struct Progress {
+    migrated: usize,
+    skipped: usize,
+    failed: usize,
+}
+
+impl Progress {
+    fn total(&self) -> usize {
+        self.migrated + self.skipped + self.failed
+    }
+
+    fn report(&self) {
+        tracing::info!(
+            processed = self.total(),
+            migrated = self.migrated,
+            already_present = self.skipped,
+            failed = self.failed,
+            "migration progress"
+        );
+    }
+}
+
+struct LegacyFile {
+    blob: PathBuf,
+    checksum: [u8; 32],
+    version: DateTime<Utc>,
+}
+
+fn read_link(link: &Path) -> std::io::Result<LegacyFile> {
+    let target = std::fs::read_link(link)?;
+    let blob = link.parent().unwrap().join(target);
+    let checksum = blob
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(hex_to_byte_array)
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{} doesn't point to a blob", link.display()),
+            )
+        })?;
+    let version = link.symlink_metadata()?.modified()?.into();
+    Ok(LegacyFile {
+        blob,
+        checksum,
+        version,
+    })
+}
+
+/// Decompresses a legacy blob to learn its size and make sure it matches its checksum.
+fn verify_blob(content: &[u8], checksum: &[u8; 32]) -> std::io::Result<usize> {
+    let mut decoder = flate2::read::GzDecoder::new(content);
+    let mut hasher = Sha256::new();
+    let mut buf = [0; 65536];
+    let mut size = 0;
+    loop {
+        let nread = decoder.read(&mut buf)?;
+        if nread == 0 {
+            break;
+        }
+        Digest::update(&mut hasher, &buf[..nread]);
+        size += nread;
+    }
+
+    if hasher.finalize()[..] != checksum[..] {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "blob contents don't match its checksum",
+        ));
+    }
+    Ok(size)
+}
+
+async fn migrate_file(
+    storage: &LocalStorage,
+    path: &str,
+    file: LegacyFile,
+) -> std::io::Result<bool> {
+    // Files that have already been migrated are left alone, which makes it safe
+    // to simply rerun an interrupted migration.
+    match storage.metadata(path).await {
+        Ok(metadata) if metadata.checksum == file.checksum && metadata.version == file.version => {
+            return Ok(false)
+        }
+        Ok(_) => (),
+        Err(StorageError::NotFound(_)) => (),
+        Err(e) => return Err(e.into()),
+    }
+
+    let content = std::fs::read(&file.blob)?;
+    let logical_size = verify_blob(&content, &file.checksum)?;
+    let outcome = storage
+        .put(
+            path,
+            file.version,
+            Upload {
+                content: Content::Memory(&content),
+                content_encoding: Compression::Gzip,
+                checksum: Some(file.checksum),
+                logical_size: Some(logical_size),
+                filename: None,
+                compression: None,
+                if_match: None,
+            },
+        )
+        .await?;
+    Ok(matches!(outcome, PutOutcome::Stored { .. }))
+}
+
+/// Copies all files from the legacy store in `legacy` into `storage`.
+pub async fn migrate_from_legacy(legacy: &Path, storage: &LocalStorage) -> std::io::Result<()> {
+    let links = legacy.join("links");
+    let mut progress = Progress::default();
+    let mut readdir_stack = vec![links.read_dir()?];
+
+    while let Some(current) = readdir_stack.last_mut() {
+        let Some(entry) = current.next() else {
+            readdir_stack.pop();
+            continue;
+        };
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            readdir_stack.push(entry.path().read_dir()?);
+            continue;
+        } else if !file_type.is_symlink() {
+            continue;
+        }
+
+        let link = entry.path();
+        let Some(path) = link.strip_prefix(&links).unwrap().to_str() else {
+            tracing::warn!(link = %link.display(), "skipping a path that is not valid UTF-8");
+            progress.failed += 1;
+            continue;
+        };
+        let result = match read_link(&link) {
+            Ok(file) => migrate_file(storage, path, file).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(true) => progress.migrated += 1,
+            Ok(false) => progress.skipped += 1,
+            Err(e) => {
+                tracing::error!(path, error = %e, "failed to migrate");
+                progress.failed += 1;
+            }
+        }
+
+        if progress.total() % PROGRESS_INTERVAL == 0 {
+            progress.report();
+        }
+    }
+
+    progress.report();
+    Ok(())
+}
@@ -0,0 +1,79 @@
+/// Limits on client supplied paths, checked before they are mapped onto the filesystem.
+#[derive(clap::Args)]
+pub struct PathLimits {
+    /// Maximum length of a file path in bytes.
+    #[clap(long = "max-path-length", default_value_t = 1024)]
+    pub max_length: usize,
+    /// Maximum number of components in a file path, counting the file name.
+    #[clap(long = "max-path-depth", default_value_t = 64)]
+    pub max_depth: usize,
+    /// Maximum length of a single file or directory name in bytes.
+    #[clap(long = "max-path-component-length", default_value_t = 255)]
+    pub max_component_length: usize,
+}
+
+fn invalid_path(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, message)
+}
+
+/// Splits a client supplied path into its components, ignoring a leading and
+/// a trailing slash and dropping `.` components.
+///
+/// `..` is refused outright, so a path can neither escape the store nor slip past
+/// a check of its leading components. So are empty components and NUL bytes, which
+/// the filesystem would otherwise either silently drop or choke on.
+pub fn components(path: &str) -> std::io::Result<Vec<&str>> {
+    let path = path.strip_prefix('/').unwrap_or(path);
+    let path = path.strip_suffix('/').unwrap_or(path);
+    if path.is_empty() {
+        return Ok(Vec::new());
+    }
+    path.split('/')
+        .filter(|c| *c != ".")
+        .map(|c| match c {
+            "" => Err(invalid_path(
+                "Path must not contain empty components".into(),
+            )),
+            ".." => Err(invalid_path("Path must not contain '..' components".into())),
+            c if c.contains('\0') => Err(invalid_path("Path must not contain NUL bytes".into())),
+            c => Ok(c),
+        })
+        .collect()
+}
+
+impl PathLimits {
+    pub fn validate(&self, path: &str) -> std::io::Result<()> {
+        if path.len() > self.max_length {
+            return Err(invalid_path(format!(
+                "Path is longer than the maximum of {} bytes",
+                self.max_length
+            )));
+        }
+
+        if components(path)?.len() > self.max_depth {
+            return Err(invalid_path(format!(
+                "Path has more than the maximum of {} components",
+                self.max_depth
+            )));
+        }
+
+        if path
+            .split('/')
+            .any(|component| component.len() > self.max_component_length)
+        {
+            return Err(invalid_path(format!(
+                "Path has a component longer than the maximum of {} bytes",
+                self.max_component_length
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Validates a client supplied path and normalizes it into its components
+    /// joined with `/`, which is what both storage backends store files under.
+    pub fn normalize(&self, path: &str) -> std::io::Result<String> {
+        self.validate(path)?;
+        Ok(components(path)?.join("/"))
+    }
+}
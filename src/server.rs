@@ -0,0 +1,3258 @@
+use std::{
+    fmt::Write,
+    io::{Read, Seek},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{ConnectInfo, FromRef, FromRequestParts, Path, Query, Request, State},
+    http::request::Parts,
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+    routing::{get, head, post},
+};
+use base64::prelude::*;
+use chrono::{DateTime, FixedOffset, Utc};
+use futures_util::{FutureExt, StreamExt, TryStreamExt};
+use http_body_util::BodyExt;
+use rand::RngCore;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::{
+    access, audit, capacity, headers, logging, metrics, migrate, spool, stats, storage, tls,
+    uploads, util,
+};
+use storage::{
+    DeleteOutcome, FileMetadata, IfMatch, ListEntry, ListOptions, PromoteMode, PutOutcome, Storage,
+    StorageError, Upload,
+};
+use util::{
+    bytes_to_hex, hex_to_byte_array, is_gzip_coding, is_zstd_coding, parse_checksum, parse_seconds,
+    percent_decode, percent_encode_attr, percent_encode_component,
+};
+
+use access::{AccessControl, Denied, Permission, TokenFingerprint};
+use audit::AuditLog;
+use capacity::CapacityWatcher;
+use metrics::Metrics;
+use spool::Spool;
+use stats::{LatencyStats, Operation};
+use uploads::{AppendOutcome, UploadSessions};
+
+fn make_empty_body() -> Body {
+    axum::body::Body::new(http_body_util::Empty::new())
+}
+
+fn make_body(data: impl Into<Bytes>) -> Body {
+    axum::body::Body::new(http_body_util::Full::new(data.into()))
+}
+
+fn make_error_response(data: impl Into<Bytes>, status: StatusCode) -> Response {
+    let mut r = Response::new(make_body(data));
+    *r.status_mut() = status;
+    r
+}
+
+fn handle_storage_error(error: StorageError) -> Response {
+    match error {
+        StorageError::NotFound(_) => make_error_response(error.to_string(), StatusCode::NOT_FOUND),
+        StorageError::InvalidInput(_) => {
+            make_error_response(error.to_string(), StatusCode::BAD_REQUEST)
+        }
+        StorageError::TimedOut(_) => {
+            make_error_response(error.to_string(), StatusCode::SERVICE_UNAVAILABLE)
+        }
+        StorageError::ChecksumMismatch(_) => {
+            make_error_response(error.to_string(), StatusCode::UNPROCESSABLE_ENTITY)
+        }
+        StorageError::TooLarge(_) => {
+            make_error_response(error.to_string(), StatusCode::PAYLOAD_TOO_LARGE)
+        }
+        // A file and a directory colliding at the same path.
+        StorageError::IsDirectory(_) | StorageError::Conflict(_) => {
+            make_error_response(error.to_string(), StatusCode::CONFLICT)
+        }
+        StorageError::Corrupt(_) => {
+            tracing::error!(%error, "store is corrupt");
+            make_error_response(error.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+        }
+        StorageError::Io(error) => match error.kind() {
+            std::io::ErrorKind::StorageFull
+            | std::io::ErrorKind::QuotaExceeded
+            | std::io::ErrorKind::WriteZero => make_error_response(
+                format!("Insufficient storage space: {error}"),
+                StatusCode::INSUFFICIENT_STORAGE,
+            ),
+            _ => {
+                tracing::error!(%error, "storage failed");
+                make_error_response(
+                    format!("Internal storage error: {error}"),
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                )
+            }
+        },
+    }
+}
+
+/// For errors outside of the storage layer, e.g. of the spool or upload sessions.
+fn handle_io_error(error: std::io::Error) -> Response {
+    handle_storage_error(error.into())
+}
+
+fn content_disposition(path: &str, filename: Option<&str>) -> String {
+    let filename = filename.unwrap_or_else(|| path.rsplit('/').next().unwrap_or(path));
+    let fallback = filename
+        .chars()
+        .map(|c| match c {
+            ' ' | '!' | '#'..='[' | ']'..='~' => c,
+            _ => '_',
+        })
+        .collect::<String>();
+
+    if fallback == filename {
+        format!("attachment; filename=\"{filename}\"")
+    } else {
+        format!(
+            "attachment; filename=\"{fallback}\"; filename*=UTF-8''{}",
+            percent_encode_attr(filename)
+        )
+    }
+}
+
+/// Extracts the filename from either a `Content-Disposition` or an `X-Filename` header.
+/// Returns `None` if the header is present but malformed.
+fn parse_declared_filename(headers: &axum::http::HeaderMap) -> Option<Option<String>> {
+    if let Some(value) = headers.get("Content-Disposition") {
+        let value = value.to_str().ok()?;
+        let mut plain = None;
+        for param in value.split(';').skip(1).map(str::trim) {
+            let (name, value) = param.split_once('=')?;
+            match name.trim().to_ascii_lowercase().as_str() {
+                "filename*" => {
+                    let (charset, rest) = value.split_once('\'')?;
+                    let (_language, encoded) = rest.split_once('\'')?;
+                    if !charset.eq_ignore_ascii_case("utf-8") {
+                        return None;
+                    }
+                    return String::from_utf8(percent_decode(encoded)?).ok().map(Some);
+                }
+                "filename" => {
+                    plain = Some(match value.strip_prefix('"') {
+                        Some(quoted) => quoted
+                            .strip_suffix('"')?
+                            .replace("\\\"", "\"")
+                            .replace("\\\\", "\\"),
+                        None => value.to_string(),
+                    })
+                }
+                _ => (),
+            }
+        }
+        Some(plain)
+    } else if let Some(value) = headers.get(headers::X_FILENAME) {
+        String::from_utf8(value.as_bytes().to_vec()).ok().map(Some)
+    } else {
+        Some(None)
+    }
+}
+
+/// What `Accept-Encoding` says about the codings `is_coding` matches, per RFC 9110
+/// section 12.5.3, `None` if it doesn't mention them, not even through `*`.
+fn coding_acceptance(
+    headers: &axum::http::HeaderMap,
+    is_coding: impl Fn(&[u8]) -> bool,
+) -> Option<bool> {
+    let mut explicit = None;
+    let mut wildcard = None;
+    for value in headers.get_all("Accept-Encoding") {
+        for item in value.as_bytes().split(|&c| c == b',') {
+            let mut parts = item.split(|&c| c == b';');
+            let coding = parts.next().unwrap_or_default().trim_ascii();
+            let acceptable = !parts.any(|param| {
+                let param = param.trim_ascii().to_ascii_lowercase();
+                param.starts_with(b"q=")
+                    && std::str::from_utf8(&param[2..])
+                        .ok()
+                        .and_then(|q| q.trim().parse::<f32>().ok())
+                        .is_some_and(|q| q == 0.0)
+            });
+            if is_coding(coding) {
+                explicit = Some(explicit.unwrap_or(false) || acceptable);
+            } else if coding == b"*" {
+                wildcard = Some(acceptable);
+            }
+        }
+    }
+    explicit.or(wildcard)
+}
+
+/// Whether the client accepts gzipped responses.
+///
+/// Without an `Accept-Encoding` any coding would technically do, but that's what
+/// simple clients like curl send, and they expect the file as it is.
+fn accepts_gzip(headers: &axum::http::HeaderMap) -> bool {
+    coding_acceptance(headers, is_gzip_coding).unwrap_or(false)
+}
+
+/// Whether the client accepts zstd compressed responses, which it only does if it
+/// says so, like for gzip.
+fn accepts_zstd(headers: &axum::http::HeaderMap) -> bool {
+    coding_acceptance(headers, is_zstd_coding).unwrap_or(false)
+}
+
+/// Whether the client accepts responses without a Content-Encoding, which it does
+/// unless it explicitly refuses them.
+fn accepts_identity(headers: &axum::http::HeaderMap) -> bool {
+    coding_acceptance(headers, |coding| coding.eq_ignore_ascii_case(b"identity")).unwrap_or(true)
+}
+
+/// The compression a file is sent with, which is the one it's stored with unless
+/// the client can't handle that. Clients never have the server's zstd dictionary.
+fn served_compression(
+    metadata: &FileMetadata,
+    headers: &axum::http::HeaderMap,
+    http: HttpConfig,
+) -> storage::Compression {
+    match metadata.compression {
+        storage::Compression::Gzip if !http.always_gzip && !accepts_gzip(headers) => {
+            storage::Compression::None
+        }
+        storage::Compression::Zstd
+            if metadata.zstd_dictionary.is_some() || !accepts_zstd(headers) =>
+        {
+            storage::Compression::None
+        }
+        compression => compression,
+    }
+}
+
+/// Whether files stored with `compression` are sent differently depending on
+/// `Accept-Encoding`. `--always-gzip` only applies to gzip, which is all the original
+/// filetracker clients know.
+fn is_negotiated(compression: storage::Compression, http: HttpConfig) -> bool {
+    match compression {
+        storage::Compression::None => false,
+        storage::Compression::Gzip => !http.always_gzip,
+        storage::Compression::Zstd => true,
+    }
+}
+
+/// Whether a file's responses depend on `Accept-Encoding`, which they don't for
+/// blobs compressed with a dictionary since those are always decompressed.
+fn varies(metadata: &FileMetadata, http: HttpConfig) -> bool {
+    is_negotiated(metadata.compression, http) && metadata.zstd_dictionary.is_none()
+}
+
+/// The entity tag of a file sent with `served` compression.
+///
+/// The checksum describes the decompressed contents, so negotiated compressed
+/// responses get a strong tag with the content coding appended, keeping it distinct
+/// from that of the same file sent as is. Files always sent gzipped, as by the
+/// original filetracker, only have the one representation and keep the plain tag.
+fn representation_tag(
+    checksum: &[u8; 32],
+    served: storage::Compression,
+    http: HttpConfig,
+) -> String {
+    let hex = bytes_to_hex(checksum);
+    match served {
+        storage::Compression::Gzip if is_negotiated(served, http) => format!("\"{hex}-gzip\""),
+        storage::Compression::Zstd => format!("\"{hex}-zstd\""),
+        _ => format!("\"{hex}\""),
+    }
+}
+
+/// Starts a response with the headers describing a file, sent with `served` compression.
+fn file_response_builder(
+    path: &str,
+    metadata: FileMetadata,
+    served: storage::Compression,
+    http: HttpConfig,
+) -> axum::http::response::Builder {
+    let mut builder = match served {
+        storage::Compression::None => Response::builder(),
+        storage::Compression::Gzip => Response::builder().header("Content-Encoding", "gzip"),
+        storage::Compression::Zstd => Response::builder().header("Content-Encoding", "zstd"),
+    };
+    if varies(&metadata, http) {
+        builder = builder.header("Vary", "Accept-Encoding");
+    }
+    // NOTE: Like SHA256-Checksum, these describe the decompressed contents even when
+    //       they are sent gzipped, unlike what RFC 1864 says for Content-MD5.
+    if let Some(md5) = metadata.extra_digests.md5 {
+        builder = builder.header(headers::CONTENT_MD5, BASE64_STANDARD.encode(md5));
+    }
+    if let Some(crc32) = metadata.extra_digests.crc32 {
+        builder = builder.header(headers::X_CRC32, format!("{crc32:08x}"));
+    }
+
+    builder
+        .header(headers::LOGICAL_SIZE, metadata.decompressed_size)
+        // NOTE: This header is not present in the original version of filetracker.
+        //       It is included as an extension.
+        //       Also this is not X-SHA256-Checksum because the original filetracker developers
+        //       apparently were not aware of such a thing as "standards".
+        .header(headers::SHA256_CHECKSUM, bytes_to_hex(&metadata.checksum))
+        .header("ETag", representation_tag(&metadata.checksum, served, http))
+        .header(
+            headers::LAST_MODIFIED,
+            http.date_format.format(metadata.version),
+        )
+        .header("Accept-Ranges", "bytes")
+        .header("Content-Type", "application/octet-stream")
+        .header(
+            "Content-Disposition",
+            content_disposition(path, metadata.filename.as_deref()),
+        )
+}
+
+#[derive(Serialize)]
+struct Capabilities {
+    /// Content-Encodings accepted on uploads.
+    upload_encodings: &'static [&'static str],
+    /// Values accepted by the `compression` parameter of PUT.
+    compressions: Vec<String>,
+    max_logical_size: Option<usize>,
+    /// The largest upload body accepted, in bytes.
+    max_upload_size: Option<usize>,
+    /// Whether older versions of a file can be prevented from overwriting newer ones.
+    versioning: bool,
+    write_policy: String,
+    /// Whether writes must carry a `last_modified` of their own.
+    require_version: bool,
+    /// Whether PUTs must declare their Content-Length.
+    require_content_length: bool,
+    /// Whether requests must carry a bearer token scoped to the paths they access.
+    access_control: bool,
+    /// Whether reads are allowed without a token even with access control.
+    public_read: bool,
+    /// Whether gzipped files are decompressed for clients that don't accept gzip.
+    content_negotiation: bool,
+    ranges: bool,
+    /// Extensions to the original filetracker protocol.
+    extensions: &'static [&'static str],
+}
+
+#[derive(Serialize)]
+struct VersionInfo {
+    /// The only thing the original filetracker client looks at.
+    protocol_versions: &'static [u32],
+    capabilities: Capabilities,
+}
+
+async fn get_version(
+    State(storage): State<Arc<dyn Storage>>,
+    State(http): State<HttpConfig>,
+    State(access): State<Option<Arc<AccessControl>>>,
+) -> Response {
+    use clap::ValueEnum;
+
+    let config = storage.config();
+    let name = |value: clap::builder::PossibleValue| value.get_name().to_string();
+    let info = VersionInfo {
+        protocol_versions: &[2],
+        capabilities: Capabilities {
+            upload_encodings: &["gzip", "zstd"],
+            compressions: storage::Compression::value_variants()
+                .iter()
+                .filter_map(|x| x.to_possible_value().map(name))
+                .collect(),
+            max_logical_size: config.max_logical_size,
+            max_upload_size: http.max_upload_size,
+            versioning: config.write_policy != storage::WritePolicy::Always,
+            write_policy: name(config.write_policy.to_possible_value().unwrap()),
+            require_version: http.require_version,
+            require_content_length: http.require_content_length,
+            access_control: access.is_some(),
+            content_negotiation: !http.always_gzip,
+            public_read: access.as_ref().is_none_or(|access| access.public_read()),
+            ranges: true,
+            extensions: &[
+                "sha256-checksum",
+                "logical-size",
+                "if-match",
+                "if-none-match",
+                "if-modified-since",
+                "metadata-json",
+                "exists",
+                "batch-delete",
+                "deduplicated",
+                "promote",
+                "copy",
+                "list-json",
+                "resumable-uploads",
+                "stats",
+                "admin-stats",
+                "admin-scrub",
+                "metrics",
+                "blobs",
+                "link-only",
+            ],
+        },
+    };
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(make_body(serde_json::to_string(&info).unwrap()))
+        .unwrap()
+}
+
+/// Like [`handle_storage_error`], but reading a directory isn't a conflict like
+/// writing to one, there just is no file there.
+fn handle_read_error(path: &str, error: StorageError, http: HttpConfig) -> Response {
+    if !matches!(error, StorageError::IsDirectory(_)) {
+        return handle_storage_error(error);
+    }
+
+    let listing = format!("/list/{}", percent_encode_component(path, true));
+    match http.directory_response {
+        DirectoryResponse::NotFound => make_error_response(
+            format!("{path} is a directory, its contents are listed at {listing}"),
+            StatusCode::NOT_FOUND,
+        ),
+        DirectoryResponse::Redirect => Response::builder()
+            .status(StatusCode::TEMPORARY_REDIRECT)
+            .header("Location", listing)
+            .body(make_empty_body())
+            .unwrap(),
+    }
+}
+
+/// Entity tags are the quoted checksums of stored files, see [`representation_tag`]
+/// for compressed responses.
+fn entity_tag(checksum: &[u8; 32]) -> String {
+    format!("\"{}\"", bytes_to_hex(checksum))
+}
+
+/// Checks whether `If-None-Match` matches the representation tagged `etag`, using
+/// the weak comparison so that `W/` tags match too.
+fn if_none_match_matches(value: &axum::http::HeaderValue, etag: &str) -> bool {
+    let Ok(value) = value.to_str() else {
+        return false;
+    };
+    if value.trim() == "*" {
+        return true;
+    }
+
+    value.split(',').map(str::trim).any(|tag| {
+        let tag = tag.strip_prefix("W/").unwrap_or(tag);
+        tag.eq_ignore_ascii_case(etag)
+    })
+}
+
+/// Answers a conditional GET or HEAD with a 304 if the client already has the file.
+async fn check_not_modified(
+    storage: &dyn Storage,
+    path: &str,
+    headers: &axum::http::HeaderMap,
+    http: HttpConfig,
+) -> Option<Response> {
+    let if_none_match = headers.get("If-None-Match");
+    // IMF-fixdate, which clients are supposed to send, parses as RFC 2822 too.
+    let if_modified_since = headers
+        .get("If-Modified-Since")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| DateTime::parse_from_rfc2822(value).ok());
+    if if_none_match.is_none() && if_modified_since.is_none() {
+        return None;
+    }
+
+    // Errors are left for the actual request to report.
+    let metadata = storage.metadata(path).await.ok()?;
+    let served = served_compression(&metadata, headers, http);
+    let etag = representation_tag(&metadata.checksum, served, http);
+    let not_modified = match if_none_match {
+        // If-Modified-Since is only a fallback for clients without an entity tag.
+        Some(value) => if_none_match_matches(value, &etag),
+        // HTTP dates have a resolution of a second.
+        None => {
+            if_modified_since.is_some_and(|since| metadata.version.timestamp() <= since.timestamp())
+        }
+    };
+    not_modified.then(|| {
+        let mut builder = Response::builder().status(StatusCode::NOT_MODIFIED);
+        if varies(&metadata, http) {
+            builder = builder.header("Vary", "Accept-Encoding");
+        }
+        builder
+            .header("ETag", etag)
+            .header(
+                headers::LAST_MODIFIED,
+                http.date_format.format(metadata.version),
+            )
+            .body(make_empty_body())
+            .unwrap()
+    })
+}
+
+/// A `Range` header resolved against the logical size of a file.
+#[derive(Debug, PartialEq, Eq)]
+enum ByteRange {
+    /// No usable range was requested, the whole file should be sent.
+    Whole,
+    /// The inclusive range of bytes to send.
+    Partial {
+        start: u64,
+        end: u64,
+    },
+    Unsatisfiable,
+}
+
+/// Parses a single `bytes=` range, anything else (including multiple ranges)
+/// is ignored as RFC 9110 allows.
+fn parse_range(value: &str, size: u64) -> ByteRange {
+    let Some(spec) = value.trim().strip_prefix("bytes=") else {
+        return ByteRange::Whole;
+    };
+    let Some((first, last)) = spec.trim().split_once('-') else {
+        return ByteRange::Whole;
+    };
+    if spec.contains(',') {
+        return ByteRange::Whole;
+    }
+
+    let (first, last) = (first.trim(), last.trim());
+    let (start, end) = match (first.parse::<u64>(), last.parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(size.saturating_sub(1))),
+        (Ok(start), Err(_)) if last.is_empty() => (start, size.saturating_sub(1)),
+        (Err(_), Ok(suffix)) if first.is_empty() => {
+            if suffix == 0 {
+                return ByteRange::Unsatisfiable;
+            }
+            (size.saturating_sub(suffix), size.saturating_sub(1))
+        }
+        _ => return ByteRange::Whole,
+    };
+    if start >= size {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial { start, end }
+}
+
+/// Checks whether `If-Range` still describes the stored file, so that a resumed
+/// download doesn't splice together two different versions. Ranges are always sent
+/// without a Content-Encoding, so only the plain [`entity_tag`] matches.
+fn if_range_matches(
+    value: &axum::http::HeaderValue,
+    metadata: &FileMetadata,
+    http: HttpConfig,
+) -> bool {
+    let Ok(value) = value.to_str() else {
+        return false;
+    };
+    let value = value.trim();
+    value == entity_tag(&metadata.checksum) || value == http.date_format.format(metadata.version)
+}
+
+/// Streams `reader` from a blocking thread, for readers that do more than plain file reads.
+fn blocking_stream_body(
+    reader: impl FnOnce() -> std::io::Result<Box<dyn Read + Send>> + Send + 'static,
+) -> Body {
+    const CHUNK_SIZE: usize = 64 * 1024;
+    const QUEUE_LENGTH: usize = 4;
+
+    let (sender, receiver) = tokio::sync::mpsc::channel(QUEUE_LENGTH);
+    tokio::task::spawn_blocking(move || {
+        let mut reader = match reader() {
+            Ok(reader) => reader,
+            Err(e) => {
+                _ = sender.blocking_send(Err(e));
+                return;
+            }
+        };
+        loop {
+            let mut chunk = vec![0; CHUNK_SIZE];
+            let result = match reader.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(nread) => {
+                    chunk.truncate(nread);
+                    Ok(Bytes::from(chunk))
+                }
+                Err(e) => Err(e),
+            };
+            let failed = result.is_err();
+            if sender.blocking_send(result).is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    Body::from_stream(futures_util::stream::unfold(
+        receiver,
+        |mut receiver| async move { receiver.recv().await.map(|chunk| (chunk, receiver)) },
+    ))
+}
+
+/// Answers a GET with a `Range` of the decompressed contents of a file.
+///
+/// Ranges always refer to the logical contents, so they are sent without a
+/// Content-Encoding no matter how the file is stored or what the client accepts,
+/// and compressed blobs have to be decompressed up to the start of the range. Ranges
+/// of the compressed stream itself would be useless to clients, which can't decompress
+/// a piece from the middle of it.
+fn range_response(
+    path: &str,
+    metadata: FileMetadata,
+    mut file: storage::Blob,
+    (start, end): (u64, u64),
+    dictionary: Option<Arc<storage::ZstdDictionary>>,
+    http: HttpConfig,
+) -> Response {
+    let length = end - start + 1;
+    let size = metadata.decompressed_size;
+    let compression = metadata.compression;
+    let body = blocking_stream_body(move || {
+        let reader: Box<dyn Read + Send> = match compression {
+            storage::Compression::None => {
+                file.seek(std::io::SeekFrom::Start(start))?;
+                Box::new(file)
+            }
+            compression => {
+                let mut decoder = compression
+                    .blob_decoder(std::io::BufReader::new(file), dictionary.as_deref())?;
+                std::io::copy(&mut (&mut decoder).take(start), &mut std::io::sink())?;
+                decoder
+            }
+        };
+        Ok(Box::new(reader.take(length)))
+    });
+
+    file_response_builder(path, metadata, storage::Compression::None, http)
+        .status(StatusCode::PARTIAL_CONTENT)
+        .header("Content-Range", format!("bytes {start}-{end}/{size}"))
+        .header("Content-Length", length)
+        .body(body)
+        .unwrap()
+}
+
+async fn get_file(
+    Path(path): Path<String>,
+    State(storage): State<Arc<dyn Storage>>,
+    State(http): State<HttpConfig>,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    if let Some(response) = check_not_modified(&*storage, &path, &headers, http).await {
+        return response;
+    }
+
+    let (metadata, file) = match storage.open(&path).await {
+        Ok(opened) => opened,
+        Err(e) => return handle_read_error(&path, e, http),
+    };
+
+    // Ranges are only served without a Content-Encoding, see `range_response`, so
+    // clients refusing that get the whole file instead, as if they hadn't asked.
+    let range = match headers.get("Range") {
+        Some(_) if !accepts_identity(&headers) => ByteRange::Whole,
+        Some(_)
+            if headers
+                .get("If-Range")
+                .is_some_and(|value| !if_range_matches(value, &metadata, http)) =>
+        {
+            ByteRange::Whole
+        }
+        Some(value) => parse_range(
+            value.to_str().unwrap_or_default(),
+            metadata.decompressed_size as u64,
+        ),
+        None => ByteRange::Whole,
+    };
+    match range {
+        ByteRange::Whole => (),
+        ByteRange::Partial { start, end } => {
+            let dictionary = storage.config().zstd_dict.clone();
+            return range_response(&path, metadata, file, (start, end), dictionary, http);
+        }
+        ByteRange::Unsatisfiable => {
+            let mut response = make_error_response(
+                "The requested range is outside of the file",
+                StatusCode::RANGE_NOT_SATISFIABLE,
+            );
+            response.headers_mut().insert(
+                "Content-Range",
+                format!("bytes */{}", metadata.decompressed_size)
+                    .try_into()
+                    .unwrap(),
+            );
+            return response;
+        }
+    }
+
+    let served = served_compression(&metadata, &headers, http);
+    if served != metadata.compression {
+        let length = metadata.decompressed_size;
+        let compression = metadata.compression;
+        let dictionary = storage.config().zstd_dict.clone();
+        let body = blocking_stream_body(move || {
+            compression.blob_decoder(std::io::BufReader::new(file), dictionary.as_deref())
+        });
+        return file_response_builder(&path, metadata, served, http)
+            .header("Content-Length", length)
+            .body(body)
+            .unwrap();
+    }
+
+    let length = match file.size() {
+        Ok(length) => length,
+        Err(e) => return handle_io_error(e),
+    };
+
+    // NOTE: The blob is streamed after its lock has been released, which is fine
+    //       since blobs are never modified and an open file survives its removal.
+    let body = match file {
+        storage::Blob::File(file) => Body::from_stream(tokio_util::io::ReaderStream::new(
+            tokio::fs::File::from_std(file),
+        )),
+        storage::Blob::Memory(data) => Body::from(Bytes::from_owner(data.into_inner())),
+    };
+    file_response_builder(&path, metadata, served, http)
+        .header("Content-Length", length)
+        .body(body)
+        .unwrap()
+}
+
+async fn head_file(
+    Path(path): Path<String>,
+    State(storage): State<Arc<dyn Storage>>,
+    State(http): State<HttpConfig>,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    if let Some(response) = check_not_modified(&*storage, &path, &headers, http).await {
+        return response;
+    }
+
+    match storage.head(&path).await {
+        Ok((metadata, info)) => {
+            let served = served_compression(&metadata, &headers, http);
+            let length = match served == metadata.compression {
+                true => info.size,
+                false => metadata.decompressed_size as u64,
+            };
+            let mut builder = file_response_builder(&path, metadata, served, http)
+                .header("Content-Length", length)
+                .header(headers::BLOB_CREATED, http.date_format.format(info.created));
+            if let Some(accessed) = info.accessed {
+                builder = builder.header(headers::BLOB_ACCESSED, http.date_format.format(accessed));
+            }
+            builder.body(make_empty_body()).unwrap()
+        }
+        Err(e) => handle_read_error(&path, e, http),
+    }
+}
+
+/// Returns a file's metadata as JSON, in the same form it's stored in.
+async fn get_meta(Path(path): Path<String>, State(storage): State<Arc<dyn Storage>>) -> Response {
+    match storage.metadata(&path).await {
+        Ok(metadata) => Response::builder()
+            .header("Content-Type", "application/json")
+            .body(make_body(serde_json::to_string(&metadata).unwrap()))
+            .unwrap(),
+        Err(e) => handle_storage_error(e),
+    }
+}
+
+#[derive(Deserialize)]
+struct LastModifiedQuery {
+    #[serde(default, deserialize_with = "deserialize_last_modified")]
+    last_modified: Option<DateTime<Utc>>,
+}
+
+// Extracted directly instead of through Query so that a malformed timestamp gets
+// an explanation instead of an opaque deserialization error.
+#[axum::async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for LastModifiedQuery {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        match Query::<LastModifiedQuery>::from_request_parts(parts, state).await {
+            Ok(Query(query)) => Ok(query),
+            Err(rejection) => Err(make_error_response(
+                format!(
+                    "Invalid last_modified query parameter, expected an RFC 2822 date-time \
+                     such as \"Mon, 01 Jan 2024 12:00:00 +0000\": {}",
+                    rejection.body_text()
+                ),
+                StatusCode::BAD_REQUEST,
+            )),
+        }
+    }
+}
+
+impl LastModifiedQuery {
+    /// The version requested by the client, defaulting to the current time unless
+    /// the server requires clients to always supply one.
+    fn version_or_now(&self, http: HttpConfig) -> Option<DateTime<Utc>> {
+        match self.last_modified {
+            Some(version) => Some(version),
+            None if http.require_version => None,
+            None => Some(Utc::now()),
+        }
+    }
+}
+
+fn missing_version_response() -> Response {
+    make_error_response(
+        "This server requires an explicit last_modified query parameter",
+        StatusCode::BAD_REQUEST,
+    )
+}
+
+fn deserialize_last_modified<'de, D: Deserializer<'de>>(
+    de: D,
+) -> Result<Option<DateTime<Utc>>, D::Error> {
+    struct V;
+    impl serde::de::Visitor<'_> for V {
+        type Value = DateTime<FixedOffset>;
+
+        fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+            formatter.write_str("RFC 2822 formatted date-time string")
+        }
+
+        fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+        where
+            E: serde::de::Error,
+        {
+            DateTime::parse_from_rfc2822(v).map_err(serde::de::Error::custom)
+        }
+
+        fn visit_string<E>(self, v: String) -> Result<Self::Value, E>
+        where
+            E: serde::de::Error,
+        {
+            self.visit_str(&v)
+        }
+    }
+    de.deserialize_str(V).map(|x| Some(x.to_utc()))
+}
+
+/// A boolean query parameter, which may also be given as `1` or `0`, or without a
+/// value to set it.
+fn deserialize_flag<'de, D: Deserializer<'de>>(de: D) -> Result<bool, D::Error> {
+    match <std::borrow::Cow<str>>::deserialize(de)?.as_ref() {
+        "true" | "1" | "" => Ok(true),
+        "false" | "0" => Ok(false),
+        other => Err(serde::de::Error::invalid_value(
+            serde::de::Unexpected::Str(other),
+            &"true, false, 1, 0 or nothing",
+        )),
+    }
+}
+
+#[derive(Deserialize)]
+struct PutQuery {
+    /// Only check whether the upload would be accepted, without storing it.
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    validate: bool,
+    /// Store the file with this compression instead of the default one.
+    compression: Option<RequestedCompression>,
+    /// Link the file to already stored content with the declared checksum and size
+    /// instead of uploading it, see `HEAD /blobs/<sha256>`.
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    link_only: bool,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum RequestedCompression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl From<RequestedCompression> for storage::Compression {
+    fn from(requested: RequestedCompression) -> Self {
+        match requested {
+            RequestedCompression::None => storage::Compression::None,
+            RequestedCompression::Gzip => storage::Compression::Gzip,
+            RequestedCompression::Zstd => storage::Compression::Zstd,
+        }
+    }
+}
+
+/// Headers describing an uploaded body, shared by PUT and resumable uploads.
+#[derive(Clone)]
+struct UploadHeaders {
+    encoding: storage::Compression,
+    checksum: Option<[u8; 32]>,
+    logical_size: Option<usize>,
+    filename: Option<String>,
+    if_match: Option<IfMatch>,
+}
+
+/// Parses `If-Match`, see [`entity_tag`].
+fn parse_if_match(value: &axum::http::HeaderValue) -> Option<IfMatch> {
+    let value = value.to_str().ok()?.trim();
+    if value == "*" {
+        return Some(IfMatch::Any);
+    }
+
+    let mut checksums = Vec::new();
+    for tag in value.split(',').map(str::trim) {
+        // Weak tags never match since If-Match uses strong comparison.
+        if tag.starts_with("W/") {
+            continue;
+        }
+        let hex = tag.strip_prefix('"')?.strip_suffix('"')?;
+        // Tags of compressed responses name the same file, see `representation_tag`.
+        let hex = hex
+            .strip_suffix("-gzip")
+            .or_else(|| hex.strip_suffix("-zstd"))
+            .unwrap_or(hex);
+        checksums.push(hex_to_byte_array(&hex.to_ascii_lowercase())?);
+    }
+    Some(IfMatch::Checksums(checksums))
+}
+
+impl UploadHeaders {
+    fn parse(headers: &axum::http::HeaderMap) -> Result<Self, String> {
+        let encoding = match headers.get("Content-Encoding") {
+            Some(value) if is_gzip_coding(value.as_bytes()) => storage::Compression::Gzip,
+            Some(value) if is_zstd_coding(value.as_bytes()) => storage::Compression::Zstd,
+            None => storage::Compression::None,
+            _ => return Err("Unsupported Content-Encoding".into()),
+        };
+
+        let checksum = match headers.get(headers::SHA256_CHECKSUM) {
+            Some(value) => Some(
+                value
+                    .to_str()
+                    .map_err(|e| e.to_string())
+                    .and_then(parse_checksum)
+                    .map_err(|e| format!("Invalid SHA256-Checksum: {e}"))?,
+            ),
+            None => None,
+        };
+
+        let logical_size = match headers
+            .get(headers::LOGICAL_SIZE)
+            .map(|value| value.to_str().ok().and_then(|value| value.parse().ok()))
+        {
+            Some(Some(size)) => Some(size),
+            Some(None) => return Err("Invalid Logical-Size".into()),
+            None => None,
+        };
+
+        let Some(filename) = parse_declared_filename(headers) else {
+            return Err("Invalid declared filename".into());
+        };
+
+        let if_match = match headers.get("If-Match") {
+            Some(value) => Some(parse_if_match(value).ok_or("Invalid If-Match")?),
+            None => None,
+        };
+
+        Ok(Self {
+            encoding,
+            checksum,
+            logical_size,
+            filename,
+            if_match,
+        })
+    }
+
+    fn into_upload(
+        self,
+        content: storage::Content<'_>,
+        compression: Option<storage::Compression>,
+    ) -> Upload<'_> {
+        Upload {
+            content,
+            content_encoding: self.encoding,
+            checksum: self.checksum,
+            logical_size: self.logical_size,
+            filename: self.filename,
+            compression,
+            if_match: self.if_match,
+        }
+    }
+}
+
+/// Records the effects of a request in the audit log, if there is one.
+struct Auditor {
+    log: Option<Arc<AuditLog>>,
+    token: Option<TokenFingerprint>,
+    client: Option<SocketAddr>,
+}
+
+#[axum::async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Auditor
+where
+    Option<Arc<AuditLog>>: FromRef<S>,
+{
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self {
+            log: FromRef::from_ref(state),
+            token: parts.extensions.get::<TokenFingerprint>().cloned(),
+            client: parts
+                .extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|info| info.0),
+        })
+    }
+}
+
+impl Auditor {
+    fn record(&self, event: impl FnOnce() -> audit::Event) {
+        if let Some(log) = &self.log {
+            let token = self.token.as_ref().map(|token| token.0.as_str());
+            log.record(event(), token, self.client);
+        }
+    }
+}
+
+fn put_event(
+    path: &str,
+    version: DateTime<Utc>,
+    result: &Result<PutOutcome, StorageError>,
+) -> audit::Event {
+    let (outcome, checksum) = match result {
+        Ok(PutOutcome::Stored { checksum, .. }) => ("stored", Some(bytes_to_hex(checksum))),
+        Ok(PutOutcome::Superseded { .. }) => ("superseded", None),
+        Ok(PutOutcome::Rejected { .. }) => ("rejected", None),
+        Ok(PutOutcome::PreconditionFailed) => ("precondition_failed", None),
+        Err(StorageError::NotFound(_)) => ("not_found", None),
+        Err(_) => ("failed", None),
+    };
+    audit::Event {
+        action: audit::Action::Put,
+        path: path.to_string(),
+        to: None,
+        version: Some(version),
+        checksum,
+        outcome,
+    }
+}
+
+fn delete_event(
+    path: &str,
+    max_version: Option<DateTime<Utc>>,
+    result: &Result<DeleteOutcome, StorageError>,
+) -> audit::Event {
+    audit::Event {
+        action: audit::Action::Delete,
+        path: path.to_string(),
+        to: None,
+        version: max_version,
+        checksum: None,
+        outcome: match result {
+            Ok(DeleteOutcome::Deleted) => "deleted",
+            Ok(DeleteOutcome::Superseded { .. }) => "superseded",
+            Err(StorageError::NotFound(_)) => "not_found",
+            Err(_) => "failed",
+        },
+    }
+}
+
+fn put_response(
+    result: Result<PutOutcome, StorageError>,
+    version: DateTime<Utc>,
+    http: HttpConfig,
+) -> Response {
+    // Lets clients whose write was ignored reconcile without another round trip.
+    let current = |builder: axum::http::response::Builder, version, checksum: [u8; 32]| {
+        builder
+            .header(headers::X_CURRENT_VERSION, http.date_format.format(version))
+            .header(headers::X_CURRENT_CHECKSUM, bytes_to_hex(&checksum))
+    };
+
+    match result {
+        Ok(PutOutcome::Stored { deduplicated, .. }) => Response::builder()
+            .header(headers::LAST_MODIFIED, http.date_format.format(version))
+            .header(headers::X_DEDUPLICATED, deduplicated.to_string())
+            .body(make_empty_body())
+            .unwrap(),
+        Ok(PutOutcome::Superseded {
+            version: current_version,
+            checksum,
+        }) => current(Response::builder(), current_version, checksum)
+            .header(headers::LAST_MODIFIED, http.date_format.format(version))
+            .body(make_empty_body())
+            .unwrap(),
+        Ok(PutOutcome::PreconditionFailed) => make_error_response(
+            "The stored file does not match If-Match",
+            StatusCode::PRECONDITION_FAILED,
+        ),
+        Ok(PutOutcome::Rejected { version, checksum }) => {
+            current(Response::builder(), version, checksum)
+                .status(StatusCode::CONFLICT)
+                .body(make_body("A newer version of this file is already stored"))
+                .unwrap()
+        }
+        Err(err) => handle_storage_error(err),
+    }
+}
+
+/// The result of a PUT whose outcome doesn't depend on its body, which is the case
+/// when a newer version is stored or when the content the client declared by its
+/// checksum and size is, in which case the file is linked to it. `None` if the body
+/// is needed after all.
+async fn put_without_body(
+    storage: &dyn Storage,
+    path: &str,
+    version: DateTime<Utc>,
+    upload: Upload<'_>,
+) -> Option<Result<PutOutcome, StorageError>> {
+    match storage.precheck_put(path, version, &upload).await {
+        Ok(Some(outcome)) => return Some(Ok(outcome)),
+        Ok(None) => (),
+        Err(e) => return Some(Err(e)),
+    }
+
+    let (Some(checksum), Some(_)) = (upload.checksum, upload.logical_size) else {
+        return None;
+    };
+    match storage.has_blob(&checksum).await {
+        Ok(true) => (),
+        Ok(false) => return None,
+        Err(e) => return Some(Err(e)),
+    }
+    match storage.link(path, version, upload).await {
+        // Removed in the meantime or not stored with the requested compression.
+        Err(StorageError::NotFound(_)) => None,
+        result => Some(result),
+    }
+}
+
+/// Refuses bodies declaring a Content-Length above the maximum upload size before
+/// any of them is read. Bodies that don't declare it are cut off while received.
+fn check_content_length(
+    headers: &axum::http::HeaderMap,
+    max_upload_size: Option<usize>,
+) -> Option<Response> {
+    let max = max_upload_size?;
+    let length: u64 = headers.get("Content-Length")?.to_str().ok()?.parse().ok()?;
+    (length > max as u64).then(|| {
+        make_error_response(
+            format!("The body exceeds the maximum upload size of {max} bytes"),
+            StatusCode::PAYLOAD_TOO_LARGE,
+        )
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn put_file(
+    Path(path): Path<String>,
+    State(storage): State<Arc<dyn Storage>>,
+    State(http): State<HttpConfig>,
+    State(spool): State<Arc<Spool>>,
+    State(capacity): State<Arc<CapacityWatcher>>,
+    auditor: Auditor,
+    query: LastModifiedQuery,
+    Query(put_query): Query<PutQuery>,
+    request: Request,
+) -> Response {
+    let Some(version) = query.version_or_now(http) else {
+        return missing_version_response();
+    };
+    if http.require_content_length && !request.headers().contains_key("Content-Length") {
+        return make_error_response(
+            "Uploads must declare their Content-Length",
+            StatusCode::LENGTH_REQUIRED,
+        );
+    }
+    if let Some(response) = check_content_length(request.headers(), http.max_upload_size) {
+        return response;
+    }
+    if let Some(message) = capacity.shortage() {
+        return make_error_response(message, StatusCode::INSUFFICIENT_STORAGE);
+    }
+
+    let headers = match UploadHeaders::parse(request.headers()) {
+        Ok(headers) => headers,
+        Err(message) => return make_error_response(message, StatusCode::BAD_REQUEST),
+    };
+
+    let compression = put_query.compression.map(storage::Compression::from);
+
+    if put_query.link_only {
+        // A body would suggest the client expects its content to be stored.
+        if request
+            .headers()
+            .get("Content-Length")
+            .is_some_and(|length| length != "0")
+        {
+            return make_error_response(
+                "Uploads with link_only must not have a body",
+                StatusCode::BAD_REQUEST,
+            );
+        }
+        if put_query.validate {
+            return make_error_response(
+                "link_only can't be combined with validate",
+                StatusCode::BAD_REQUEST,
+            );
+        }
+        let upload = headers.into_upload(storage::Content::Memory(&[]), compression);
+        let result = storage.link(&path, version, upload).await;
+        auditor.record(|| put_event(&path, version, &result));
+        return put_response(result, version, http);
+    }
+
+    // Hyper only sends 100 Continue once the body is read, so clients waiting for it
+    // don't send the body at all if the request is answered here.
+    let expects_continue = request
+        .headers()
+        .get("Expect")
+        .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"100-continue"));
+    if expects_continue && !put_query.validate {
+        let upload = headers
+            .clone()
+            .into_upload(storage::Content::Memory(&[]), compression);
+        if let Some(result) = put_without_body(&*storage, &path, version, upload).await {
+            auditor.record(|| put_event(&path, version, &result));
+            return put_response(result, version, http);
+        }
+    }
+
+    // NOTE: A gzipped body with compression=none is decompressed and stored as is.
+    //       Chunked bodies are received just the same, the size and checksum are
+    //       computed from whatever arrived.
+    let body = match spool
+        .receive(request.into_body(), http.max_upload_size)
+        .await
+    {
+        Ok(body) => body,
+        Err(e) => return handle_io_error(e),
+    };
+    let upload = headers.into_upload(body.content(), compression);
+
+    if put_query.validate {
+        return match storage.validate(&path, &upload).await {
+            Ok(()) => Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(make_empty_body())
+                .unwrap(),
+            Err(err) => handle_storage_error(err),
+        };
+    }
+
+    let result = storage.put(&path, version, upload).await;
+    auditor.record(|| put_event(&path, version, &result));
+    put_response(result, version, http)
+}
+
+#[derive(Deserialize)]
+struct CopyQuery {
+    /// Move the file instead of copying it, for POSTs.
+    #[serde(default, rename = "move")]
+    remove_source: bool,
+}
+
+/// Whether a request to `/files/` copies (`false`) or moves (`true`) the file, `None`
+/// if it does neither. Clients that can't send COPY or MOVE can POST instead.
+fn copy_mode(method: &axum::http::Method, uri: &axum::http::Uri) -> Option<bool> {
+    match method.as_str() {
+        "COPY" => Some(false),
+        "MOVE" => Some(true),
+        "POST" => Some(
+            Query::<CopyQuery>::try_from_uri(uri).is_ok_and(|Query(query)| query.remove_source),
+        ),
+        _ => None,
+    }
+}
+
+/// The file named by the `Destination` header of a copy.
+fn destination(request_headers: &axum::http::HeaderMap) -> Result<String, &'static str> {
+    let invalid = "Invalid Destination header";
+    let value = request_headers
+        .get(headers::DESTINATION)
+        .ok_or("Copies and moves need a Destination header")?;
+    let uri = value
+        .to_str()
+        .ok()
+        .and_then(|value| value.parse::<axum::http::Uri>().ok())
+        .ok_or(invalid)?;
+    let path = uri
+        .path()
+        .strip_prefix("/files/")
+        .ok_or("The Destination must be a path under /files/")?;
+    util::percent_decode(path)
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .ok_or(invalid)
+}
+
+/// Stores a file under the path in `Destination` without uploading it again,
+/// by pointing the new file at the same blob.
+#[allow(clippy::too_many_arguments)]
+async fn copy_file(
+    Path(path): Path<String>,
+    State(storage): State<Arc<dyn Storage>>,
+    State(http): State<HttpConfig>,
+    auditor: Auditor,
+    query: LastModifiedQuery,
+    method: axum::http::Method,
+    uri: axum::http::Uri,
+    request_headers: axum::http::HeaderMap,
+) -> Response {
+    let mut response = match copy_mode(&method, &uri) {
+        Some(remove_source) => {
+            copy_or_move(
+                path,
+                storage,
+                http,
+                auditor,
+                query,
+                remove_source,
+                request_headers,
+            )
+            .await
+        }
+        None => make_error_response("Method not allowed", StatusCode::METHOD_NOT_ALLOWED),
+    };
+    // Otherwise the router sets one without COPY and MOVE, since it only knows of
+    // them through the fallback.
+    response.headers_mut().insert(
+        "Allow",
+        axum::http::HeaderValue::from_static("GET,HEAD,PUT,DELETE,POST,COPY,MOVE"),
+    );
+    response
+}
+
+async fn copy_or_move(
+    path: String,
+    storage: Arc<dyn Storage>,
+    http: HttpConfig,
+    auditor: Auditor,
+    query: LastModifiedQuery,
+    remove_source: bool,
+    request_headers: axum::http::HeaderMap,
+) -> Response {
+    let to = match destination(&request_headers) {
+        Ok(to) => to,
+        Err(message) => return make_error_response(message, StatusCode::BAD_REQUEST),
+    };
+    let Some(version) = query.version_or_now(http) else {
+        return missing_version_response();
+    };
+
+    let result = storage.copy(&path, &to, version, remove_source).await;
+    auditor.record(|| audit::Event {
+        action: match remove_source {
+            true => audit::Action::Move,
+            false => audit::Action::Copy,
+        },
+        to: Some(to.clone()),
+        ..put_event(&path, version, &result)
+    });
+    put_response(result, version, http)
+}
+
+#[derive(Deserialize)]
+struct DeleteQuery {
+    /// Delete the file regardless of its version.
+    #[serde(default)]
+    force: bool,
+}
+
+async fn delete_file(
+    Path(path): Path<String>,
+    State(storage): State<Arc<dyn Storage>>,
+    State(http): State<HttpConfig>,
+    auditor: Auditor,
+    query: LastModifiedQuery,
+    Query(delete_query): Query<DeleteQuery>,
+) -> Response {
+    let max_version = if delete_query.force {
+        None
+    } else {
+        let Some(version) = query.version_or_now(http) else {
+            return missing_version_response();
+        };
+        Some(version)
+    };
+
+    // NOTE: A kept file still results in a 200 for compatibility with the original
+    //       filetracker, X-Deleted is how clients can tell the difference.
+    let result = storage.delete(&path, max_version).await;
+    auditor.record(|| delete_event(&path, max_version, &result));
+    match result {
+        Ok(DeleteOutcome::Deleted) => Response::builder()
+            .header(headers::X_DELETED, "true")
+            .body(make_empty_body())
+            .unwrap(),
+        Ok(DeleteOutcome::Superseded { version }) => Response::builder()
+            .header(headers::X_DELETED, "false")
+            .header(headers::LAST_MODIFIED, http.date_format.format(version))
+            .body(make_empty_body())
+            .unwrap(),
+        Err(e) => handle_storage_error(e),
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum ListFormat {
+    Text,
+    Html,
+    /// Paginated, see `limit` and `cursor`.
+    Json,
+}
+
+/// Entries in a page of a JSON listing unless the client asks for another number.
+const DEFAULT_LIST_PAGE_SIZE: usize = 1000;
+
+#[derive(Deserialize)]
+struct ListQuery {
+    /// Defaults to HTML for clients that accept it and to text otherwise.
+    format: Option<ListFormat>,
+    /// Entries per page of a JSON listing, capped by `--list-max-entries`.
+    limit: Option<usize>,
+    /// Where to continue a JSON listing, from the previous page's `next_cursor`.
+    cursor: Option<String>,
+    /// How many directory levels to list, 0 lists the whole tree.
+    #[serde(default)]
+    depth: usize,
+    /// `false` lists only the direct children, with subdirectories as entries of their own.
+    #[serde(default = "default_recursive")]
+    recursive: bool,
+    /// Only list files with this content.
+    checksum: Option<String>,
+    /// Leave out files with corrupt metadata instead of failing, see `X-Skipped-Entries`.
+    #[serde(default)]
+    skip_corrupt: bool,
+}
+
+fn default_recursive() -> bool {
+    true
+}
+
+fn html_escape(data: &str) -> String {
+    let mut result = String::with_capacity(data.len());
+    for c in data.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&#39;"),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+fn accepts_html(headers: &axum::http::HeaderMap) -> bool {
+    headers
+        .get_all("Accept")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|media_type| media_type.split(';').next().unwrap().trim() == "text/html")
+}
+
+fn html_index(path: &str, mut entries: Vec<(String, ListEntry)>) -> Response {
+    entries.sort_by(|(a_name, a), (b_name, b)| {
+        matches!(b, ListEntry::Directory)
+            .cmp(&matches!(a, ListEntry::Directory))
+            .then_with(|| a_name.cmp(b_name))
+    });
+
+    let base = path.trim_matches('/');
+    let full_path = |name: &str| match base {
+        "" => name.to_string(),
+        _ => format!("{base}/{name}"),
+    };
+    let list_href = |path: &str| match path {
+        "" => "/list/?format=html".to_string(),
+        _ => format!(
+            "/list/{}/?format=html",
+            percent_encode_component(path, true)
+        ),
+    };
+
+    let title = html_escape(&format!("Index of /{base}"));
+    let mut result = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{title}</title></head>\n\
+         <body>\n<h1>{title}</h1>\n<table>\n"
+    );
+    if !base.is_empty() {
+        let parent = base.rsplit_once('/').map_or("", |(parent, _)| parent);
+        writeln!(
+            result,
+            "<tr><td><a href=\"{}\">../</a></td><td></td><td></td></tr>",
+            html_escape(&list_href(parent))
+        )
+        .unwrap();
+    }
+    for (name, entry) in entries {
+        let full = full_path(&name);
+        match entry {
+            ListEntry::Directory => writeln!(
+                result,
+                "<tr><td><a href=\"{}\">{}/</a></td><td></td><td></td></tr>",
+                html_escape(&list_href(&full)),
+                html_escape(&name)
+            ),
+            ListEntry::File(metadata) => writeln!(
+                result,
+                "<tr><td><a href=\"{}\">{}</a></td><td>{}</td><td>{}</td></tr>",
+                html_escape(&format!("/files/{}", percent_encode_component(&full, true))),
+                html_escape(&name),
+                metadata.decompressed_size,
+                metadata.version.to_rfc2822()
+            ),
+            ListEntry::Corrupt => continue,
+        }
+        .unwrap();
+    }
+    result.push_str("</table>\n</body>\n</html>\n");
+
+    Response::builder()
+        .header("Content-Type", "text/html; charset=utf-8")
+        .body(make_body(result))
+        .unwrap()
+}
+
+async fn list_files(
+    path: Option<Path<String>>,
+    State(storage): State<Arc<dyn Storage>>,
+    State(http): State<HttpConfig>,
+    query: LastModifiedQuery,
+    Query(list_query): Query<ListQuery>,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    let path = path.as_deref().map(String::as_str).unwrap_or("");
+    let max_version = query.last_modified.unwrap_or_else(Utc::now);
+    let checksum = match list_query.checksum.as_deref().map(parse_checksum) {
+        Some(Ok(checksum)) => Some(checksum),
+        Some(Err(e)) => {
+            return make_error_response(format!("Invalid checksum: {e}"), StatusCode::BAD_REQUEST)
+        }
+        None => None,
+    };
+    let format = list_query
+        .format
+        .unwrap_or_else(|| match accepts_html(&headers) {
+            true => ListFormat::Html,
+            false => ListFormat::Text,
+        });
+    let html = format == ListFormat::Html;
+    let json = format == ListFormat::Json;
+    if !json && (list_query.limit.is_some() || list_query.cursor.is_some()) {
+        return make_error_response(
+            "Only JSON listings can be paginated",
+            StatusCode::BAD_REQUEST,
+        );
+    }
+    if !list_query.recursive && list_query.depth > 1 {
+        return make_error_response(
+            "A non-recursive listing has a single level",
+            StatusCode::BAD_REQUEST,
+        );
+    }
+    if list_query.limit == Some(0) {
+        return make_error_response("The limit must be positive", StatusCode::BAD_REQUEST);
+    }
+    let after = match list_query.cursor.as_deref().map(decode_cursor) {
+        Some(Some(after)) => Some(after),
+        Some(None) => return make_error_response("Invalid cursor", StatusCode::BAD_REQUEST),
+        None => None,
+    };
+
+    // The HTML index is meant for browsing, so it only shows a single level.
+    let options = if html {
+        ListOptions {
+            max_version,
+            max_depth: Some(1),
+            directories: true,
+            skip_corrupt: list_query.skip_corrupt,
+            sorted: false,
+            after: None,
+        }
+    } else {
+        ListOptions {
+            max_depth: match list_query.recursive {
+                true => (list_query.depth != 0).then_some(list_query.depth),
+                false => Some(1),
+            },
+            directories: !list_query.recursive,
+            skip_corrupt: list_query.skip_corrupt,
+            // Pages are only consistent with one another if the order is stable.
+            sorted: json,
+            after,
+            ..ListOptions::recursive(max_version)
+        }
+    };
+    let max_entries = match json {
+        true => Some(
+            list_query
+                .limit
+                .unwrap_or(DEFAULT_LIST_PAGE_SIZE)
+                .min(http.list_max_entries.unwrap_or(usize::MAX)),
+        ),
+        false => http.list_max_entries,
+    };
+
+    // NOTE: Finding files by checksum has to walk the whole tree, an index from
+    //       checksums to paths would be needed to make this fast.
+    let stream = match storage.list_stream(path, options).await {
+        Ok(stream) => stream,
+        Err(e) => return handle_storage_error(e),
+    };
+    if format == ListFormat::Text && max_entries.is_none() {
+        return text_index_stream(stream, checksum, list_query.skip_corrupt).await;
+    }
+
+    // NOTE: Listings of bounded size are collected before responding so that an
+    //       error halfway through the walk can still be reported with a proper status.
+    let mut stream = std::pin::pin!(stream);
+    let mut entries = Vec::new();
+    let mut skipped = 0;
+    let mut truncated = false;
+    while let Some(entry) = stream.next().await {
+        let (path, entry) = match entry {
+            Ok((_, ListEntry::Corrupt)) => {
+                skipped += 1;
+                continue;
+            }
+            Ok(entry) => entry,
+            Err(e) => return handle_storage_error(e),
+        };
+        if !matches_checksum(checksum, &entry) {
+            continue;
+        }
+        // Dropping the stream also stops the walk.
+        if max_entries.is_some_and(|max| entries.len() >= max) {
+            truncated = true;
+            break;
+        }
+        entries.push((path, entry));
+    }
+
+    let mut response = match format {
+        ListFormat::Html => html_index(path, entries),
+        ListFormat::Text => text_index(entries),
+        ListFormat::Json => json_index(entries, truncated),
+    };
+    if list_query.skip_corrupt {
+        response
+            .headers_mut()
+            .insert(headers::X_SKIPPED_ENTRIES, skipped.into());
+    }
+    // A JSON listing continues on the next page instead.
+    if truncated && !json {
+        *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+        response
+            .headers_mut()
+            .insert(headers::X_TRUNCATED, "true".parse().unwrap());
+    }
+    response
+}
+
+/// Whether an entry belongs in a listing restricted to files with `checksum`.
+fn matches_checksum(checksum: Option<[u8; 32]>, entry: &ListEntry) -> bool {
+    match (checksum, entry) {
+        (Some(checksum), ListEntry::File(metadata)) => metadata.checksum == checksum,
+        (Some(_), ListEntry::Directory | ListEntry::Corrupt) => false,
+        (None, _) => true,
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum JsonListEntry {
+    File {
+        path: String,
+        version: String,
+        size: usize,
+        checksum: String,
+        /// As named by the `compression` parameter of PUT.
+        compression: String,
+    },
+    Directory {
+        path: String,
+    },
+}
+
+#[derive(Serialize)]
+struct JsonListPage {
+    entries: Vec<JsonListEntry>,
+    /// Passed as `cursor` to get the next page, `null` on the last one.
+    next_cursor: Option<String>,
+}
+
+// The path of the last entry of a page, opaque to clients so that it can change.
+fn encode_cursor(path: &str) -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(path)
+}
+
+fn decode_cursor(cursor: &str) -> Option<String> {
+    String::from_utf8(BASE64_URL_SAFE_NO_PAD.decode(cursor).ok()?).ok()
+}
+
+fn json_index(entries: Vec<(String, ListEntry)>, truncated: bool) -> Response {
+    let next_cursor = entries
+        .last()
+        .filter(|_| truncated)
+        .map(|(path, _)| encode_cursor(path));
+    let entries = entries
+        .into_iter()
+        .filter_map(|(path, entry)| match entry {
+            ListEntry::File(metadata) => Some(JsonListEntry::File {
+                path,
+                version: metadata.version.to_rfc2822(),
+                size: metadata.decompressed_size,
+                checksum: bytes_to_hex(&metadata.checksum),
+                compression: clap::ValueEnum::to_possible_value(&metadata.compression)
+                    .unwrap()
+                    .get_name()
+                    .to_string(),
+            }),
+            ListEntry::Directory => Some(JsonListEntry::Directory { path }),
+            ListEntry::Corrupt => None,
+        })
+        .collect();
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(make_body(
+            serde_json::to_string(&JsonListPage {
+                entries,
+                next_cursor,
+            })
+            .unwrap(),
+        ))
+        .unwrap()
+}
+
+/// Sends the listing format of the original filetracker as the walk advances,
+/// keeping memory bounded no matter how large the tree is.
+///
+/// The status has to be sent before the walk is done, so an error halfway through
+/// aborts the response, and `X-Skipped-Entries` is sent as a trailer to clients
+/// that accept them with `TE: trailers`.
+async fn text_index_stream(
+    entries: impl futures_util::Stream<Item = Result<(String, ListEntry), StorageError>>
+        + Send
+        + 'static,
+    checksum: Option<[u8; 32]>,
+    skip_corrupt: bool,
+) -> Response {
+    use http_body::Frame;
+    use http_body_util::StreamBody;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Lines sent together, so that large listings aren't sent a chunk per file.
+    const BATCH_SIZE: usize = 256;
+
+    // An error right away, e.g. corrupt metadata in a small directory, can still
+    // get a proper status.
+    let mut entries = Box::pin(entries);
+    let first = match entries.next().await {
+        Some(Err(e)) => return handle_storage_error(e),
+        first => first,
+    };
+    let entries = futures_util::stream::iter(first).chain(entries);
+
+    let skipped = Arc::new(AtomicUsize::new(0));
+    let counter = skipped.clone();
+    let lines = entries.filter_map(move |entry| {
+        let line = match entry {
+            Ok((_, ListEntry::Corrupt)) => {
+                counter.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+            Ok((path, entry)) if matches_checksum(checksum, &entry) => {
+                let mut line = String::new();
+                write_text_entry(&mut line, &path, &entry);
+                Some(Ok(line))
+            }
+            Ok(_) => None,
+            Err(e) => Some(Err(e)),
+        };
+        std::future::ready(line)
+    });
+    let frames = lines.ready_chunks(BATCH_SIZE).map(|lines| {
+        let mut batch = String::new();
+        for line in lines {
+            batch.push_str(&line?);
+        }
+        std::io::Result::Ok(Frame::data(Bytes::from(batch)))
+    });
+
+    if !skip_corrupt {
+        return Response::new(Body::new(StreamBody::new(frames)));
+    }
+    let trailers = futures_util::stream::once(async move {
+        let mut trailers = axum::http::HeaderMap::new();
+        trailers.insert(
+            headers::X_SKIPPED_ENTRIES,
+            skipped.load(Ordering::Relaxed).into(),
+        );
+        Ok(Frame::trailers(trailers))
+    });
+    Response::builder()
+        // Matched against the lowercase names of the trailers by hyper.
+        .header("Trailer", headers::X_SKIPPED_ENTRIES.to_ascii_lowercase())
+        .body(Body::new(StreamBody::new(frames.chain(trailers))))
+        .unwrap()
+}
+
+/// The listing format of the original filetracker.
+fn text_index(entries: Vec<(String, ListEntry)>) -> Response {
+    let mut result = String::new();
+    for (path, entry) in entries {
+        write_text_entry(&mut result, &path, &entry);
+    }
+    Response::new(make_body(result))
+}
+
+/// Appends an entry in the listing format of the original filetracker. It has no
+/// notion of directories, so those are marked with a trailing slash, version 0 and
+/// size 0.
+fn write_text_entry(out: &mut String, path: &str, entry: &ListEntry) {
+    match entry {
+        ListEntry::File(metadata) => write!(
+            out,
+            "{path}\n{}\n{}\n",
+            metadata.version.timestamp(),
+            metadata.decompressed_size
+        )
+        .unwrap(),
+        ListEntry::Directory => write!(out, "{path}/\n0\n0\n").unwrap(),
+        ListEntry::Corrupt => (),
+    }
+}
+
+#[derive(Serialize)]
+struct ExistsEntry {
+    path: String,
+    exists: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    checksum: Option<String>,
+}
+
+/// Accepts either a JSON array of paths or a newline delimited list of them.
+fn parse_path_list(body: &[u8]) -> Option<Vec<String>> {
+    let text = std::str::from_utf8(body).ok()?;
+    if text.trim_start().starts_with('[') {
+        serde_json::from_str(text).ok()
+    } else {
+        Some(
+            text.lines()
+                .filter(|line| !line.is_empty())
+                .map(str::to_string)
+                .collect(),
+        )
+    }
+}
+
+/// How many lookups of an `/exists` request are in flight at once.
+const EXISTS_CONCURRENCY: usize = 16;
+
+async fn check_exists(State(storage): State<Arc<dyn Storage>>, body: Bytes) -> Response {
+    let Some(paths) = parse_path_list(&body) else {
+        return make_error_response("Invalid path list", StatusCode::BAD_REQUEST);
+    };
+
+    let results = futures_util::stream::iter(paths)
+        .map(|path| {
+            let storage = &storage;
+            async move {
+                match storage.metadata(&path).await {
+                    Ok(metadata) => Ok(ExistsEntry {
+                        path,
+                        exists: true,
+                        version: Some(metadata.version.to_rfc2822()),
+                        checksum: Some(bytes_to_hex(&metadata.checksum)),
+                    }),
+                    Err(
+                        StorageError::NotFound(_)
+                        | StorageError::Conflict(_)
+                        | StorageError::IsDirectory(_),
+                    ) => Ok(ExistsEntry {
+                        path,
+                        exists: false,
+                        version: None,
+                        checksum: None,
+                    }),
+                    Err(e) => Err(e),
+                }
+            }
+        })
+        .buffered(EXISTS_CONCURRENCY)
+        .try_collect::<Vec<_>>()
+        .await;
+
+    match results {
+        Ok(entries) => Response::builder()
+            .header("Content-Type", "application/json")
+            .body(make_body(serde_json::to_string(&entries).unwrap()))
+            .unwrap(),
+        Err(e) => handle_storage_error(e),
+    }
+}
+
+/// Tells clients whether content is stored, so that they can link files to it with
+/// `link_only` instead of uploading it again.
+async fn head_blob(
+    Path(checksum): Path<String>,
+    State(storage): State<Arc<dyn Storage>>,
+) -> Response {
+    let checksum = match parse_checksum(&checksum.to_ascii_lowercase()) {
+        Ok(checksum) => checksum,
+        Err(e) => {
+            return make_error_response(format!("Invalid checksum: {e}"), StatusCode::BAD_REQUEST)
+        }
+    };
+    match storage.has_blob(&checksum).await {
+        Ok(true) => Response::builder().body(make_empty_body()).unwrap(),
+        Ok(false) => make_error_response("Content is not stored", StatusCode::NOT_FOUND),
+        Err(e) => handle_storage_error(e),
+    }
+}
+
+/// How many deletions of a batch are in flight at once.
+const BATCH_DELETE_CONCURRENCY: usize = 16;
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BatchDeleteItem {
+    Path(String),
+    Entry {
+        path: String,
+        /// Overrides the request's `last_modified` for this path.
+        #[serde(default, deserialize_with = "deserialize_last_modified")]
+        last_modified: Option<DateTime<Utc>>,
+    },
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum BatchDeleteStatus {
+    Deleted,
+    NotFound,
+    Superseded,
+    Error,
+}
+
+#[derive(Serialize)]
+struct BatchDeleteEntry {
+    path: String,
+    status: BatchDeleteStatus,
+    /// The version of a file that was kept because it is newer than requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Deletes every path in a JSON array of either plain paths or
+/// `{"path": ..., "last_modified": ...}` objects.
+///
+/// Each path gets its own result, a failure to delete one of them doesn't stop the others.
+async fn batch_delete(
+    State(storage): State<Arc<dyn Storage>>,
+    State(http): State<HttpConfig>,
+    auditor: Auditor,
+    query: LastModifiedQuery,
+    Query(delete_query): Query<DeleteQuery>,
+    body: Bytes,
+) -> Response {
+    let items: Vec<BatchDeleteItem> = match serde_json::from_slice(&body) {
+        Ok(items) => items,
+        Err(e) => {
+            return make_error_response(format!("Invalid path list: {e}"), StatusCode::BAD_REQUEST)
+        }
+    };
+    if http.require_version
+        && !delete_query.force
+        && query.last_modified.is_none()
+        && items.iter().any(|item| {
+            !matches!(
+                item,
+                BatchDeleteItem::Entry {
+                    last_modified: Some(_),
+                    ..
+                }
+            )
+        })
+    {
+        return missing_version_response();
+    }
+    let default_max_version = query.last_modified.unwrap_or_else(Utc::now);
+
+    let entries: Vec<BatchDeleteEntry> = futures_util::stream::iter(items)
+        .map(|item| {
+            let storage = &storage;
+            let auditor = &auditor;
+            let (path, last_modified) = match item {
+                BatchDeleteItem::Path(path) => (path, None),
+                BatchDeleteItem::Entry {
+                    path,
+                    last_modified,
+                } => (path, last_modified),
+            };
+            let max_version =
+                (!delete_query.force).then(|| last_modified.unwrap_or(default_max_version));
+            async move {
+                let result = storage.delete(&path, max_version).await;
+                auditor.record(|| delete_event(&path, max_version, &result));
+                let (status, version, error) = match result {
+                    Ok(DeleteOutcome::Deleted) => (BatchDeleteStatus::Deleted, None, None),
+                    Ok(DeleteOutcome::Superseded { version }) => (
+                        BatchDeleteStatus::Superseded,
+                        Some(version.to_rfc2822()),
+                        None,
+                    ),
+                    Err(
+                        StorageError::NotFound(_)
+                        | StorageError::Conflict(_)
+                        | StorageError::IsDirectory(_),
+                    ) => (BatchDeleteStatus::NotFound, None, None),
+                    Err(e) => (BatchDeleteStatus::Error, None, Some(e.to_string())),
+                };
+                BatchDeleteEntry {
+                    path,
+                    status,
+                    version,
+                    error,
+                }
+            }
+        })
+        .buffered(BATCH_DELETE_CONCURRENCY)
+        .collect()
+        .await;
+
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(make_body(serde_json::to_string(&entries).unwrap()))
+        .unwrap()
+}
+
+#[derive(Deserialize)]
+struct PromoteQuery {
+    from: String,
+    to: String,
+    #[serde(default = "default_promote_mode")]
+    mode: PromoteMode,
+}
+
+fn default_promote_mode() -> PromoteMode {
+    PromoteMode::Replace
+}
+
+/// Moves all files under one prefix to another, e.g. to publish a set of files
+/// that has been uploaded to a staging prefix.
+async fn promote(
+    State(storage): State<Arc<dyn Storage>>,
+    auditor: Auditor,
+    Query(query): Query<PromoteQuery>,
+) -> Response {
+    let result = storage.promote(&query.from, &query.to, query.mode).await;
+    auditor.record(|| audit::Event {
+        action: audit::Action::Promote,
+        path: query.from.clone(),
+        to: Some(query.to.clone()),
+        version: None,
+        checksum: None,
+        outcome: match result {
+            Ok(_) => "promoted",
+            Err(_) => "failed",
+        },
+    });
+    match result {
+        Ok(outcome) => Response::builder()
+            .header("Content-Type", "application/json")
+            .body(make_body(serde_json::to_string(&outcome).unwrap()))
+            .unwrap(),
+        Err(e) => handle_storage_error(e),
+    }
+}
+
+#[derive(Deserialize)]
+struct StartUploadQuery {
+    path: String,
+}
+
+async fn start_upload(
+    State(storage): State<Arc<dyn Storage>>,
+    State(uploads): State<Arc<UploadSessions>>,
+    Query(query): Query<StartUploadQuery>,
+) -> Response {
+    // Rejected right away rather than once the whole file has been uploaded.
+    if let Err(e) = storage.config().path_limits.normalize(&query.path) {
+        return handle_io_error(e);
+    }
+    match uploads.start(query.path) {
+        Ok(id) => Response::builder()
+            .status(StatusCode::CREATED)
+            .header("Location", format!("/uploads/{id}"))
+            .header("Content-Type", "application/json")
+            .body(make_body(serde_json::json!({ "id": id }).to_string()))
+            .unwrap(),
+        Err(e) => handle_io_error(e),
+    }
+}
+
+async fn head_upload(
+    Path(id): Path<String>,
+    State(uploads): State<Arc<UploadSessions>>,
+) -> Response {
+    match uploads.length(&id).await {
+        Ok(length) => Response::builder()
+            .header(headers::UPLOAD_OFFSET, length)
+            .body(make_empty_body())
+            .unwrap(),
+        Err(e) => handle_io_error(e),
+    }
+}
+
+/// Appends a chunk of data to a resumable upload, the `Upload-Offset` header says where
+/// it starts. Resending already received data is allowed, leaving a gap is not.
+async fn patch_upload(
+    Path(id): Path<String>,
+    State(uploads): State<Arc<UploadSessions>>,
+    State(http): State<HttpConfig>,
+    State(capacity): State<Arc<CapacityWatcher>>,
+    request: Request,
+) -> Response {
+    if let Some(message) = capacity.shortage() {
+        return make_error_response(message, StatusCode::INSUFFICIENT_STORAGE);
+    }
+    let Some(offset) = request
+        .headers()
+        .get(headers::UPLOAD_OFFSET)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+    else {
+        return make_error_response("Missing or invalid Upload-Offset", StatusCode::BAD_REQUEST);
+    };
+
+    // The limit applies to the upload as a whole, i.e. to where this chunk ends.
+    let max_size = http
+        .max_upload_size
+        .map_or(u64::MAX, |max| (max as u64).saturating_sub(offset));
+    let body = http_body_util::Limited::new(request.into_body(), max_size as usize);
+    let content = match body.collect().await {
+        Ok(content) => content.to_bytes(),
+        Err(e) if e.is::<http_body_util::LengthLimitError>() => {
+            return make_error_response(
+                format!(
+                    "The upload exceeds the maximum upload size of {} bytes",
+                    http.max_upload_size.unwrap()
+                ),
+                StatusCode::PAYLOAD_TOO_LARGE,
+            )
+        }
+        Err(e) => {
+            return make_error_response(
+                format!("Failed to receive the body: {e}"),
+                StatusCode::BAD_REQUEST,
+            )
+        }
+    };
+    match uploads.append(&id, offset, &content).await {
+        Ok(AppendOutcome::Appended { length }) => Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .header(headers::UPLOAD_OFFSET, length)
+            .body(make_empty_body())
+            .unwrap(),
+        Ok(AppendOutcome::Gap { length }) => Response::builder()
+            .status(StatusCode::CONFLICT)
+            .header(headers::UPLOAD_OFFSET, length)
+            .body(make_body(
+                "Upload-Offset is past the end of the received data",
+            ))
+            .unwrap(),
+        Err(e) => handle_io_error(e),
+    }
+}
+
+/// Stores a finished resumable upload. The request carries the same headers as a PUT
+/// would, describing the assembled content.
+async fn complete_upload(
+    Path(id): Path<String>,
+    State(storage): State<Arc<dyn Storage>>,
+    State(uploads): State<Arc<UploadSessions>>,
+    State(http): State<HttpConfig>,
+    auditor: Auditor,
+    query: LastModifiedQuery,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    let Some(version) = query.version_or_now(http) else {
+        return missing_version_response();
+    };
+
+    let upload_headers = match UploadHeaders::parse(&headers) {
+        Ok(headers) => headers,
+        Err(message) => return make_error_response(message, StatusCode::BAD_REQUEST),
+    };
+
+    // Streamed from the session's file like a spooled PUT body, the session stays
+    // locked until then so that the data can't change while it is stored.
+    let opened = match uploads.open(&id).await {
+        Ok(opened) => opened,
+        Err(e) => return handle_io_error(e),
+    };
+    let path = opened.path().to_string();
+    let content = storage::Content::File {
+        file: &opened.file,
+        len: opened.length() as usize,
+    };
+
+    let result = storage
+        .put(&path, version, upload_headers.into_upload(content, None))
+        .await;
+    auditor.record(|| put_event(&path, version, &result));
+    if result.is_ok() {
+        if let Err(e) = uploads.remove(&id) {
+            return handle_io_error(e);
+        }
+    }
+    put_response(result, version, http)
+}
+
+/// What a request needs to get through access control.
+enum AccessTarget {
+    /// Anyone may make it, e.g. `/version`.
+    Public,
+    /// Any configured token will do, e.g. to continue an upload session it already knows.
+    Authenticated,
+    /// The token must be scoped to each of these paths.
+    Paths(Vec<(String, Permission)>),
+}
+
+fn access_target(request: &Request) -> AccessTarget {
+    // Decoded the same way the `Path` extractor does, undecodable ones are rejected there.
+    let decode = |encoded: &str| {
+        util::percent_decode(encoded)
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .unwrap_or_else(|| encoded.to_string())
+    };
+    let uri = request.uri();
+    let path = uri.path();
+    if let Some(path) = path.strip_prefix("/files/") {
+        if let Some(remove_source) = copy_mode(request.method(), uri) {
+            let source = match remove_source {
+                true => Permission::Write,
+                false => Permission::Read,
+            };
+            let mut paths = vec![(decode(path), source)];
+            // A missing or invalid one is rejected by the handler.
+            if let Ok(destination) = destination(request.headers()) {
+                paths.push((destination, Permission::Write));
+            }
+            return AccessTarget::Paths(paths);
+        }
+        let permission = match *request.method() {
+            axum::http::Method::GET | axum::http::Method::HEAD => Permission::Read,
+            _ => Permission::Write,
+        };
+        AccessTarget::Paths(vec![(decode(path), permission)])
+    } else if let Some(path) = path.strip_prefix("/meta/") {
+        AccessTarget::Paths(vec![(decode(path), Permission::Read)])
+    } else if let Some(path) = path.strip_prefix("/list") {
+        let path = path.strip_prefix('/').unwrap_or(path);
+        AccessTarget::Paths(vec![(decode(path), Permission::Read)])
+    } else if path == "/exists" {
+        // The paths are in the body, so these need a token for the whole store.
+        AccessTarget::Paths(vec![(String::new(), Permission::Read)])
+    } else if path == "/batch-delete" {
+        AccessTarget::Paths(vec![(String::new(), Permission::Write)])
+    } else if path == "/promote" {
+        match Query::<PromoteQuery>::try_from_uri(uri) {
+            Ok(Query(query)) => AccessTarget::Paths(vec![
+                (query.from, Permission::Write),
+                (query.to, Permission::Write),
+            ]),
+            // Rejected by the handler anyway.
+            Err(_) => AccessTarget::Authenticated,
+        }
+    } else if path == "/uploads" {
+        match Query::<StartUploadQuery>::try_from_uri(uri) {
+            Ok(Query(query)) => AccessTarget::Paths(vec![(query.path, Permission::Write)]),
+            Err(_) => AccessTarget::Authenticated,
+        }
+    } else if path.starts_with("/uploads/")
+        || path.starts_with("/admin/")
+        // Tells whether some content is stored, though not where.
+        || path.starts_with("/blobs/")
+    {
+        AccessTarget::Authenticated
+    } else {
+        AccessTarget::Public
+    }
+}
+
+async fn access_middleware(
+    State(access): State<Option<Arc<AccessControl>>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let Some(access) = access else {
+        return next.run(request).await;
+    };
+
+    let token = request
+        .headers()
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim);
+    let fingerprint = token
+        .filter(|_| access.authenticate(token).is_ok())
+        .map(TokenFingerprint::of);
+    let result = match access_target(&request) {
+        AccessTarget::Public => Ok(()),
+        AccessTarget::Authenticated => access.authenticate(token),
+        AccessTarget::Paths(paths) => paths
+            .iter()
+            .try_for_each(|(path, permission)| access.check(token, path, *permission)),
+    };
+    match result {
+        Ok(()) => {
+            if let Some(fingerprint) = fingerprint {
+                request.extensions_mut().insert(fingerprint);
+            }
+            next.run(request).await
+        }
+        Err(Denied::Unauthenticated) => {
+            let mut response =
+                make_error_response("Missing or unknown access token", StatusCode::UNAUTHORIZED);
+            response.headers_mut().insert(
+                "WWW-Authenticate",
+                axum::http::HeaderValue::from_static("Bearer"),
+            );
+            response
+        }
+        Err(Denied::OutOfScope) => make_error_response(
+            "Access token is not allowed to access this path",
+            StatusCode::FORBIDDEN,
+        ),
+        Err(Denied::InvalidPath(message)) => make_error_response(message, StatusCode::BAD_REQUEST),
+    }
+}
+
+/// Groups requests by the endpoint they are for, keeping the number of label values bounded.
+fn route_label(path: &str) -> &'static str {
+    match path.split('/').nth(1).unwrap_or_default() {
+        "files" => "files",
+        "meta" => "meta",
+        "list" => "list",
+        "exists" => "exists",
+        "batch-delete" => "batch-delete",
+        "promote" => "promote",
+        "uploads" => "uploads",
+        "version" => "version",
+        "stats" => "stats",
+        "admin" => "admin",
+        "metrics" => "metrics",
+        "blobs" => "blobs",
+        _ => "other",
+    }
+}
+
+fn method_label(method: &axum::http::Method) -> &'static str {
+    use axum::http::Method;
+    match *method {
+        Method::GET => "GET",
+        Method::HEAD => "HEAD",
+        Method::PUT => "PUT",
+        Method::POST => "POST",
+        Method::PATCH => "PATCH",
+        Method::DELETE => "DELETE",
+        _ if method.as_str() == "COPY" => "COPY",
+        _ if method.as_str() == "MOVE" => "MOVE",
+        _ => "other",
+    }
+}
+
+/// Counts the bytes of a body as they pass through.
+fn counted_body(body: Body, counter: Arc<Metrics>, sent: bool) -> Body {
+    Body::new(body.map_frame(move |frame| {
+        if let Some(data) = frame.data_ref() {
+            let total = match sent {
+                true => &counter.bytes_sent,
+                false => &counter.bytes_received,
+            };
+            total.fetch_add(data.len() as u64, std::sync::atomic::Ordering::Relaxed);
+        }
+        frame
+    }))
+}
+
+async fn stats_middleware(
+    State(latency): State<Arc<LatencyStats>>,
+    State(metrics): State<Arc<Metrics>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let path = request.uri().path();
+    let operation = if path.starts_with("/files/") {
+        match *request.method() {
+            axum::http::Method::GET => Some(Operation::Get),
+            axum::http::Method::PUT => Some(Operation::Put),
+            axum::http::Method::DELETE => Some(Operation::Delete),
+            _ => None,
+        }
+    } else if path == "/list" || path.starts_with("/list/") {
+        Some(Operation::List)
+    } else {
+        None
+    };
+    let route = route_label(path);
+    let method = method_label(request.method());
+
+    let start = std::time::Instant::now();
+    let request = request.map(|body| counted_body(body, metrics.clone(), false));
+    let response = next.run(request).await;
+    let elapsed = start.elapsed();
+    if let Some(operation) = operation {
+        latency.record(operation, elapsed);
+    }
+    metrics.record_request(route, method, response.status().as_u16(), elapsed);
+    response.map(|body| counted_body(body, metrics, true))
+}
+
+/// Logs a request once its response body has been sent, or the client went away.
+struct AccessRecord {
+    span: tracing::Span,
+    status: u16,
+    start: std::time::Instant,
+    bytes: u64,
+}
+
+impl AccessRecord {
+    fn count(&mut self, data: Option<&axum::body::Bytes>) {
+        self.bytes += data.map_or(0, |data| data.len() as u64);
+    }
+}
+
+impl Drop for AccessRecord {
+    fn drop(&mut self) {
+        tracing::info!(
+            parent: &self.span,
+            status = self.status,
+            duration_ms = self.start.elapsed().as_secs_f64() * 1000.0,
+            bytes = self.bytes,
+            "request finished"
+        );
+    }
+}
+
+/// Runs requests in a span describing them, which whatever a handler logs is part of.
+async fn access_log_middleware(request: Request, next: Next) -> Response {
+    use tracing::Instrument;
+
+    let span = tracing::info_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        client = tracing::field::Empty,
+    );
+    if span.is_disabled() {
+        return next.run(request).await;
+    }
+    if let Some(ConnectInfo(client)) = request.extensions().get::<ConnectInfo<SocketAddr>>() {
+        span.record("client", tracing::field::display(client));
+    }
+
+    let start = std::time::Instant::now();
+    let response = next.run(request).instrument(span.clone()).await;
+    let mut record = AccessRecord {
+        span,
+        status: response.status().as_u16(),
+        start,
+        bytes: 0,
+    };
+    // The record is dropped along with the body, i.e. when the response is finished.
+    response.map(|body| {
+        Body::new(body.map_frame(move |frame| {
+            // Through a method so that the closure owns the whole record.
+            record.count(frame.data_ref());
+            frame
+        }))
+    })
+}
+
+#[derive(Serialize)]
+struct LatencyReport {
+    get: Option<stats::Percentiles>,
+    put: Option<stats::Percentiles>,
+    delete: Option<stats::Percentiles>,
+    list: Option<stats::Percentiles>,
+}
+
+#[derive(Serialize)]
+struct LockReport {
+    files: usize,
+    blobs: usize,
+}
+
+/// Totals over the whole store, each `None` until counted after startup.
+#[derive(Serialize)]
+struct StorageReport {
+    files: Option<storage::FileTotals>,
+    blobs: Option<storage::BlobTotals>,
+    /// Files per blob, i.e. how many copies deduplication saved on average.
+    dedup_ratio: Option<f64>,
+    /// Logical bytes per byte on disk, the savings of deduplication and compression together.
+    storage_ratio: Option<f64>,
+    /// Entries in the lock maps.
+    locks: LockReport,
+}
+
+#[derive(Serialize)]
+struct StatsReport {
+    latency: LatencyReport,
+    storage: StorageReport,
+    /// Of the filesystem holding the data directory, as of the last check.
+    disk: Option<capacity::DiskSpace>,
+}
+
+async fn get_metrics(
+    State(metrics): State<Arc<Metrics>>,
+    State(storage): State<Arc<dyn Storage>>,
+) -> Response {
+    use std::sync::atomic::Ordering;
+
+    let mut out = String::new();
+    metrics.render(&mut out);
+
+    let blobs = storage.blob_counters();
+    metrics::write_value(
+        &mut out,
+        "filetracker_blobs_created_total",
+        "counter",
+        "Blobs written for new content.",
+        blobs.created.load(Ordering::Relaxed),
+    );
+    metrics::write_value(
+        &mut out,
+        "filetracker_blobs_deduplicated_total",
+        "counter",
+        "Writes of content that was already stored.",
+        blobs.deduplicated.load(Ordering::Relaxed),
+    );
+    metrics::write_value(
+        &mut out,
+        "filetracker_blob_compressions_total",
+        "counter",
+        "Passes compressing uploaded content, to store it or to decide whether to.",
+        blobs.compressions.load(Ordering::Relaxed),
+    );
+    metrics::write_value(
+        &mut out,
+        "filetracker_blobs_removed_total",
+        "counter",
+        "Blobs removed once no file referred to them anymore.",
+        blobs.removed.load(Ordering::Relaxed),
+    );
+    // Left out until the blobs present at startup have been counted.
+    if let Some(totals) = blobs.current() {
+        metrics::write_value(
+            &mut out,
+            "filetracker_blobs",
+            "gauge",
+            "Blobs stored.",
+            totals.blobs,
+        );
+        metrics::write_value(
+            &mut out,
+            "filetracker_blob_bytes",
+            "gauge",
+            "Bytes taken up by blobs.",
+            totals.bytes,
+        );
+    }
+    if let Some(totals) = storage.file_counters().current() {
+        metrics::write_value(
+            &mut out,
+            "filetracker_files",
+            "gauge",
+            "Files stored.",
+            totals.files,
+        );
+        metrics::write_value(
+            &mut out,
+            "filetracker_logical_bytes",
+            "gauge",
+            "Sum of the decompressed sizes of all files.",
+            totals.logical_bytes,
+        );
+    }
+    let (file_locks, blob_locks) = storage.lock_counts();
+    metrics::write_value(
+        &mut out,
+        "filetracker_file_locks",
+        "gauge",
+        "Entries in the lock map of files.",
+        file_locks,
+    );
+    metrics::write_value(
+        &mut out,
+        "filetracker_blob_locks",
+        "gauge",
+        "Entries in the lock map of blobs.",
+        blob_locks,
+    );
+
+    Response::builder()
+        .header("Content-Type", "text/plain; version=0.0.4")
+        .body(make_body(out))
+        .unwrap()
+}
+
+async fn get_stats(
+    State(latency): State<Arc<LatencyStats>>,
+    State(storage): State<Arc<dyn Storage>>,
+    State(capacity): State<Arc<CapacityWatcher>>,
+) -> Response {
+    let files = storage.file_counters().current();
+    let blobs = storage.blob_counters().current();
+    let ratio = |numerator: u64, denominator: u64| {
+        (denominator > 0).then(|| numerator as f64 / denominator as f64)
+    };
+    let (file_locks, blob_locks) = storage.lock_counts();
+    let report = StatsReport {
+        latency: LatencyReport {
+            get: latency.percentiles(Operation::Get),
+            put: latency.percentiles(Operation::Put),
+            delete: latency.percentiles(Operation::Delete),
+            list: latency.percentiles(Operation::List),
+        },
+        storage: StorageReport {
+            files,
+            blobs,
+            dedup_ratio: files
+                .zip(blobs)
+                .and_then(|(files, blobs)| ratio(files.files, blobs.blobs)),
+            storage_ratio: files
+                .zip(blobs)
+                .and_then(|(files, blobs)| ratio(files.logical_bytes, blobs.bytes)),
+            locks: LockReport {
+                files: file_locks,
+                blobs: blob_locks,
+            },
+        },
+        disk: capacity.current(),
+    };
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(make_body(serde_json::to_string(&report).unwrap()))
+        .unwrap()
+}
+
+async fn get_scrub_report(State(storage): State<Arc<dyn Storage>>) -> Response {
+    match storage.scrub_report() {
+        Some(report) => Response::builder()
+            .header("Content-Type", "application/json")
+            .body(make_body(serde_json::to_string(&report).unwrap()))
+            .unwrap(),
+        None => make_error_response(
+            "The scrubber is not running, see --scrub-rate",
+            StatusCode::NOT_FOUND,
+        ),
+    }
+}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "<non-string panic payload>"
+    }
+}
+
+async fn catch_panic_middleware(request: Request, next: Next) -> Response {
+    let method = request.method().to_string();
+    let uri = request.uri().to_string();
+    match match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| next.run(request))) {
+        Ok(future) => std::panic::AssertUnwindSafe(future).catch_unwind().await,
+        Err(error) => Err(error),
+    } {
+        Ok(response) => response,
+        Err(payload) => {
+            let mut id = [0; 8];
+            rand::thread_rng().fill_bytes(&mut id);
+            let id = bytes_to_hex(&id);
+
+            // NOTE: The backtrace, if enabled with RUST_BACKTRACE, has already been
+            //       printed by the panic hook since the stack is gone by now.
+            // The method and URI are repeated since the request span is only
+            // recorded at the info level.
+            tracing::error!(
+                request_id = %id,
+                %method,
+                %uri,
+                payload = panic_message(&*payload),
+                "request panicked"
+            );
+            make_error_response(
+                format!("Internal server error, request id {id}"),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        }
+    }
+}
+
+/// An RFC 9457 problem details object.
+#[derive(Serialize)]
+struct Problem {
+    r#type: &'static str,
+    title: &'static str,
+    status: u16,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    detail: String,
+}
+
+/// Rewrites plain text error responses into `application/problem+json` if configured to.
+///
+/// Done as a middleware so that it also covers the rejections produced by axum itself.
+async fn error_format_middleware(
+    State(http): State<HttpConfig>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let response = next.run(request).await;
+    let status = response.status();
+    let is_error = status.is_client_error() || status.is_server_error();
+    let is_plain = response
+        .headers()
+        .get("Content-Type")
+        .is_none_or(|value| value.as_bytes().starts_with(b"text/plain"));
+    if http.error_format == ErrorFormat::Plain || !is_error || !is_plain {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    // Error messages are tiny, anything else is best left alone.
+    let detail = match axum::body::to_bytes(body, 64 * 1024).await {
+        Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+        Err(_) => String::new(),
+    };
+    let problem = Problem {
+        r#type: "about:blank",
+        title: status.canonical_reason().unwrap_or("Unknown error"),
+        status: status.as_u16(),
+        detail,
+    };
+    parts.headers.remove("Content-Length");
+    parts.headers.insert(
+        "Content-Type",
+        axum::http::HeaderValue::from_static("application/problem+json"),
+    );
+    Response::from_parts(parts, make_body(serde_json::to_string(&problem).unwrap()))
+}
+
+#[derive(clap::Parser)]
+#[clap(args_override_self = true)]
+pub struct Opts {
+    /// Read defaults for the options below from this TOML file, with keys named like
+    /// the options, e.g. `log_level = "debug"`. The command line takes precedence.
+    #[clap(long, env = CONFIG_ENV)]
+    config: Option<PathBuf>,
+    #[clap(long = "listen", short = 'l', default_value = "127.0.0.1:9999")]
+    address: SocketAddr,
+    #[clap(long, short)]
+    directory: PathBuf,
+    #[clap(flatten)]
+    storage: storage::StorageConfig,
+    #[clap(flatten)]
+    uploads: uploads::UploadConfig,
+    #[clap(flatten)]
+    http: HttpConfig,
+    #[clap(flatten)]
+    serve: ServeConfig,
+    #[clap(flatten)]
+    access: access::AccessConfig,
+    #[clap(flatten)]
+    audit: audit::AuditConfig,
+    #[clap(flatten)]
+    spool: spool::SpoolConfig,
+    #[clap(flatten)]
+    capacity: capacity::CapacityConfig,
+    #[clap(flatten)]
+    log: logging::LogConfig,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+const CONFIG_ENV: &str = "FILETRACKER_CONFIG";
+
+impl Opts {
+    /// Parses the command line along with the configuration file, exiting with an
+    /// error message on failure like [`clap::Parser::parse`].
+    pub fn load() -> Self {
+        use clap::{CommandFactory, FromArgMatches};
+
+        let mut command = Self::command();
+        let args =
+            crate::config::with_config_file(std::env::args_os().collect(), &command, CONFIG_ENV)
+                .unwrap_or_else(|e| {
+                    command
+                        .error(clap::error::ErrorKind::InvalidValue, e)
+                        .exit()
+                });
+        Self::from_arg_matches(&command.get_matches_from(args)).unwrap_or_else(|e| e.exit())
+    }
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Import a store created by the original Python filetracker into the directory and exit.
+    #[clap(alias = "import-legacy")]
+    MigrateFromLegacy { old_dir: PathBuf },
+    /// Recompute blob refcounts, remove unreferenced blobs and leftovers of unfinished
+    /// writes, then exit. The server must not be running.
+    Gc {
+        /// Only report what would be done.
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Re-hash every blob and check that every file's blob exists, then exit with a
+    /// non-zero status if anything is wrong. The server must not be running.
+    Fsck {
+        /// Move corrupt blobs to the quarantine directory in the data directory.
+        /// Files referring to them then appear as missing.
+        #[clap(long)]
+        quarantine: bool,
+    },
+}
+
+#[derive(clap::Args, Clone)]
+pub struct ServeConfig {
+    /// Also accept HTTP/2 connections, including cleartext ones with prior knowledge.
+    #[clap(long)]
+    pub http2: bool,
+    /// Keep HTTP/1.1 connections open between requests.
+    #[clap(long, default_value_t = true, action = clap::ArgAction::Set)]
+    pub keep_alive: bool,
+    /// Ping idle HTTP/2 connections this often, in seconds, to detect dead peers.
+    #[clap(long, value_parser = parse_seconds)]
+    pub http2_keep_alive_interval: Option<Duration>,
+    /// Maximum number of concurrent requests on a single HTTP/2 connection.
+    #[clap(long)]
+    pub http2_max_concurrent_streams: Option<u32>,
+    #[clap(flatten)]
+    pub tls: tls::TlsConfig,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum DateFormat {
+    /// `Thu, 01 Jan 2099 00:00:00 +0000`, what the original filetracker sends.
+    Rfc2822,
+    /// `Thu, 01 Jan 2099 00:00:00 GMT`, the format mandated by RFC 7231.
+    ImfFixdate,
+}
+
+impl DateFormat {
+    pub fn format(self, date: DateTime<Utc>) -> String {
+        match self {
+            DateFormat::Rfc2822 => date.to_rfc2822(),
+            DateFormat::ImfFixdate => date.format("%a, %d %b %Y %H:%M:%S GMT").to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ErrorFormat {
+    /// A plain text message, like the original filetracker.
+    Plain,
+    /// `application/problem+json` as described by RFC 9457.
+    Problem,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum DirectoryResponse {
+    /// Respond with a 404 pointing at the listing of the directory.
+    NotFound,
+    /// Redirect to the listing of the directory.
+    Redirect,
+}
+
+#[derive(Clone, Copy, clap::Args)]
+pub struct HttpConfig {
+    /// Format of the Last-Modified headers sent in responses.
+    ///
+    /// Both are understood by filetracker clients, but strict HTTP caches may reject
+    /// rfc2822 dates.
+    #[clap(long = "last-modified-format", value_enum, default_value = "rfc2822")]
+    pub date_format: DateFormat,
+    /// Reject PUTs and DELETEs without a last_modified instead of versioning them with
+    /// the server's clock, for setups that rely on clients to order their writes.
+    #[clap(long)]
+    pub require_version: bool,
+    /// How GET and HEAD requests for a directory are answered.
+    #[clap(long, value_enum, default_value = "not-found")]
+    pub directory_response: DirectoryResponse,
+    /// Format of the bodies of error responses.
+    #[clap(long, value_enum, default_value = "plain")]
+    pub error_format: ErrorFormat,
+    /// Stop listings after this many entries, answering with a 206 and `X-Truncated`.
+    #[clap(long)]
+    pub list_max_entries: Option<usize>,
+    /// Answer PUTs without a Content-Length, e.g. chunked ones, with 411 Length Required
+    /// instead of reading bodies of unknown size.
+    #[clap(long)]
+    pub require_content_length: bool,
+    /// Answer uploads with bodies larger than this many bytes with 413 Payload Too Large,
+    /// refusing them up front if they declare their Content-Length. Applies to gzipped
+    /// bodies as sent, and to resumable uploads as a whole.
+    #[clap(long)]
+    pub max_upload_size: Option<usize>,
+    /// Send gzipped files as they are stored even to clients that don't accept gzip,
+    /// like the original filetracker does, instead of decompressing them.
+    #[clap(long)]
+    pub always_gzip: bool,
+}
+
+#[derive(Clone, FromRef)]
+pub struct AppState {
+    pub storage: Arc<dyn Storage>,
+    pub uploads: Arc<UploadSessions>,
+    pub http: HttpConfig,
+    pub latency: Arc<LatencyStats>,
+    pub metrics: Arc<Metrics>,
+    pub access: Option<Arc<AccessControl>>,
+    pub audit: Option<Arc<AuditLog>>,
+    pub spool: Arc<Spool>,
+    pub capacity: Arc<CapacityWatcher>,
+}
+
+impl AppState {
+    /// Opens (or creates) the store in `directory`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        directory: &std::path::Path,
+        storage: storage::StorageConfig,
+        uploads: &uploads::UploadConfig,
+        http: HttpConfig,
+        access: &access::AccessConfig,
+        audit: &audit::AuditConfig,
+        spool: &spool::SpoolConfig,
+        capacity: &capacity::CapacityConfig,
+    ) -> std::io::Result<Self> {
+        Ok(Self {
+            storage: storage::open_backend(directory, storage)?,
+            uploads: Arc::new(UploadSessions::create(directory.join("uploads"), uploads)?),
+            http,
+            latency: Arc::default(),
+            metrics: Arc::default(),
+            access: AccessControl::load(access)?.map(Arc::new),
+            audit: AuditLog::open(audit)?.map(Arc::new),
+            spool: Arc::new(Spool::create(directory.join("spool"), spool)?),
+            capacity: Arc::new(CapacityWatcher::start(directory, capacity)?),
+        })
+    }
+}
+
+pub fn build_app(state: AppState) -> axum::Router {
+    axum::Router::new()
+        .route("/version", get(get_version))
+        // filetracker client spaghetti code compatibility
+        .route("/version/", get(get_version))
+        .route(
+            "/files/*path",
+            get(get_file)
+                .head(head_file)
+                .put(put_file)
+                .delete(delete_file)
+                .post(copy_file)
+                // For COPY and MOVE, which have no `MethodFilter` of their own.
+                .fallback(copy_file),
+        )
+        .route("/meta/*path", get(get_meta))
+        .route("/list/*path", get(list_files))
+        .route("/list/", get(list_files))
+        .route("/list", get(list_files))
+        .route("/exists", post(check_exists))
+        .route("/blobs/:checksum", head(head_blob))
+        .route("/batch-delete", post(batch_delete))
+        .route("/promote", post(promote))
+        .route("/uploads", post(start_upload))
+        .route("/uploads/:id", head(head_upload).patch(patch_upload))
+        .route("/uploads/:id/complete", post(complete_upload))
+        .route("/stats", get(get_stats))
+        .route("/admin/stats", get(get_stats))
+        .route("/admin/scrub", get(get_scrub_report))
+        .route("/metrics", get(get_metrics))
+        .layer(axum::middleware::from_fn(catch_panic_middleware))
+        .layer(axum::middleware::from_fn_with_state(
+            state.access.clone(),
+            access_middleware,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            state.http,
+            error_format_middleware,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            stats_middleware,
+        ))
+        .layer(axum::middleware::from_fn(access_log_middleware))
+        .with_state(state)
+}
+
+async fn shutdown_signal() {
+    #[cfg(target_family = "unix")]
+    let cause = {
+        use tokio::select;
+        use tokio::signal::unix::*;
+
+        let mut sigint = signal(SignalKind::interrupt()).unwrap();
+        let mut sigterm = signal(SignalKind::terminate()).unwrap();
+        select! {
+            _ = sigint.recv() => "SIGINT",
+            _ = sigterm.recv() => "SIGTERM"
+        }
+    };
+    #[cfg(not(target_family = "unix"))]
+    let cause = {
+        tokio::signal::ctrl_c().await.unwrap();
+        "ctrl-c"
+    };
+
+    tracing::info!(cause, "signal received, shutting down gracefully");
+}
+
+/// Like `axum::serve`, but with control over the protocols and connection settings.
+pub async fn serve(
+    listener: tokio::net::TcpListener,
+    app: axum::Router,
+    config: &ServeConfig,
+) -> std::io::Result<()> {
+    use hyper_util::{
+        rt::{TokioExecutor, TokioIo},
+        server::{conn::auto, graceful::GracefulShutdown},
+        service::TowerToHyperService,
+    };
+    use tower::ServiceExt;
+
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    builder.http1().keep_alive(config.keep_alive);
+    builder
+        .http2()
+        .max_concurrent_streams(config.http2_max_concurrent_streams)
+        .keep_alive_interval(config.http2_keep_alive_interval);
+    let builder = match config.http2 {
+        true => builder,
+        false => builder.http1_only(),
+    };
+
+    let tls = config.tls.load(config.http2)?;
+    // Stopped along with the server, which may be started again in the same process.
+    let reloader = match &tls {
+        Some(tls) => Some(tokio::spawn(tls.reload_on_hangup()?)),
+        None => None,
+    };
+
+    let graceful = GracefulShutdown::new();
+    let mut shutdown = std::pin::pin!(shutdown_signal());
+    loop {
+        let (stream, peer) = tokio::select! {
+            result = listener.accept() => match result {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // Most likely out of file descriptors, back off for a moment.
+                    tracing::warn!(error = %e, "failed to accept connection");
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+
+        let service = app.clone().map_request(move |mut request: Request<_>| {
+            request.extensions_mut().insert(ConnectInfo(peer));
+            request
+        });
+        let service = TowerToHyperService::new(service);
+        let Some(tls) = &tls else {
+            let connection = builder.serve_connection(TokioIo::new(stream), service);
+            let connection = graceful.watch(connection.into_owned());
+            tokio::spawn(async move {
+                // Errors here are clients misbehaving or going away, nothing to act on.
+                _ = connection.await;
+            });
+            continue;
+        };
+        // The handshake happens in the connection's task so that slow clients don't
+        // hold up accepting others.
+        let handshake = tls.acceptor.accept(stream);
+        let (builder, watcher) = (builder.clone(), graceful.watcher());
+        tokio::spawn(async move {
+            let stream = match handshake.await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    tracing::debug!(%peer, error = %e, "TLS handshake failed");
+                    return;
+                }
+            };
+            let connection = builder.serve_connection(TokioIo::new(stream), service);
+            _ = watcher.watch(connection.into_owned()).await;
+        });
+    }
+
+    if let Some(reloader) = reloader {
+        reloader.abort();
+    }
+    drop(listener);
+    graceful.shutdown().await;
+    Ok(())
+}
+
+pub async fn run(opts: Opts) -> std::io::Result<()> {
+    logging::init(opts.log);
+
+    match opts.command {
+        Some(Command::MigrateFromLegacy { old_dir }) => {
+            let storage = storage::LocalStorage::new(&opts.directory, opts.storage)?;
+            return migrate::migrate_from_legacy(&old_dir, &storage).await;
+        }
+        Some(Command::Gc { dry_run }) => {
+            let storage = storage::LocalStorage::new(&opts.directory, opts.storage)?;
+            let report = storage.collect_garbage(dry_run)?;
+            tracing::info!(
+                files = report.files,
+                missing_blobs = report.missing_blobs,
+                corrupt_metadata = report.corrupt_metadata,
+                dry_run,
+                removed_blobs = report.removed_blobs,
+                freed_bytes = report.freed_bytes,
+                fixed_refcounts = report.fixed_refcounts,
+                removed_leftovers = report.removed_leftovers,
+                "garbage collection finished"
+            );
+            return Ok(());
+        }
+        Some(Command::Fsck { quarantine }) => {
+            let storage = storage::LocalStorage::new(&opts.directory, opts.storage)?;
+            let report = storage.fsck(quarantine)?;
+            tracing::info!(
+                checked_blobs = report.checked_blobs,
+                corrupt_blobs = report.corrupt_blobs,
+                quarantined_blobs = report.quarantined_blobs,
+                files = report.files,
+                missing_blobs = report.missing_blobs,
+                corrupt_metadata = report.corrupt_metadata,
+                "fsck finished"
+            );
+            if !report.is_clean() {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        None => (),
+    }
+
+    let state = AppState::new(
+        &opts.directory,
+        opts.storage,
+        &opts.uploads,
+        opts.http,
+        &opts.access,
+        &opts.audit,
+        &opts.spool,
+        &opts.capacity,
+    )?;
+    let listener = tokio::net::TcpListener::bind(opts.address).await?;
+    serve(listener, build_app(state), &opts.serve).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unexpected_io_errors_are_internal_errors() {
+        let error = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+        let response = handle_storage_error(error.into());
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let error = std::io::Error::from(std::io::ErrorKind::StorageFull);
+        let response = handle_storage_error(error.into());
+        assert_eq!(response.status(), StatusCode::INSUFFICIENT_STORAGE);
+    }
+
+    #[test]
+    fn lock_timeouts_are_unavailable() {
+        let error = std::io::Error::from(std::io::ErrorKind::TimedOut);
+        let response = handle_storage_error(error.into());
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    /// Collects what is logged to it, for tests to look at.
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl tracing_subscriber::fmt::MakeWriter<'_> for CapturedLogs {
+        type Writer = Self;
+
+        fn make_writer(&self) -> Self {
+            self.clone()
+        }
+    }
+
+    async fn panicking_handler() -> &'static str {
+        panic!("the handler gave up")
+    }
+
+    // NOTE: The subscriber is only the default on this thread, which the single
+    //       threaded runtime polls the whole request on.
+    #[tokio::test]
+    async fn caught_panics_are_logged_with_their_payload() {
+        use tower::ServiceExt;
+
+        let logs = CapturedLogs::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(logs.clone())
+            .with_ansi(false)
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let app = axum::Router::new()
+            .route("/panic", axum::routing::get(panicking_handler))
+            .layer(axum::middleware::from_fn(catch_panic_middleware))
+            .layer(axum::middleware::from_fn(access_log_middleware));
+        let response = app
+            .oneshot(Request::get("/panic").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        let id = body
+            .strip_prefix("Internal server error, request id ")
+            .unwrap();
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let panicked = logs
+            .lines()
+            .find(|line| line.contains("request panicked"))
+            .unwrap();
+        assert!(panicked.contains("ERROR"), "{panicked}");
+        assert!(
+            panicked.contains(r#"payload="the handler gave up""#),
+            "{panicked}"
+        );
+        assert!(panicked.contains(&format!("request_id={id}")), "{panicked}");
+        // Within the request's span, which ends with the status.
+        assert!(
+            panicked.contains("request{method=GET uri=/panic}"),
+            "{panicked}"
+        );
+        assert!(logs.contains("request finished status=500"), "{logs}");
+    }
+}
@@ -0,0 +1,133 @@
+//! Receiving upload bodies without holding the large ones in memory.
+
+use std::path::PathBuf;
+
+use futures_util::StreamExt;
+use rand::RngCore;
+use tokio::io::AsyncWriteExt;
+
+use crate::{storage::Content, util::bytes_to_hex};
+
+#[derive(clap::Args)]
+pub struct SpoolConfig {
+    /// Keep upload bodies of up to this many bytes in memory, larger ones are written
+    /// to a temporary file in the data directory as they arrive.
+    #[clap(long, default_value_t = 8 << 20)]
+    pub spool_threshold: usize,
+}
+
+/// A body written out to a temporary file, which is removed when this is dropped.
+pub struct SpoolFile {
+    file: std::fs::File,
+    path: PathBuf,
+    len: usize,
+}
+
+impl Drop for SpoolFile {
+    fn drop(&mut self) {
+        _ = std::fs::remove_file(&self.path);
+    }
+}
+
+pub enum Spooled {
+    Memory(Vec<u8>),
+    File(SpoolFile),
+}
+
+impl Spooled {
+    pub fn content(&self) -> Content<'_> {
+        match self {
+            Spooled::Memory(content) => Content::Memory(content),
+            Spooled::File(spooled) => Content::File {
+                file: &spooled.file,
+                len: spooled.len,
+            },
+        }
+    }
+}
+
+pub struct Spool {
+    directory: PathBuf,
+    threshold: usize,
+}
+
+impl Spool {
+    /// Creates the spool directory, removing anything left behind by a crash.
+    pub fn create(directory: PathBuf, config: &SpoolConfig) -> std::io::Result<Self> {
+        match std::fs::remove_dir_all(&directory) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+        std::fs::create_dir_all(&directory)?;
+        Ok(Self {
+            directory,
+            threshold: config.spool_threshold,
+        })
+    }
+
+    /// Receives a whole body, switching over to a file once it grows past the threshold.
+    ///
+    /// Gives up with [`FileTooLarge`](std::io::ErrorKind::FileTooLarge) as soon as
+    /// more than `max_size` bytes have arrived.
+    pub async fn receive(
+        &self,
+        body: axum::body::Body,
+        max_size: Option<usize>,
+    ) -> std::io::Result<Spooled> {
+        let failed = |e: axum::Error| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Failed to receive the body: {e}"),
+            )
+        };
+        let check_size = |received: usize| match max_size {
+            Some(max) if received > max => Err(std::io::Error::new(
+                std::io::ErrorKind::FileTooLarge,
+                format!("The body exceeds the maximum upload size of {max} bytes"),
+            )),
+            _ => Ok(()),
+        };
+
+        let mut stream = body.into_data_stream();
+        let mut buffer = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(failed)?;
+            check_size(buffer.len() + chunk.len())?;
+            if buffer.len() + chunk.len() <= self.threshold {
+                buffer.extend_from_slice(&chunk);
+                continue;
+            }
+
+            let mut id = [0; 16];
+            rand::thread_rng().fill_bytes(&mut id);
+            let path = self.directory.join(bytes_to_hex(&id));
+            let file = tokio::fs::File::options()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&path)
+                .await?;
+            // Removes the file should anything below fail.
+            let mut spooled = SpoolFile {
+                file: file.try_clone().await?.into_std().await,
+                path,
+                len: 0,
+            };
+
+            let mut writer = tokio::io::BufWriter::with_capacity(256 * 1024, file);
+            writer.write_all(&buffer).await?;
+            writer.write_all(&chunk).await?;
+            spooled.len = buffer.len() + chunk.len();
+            drop(buffer);
+            while let Some(chunk) = stream.next().await {
+                let chunk = chunk.map_err(failed)?;
+                check_size(spooled.len + chunk.len())?;
+                writer.write_all(&chunk).await?;
+                spooled.len += chunk.len();
+            }
+            writer.flush().await?;
+            return Ok(Spooled::File(spooled));
+        }
+        Ok(Spooled::Memory(buffer))
+    }
+}
@@ -0,0 +1,141 @@
+//! Bounded in-memory latency histograms for the `/stats` endpoint.
+
+use std::{
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::{Duration, SystemTime},
+};
+
+use serde::Serialize;
+
+/// Bucket `i` counts requests that took less than `2^i` microseconds,
+/// the last one also holds everything slower than that (about 34 seconds).
+const BUCKETS: usize = 26;
+/// Number of one minute slots the percentiles are computed over.
+const WINDOW_MINUTES: u64 = 5;
+/// Requests are spread over shards by thread to avoid contending on the same counters.
+const SHARDS: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    Get,
+    Put,
+    Delete,
+    List,
+}
+
+impl Operation {
+    const ALL: [Operation; 4] = [
+        Operation::Get,
+        Operation::Put,
+        Operation::Delete,
+        Operation::List,
+    ];
+}
+
+#[derive(Default)]
+struct Slot {
+    /// The minute since the epoch this slot is currently counting.
+    minute: AtomicU64,
+    counts: [AtomicU64; BUCKETS],
+}
+
+#[derive(Default)]
+struct Histogram {
+    shards: [[Slot; WINDOW_MINUTES as usize]; SHARDS],
+}
+
+fn current_minute() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / 60
+}
+
+fn shard_index() -> usize {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    thread_local! {
+        static INDEX: usize = NEXT.fetch_add(1, Ordering::Relaxed) % SHARDS;
+    }
+    INDEX.with(|x| *x)
+}
+
+fn bucket_for(duration: Duration) -> usize {
+    let micros = duration.as_micros().max(1);
+    (u128::BITS - micros.leading_zeros()).min(BUCKETS as u32 - 1) as usize
+}
+
+impl Histogram {
+    fn record(&self, duration: Duration) {
+        let minute = current_minute();
+        let slot = &self.shards[shard_index()][(minute % WINDOW_MINUTES) as usize];
+
+        // NOTE: A request recorded concurrently with the reset may get lost,
+        //       which is fine for statistics.
+        if slot.minute.swap(minute, Ordering::Relaxed) != minute {
+            for count in &slot.counts {
+                count.store(0, Ordering::Relaxed);
+            }
+        }
+        slot.counts[bucket_for(duration)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn merged(&self) -> [u64; BUCKETS] {
+        let minute = current_minute();
+        let mut result = [0; BUCKETS];
+        for slot in self.shards.iter().flatten() {
+            if slot.minute.load(Ordering::Relaxed) + WINDOW_MINUTES <= minute {
+                continue;
+            }
+            for (total, count) in result.iter_mut().zip(&slot.counts) {
+                *total += count.load(Ordering::Relaxed);
+            }
+        }
+        result
+    }
+}
+
+#[derive(Serialize)]
+pub struct Percentiles {
+    pub count: u64,
+    /// Upper bounds of the histogram buckets containing each percentile, in milliseconds.
+    pub p50_ms: f64,
+    pub p95_ms: f64,
+    pub p99_ms: f64,
+}
+
+fn percentile(counts: &[u64; BUCKETS], total: u64, fraction: f64) -> f64 {
+    let rank = ((total as f64 * fraction).ceil() as u64).max(1);
+    let mut seen = 0;
+    for (i, count) in counts.iter().enumerate() {
+        seen += count;
+        if seen >= rank {
+            return (1u64 << i) as f64 / 1000.0;
+        }
+    }
+    0.0
+}
+
+/// Latencies of the most recent requests, per operation.
+#[derive(Default)]
+pub struct LatencyStats {
+    histograms: [Histogram; Operation::ALL.len()],
+}
+
+impl LatencyStats {
+    pub fn record(&self, operation: Operation, duration: Duration) {
+        self.histograms[operation as usize].record(duration);
+    }
+
+    /// Computes percentiles over the last few minutes, `None` if there were no requests.
+    pub fn percentiles(&self, operation: Operation) -> Option<Percentiles> {
+        let counts = self.histograms[operation as usize].merged();
+        let total = counts.iter().sum();
+        (total != 0).then(|| Percentiles {
+            count: total,
+            p50_ms: percentile(&counts, total, 0.50),
+            p95_ms: percentile(&counts, total, 0.95),
+            p99_ms: percentile(&counts, total, 0.99),
+        })
+    }
+}
@@ -1,237 +1,2027 @@
 use std::{
-    fs::ReadDir,
-    io::Read,
+    collections::{HashMap, HashSet},
+    fs::DirEntry,
+    io::{Read, Seek, SeekFrom, Write},
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+    time::Duration,
 };
 
+use async_trait::async_trait;
 use chrono::{DateTime, Utc};
+use futures_util::{stream::BoxStream, StreamExt};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
-use crate::{blobstorage::BlobStorage, lockmap::LockMap};
+use crate::{
+    blobstorage::BlobStorage,
+    lockmap::{self, LockMap},
+    path::PathLimits,
+    util::{blocking, parse_seconds, FsyncPolicy},
+};
+
+mod error;
+mod memory;
+#[cfg(feature = "s3")]
+mod s3;
+
+pub use crate::blobstorage::{
+    BlobCounters, BlobInfo, BlobTotals, FsckReport, GcReport, ScrubReport,
+};
+pub use error::StorageError;
+pub use memory::MemoryStorage;
+#[cfg(feature = "s3")]
+pub use s3::{S3Config, S3Storage};
 
-pub trait Storage {
-    async fn get(&self, path: &str) -> std::io::Result<(FileMetadata, Vec<u8>)>;
-    async fn head(&self, path: &str) -> std::io::Result<(FileMetadata, u64)>;
+/// The entries of a listing, see [`Storage::list`].
+pub type ListIter = Box<dyn Iterator<Item = Result<(String, ListEntry), StorageError>> + Send>;
+/// Like [`ListIter`], for [`Storage::list_stream`].
+pub type ListStream = BoxStream<'static, Result<(String, ListEntry), StorageError>>;
+
+/// A storage backend, which the server holds as a `dyn Storage` so that it can be
+/// picked at runtime.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    fn config(&self) -> &StorageConfig;
+    fn blob_counters(&self) -> &BlobCounters;
+    fn file_counters(&self) -> &FileCounters;
+    /// Number of entries in the lock maps of files and blobs.
+    fn lock_counts(&self) -> (usize, usize);
+    /// What the background scrubber has found, `None` if it isn't running.
+    fn scrub_report(&self) -> Option<ScrubReport>;
+
+    /// Opens the blob of a file, whose contents are in the compression given by its metadata.
+    async fn open(&self, path: &str) -> Result<(FileMetadata, Blob), StorageError>;
+    async fn head(&self, path: &str) -> Result<(FileMetadata, BlobInfo), StorageError>;
+    /// Reads only the metadata of a file, without touching its blob.
+    async fn metadata(&self, path: &str) -> Result<FileMetadata, StorageError>;
     async fn put(
         &self,
         path: &str,
         version: DateTime<Utc>,
-        content: &[u8],
-        content_is_gzipped: bool,
-        checksum: Option<[u8; 32]>,
-        logical_size: Option<usize>,
-    ) -> std::io::Result<()>;
-    async fn delete(&self, path: &str, max_version: DateTime<Utc>) -> std::io::Result<()>;
-    async fn list(
+        upload: Upload<'_>,
+    ) -> Result<PutOutcome, StorageError>;
+    /// Performs all the checks `put` would without storing anything.
+    async fn validate(&self, path: &str, upload: &Upload<'_>) -> Result<(), StorageError>;
+    /// The outcome `put` would have regardless of the content, if any, i.e. when
+    /// `If-Match` fails or the write policy keeps a newer stored version. Nothing is
+    /// locked, so `put` may still turn out differently.
+    async fn precheck_put(
+        &self,
+        path: &str,
+        version: DateTime<Utc>,
+        upload: &Upload<'_>,
+    ) -> Result<Option<PutOutcome>, StorageError> {
+        let current = match self.metadata(path).await {
+            Ok(metadata) => Some(metadata),
+            Err(StorageError::NotFound(_)) => None,
+            Err(e) => return Err(e),
+        };
+        if let Some(if_match) = &upload.if_match {
+            if !if_match.matches(current.as_ref().map(|meta| &meta.checksum)) {
+                return Ok(Some(PutOutcome::PreconditionFailed));
+            }
+        }
+        Ok(current
+            .as_ref()
+            .and_then(|meta| refuse_older(self.config().write_policy, meta, version)))
+    }
+    /// Whether content with this checksum is stored, in any compression.
+    async fn has_blob(&self, checksum: &[u8; 32]) -> Result<bool, StorageError>;
+    /// Like `put`, but links the file to content that is already stored, as identified
+    /// by the checksum and size `upload` declares, instead of reading its content.
+    /// Fails with `NotFound` if there's no such content.
+    async fn link(
         &self,
         path: &str,
-        max_version: DateTime<Utc>,
-    ) -> std::io::Result<impl Iterator<Item = std::io::Result<(String, FileMetadata)>>>;
+        version: DateTime<Utc>,
+        upload: Upload<'_>,
+    ) -> Result<PutOutcome, StorageError>;
+    /// Deletes a file unless it is newer than `max_version`, `None` deletes unconditionally.
+    async fn delete(
+        &self,
+        path: &str,
+        max_version: Option<DateTime<Utc>>,
+    ) -> Result<DeleteOutcome, StorageError>;
+    /// Stores the file at `from` under `to` as `version` too, reusing its blob instead
+    /// of uploading the content again. The write policy applies to `to` just like for
+    /// `put`. With `remove_source` the file is moved rather than copied.
+    async fn copy(
+        &self,
+        from: &str,
+        to: &str,
+        version: DateTime<Utc>,
+        remove_source: bool,
+    ) -> Result<PutOutcome, StorageError>;
+    /// Moves all files under the prefix `from` to the same paths under `to`,
+    /// keeping their blobs and versions.
+    async fn promote(
+        &self,
+        from: &str,
+        to: &str,
+        mode: PromoteMode,
+    ) -> Result<PromoteOutcome, StorageError>;
+    async fn list(&self, path: &str, options: ListOptions) -> Result<ListIter, StorageError>;
+    /// Like `list`, but walks the tree on a blocking thread so that it can be consumed
+    /// from async code without stalling the runtime. The stream doesn't borrow the
+    /// storage, so it can e.g. be sent as a response body.
+    async fn list_stream(
+        &self,
+        path: &str,
+        options: ListOptions,
+    ) -> Result<ListStream, StorageError>;
+}
+
+/// An opened blob, which stays readable even if it is removed in the meantime.
+pub enum Blob {
+    File(std::fs::File),
+    Memory(std::io::Cursor<Arc<[u8]>>),
+}
+
+impl Blob {
+    /// Size of the blob as stored, i.e. after compression.
+    pub fn size(&self) -> std::io::Result<u64> {
+        match self {
+            Blob::File(file) => Ok(file.metadata()?.len()),
+            Blob::Memory(data) => Ok(data.get_ref().len() as u64),
+        }
+    }
+}
+
+impl Read for Blob {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Blob::File(file) => file.read(buf),
+            Blob::Memory(data) => data.read(buf),
+        }
+    }
+}
+
+impl Seek for Blob {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match self {
+            Blob::File(file) => file.seek(pos),
+            Blob::Memory(data) => data.seek(pos),
+        }
+    }
+}
+
+pub struct ListOptions {
+    /// Files newer than this are left out.
+    pub max_version: DateTime<Utc>,
+    /// How many directory levels to descend into, `None` walks the whole tree.
+    pub max_depth: Option<usize>,
+    /// Whether directories are listed as entries of their own.
+    pub directories: bool,
+    /// List files whose metadata can't be parsed as [`ListEntry::Corrupt`]
+    /// instead of failing the whole walk.
+    pub skip_corrupt: bool,
+    /// Walk every directory in file name order, so that the order of the listing
+    /// is stable and can be resumed with `after`.
+    pub sorted: bool,
+    /// Leave out every entry up to and including this path, relative to the listed
+    /// directory, in the order of a sorted walk.
+    pub after: Option<String>,
+}
+
+impl ListOptions {
+    /// Options for a listing of all files in a tree, like the original filetracker's.
+    pub fn recursive(max_version: DateTime<Utc>) -> Self {
+        Self {
+            max_version,
+            max_depth: None,
+            directories: false,
+            skip_corrupt: false,
+            sorted: false,
+            after: None,
+        }
+    }
+
+    /// Whether the entry at `path` comes before where the listing starts.
+    fn is_before_start(&self, path: &str) -> bool {
+        self.after
+            .as_deref()
+            .is_some_and(|after| path.split('/').cmp(after.split('/')).is_le())
+    }
+
+    /// Whether the listing starts somewhere inside the directory at `path`.
+    fn contains_start(&self, path: &str) -> bool {
+        self.after.as_deref().is_some_and(|after| {
+            after
+                .strip_prefix(path)
+                .is_some_and(|rest| rest.starts_with('/'))
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum ListEntry {
+    File(FileMetadata),
+    Directory,
+    /// A file with unparseable metadata, see [`ListOptions::skip_corrupt`].
+    Corrupt,
+}
+
+/// The body of an upload, which is read once for every pass over it.
+#[derive(Clone, Copy)]
+pub enum Content<'a> {
+    Memory(&'a [u8]),
+    /// A temporary file the body was written to because it was too large to keep in memory.
+    File {
+        file: &'a std::fs::File,
+        len: usize,
+    },
+}
+
+impl<'a> Content<'a> {
+    pub fn len(&self) -> usize {
+        match self {
+            Content::Memory(content) => content.len(),
+            Content::File { len, .. } => *len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Reads the content from the start.
+    fn reader(&self) -> std::io::Result<Box<dyn Read + Send + 'a>> {
+        match *self {
+            Content::Memory(content) => Ok(Box::new(content)),
+            Content::File { mut file, .. } => {
+                file.seek(SeekFrom::Start(0))?;
+                Ok(Box::new(std::io::BufReader::new(file)))
+            }
+        }
+    }
+
+    fn read_at(&self, offset: usize, len: usize) -> std::io::Result<Vec<u8>> {
+        match *self {
+            Content::Memory(content) => Ok(content[offset..offset + len].to_vec()),
+            Content::File { mut file, .. } => {
+                let mut buf = vec![0; len];
+                file.seek(SeekFrom::Start(offset as u64))?;
+                file.read_exact(&mut buf)?;
+                Ok(buf)
+            }
+        }
+    }
+}
+
+/// An uploaded file along with everything the client told us about it.
+pub struct Upload<'a> {
+    pub content: Content<'a>,
+    /// The compression the content was uploaded with.
+    pub content_encoding: Compression,
+    pub checksum: Option<[u8; 32]>,
+    pub logical_size: Option<usize>,
+    pub filename: Option<String>,
+    /// Store the file with this compression instead of the server's default.
+    pub compression: Option<Compression>,
+    pub if_match: Option<IfMatch>,
+}
+
+/// Checksums the currently stored file must have for a write to go ahead.
+#[derive(Clone)]
+pub enum IfMatch {
+    /// Any file must exist.
+    Any,
+    Checksums(Vec<[u8; 32]>),
+}
+
+impl IfMatch {
+    fn matches(&self, current: Option<&[u8; 32]>) -> bool {
+        match (self, current) {
+            (_, None) => false,
+            (IfMatch::Any, Some(_)) => true,
+            (IfMatch::Checksums(checksums), Some(current)) => checksums.contains(current),
+        }
+    }
+}
+
+/// What `put` should do when the path already holds a newer version.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum WritePolicy {
+    /// Keep the newer version and silently ignore the write.
+    LastWriterWins,
+    /// Keep the newer version and report the write as rejected.
+    RejectOlder,
+    /// Overwrite the file regardless of versions.
+    Always,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PutOutcome {
+    Stored {
+        checksum: [u8; 32],
+        /// Whether the content was already stored for another file, so no new blob was written.
+        deduplicated: bool,
+    },
+    /// A newer version was already present and has been kept.
+    Superseded {
+        version: DateTime<Utc>,
+        checksum: [u8; 32],
+    },
+    /// Like `Superseded`, but the write policy asks for this to be reported as an error.
+    Rejected {
+        version: DateTime<Utc>,
+        checksum: [u8; 32],
+    },
+    /// The stored file didn't match the upload's `if_match`.
+    PreconditionFailed,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DeleteOutcome {
+    Deleted,
+    /// The file was newer than the requested version and has been kept.
+    Superseded {
+        version: DateTime<Utc>,
+    },
+}
+
+/// What happens to files already under the destination of a promotion that
+/// aren't being replaced by one from the source.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PromoteMode {
+    /// Delete them, so that the destination ends up with exactly the source's files.
+    Replace,
+    /// Keep them alongside the promoted files.
+    Merge,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct PromoteOutcome {
+    /// Number of files moved over from the source.
+    pub moved: usize,
+    /// Number of files deleted from the destination.
+    pub removed: usize,
+}
+
+/// Where files are kept.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum BackendKind {
+    /// In the data directory.
+    Local,
+    /// In memory, losing all files when the server stops.
+    Memory,
+    /// In the S3 bucket given with `--bucket`.
+    #[cfg(feature = "s3")]
+    S3,
+}
+
+#[derive(clap::Args)]
+pub struct StorageConfig {
+    /// Where to keep files. Uploads in progress are still kept in the data directory.
+    #[clap(long, value_enum, default_value = "local")]
+    pub backend: BackendKind,
+    #[cfg(feature = "s3")]
+    #[clap(flatten)]
+    pub s3: S3Config,
+    /// When to fsync written blobs and metadata, trading throughput for durability.
+    #[clap(long, value_enum, default_value = "none")]
+    pub fsync: FsyncPolicy,
+    /// How to handle uploads older than the currently stored version.
+    #[clap(long, value_enum, default_value = "last-writer-wins")]
+    pub write_policy: WritePolicy,
+    /// Give up on requests that wait longer than this many seconds for a file lock.
+    #[clap(long, value_parser = parse_seconds)]
+    pub lock_timeout: Option<Duration>,
+    #[clap(flatten)]
+    pub path_limits: PathLimits,
+    /// Directory where new blobs are written before being moved into the store,
+    /// the blob directory itself is used by default.
+    #[clap(long)]
+    pub staging_dir: Option<PathBuf>,
+    /// Sanity check pre-compressed uploads with a client supplied checksum and size
+    /// against their gzip trailer.
+    #[clap(long, default_value_t = true, action = clap::ArgAction::Set)]
+    pub check_gzip_trailer: bool,
+    /// Hash uncompressed uploads even if the client supplied their checksum, rejecting
+    /// them if it doesn't match. Pre-compressed ones are only checked against their
+    /// gzip trailer or zstd frame header.
+    #[clap(long, default_value_t = true, action = clap::ArgAction::Set)]
+    pub verify_checksums: bool,
+    /// How to compress newly written metadata files, existing ones are readable either way.
+    #[clap(long, value_enum, default_value = "none")]
+    pub metadata_compression: Compression,
+    /// Record when each blob was last read, at the cost of a write on every download.
+    #[clap(long)]
+    pub track_blob_access: bool,
+    /// Keep re-verifying the checksums of all blobs in the background, reading at most
+    /// this many bytes per second. Its findings are reported at `/admin/scrub`.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub scrub_rate: Option<u64>,
+    /// Move blobs the scrubber finds corrupt to `<data dir>/quarantine` like
+    /// `fsck --quarantine` does, so that reads of the files using them fail instead
+    /// of serving corrupt content.
+    #[clap(long)]
+    pub scrub_quarantine: bool,
+    /// Lock the data directory so that a second server can't be started on it.
+    #[clap(long, default_value_t = true, action = clap::ArgAction::Set)]
+    pub data_dir_lock: bool,
+    /// Reject uploads declaring a Logical-Size larger than this many bytes.
+    #[clap(long)]
+    pub max_logical_size: Option<usize>,
+    /// How to compress blobs, `gzip` or `zstd`, optionally with a level as in `zstd:19`.
+    /// Without one gzip uses `--blob-compression-level` and zstd its default level.
+    #[clap(long, value_parser = parse_blob_compression, default_value = "gzip")]
+    pub compression: BlobCompression,
+    /// A dictionary trained by `zstd --train` to compress zstd blobs with, which shrinks
+    /// small files of similar structure a lot. Blobs compressed with it record its id and
+    /// can only be read while it's given, so it mustn't be changed once used.
+    #[clap(long, value_name = "FILE", value_parser = load_zstd_dictionary)]
+    pub zstd_dict: Option<Arc<ZstdDictionary>>,
+    /// Store uploads uncompressed unless compressing shrinks them to at most this fraction
+    /// of their size. Deciding requires compressing them in full before writing.
+    ///
+    /// Doesn't apply to uploads requesting a specific compression.
+    #[clap(long, default_value_t = 0.95)]
+    pub blob_compression_min_ratio: f64,
+    /// Store uploads smaller than this many bytes uncompressed without trying to compress them.
+    #[clap(long, default_value_t = 0)]
+    pub blob_compression_min_size: usize,
+    /// Store uploads larger than this many bytes uncompressed without trying to compress
+    /// them, e.g. to keep large test data uploads from being CPU-bound.
+    #[clap(long)]
+    pub blob_compression_max_size: Option<usize>,
+    /// Gzip level used for blobs, from 1 (fastest) to 9 (smallest).
+    #[clap(long, default_value_t = 9, value_parser = clap::value_parser!(u32).range(1..=9))]
+    pub blob_compression_level: u32,
+    /// Store uploads uncompressed, decompressing gzipped ones, for deployments that
+    /// leave compression to a proxy in front of the server.
+    ///
+    /// Doesn't apply to uploads requesting a specific compression.
+    #[clap(long)]
+    pub no_compression: bool,
+    /// Remember this many of the most recently written blobs, so that uploads of the
+    /// same content arriving soon after reference them right away instead of looking
+    /// for them on disk and preparing the content again. 0 disables this.
+    #[clap(long, default_value_t = 1024)]
+    pub recent_blobs: usize,
+    /// How many seconds blobs are remembered for, see `--recent-blobs`.
+    #[clap(long, value_parser = parse_seconds, default_value = "60")]
+    pub recent_blobs_ttl: Duration,
+    /// Digests to compute for uploads in addition to SHA-256, for clients that verify
+    /// downloads with something else.
+    #[clap(long, value_enum, value_delimiter = ',')]
+    pub extra_digests: Vec<ExtraDigest>,
+}
+
+impl StorageConfig {
+    /// The level blobs are compressed at with `compression`.
+    fn compression_level(&self, compression: Compression) -> u32 {
+        match (compression, self.compression.level) {
+            (compression, Some(level)) if compression == self.compression.compression => level,
+            (Compression::Zstd, _) => zstd::DEFAULT_COMPRESSION_LEVEL as u32,
+            _ => self.blob_compression_level,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct FileTotals {
+    pub files: u64,
+    /// Sum of the decompressed sizes of all files.
+    pub logical_bytes: u64,
+}
+
+/// Totals over all files, counted at startup and kept up to date as files change.
+#[derive(Default)]
+pub struct FileCounters {
+    /// Changes since startup.
+    files_delta: AtomicI64,
+    logical_bytes_delta: AtomicI64,
+    /// Files present at startup, known once they have been counted in the background.
+    initial: OnceLock<FileTotals>,
+}
+
+impl FileCounters {
+    /// Records a file being stored, `replaced` being the size of the one it overwrote.
+    fn record_stored(&self, size: usize, replaced: Option<usize>) {
+        if replaced.is_none() {
+            self.files_delta.fetch_add(1, Ordering::Relaxed);
+        }
+        let delta = size as i64 - replaced.unwrap_or(0) as i64;
+        self.logical_bytes_delta.fetch_add(delta, Ordering::Relaxed);
+    }
+
+    fn record_removed(&self, size: usize) {
+        self.files_delta.fetch_sub(1, Ordering::Relaxed);
+        self.logical_bytes_delta
+            .fetch_sub(size as i64, Ordering::Relaxed);
+    }
+
+    /// The files currently stored, once known.
+    ///
+    /// NOTE: Files changed while the initial count is running may be counted twice.
+    pub fn current(&self) -> Option<FileTotals> {
+        let initial = *self.initial.get()?;
+        Some(FileTotals {
+            files: initial
+                .files
+                .saturating_add_signed(self.files_delta.load(Ordering::Relaxed)),
+            logical_bytes: initial
+                .logical_bytes
+                .saturating_add_signed(self.logical_bytes_delta.load(Ordering::Relaxed)),
+        })
+    }
+}
+
+/// Counts the files under `metadata` and adds up their sizes, skipping corrupt ones.
+fn count_files(metadata: &Path) -> std::io::Result<FileTotals> {
+    let options = ListOptions {
+        skip_corrupt: true,
+        ..ListOptions::recursive(DateTime::<Utc>::MAX_UTC)
+    };
+    let lister = FileLister {
+        readdir_stack: vec![read_dir(metadata, options.sorted)?],
+        metadata: metadata.to_owned(),
+        options,
+        cancelled: None,
+    };
+    let mut totals = FileTotals::default();
+    for entry in lister {
+        if let (_, ListEntry::File(metadata)) = entry? {
+            totals.files += 1;
+            totals.logical_bytes += metadata.decompressed_size as u64;
+        }
+    }
+    Ok(totals)
 }
 
 pub struct LocalStorage {
     locks: LockMap<String>,
-    blobs: BlobStorage,
+    /// Held shared by every operation on files, listings included for as long as they
+    /// walk the tree, and exclusively by promotions, so that a promotion is never seen
+    /// or interfered with halfway through.
+    promotions: Arc<tokio::sync::RwLock<()>>,
+    blobs: Arc<BlobStorage>,
+    files: Arc<FileCounters>,
     metadata: PathBuf,
+    /// Where metadata is written before being renamed into place, outside of the
+    /// metadata directory so that listings never see half written files.
+    temp: PathBuf,
+    /// Makes the names of temporary files unique.
+    temp_files: AtomicU64,
+    config: StorageConfig,
+    /// Keeps the store's lock file locked for as long as the storage is alive.
+    _directory_lock: Option<std::fs::File>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, clap::ValueEnum)]
 pub enum Compression {
     None,
     Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// The name of the compression as a content coding.
+    pub fn coding(self) -> &'static str {
+        match self {
+            Compression::None => "identity",
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    /// Wraps `reader` to decompress the data it yields.
+    pub fn decoder<'a>(
+        self,
+        reader: impl Read + Send + 'a,
+    ) -> std::io::Result<Box<dyn Read + Send + 'a>> {
+        self.blob_decoder(reader, None)
+    }
+
+    /// Like [`Self::decoder`], for blobs that may be compressed with `dictionary`.
+    /// Frames compressed without a dictionary decompress fine with one.
+    pub fn blob_decoder<'a>(
+        self,
+        reader: impl Read + Send + 'a,
+        dictionary: Option<&ZstdDictionary>,
+    ) -> std::io::Result<Box<dyn Read + Send + 'a>> {
+        Ok(match self {
+            Compression::None => Box::new(reader),
+            Compression::Gzip => Box::new(flate2::read::GzDecoder::new(reader)),
+            Compression::Zstd => Box::new(zstd::stream::read::Decoder::with_dictionary(
+                std::io::BufReader::new(reader),
+                dictionary.map_or(&[], |dictionary| &dictionary.data),
+            )?),
+        })
+    }
+
+    /// Wraps `reader` to compress the data it yields at `level`, with `dictionary` for zstd.
+    pub fn encoder<'a>(
+        self,
+        reader: impl Read + Send + 'a,
+        level: u32,
+        dictionary: Option<&ZstdDictionary>,
+    ) -> std::io::Result<Box<dyn Read + Send + 'a>> {
+        Ok(match self {
+            Compression::None => Box::new(reader),
+            Compression::Gzip => Box::new(flate2::read::GzEncoder::new(
+                reader,
+                flate2::Compression::new(level),
+            )),
+            Compression::Zstd => Box::new(zstd::stream::read::Encoder::with_dictionary(
+                std::io::BufReader::new(reader),
+                level as i32,
+                dictionary.map_or(&[], |dictionary| &dictionary.data),
+            )?),
+        })
+    }
+}
+
+/// A zstd dictionary blobs are compressed with, see `--zstd-dict`.
+pub struct ZstdDictionary {
+    /// The id frames compressed with the dictionary record in their header.
+    pub id: u32,
+    data: Vec<u8>,
+}
+
+/// Loads a dictionary as trained by `zstd --train`. Raw content dictionaries aren't
+/// accepted since they have no id for blobs to record.
+fn load_zstd_dictionary(path: &str) -> Result<Arc<ZstdDictionary>, String> {
+    let data = std::fs::read(path).map_err(|e| e.to_string())?;
+    let id = zstd::zstd_safe::get_dict_id_from_dict(&data)
+        .ok_or_else(|| format!("{path} is not a zstd dictionary"))?;
+    Ok(Arc::new(ZstdDictionary { id: id.get(), data }))
+}
+
+/// The id of the dictionary a zstd compressed `blob` was compressed with, if any.
+pub(crate) fn zstd_dictionary_of(blob: impl Read) -> std::io::Result<Option<u32>> {
+    let mut header = Vec::with_capacity(ZSTD_MAX_HEADER_SIZE);
+    blob.take(ZSTD_MAX_HEADER_SIZE as u64)
+        .read_to_end(&mut header)?;
+    Ok(zstd::zstd_safe::get_dict_id_from_frame(&header).map(|id| id.get()))
+}
+
+/// Fails reading a file whose blob is compressed with a dictionary other than
+/// the configured one, which would only fail later, with a less helpful error.
+fn check_zstd_dictionary(
+    config: &StorageConfig,
+    metadata: &FileMetadata,
+) -> Result<(), StorageError> {
+    match (metadata.zstd_dictionary, &config.zstd_dict) {
+        (None, _) => Ok(()),
+        (Some(id), Some(dictionary)) if dictionary.id == id => Ok(()),
+        (Some(id), _) => Err(StorageError::Corrupt(format!(
+            "The file is compressed with zstd dictionary {id}, which isn't loaded"
+        ))),
+    }
+}
+
+/// The compression blobs are stored with by default, see `--compression`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlobCompression {
+    pub compression: Compression,
+    /// Overrides the default level of the compression.
+    pub level: Option<u32>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Parses `gzip` or `zstd`, optionally followed by a colon and a level.
+fn parse_blob_compression(value: &str) -> Result<BlobCompression, String> {
+    let (name, level) = match value.split_once(':') {
+        Some((name, level)) => (name, Some(level)),
+        None => (value, None),
+    };
+    let (compression, levels) = match name {
+        "gzip" => (Compression::Gzip, 1..=9),
+        "zstd" => (Compression::Zstd, 1..=22),
+        other => return Err(format!("expected gzip or zstd, got '{other}'")),
+    };
+    let level = level
+        .map(|level| {
+            level
+                .parse()
+                .ok()
+                .filter(|level| levels.contains(level))
+                .ok_or_else(|| {
+                    format!(
+                        "{name} levels range from {} to {}, got '{level}'",
+                        levels.start(),
+                        levels.end()
+                    )
+                })
+        })
+        .transpose()?;
+    Ok(BlobCompression { compression, level })
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct FileMetadata {
     pub version: DateTime<Utc>,
     pub checksum: [u8; 32],
     pub compression: Compression,
     pub decompressed_size: usize,
+    /// Filename declared by the uploader, used for `Content-Disposition`.
+    #[serde(default)]
+    pub filename: Option<String>,
+    #[serde(default, flatten)]
+    pub extra_digests: ExtraDigests,
+    /// The id of the dictionary a zstd compressed blob uses, see `--zstd-dict`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub zstd_dictionary: Option<u32>,
+}
+
+/// Digests computed in addition to the SHA-256 checksum, see `--extra-digests`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExtraDigests {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub md5: Option<[u8; 16]>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crc32: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ExtraDigest {
+    Md5,
+    Crc32,
+}
+
+/// Computes the checksum and any extra digests of a file's contents in a single pass.
+struct ContentHasher {
+    sha256: Option<Sha256>,
+    md5: Option<md5::Md5>,
+    crc32: Option<crc32fast::Hasher>,
+    size: usize,
+}
+
+impl ContentHasher {
+    fn new(sha256: bool, extra_digests: &[ExtraDigest]) -> Self {
+        Self {
+            sha256: sha256.then(Sha256::new),
+            md5: extra_digests
+                .contains(&ExtraDigest::Md5)
+                .then(md5::Md5::new),
+            crc32: extra_digests
+                .contains(&ExtraDigest::Crc32)
+                .then(crc32fast::Hasher::new),
+            size: 0,
+        }
+    }
+
+    /// Whether there is anything to compute at all.
+    fn is_needed(&self) -> bool {
+        self.sha256.is_some() || self.md5.is_some() || self.crc32.is_some()
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        if let Some(sha256) = &mut self.sha256 {
+            Digest::update(sha256, data);
+        }
+        if let Some(md5) = &mut self.md5 {
+            Digest::update(md5, data);
+        }
+        if let Some(crc32) = &mut self.crc32 {
+            crc32.update(data);
+        }
+        self.size += data.len();
+    }
+
+    fn update_from(&mut self, mut reader: impl Read) -> std::io::Result<()> {
+        let mut buf = [0; 65536];
+        loop {
+            let nread = reader.read(&mut buf)?;
+            if nread == 0 {
+                return Ok(());
+            }
+            self.update(&buf[..nread]);
+        }
+    }
+
+    /// Feeds the decompressed contents of a body uploaded with `encoding` to the hasher.
+    fn update_decoded(
+        &mut self,
+        encoding: Compression,
+        content: impl Read + Send,
+    ) -> std::io::Result<()> {
+        if encoding == Compression::None {
+            return self.update_from(content);
+        }
+        encoding
+            .decoder(content)
+            .and_then(|decoder| self.update_from(decoder))
+            .map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Body is not a valid {} stream: {e}", encoding.coding()),
+                )
+            })
+    }
+
+    fn finish(self) -> (usize, Option<[u8; 32]>, ExtraDigests) {
+        (
+            self.size,
+            self.sha256.map(|x| x.finalize().into()),
+            ExtraDigests {
+                md5: self.md5.map(|x| x.finalize().into()),
+                crc32: self.crc32.map(crc32fast::Hasher::finalize),
+            },
+        )
+    }
 }
 
 impl FileMetadata {
     fn read(path: &Path) -> std::io::Result<Self> {
-        serde_json::from_slice(&std::fs::read(path)?)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+        let data = std::fs::read(path)?;
+        // Plain metadata is a JSON object, so it can never start with a compression's magic.
+        let compression = match data {
+            _ if data.starts_with(&[0x1f, 0x8b]) => Compression::Gzip,
+            _ if data.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) => Compression::Zstd,
+            _ => Compression::None,
+        };
+        let metadata = serde_json::from_reader(compression.decoder(&data[..])?);
+        metadata.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+
+    fn encode(&self, compression: Compression) -> Vec<u8> {
+        let json = serde_json::to_vec(self).unwrap();
+        match compression {
+            Compression::None => json,
+            Compression::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::new(9));
+                encoder.write_all(&json).unwrap();
+                encoder.finish().unwrap()
+            }
+            Compression::Zstd => zstd::encode_all(&json[..], 19).unwrap(),
+        }
     }
 }
 
+/// The entries of a directory being walked.
+type DirEntries = Box<dyn Iterator<Item = std::io::Result<DirEntry>> + Send>;
+
+fn read_dir(path: &Path, sorted: bool) -> std::io::Result<DirEntries> {
+    let entries = path.read_dir()?;
+    if !sorted {
+        return Ok(Box::new(entries));
+    }
+    let mut entries = entries.collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(DirEntry::file_name);
+    Ok(Box::new(entries.into_iter().map(Ok)))
+}
+
 struct FileLister {
-    readdir_stack: Vec<ReadDir>,
+    readdir_stack: Vec<DirEntries>,
     metadata: PathBuf,
-    max_version: DateTime<Utc>,
+    options: ListOptions,
+    /// Checked before every directory entry, the walk ends early once it returns true.
+    cancelled: Option<Box<dyn Fn() -> bool + Send>>,
+}
+
+impl FileLister {
+    fn relative(&self, path: &Path) -> String {
+        let relative = path.strip_prefix(&self.metadata).unwrap();
+        relative.to_str().unwrap().to_string()
+    }
 }
 
 impl Iterator for FileLister {
-    type Item = std::io::Result<(String, FileMetadata)>;
+    type Item = std::io::Result<(String, ListEntry)>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        // Entries that disappear while the walk is in progress were concurrently
+        // deleted, which isn't an error.
         macro_rules! try_ {
             ($value: expr) => {
                 match $value {
                     Ok(ok) => ok,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
                     Err(e) => return Some(Err(e)),
                 }
             };
         }
 
         loop {
+            if self.cancelled.as_ref().is_some_and(|cancelled| cancelled()) {
+                return None;
+            }
             let current = self.readdir_stack.last_mut()?;
             match current.next() {
                 Some(Err(e)) => return Some(Err(e)),
+                // NOTE: DirEntry::file_type doesn't follow symlinks, so symlinked
+                //       directories are never descended into and the walk can neither
+                //       loop nor escape the metadata directory.
                 Some(Ok(e)) => match e.file_type() {
-                    Ok(ft) if ft.is_dir() => self.readdir_stack.push(try_!(e.path().read_dir())),
+                    Ok(ft) if ft.is_dir() => {
+                        let relative = self.relative(&e.path());
+                        let skipped = self.options.is_before_start(&relative);
+                        if skipped && !self.options.contains_start(&relative) {
+                            continue;
+                        }
+                        let depth = self.readdir_stack.len();
+                        if self.options.max_depth.is_none_or(|max| depth < max) {
+                            self.readdir_stack
+                                .push(try_!(read_dir(&e.path(), self.options.sorted)));
+                        }
+                        if self.options.directories && !skipped {
+                            return Some(Ok((relative, ListEntry::Directory)));
+                        }
+                    }
                     Ok(ft) if ft.is_file() => {
                         let path = e.path();
-                        let metadata = try_!(FileMetadata::read(&path));
-                        if metadata.version <= self.max_version {
-                            let relative = path.strip_prefix(&self.metadata).unwrap();
-                            return Some(Ok((relative.to_str().unwrap().to_string(), metadata)));
+                        let relative = self.relative(&path);
+                        if self.options.is_before_start(&relative) {
+                            continue;
+                        }
+                        let metadata = match FileMetadata::read(&path) {
+                            Err(e)
+                                if self.options.skip_corrupt
+                                    && e.kind() == std::io::ErrorKind::InvalidData =>
+                            {
+                                return Some(Ok((relative, ListEntry::Corrupt)));
+                            }
+                            result => try_!(result),
+                        };
+                        if metadata.version <= self.options.max_version {
+                            return Some(Ok((relative, ListEntry::File(metadata))));
                         }
                     }
+                    // Symlinks and other special files.
                     Ok(_) => (),
                     Err(e) => return Some(Err(e)),
                 },
                 None => {
-                    self.readdir_stack.pop().unwrap();
+                    self.readdir_stack.pop();
                 }
             }
         }
     }
 }
 
+/// Since files are stored at their literal paths, a file and a directory can't share
+/// one. Replaces the resulting OS errors with an explanation of which one is in the way.
+fn explain_collision(path: &str, error: std::io::Error) -> StorageError {
+    match error.kind() {
+        std::io::ErrorKind::IsADirectory => {
+            StorageError::IsDirectory(format!("{path}: a directory exists where a file is needed"))
+        }
+        std::io::ErrorKind::NotADirectory => {
+            StorageError::Conflict(format!("{path}: a file exists where a directory is needed"))
+        }
+        _ => error.into(),
+    }
+}
+
+/// Cheaply checks that a gzip stream is plausibly `logical_size` bytes long when
+/// decompressed by looking at the size stored in its trailer.
+///
+/// This catches clients that describe the compressed data instead of its contents,
+/// which would otherwise get stored under the wrong checksum.
+fn check_gzip_trailer(content: Content, logical_size: usize) -> std::io::Result<()> {
+    let invalid = |message| {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            message,
+        ))
+    };
+
+    if content.len() < 18 || content.read_at(0, 3)? != [0x1f, 0x8b, 0x08] {
+        return invalid("Content is not a valid gzip stream");
+    }
+
+    // NOTE: The trailer only stores the size modulo 2^32 and for multi-member streams
+    //       only describes the last member, so this can't be anything more than a heuristic.
+    let trailer = content.read_at(content.len() - 4, 4)?;
+    let trailer_size = u32::from_le_bytes(trailer.try_into().unwrap());
+    if trailer_size != logical_size as u32 {
+        return invalid("Logical-Size does not match the size recorded in the gzip trailer");
+    }
+
+    Ok(())
+}
+
+/// The largest a zstd frame header can be.
+const ZSTD_MAX_HEADER_SIZE: usize = 18;
+
+/// Sanity checks `logical_size` against the content size the header of a zstd
+/// compressed `content` declares, if it does.
+fn check_zstd_header(content: Content, logical_size: usize) -> std::io::Result<()> {
+    let invalid = |message| {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            message,
+        ))
+    };
+
+    // NOTE: Like the gzip trailer, the header only describes the first frame, so this
+    //       can't be anything more than a heuristic for bodies of several frames.
+    let header = content.read_at(0, content.len().min(ZSTD_MAX_HEADER_SIZE))?;
+    match zstd::zstd_safe::get_frame_content_size(&header) {
+        Err(_) => invalid("Content is not a valid zstd stream"),
+        // The server couldn't decompress them without the client's dictionary.
+        Ok(_) if zstd::zstd_safe::get_dict_id_from_frame(&header).is_some() => {
+            invalid("zstd streams compressed with a dictionary are not supported")
+        }
+        Ok(Some(size)) if size != logical_size as u64 => {
+            invalid("Logical-Size does not match the size recorded in the zstd frame header")
+        }
+        Ok(_) => Ok(()),
+    }
+}
+
+/// The checksum and decompressed size of the content a file should be linked to,
+/// which the client has to declare since the content itself isn't uploaded.
+fn link_target(upload: &Upload, config: &StorageConfig) -> Result<([u8; 32], usize), StorageError> {
+    let (Some(checksum), Some(logical_size)) = (upload.checksum, upload.logical_size) else {
+        return Err(StorageError::InvalidInput(
+            "Linking requires both SHA256-Checksum and Logical-Size".into(),
+        ));
+    };
+    if config
+        .max_logical_size
+        .is_some_and(|max| logical_size > max)
+    {
+        return Err(StorageError::InvalidInput(
+            "Logical-Size exceeds the maximum file size".into(),
+        ));
+    }
+    Ok((checksum, logical_size))
+}
+
+/// Checks that a stored blob is plausibly `logical_size` bytes long when decompressed,
+/// for files linked to it. Uncompressed ones are checked exactly.
+fn check_blob_size(
+    blob: Content,
+    compression: Compression,
+    logical_size: usize,
+    config: &StorageConfig,
+) -> Result<(), StorageError> {
+    match compression {
+        Compression::None if blob.len() != logical_size => Err(StorageError::InvalidInput(
+            "Logical-Size does not match the size of the stored content".into(),
+        )),
+        Compression::Gzip if config.check_gzip_trailer => {
+            Ok(check_gzip_trailer(blob, logical_size)?)
+        }
+        Compression::Zstd => Ok(check_zstd_header(blob, logical_size)?),
+        Compression::None | Compression::Gzip => Ok(()),
+    }
+}
+
+/// Deflate can't compress data by more than a factor of about 1032.
+const MAX_GZIP_RATIO: usize = 1032;
+/// Zstd can't by more than about 32768, a run length encoded block of 128 KiB taking 4 bytes.
+const MAX_ZSTD_RATIO: usize = 32768;
+
+/// Rejects a client supplied `logical_size` that can't possibly describe the body,
+/// before it ends up in metadata.
+fn check_logical_size(upload: &Upload, config: &StorageConfig) -> std::io::Result<()> {
+    let Some(logical_size) = upload.logical_size else {
+        return Ok(());
+    };
+    let invalid = |message| {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            message,
+        ))
+    };
+
+    if config
+        .max_logical_size
+        .is_some_and(|max| logical_size > max)
+    {
+        return invalid("Logical-Size exceeds the maximum file size");
+    }
+    // The gzip header and trailer alone take up 18 bytes, the smallest zstd frame 9.
+    let (min_size, max_ratio) = match upload.content_encoding {
+        Compression::None if logical_size != upload.content.len() => {
+            return invalid("Logical-Size does not match the size of the body");
+        }
+        Compression::None => return Ok(()),
+        Compression::Gzip => (18, MAX_GZIP_RATIO),
+        Compression::Zstd => (9, MAX_ZSTD_RATIO),
+    };
+    let max = upload.content.len().saturating_mul(max_ratio);
+    if upload.content.len() < min_size || logical_size > max {
+        return invalid("Logical-Size is implausibly large for the compressed body");
+    }
+    Ok(())
+}
+
+/// Checks an uploaded body and computes its decompressed size and checksum
+/// (unless the client supplied both), along with the configured extra digests.
+fn inspect_content(
+    upload: &Upload,
+    config: &StorageConfig,
+) -> Result<(usize, [u8; 32], ExtraDigests), StorageError> {
+    check_logical_size(upload, config)?;
+
+    let content = upload.content;
+    let encoding = upload.content_encoding;
+    let trusted = match (upload.checksum, upload.logical_size) {
+        (Some(checksum), _) if encoding == Compression::None => Some((content.len(), checksum)),
+        (Some(checksum), Some(logical_size)) => {
+            match encoding {
+                Compression::Gzip if config.check_gzip_trailer => {
+                    check_gzip_trailer(content, logical_size)?
+                }
+                Compression::Zstd => check_zstd_header(content, logical_size)?,
+                _ => (),
+            }
+            Some((logical_size, checksum))
+        }
+        _ => None,
+    };
+
+    let verify = config.verify_checksums && encoding == Compression::None;
+    let mut hasher = ContentHasher::new(trusted.is_none() || verify, &config.extra_digests);
+    if hasher.is_needed() {
+        hasher.update_decoded(encoding, content.reader()?)?;
+    }
+    let (decompressed_size, computed, extra_digests) = hasher.finish();
+    match (trusted, computed) {
+        (Some((_, checksum)), Some(computed)) if checksum != computed => {
+            Err(StorageError::ChecksumMismatch(format!(
+                "SHA256-Checksum does not match the content, whose checksum is {}",
+                crate::util::bytes_to_hex(&computed)
+            )))
+        }
+        (Some((size, checksum)), _) => Ok((size, checksum, extra_digests)),
+        (None, computed) => Ok((decompressed_size, computed.unwrap(), extra_digests)),
+    }
+}
+
+/// Returns a reader yielding an uploaded body in the form it should be stored in,
+/// `compressed` being the already compressed content if there is one.
+///
+/// Bodies uploaded with another compression are recompressed on the fly.
+fn content_reader<'a>(
+    upload: &Upload<'a>,
+    compression: Compression,
+    compressed: Option<Vec<u8>>,
+    config: &StorageConfig,
+    counters: &BlobCounters,
+) -> std::io::Result<Box<dyn Read + Send + 'a>> {
+    let content = upload.content.reader()?;
+    let encoding = upload.content_encoding;
+    Ok(match compressed {
+        Some(compressed) => Box::new(std::io::Cursor::new(compressed)),
+        None if encoding == compression => content,
+        None => {
+            if compression != Compression::None {
+                counters.compressions.fetch_add(1, Ordering::Relaxed);
+            }
+            compression.encoder(
+                encoding.decoder(content)?,
+                config.compression_level(compression),
+                config.zstd_dict.as_deref(),
+            )?
+        }
+    })
+}
+
+/// Picks how to store an upload that didn't ask for a specific compression,
+/// returning the compressed content if it had to be compressed to decide.
+///
+/// Content that is already stored (as `is_stored` tells) keeps its compression so that it stays
+/// deduplicated, otherwise it's only compressed if that saves enough space. Uploads that
+/// are already compressed are stored as they are then, rather than recompressed.
+///
+/// Content spooled to a file isn't kept compressed in memory, only measured,
+/// and is thus compressed a second time when written.
+fn choose_compression(
+    config: &StorageConfig,
+    upload: &Upload,
+    decompressed_size: usize,
+    is_stored: impl Fn(Compression) -> bool,
+    counters: &BlobCounters,
+) -> std::io::Result<(Compression, Option<Vec<u8>>)> {
+    use clap::ValueEnum;
+
+    if config.no_compression {
+        return Ok((Compression::None, None));
+    }
+
+    if let Some(&existing) = Compression::value_variants()
+        .iter()
+        .find(|&&compression| is_stored(compression))
+    {
+        return Ok((existing, None));
+    }
+    if decompressed_size < config.blob_compression_min_size
+        || config
+            .blob_compression_max_size
+            .is_some_and(|max| decompressed_size > max)
+    {
+        return Ok((Compression::None, None));
+    }
+
+    let encoding = upload.content_encoding;
+    let compression = match encoding {
+        Compression::None => config.compression.compression,
+        compressed => compressed,
+    };
+    if encoding != compression {
+        counters.compressions.fetch_add(1, Ordering::Relaxed);
+    }
+    let encoder = || {
+        compression.encoder(
+            encoding.decoder(upload.content.reader()?)?,
+            config.compression_level(compression),
+            config.zstd_dict.as_deref(),
+        )
+    };
+    let (compressed_size, compressed) = match upload.content {
+        _ if encoding == compression => (upload.content.len(), None),
+        Content::Memory(content) => {
+            let mut compressed = Vec::with_capacity(content.len() / 2);
+            encoder()?.read_to_end(&mut compressed)?;
+            (compressed.len(), Some(compressed))
+        }
+        Content::File { .. } => {
+            let size = std::io::copy(&mut encoder()?, &mut std::io::sink())?;
+            (size as usize, None)
+        }
+    };
+    if compressed_size as f64 > decompressed_size as f64 * config.blob_compression_min_ratio {
+        Ok((Compression::None, None))
+    } else {
+        Ok((compression, compressed))
+    }
+}
+
+/// The outcome of a write of `version` that the write policy doesn't let replace
+/// `current`, `None` if it may go ahead.
+fn refuse_older(
+    policy: WritePolicy,
+    current: &FileMetadata,
+    version: DateTime<Utc>,
+) -> Option<PutOutcome> {
+    if current.version <= version {
+        return None;
+    }
+    match policy {
+        WritePolicy::LastWriterWins => Some(PutOutcome::Superseded {
+            version: current.version,
+            checksum: current.checksum,
+        }),
+        WritePolicy::RejectOlder => Some(PutOutcome::Rejected {
+            version: current.version,
+            checksum: current.checksum,
+        }),
+        WritePolicy::Always => None,
+    }
+}
+
+/// Normalizes the prefixes of a promotion, which must not contain one another.
+fn promotion_prefixes<'a>(from: &'a str, to: &'a str) -> std::io::Result<(&'a str, &'a str)> {
+    let (from, to) = (from.trim_matches('/'), to.trim_matches('/'));
+    let nested = |outer: &str, inner: &str| {
+        inner == outer
+            || inner
+                .strip_prefix(outer)
+                .is_some_and(|x| x.starts_with('/'))
+    };
+    if from.is_empty() || to.is_empty() || nested(from, to) || nested(to, from) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Promotion prefixes must be non-empty and must not contain one another",
+        ));
+    }
+    Ok((from, to))
+}
+
+/// Takes an exclusive advisory lock on `<root>/.lock`, since multiple servers
+/// sharing a directory would corrupt each other's refcounts.
+fn lock_directory(root: &Path) -> std::io::Result<std::fs::File> {
+    std::fs::create_dir_all(root)?;
+    let file = std::fs::File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(root.join(".lock"))?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(std::fs::TryLockError::WouldBlock) => Err(std::io::Error::new(
+            std::io::ErrorKind::WouldBlock,
+            format!("{} is already in use by another server", root.display()),
+        )),
+        Err(std::fs::TryLockError::Error(e)) => Err(e),
+    }
+}
+
 impl LocalStorage {
-    pub fn new(root: &Path) -> std::io::Result<Self> {
+    pub fn new(root: &Path, config: StorageConfig) -> std::io::Result<Self> {
         Ok({
             let result = Self {
-                locks: LockMap::new(),
-                blobs: BlobStorage::create(root.join("blobs"))?,
+                _directory_lock: match config.data_dir_lock {
+                    true => Some(lock_directory(root)?),
+                    false => None,
+                },
+                locks: LockMap::new(config.lock_timeout),
+                promotions: Arc::default(),
+                blobs: Arc::new(BlobStorage::create(root.join("blobs"), &config)?),
+                files: Arc::default(),
                 metadata: root.join("metadata"),
+                temp: root.join("tmp"),
+                temp_files: AtomicU64::new(0),
+                config,
             };
             std::fs::create_dir_all(&result.metadata)?;
+            std::fs::create_dir_all(&result.temp)?;
+            let (files, metadata) = (result.files.clone(), result.metadata.clone());
+            std::thread::Builder::new()
+                .name("count-files".into())
+                .spawn(move || match count_files(&metadata) {
+                    Ok(totals) => _ = files.initial.set(totals),
+                    Err(e) => tracing::error!(error = %e, "failed to count files"),
+                })?;
             result
         })
     }
 
-    fn read_meta_for(&self, path: &str) -> std::io::Result<FileMetadata> {
-        FileMetadata::read(&self.metadata.join(path))
+    /// Starts the background scrubber if `--scrub-rate` asks for it.
+    pub fn start_scrubber(&self) -> std::io::Result<()> {
+        let Some(rate) = self.config.scrub_rate else {
+            return Ok(());
+        };
+        let quarantine = self
+            .config
+            .scrub_quarantine
+            .then(|| self.metadata.with_file_name("quarantine"));
+        self.blobs.start_scrubber(rate, quarantine)
+    }
+
+    /// Maps a client supplied path onto the metadata directory.
+    fn resolve(&self, path: &str) -> std::io::Result<PathBuf> {
+        Ok(self.metadata.join(self.config.path_limits.normalize(path)?))
+    }
+
+    /// Atomically replaces the metadata at `meta_path`.
+    fn write_meta(&self, meta_path: &Path, metadata: &FileMetadata) -> std::io::Result<()> {
+        let temp = self.temp.join(format!(
+            "{}-{}",
+            std::process::id(),
+            self.temp_files.fetch_add(1, Ordering::Relaxed)
+        ));
+        self.config.fsync.write(
+            &temp,
+            meta_path,
+            metadata.encode(self.config.metadata_compression),
+        )
+    }
+
+    fn lister(&self, path: &str, options: ListOptions) -> std::io::Result<FileLister> {
+        let metadata = self.resolve(path)?;
+        let iter = read_dir(&metadata, options.sorted).map_err(|e| explain_collision(path, e))?;
+        Ok(FileLister {
+            metadata,
+            options,
+            readdir_stack: vec![iter],
+            cancelled: None,
+        })
+    }
+
+    /// Paths of all files under `prefix`, relative to it, in the order of a sorted walk.
+    fn files_under(&self, prefix: &str) -> std::io::Result<Vec<String>> {
+        let options = ListOptions {
+            sorted: true,
+            ..ListOptions::recursive(DateTime::<Utc>::MAX_UTC)
+        };
+        self.lister(prefix, options)?
+            .filter_map(|entry| match entry {
+                Ok((path, ListEntry::File(_))) => Some(Ok(path)),
+                Ok((_, ListEntry::Directory | ListEntry::Corrupt)) => None,
+                Err(e) => Some(Err(e)),
+            })
+            .collect()
+    }
+
+    /// Keeps promotions out until the guard is dropped, see `promotions`.
+    async fn hold_off_promotions(&self) -> std::io::Result<tokio::sync::OwnedRwLockReadGuard<()>> {
+        lockmap::acquire(
+            self.promotions.clone().read_owned(),
+            self.config.lock_timeout,
+        )
+        .await
+    }
+
+    /// Recomputes the refcounts of all blobs from the metadata and removes the blobs
+    /// nothing refers to, which crashes and failed writes can leave behind.
+    ///
+    /// Must not run while the store is being served.
+    pub fn collect_garbage(&self, dry_run: bool) -> std::io::Result<GcReport> {
+        let mut report = GcReport::default();
+        let mut refs = HashMap::new();
+        let mut first_corrupt = None;
+        let options = ListOptions {
+            skip_corrupt: true,
+            ..ListOptions::recursive(DateTime::<Utc>::MAX_UTC)
+        };
+        for entry in self.lister("", options)? {
+            match entry? {
+                (_, ListEntry::File(metadata)) => {
+                    *refs
+                        .entry((metadata.checksum, metadata.compression))
+                        .or_default() += 1;
+                    report.files += 1;
+                }
+                (path, ListEntry::Corrupt) => {
+                    first_corrupt.get_or_insert(path);
+                    report.corrupt_metadata += 1;
+                }
+                (_, ListEntry::Directory) => (),
+            }
+        }
+
+        // Their blobs are unknown, so anything could be in use.
+        if let Some(path) = first_corrupt.filter(|_| !dry_run) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "{} files have corrupt metadata, e.g. {path}, fix or remove them first",
+                    report.corrupt_metadata
+                ),
+            ));
+        }
+
+        self.blobs.collect_garbage(&refs, dry_run, &mut report)?;
+        for entry in self.temp.read_dir()? {
+            if !dry_run {
+                std::fs::remove_file(entry?.path())?;
+            }
+            report.removed_leftovers += 1;
+        }
+        Ok(report)
+    }
+
+    /// Re-hashes every blob and checks that every file's blob exists, printing the
+    /// problems found. Corrupt blobs are moved to `<root>/quarantine` if asked to.
+    ///
+    /// Must not run while the store is being served.
+    pub fn fsck(&self, quarantine: bool) -> std::io::Result<FsckReport> {
+        let directory = self.metadata.with_file_name("quarantine");
+        let mut report = self
+            .blobs
+            .verify(quarantine.then_some(directory.as_path()))?;
+
+        // After the blobs, so that files whose blob was just quarantined are reported.
+        let options = ListOptions {
+            skip_corrupt: true,
+            ..ListOptions::recursive(DateTime::<Utc>::MAX_UTC)
+        };
+        for entry in self.lister("", options)? {
+            match entry? {
+                (path, ListEntry::File(metadata)) => {
+                    report.files += 1;
+                    if !self.blobs.exists(&metadata.checksum, metadata.compression) {
+                        tracing::error!(
+                            path,
+                            checksum = crate::util::bytes_to_hex(&metadata.checksum),
+                            "blob is missing"
+                        );
+                        report.missing_blobs += 1;
+                    }
+                }
+                (path, ListEntry::Corrupt) => {
+                    tracing::error!(path, "metadata is corrupt");
+                    report.corrupt_metadata += 1;
+                }
+                (_, ListEntry::Directory) => (),
+            }
+        }
+        Ok(report)
+    }
+
+    fn read_meta_for(&self, path: &str) -> Result<FileMetadata, StorageError> {
+        FileMetadata::read(&self.resolve(path)?).map_err(|e| match e.kind() {
+            std::io::ErrorKind::InvalidData => {
+                StorageError::Corrupt(format!("{path}: metadata is corrupt, {e}"))
+            }
+            _ => explain_collision(path, e),
+        })
+    }
+
+    /// Like `read_meta_for`, but a missing file isn't an error.
+    fn read_current_meta_for(&self, path: &str) -> Result<Option<FileMetadata>, StorageError> {
+        match self.read_meta_for(path) {
+            Ok(meta) => Ok(Some(meta)),
+            Err(StorageError::NotFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
     }
 }
 
+#[async_trait]
 impl Storage for LocalStorage {
-    async fn get(&self, path: &str) -> std::io::Result<(FileMetadata, Vec<u8>)> {
-        let _guard = self.locks.lock_ref(path).await;
-        let metadata = self.read_meta_for(path)?;
-        let content = self.blobs.read(&metadata.checksum)?;
-        Ok((metadata, content))
+    fn config(&self) -> &StorageConfig {
+        &self.config
+    }
+
+    fn blob_counters(&self) -> &BlobCounters {
+        self.blobs.counters()
+    }
+
+    fn file_counters(&self) -> &FileCounters {
+        &self.files
+    }
+
+    fn lock_counts(&self) -> (usize, usize) {
+        (self.locks.len(), self.blobs.lock_count())
     }
 
-    async fn head(&self, path: &str) -> std::io::Result<(FileMetadata, u64)> {
-        let _guard = self.locks.lock_ref(path).await;
-        let metadata = self.read_meta_for(path)?;
-        let len = self.blobs.metadata(&metadata.checksum)?.len();
-        Ok((metadata, len))
+    fn scrub_report(&self) -> Option<ScrubReport> {
+        self.blobs.scrub_report()
+    }
+
+    async fn open(&self, path: &str) -> Result<(FileMetadata, Blob), StorageError> {
+        let _promotions = self.hold_off_promotions().await?;
+        let _guard = self.locks.read_ref(path).await?;
+        blocking(|| {
+            let metadata = self.read_meta_for(path)?;
+            check_zstd_dictionary(&self.config, &metadata)?;
+            let file = self.blobs.open(&metadata.checksum, metadata.compression)?;
+            Ok((metadata, Blob::File(file)))
+        })
+    }
+
+    async fn head(&self, path: &str) -> Result<(FileMetadata, BlobInfo), StorageError> {
+        let _promotions = self.hold_off_promotions().await?;
+        let _guard = self.locks.read_ref(path).await?;
+        blocking(|| {
+            let metadata = self.read_meta_for(path)?;
+            let info = self.blobs.info(&metadata.checksum, metadata.compression)?;
+            Ok((metadata, info))
+        })
+    }
+
+    async fn metadata(&self, path: &str) -> Result<FileMetadata, StorageError> {
+        let _promotions = self.hold_off_promotions().await?;
+        let _guard = self.locks.read_ref(path).await?;
+        blocking(|| self.read_meta_for(path))
     }
 
     async fn put(
         &self,
         path: &str,
         version: DateTime<Utc>,
-        content: &[u8],
-        content_is_gzipped: bool,
-        checksum: Option<[u8; 32]>,
-        logical_size: Option<usize>,
-    ) -> std::io::Result<()> {
-        let (decompressed_size, checksum, mut compressed) = if !content_is_gzipped {
-            (
-                content.len(),
-                checksum.unwrap_or_else(|| Sha256::new().chain_update(content).finalize().into()),
-                Box::new(flate2::read::GzEncoder::new(
-                    content,
-                    flate2::Compression::new(9),
-                )) as Box<dyn Read + Send>,
-            )
-        } else if let (Some(checksum), Some(logical_size)) = (checksum, logical_size) {
-            (
-                logical_size,
-                checksum,
-                Box::new(std::io::Cursor::new(content)) as Box<dyn Read + Send>,
-            )
-        } else {
-            let mut decoder = flate2::read::GzDecoder::new(content);
-            let mut buf = [0; 4096];
-            let mut decompressed_size = 0;
-            let mut checksum = Sha256::new();
-
-            loop {
-                let nread = decoder.read(&mut buf)?;
-                if nread == 0 {
-                    break;
-                }
-                Digest::update(&mut checksum, &buf[..nread]);
-                decompressed_size += nread;
-            }
+        upload: Upload<'_>,
+    ) -> Result<PutOutcome, StorageError> {
+        let dest_meta = self.resolve(path)?;
+        let (decompressed_size, checksum, extra_digests) =
+            blocking(|| inspect_content(&upload, &self.config))?;
 
-            (
-                decompressed_size,
-                checksum.finalize().into(),
-                Box::new(std::io::Cursor::new(content)) as Box<dyn Read + Send>,
-            )
-        };
+        let _promotions = self.hold_off_promotions().await?;
+        let _guard = self.locks.write_ref(path).await?;
+        let current = blocking(|| self.read_current_meta_for(path))?;
 
-        let _guard = self.locks.lock_ref(path).await;
-        match self.read_meta_for(path) {
-            Ok(meta) => {
-                if meta.version > version {
-                    return Ok(());
-                }
-                self.blobs.decref(&meta.checksum).await?;
+        if let Some(if_match) = &upload.if_match {
+            if !if_match.matches(current.as_ref().map(|meta| &meta.checksum)) {
+                return Ok(PutOutcome::PreconditionFailed);
             }
-            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
-            Err(e) => return Err(e),
         }
 
-        let dest_meta = self.metadata.join(path);
-        std::fs::create_dir_all(dest_meta.parent().unwrap())?;
+        if let Some(outcome) = current
+            .as_ref()
+            .and_then(|meta| refuse_older(self.config.write_policy, meta, version))
+        {
+            return Ok(outcome);
+        }
+
+        blocking(|| std::fs::create_dir_all(dest_meta.parent().unwrap()))?;
 
-        self.blobs.write(&checksum, &mut compressed).await?;
+        // NOTE: The compression is only picked, and the content only read, while holding
+        //       the blob's lock, so concurrent uploads of the same content neither write
+        //       nor compress it more than once.
+        let counters = self.blobs.counters();
+        let (compression, created) = match self
+            .blobs
+            .reuse_recent(&checksum, upload.compression)
+            .await?
+        {
+            Some(compression) => (compression, false),
+            None => {
+                self.blobs
+                    .write(&checksum, |is_stored| {
+                        let (compression, compressed) = match upload.compression {
+                            Some(compression) => (compression, None),
+                            None => choose_compression(
+                                &self.config,
+                                &upload,
+                                decompressed_size,
+                                is_stored,
+                                counters,
+                            )?,
+                        };
+                        // Only referenced, the content isn't needed.
+                        if is_stored(compression) {
+                            return Ok((compression, Box::new(std::io::empty()) as Box<_>));
+                        }
+                        let content = content_reader(
+                            &upload,
+                            compression,
+                            compressed,
+                            &self.config,
+                            counters,
+                        )?;
+                        Ok((compression, content))
+                    })
+                    .await?
+            }
+        };
 
-        std::fs::write(
-            dest_meta,
-            serde_json::to_string(&FileMetadata {
+        // NOTE: The blob's reference is taken before the metadata is written, so a crash
+        //       in between leaks a reference rather than leaving metadata pointing at
+        //       a missing blob. Leaked references are fixed by the next gc.
+        let written = blocking(|| {
+            let metadata = FileMetadata {
                 version,
                 checksum,
-                compression: Compression::Gzip,
+                compression,
                 decompressed_size,
-            })
-            .unwrap(),
-        )?;
+                filename: upload.filename,
+                extra_digests,
+                zstd_dictionary: self.blobs.zstd_dictionary(&checksum, compression)?,
+            };
+            self.write_meta(&dest_meta, &metadata)
+        });
+        if let Err(e) = written {
+            self.blobs.decref(&checksum, compression).await?;
+            return Err(e.into());
+        }
 
+        self.files.record_stored(
+            decompressed_size,
+            current.as_ref().map(|meta| meta.decompressed_size),
+        );
+
+        // The old blob is only released once nothing can fail anymore, so that
+        // a failed write doesn't leave the old metadata pointing at a freed blob.
+        if let Some(meta) = current {
+            self.blobs.decref(&meta.checksum, meta.compression).await?;
+        }
+
+        Ok(PutOutcome::Stored {
+            checksum,
+            deduplicated: !created,
+        })
+    }
+
+    async fn validate(&self, path: &str, upload: &Upload<'_>) -> Result<(), StorageError> {
+        self.resolve(path)?;
+        blocking(|| inspect_content(upload, &self.config))?;
         Ok(())
     }
 
-    async fn delete(&self, path: &str, max_version: DateTime<Utc>) -> std::io::Result<()> {
-        let _guard = self.locks.lock_ref(path).await;
-        let metadata = self.read_meta_for(path)?;
-        if metadata.version <= max_version {
-            self.blobs.decref(&metadata.checksum).await?;
-            std::fs::remove_file(self.metadata.join(path))?;
+    async fn has_blob(&self, checksum: &[u8; 32]) -> Result<bool, StorageError> {
+        use clap::ValueEnum;
+
+        Ok(blocking(|| {
+            Compression::value_variants()
+                .iter()
+                .any(|&compression| self.blobs.exists(checksum, compression))
+        }))
+    }
+
+    async fn link(
+        &self,
+        path: &str,
+        version: DateTime<Utc>,
+        upload: Upload<'_>,
+    ) -> Result<PutOutcome, StorageError> {
+        let dest_meta = self.resolve(path)?;
+        let (checksum, decompressed_size) = link_target(&upload, &self.config)?;
+
+        let _promotions = self.hold_off_promotions().await?;
+        let _guard = self.locks.write_ref(path).await?;
+        let current = blocking(|| self.read_current_meta_for(path))?;
+
+        if let Some(if_match) = &upload.if_match {
+            if !if_match.matches(current.as_ref().map(|meta| &meta.checksum)) {
+                return Ok(PutOutcome::PreconditionFailed);
+            }
         }
-        Ok(())
+
+        if let Some(outcome) = current
+            .as_ref()
+            .and_then(|meta| refuse_older(self.config.write_policy, meta, version))
+        {
+            return Ok(outcome);
+        }
+
+        blocking(|| std::fs::create_dir_all(dest_meta.parent().unwrap()))?;
+        let compression = self
+            .blobs
+            .link(&checksum, upload.compression, |blob, compression| {
+                check_blob_size(blob, compression, decompressed_size, &self.config)
+            })
+            .await?;
+
+        let written = blocking(|| {
+            // Extra digests can't be computed without reading the content.
+            let metadata = FileMetadata {
+                version,
+                checksum,
+                compression,
+                decompressed_size,
+                filename: upload.filename,
+                extra_digests: ExtraDigests::default(),
+                zstd_dictionary: self.blobs.zstd_dictionary(&checksum, compression)?,
+            };
+            self.write_meta(&dest_meta, &metadata)
+        });
+        if let Err(e) = written {
+            self.blobs.decref(&checksum, compression).await?;
+            return Err(e.into());
+        }
+
+        self.files.record_stored(
+            decompressed_size,
+            current.as_ref().map(|meta| meta.decompressed_size),
+        );
+        if let Some(meta) = current {
+            self.blobs.decref(&meta.checksum, meta.compression).await?;
+        }
+
+        Ok(PutOutcome::Stored {
+            checksum,
+            deduplicated: true,
+        })
     }
 
-    async fn list(
+    async fn delete(
         &self,
         path: &str,
-        max_version: DateTime<Utc>,
-    ) -> std::io::Result<impl Iterator<Item = std::io::Result<(String, FileMetadata)>>> {
-        let metadata = self.metadata.join(path);
-        let iter = metadata.read_dir()?;
-        Ok(FileLister {
-            metadata,
-            max_version,
-            readdir_stack: vec![iter],
+        max_version: Option<DateTime<Utc>>,
+    ) -> Result<DeleteOutcome, StorageError> {
+        let meta_path = self.resolve(path)?;
+        let _promotions = self.hold_off_promotions().await?;
+        let _guard = self.locks.write_ref(path).await?;
+        let metadata =
+            blocking(|| FileMetadata::read(&meta_path)).map_err(|e| explain_collision(path, e))?;
+        if max_version.is_some_and(|max_version| metadata.version > max_version) {
+            return Ok(DeleteOutcome::Superseded {
+                version: metadata.version,
+            });
+        }
+
+        self.blobs
+            .decref(&metadata.checksum, metadata.compression)
+            .await?;
+        blocking(|| std::fs::remove_file(meta_path))?;
+        self.files.record_removed(metadata.decompressed_size);
+        Ok(DeleteOutcome::Deleted)
+    }
+
+    async fn copy(
+        &self,
+        from: &str,
+        to: &str,
+        version: DateTime<Utc>,
+        remove_source: bool,
+    ) -> Result<PutOutcome, StorageError> {
+        let source_meta = self.resolve(from)?;
+        let dest_meta = self.resolve(to)?;
+        if source_meta == dest_meta {
+            return Err(StorageError::InvalidInput(
+                "The source and destination must be different files".into(),
+            ));
+        }
+
+        let _promotions = self.hold_off_promotions().await?;
+        // Taken in a consistent order so that opposite copies can't deadlock.
+        let (first, second) = if from < to { (from, to) } else { (to, from) };
+        let _first = self.locks.write_ref(first).await?;
+        let _second = self.locks.write_ref(second).await?;
+
+        let (source, current) = blocking(|| {
+            Result::<_, StorageError>::Ok((
+                self.read_meta_for(from)?,
+                self.read_current_meta_for(to)?,
+            ))
+        })?;
+        if let Some(outcome) = current
+            .as_ref()
+            .and_then(|meta| refuse_older(self.config.write_policy, meta, version))
+        {
+            return Ok(outcome);
+        }
+
+        let metadata = FileMetadata {
+            version,
+            ..source.clone()
+        };
+        let write_metadata = || self.write_meta(&dest_meta, &metadata);
+        blocking(|| std::fs::create_dir_all(dest_meta.parent().unwrap()))?;
+        if remove_source {
+            // The moved file keeps the reference its source held. Should the new
+            // version fail to be written, it is left in place with the old one.
+            blocking(|| {
+                std::fs::rename(&source_meta, &dest_meta)?;
+                self.config.fsync.sync_parent(&dest_meta)?;
+                if version != source.version {
+                    write_metadata()?;
+                }
+                std::io::Result::Ok(())
+            })?;
+        } else {
+            self.blobs
+                .incref(&source.checksum, source.compression)
+                .await?;
+            if let Err(e) = blocking(write_metadata) {
+                self.blobs
+                    .decref(&source.checksum, source.compression)
+                    .await?;
+                return Err(e.into());
+            }
+        }
+
+        self.files.record_stored(
+            source.decompressed_size,
+            current.as_ref().map(|meta| meta.decompressed_size),
+        );
+        if remove_source {
+            self.files.record_removed(source.decompressed_size);
+        }
+        if let Some(meta) = current {
+            self.blobs.decref(&meta.checksum, meta.compression).await?;
+        }
+
+        Ok(PutOutcome::Stored {
+            checksum: source.checksum,
+            deduplicated: true,
         })
     }
+
+    async fn promote(
+        &self,
+        from: &str,
+        to: &str,
+        mode: PromoteMode,
+    ) -> Result<PromoteOutcome, StorageError> {
+        let (from, to) = promotion_prefixes(from, to)?;
+        let from_dir = self.resolve(from)?;
+        let to_dir = self.resolve(to)?;
+
+        // Nothing else touches any file until the promotion is done, so it can't miss
+        // files put in the meantime and readers see either none or all of it.
+        let _promotion = lockmap::acquire(
+            self.promotions.clone().write_owned(),
+            self.config.lock_timeout,
+        )
+        .await?;
+
+        let sources = blocking(|| self.files_under(from))?;
+        let existing = match blocking(|| self.files_under(to)) {
+            Ok(files) => files,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let removed = match mode {
+            PromoteMode::Replace => {
+                let sources = sources.iter().collect::<HashSet<_>>();
+                existing
+                    .into_iter()
+                    .filter(|path| !sources.contains(path))
+                    .collect()
+            }
+            PromoteMode::Merge => Vec::new(),
+        };
+
+        // Files that are replaced or removed are moved aside rather than overwritten,
+        // so that every rename can be undone should a later one fail.
+        let aside = self.temp.join(format!(
+            "promotion-{}-{}",
+            std::process::id(),
+            self.temp_files.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut renames = Vec::new();
+        let promoted = blocking(|| {
+            let mut rename = |source: PathBuf, dest: PathBuf| {
+                std::fs::create_dir_all(dest.parent().unwrap())?;
+                std::fs::rename(&source, &dest)?;
+                renames.push((source, dest));
+                std::io::Result::Ok(())
+            };
+            // Removed first, so that they can't get in the way of promoted files.
+            let mut dropped = Vec::new();
+            for path in &removed {
+                let dest = to_dir.join(path);
+                dropped.push(FileMetadata::read(&dest)?);
+                rename(dest, aside.join(path))?;
+            }
+            for path in &sources {
+                let dest = to_dir.join(path);
+                match FileMetadata::read(&dest) {
+                    Ok(metadata) => {
+                        rename(dest.clone(), aside.join(path))?;
+                        dropped.push(metadata);
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                    Err(e) => return Err(explain_collision(&format!("{to}/{path}"), e)),
+                }
+                rename(from_dir.join(path), dest)
+                    .map_err(|e| explain_collision(&format!("{to}/{path}"), e))?;
+            }
+            for (_, dest) in &renames {
+                self.config.fsync.sync_parent(dest)?;
+            }
+            Ok(dropped)
+        });
+        let dropped = match promoted {
+            Ok(dropped) => dropped,
+            Err(e) => {
+                blocking(|| {
+                    for (source, dest) in renames.iter().rev() {
+                        if let Err(e) = std::fs::rename(dest, source) {
+                            tracing::error!(
+                                error = %e,
+                                file = %dest.display(),
+                                "failed to undo a rename of a failed promotion"
+                            );
+                        }
+                    }
+                    _ = std::fs::remove_dir_all(&aside);
+                });
+                return Err(e);
+            }
+        };
+
+        for metadata in &dropped {
+            self.files.record_removed(metadata.decompressed_size);
+            self.blobs
+                .decref(&metadata.checksum, metadata.compression)
+                .await?;
+        }
+        blocking(|| match std::fs::remove_dir_all(&aside) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        })?;
+
+        Ok(PromoteOutcome {
+            moved: sources.len(),
+            removed: removed.len(),
+        })
+    }
+
+    async fn list(&self, path: &str, options: ListOptions) -> Result<ListIter, StorageError> {
+        // NOTE: Iterating is still blocking, callers that want to avoid it should use
+        //       `list_stream` instead.
+        let promotions = self.hold_off_promotions().await?;
+        let lister = blocking(|| self.lister(path, options))?;
+        Ok(Box::new(lister.map(move |entry| {
+            let _promotions = &promotions;
+            Ok(entry?)
+        })))
+    }
+
+    async fn list_stream(
+        &self,
+        path: &str,
+        options: ListOptions,
+    ) -> Result<ListStream, StorageError> {
+        const QUEUE_LENGTH: usize = 256;
+
+        let promotions = self.hold_off_promotions().await?;
+        let mut lister = blocking(|| self.lister(path, options))?;
+        let (sender, receiver) = tokio::sync::mpsc::channel(QUEUE_LENGTH);
+        // Once the stream is dropped (e.g. the client went away) there's no point in
+        // walking any further, even through parts of the tree that yield nothing.
+        let watcher = sender.clone();
+        lister.cancelled = Some(Box::new(move || watcher.is_closed()));
+        tokio::task::spawn_blocking(move || {
+            let _promotions = promotions;
+            for entry in lister {
+                if sender
+                    .blocking_send(entry.map_err(StorageError::from))
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+
+        Ok(
+            futures_util::stream::unfold(receiver, |mut receiver| async move {
+                receiver.recv().await.map(|entry| (entry, receiver))
+            })
+            .boxed(),
+        )
+    }
+}
+
+/// Opens the storage backend picked with `--backend`, the local one in `root`.
+pub fn open_backend(root: &Path, config: StorageConfig) -> std::io::Result<Arc<dyn Storage>> {
+    Ok(match config.backend {
+        BackendKind::Local => {
+            let storage = LocalStorage::new(root, config)?;
+            storage.start_scrubber()?;
+            Arc::new(storage)
+        }
+        BackendKind::Memory => Arc::new(MemoryStorage::new(config)),
+        #[cfg(feature = "s3")]
+        // The bucket is indexed before serving, just like the data directory is opened.
+        BackendKind::S3 => Arc::new(blocking(|| {
+            tokio::runtime::Handle::current().block_on(S3Storage::open(config))
+        })?),
+    })
 }
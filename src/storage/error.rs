@@ -0,0 +1,86 @@
+use std::fmt::Display;
+
+/// Why a storage operation failed, for the HTTP layer to pick a response by.
+#[derive(Debug)]
+pub enum StorageError {
+    /// The file doesn't exist.
+    NotFound(String),
+    /// A directory exists where a file is needed.
+    IsDirectory(String),
+    /// The operation can't be carried out on the store as it is, e.g. because a file
+    /// exists where a directory is needed.
+    Conflict(String),
+    /// Stored data can't be read back, e.g. unparseable metadata.
+    Corrupt(String),
+    /// The request itself is invalid, e.g. a malformed path.
+    InvalidInput(String),
+    /// An upload doesn't match the checksum the client supplied for it.
+    ChecksumMismatch(String),
+    /// An upload exceeds the configured size limit.
+    TooLarge(String),
+    /// Gave up waiting for a lock.
+    TimedOut(String),
+    Io(std::io::Error),
+}
+
+impl Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageError::NotFound(message)
+            | StorageError::IsDirectory(message)
+            | StorageError::Conflict(message)
+            | StorageError::Corrupt(message)
+            | StorageError::InvalidInput(message)
+            | StorageError::ChecksumMismatch(message)
+            | StorageError::TooLarge(message)
+            | StorageError::TimedOut(message) => f.write_str(message),
+            StorageError::Io(error) => error.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StorageError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Classifies OS errors, which is how most failures of the filesystem based storage
+/// surface in the first place.
+impl From<std::io::Error> for StorageError {
+    fn from(error: std::io::Error) -> Self {
+        let message = error.to_string();
+        match error.kind() {
+            std::io::ErrorKind::NotFound => StorageError::NotFound(message),
+            std::io::ErrorKind::IsADirectory => StorageError::IsDirectory(message),
+            std::io::ErrorKind::NotADirectory => StorageError::Conflict(message),
+            std::io::ErrorKind::InvalidData => StorageError::Corrupt(message),
+            std::io::ErrorKind::InvalidInput => StorageError::InvalidInput(message),
+            std::io::ErrorKind::TimedOut => StorageError::TimedOut(message),
+            std::io::ErrorKind::FileTooLarge => StorageError::TooLarge(message),
+            _ => StorageError::Io(error),
+        }
+    }
+}
+
+/// For the command line tools, which only report errors.
+impl From<StorageError> for std::io::Error {
+    fn from(error: StorageError) -> Self {
+        let kind = match error {
+            StorageError::Io(error) => return error,
+            StorageError::NotFound(_) => std::io::ErrorKind::NotFound,
+            StorageError::IsDirectory(_) => std::io::ErrorKind::IsADirectory,
+            StorageError::Conflict(_) => std::io::ErrorKind::NotADirectory,
+            StorageError::Corrupt(_) => std::io::ErrorKind::InvalidData,
+            StorageError::InvalidInput(_) | StorageError::ChecksumMismatch(_) => {
+                std::io::ErrorKind::InvalidInput
+            }
+            StorageError::TimedOut(_) => std::io::ErrorKind::TimedOut,
+            StorageError::TooLarge(_) => std::io::ErrorKind::FileTooLarge,
+        };
+        std::io::Error::new(kind, error.to_string())
+    }
+}
@@ -0,0 +1,667 @@
+//! Storage that keeps everything in memory, for tests and deployments whose files
+//! don't have to outlive the server.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::Read,
+    ops::{Deref, DerefMut},
+    sync::{atomic::Ordering, Arc, Mutex, MutexGuard},
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures_util::StreamExt;
+
+use super::{
+    check_blob_size, check_zstd_dictionary, choose_compression, content_reader, explain_collision,
+    inspect_content, link_target, promotion_prefixes, refuse_older, zstd_dictionary_of, Blob,
+    BlobCounters, BlobInfo, BlobTotals, Compression, Content, DeleteOutcome, ExtraDigests,
+    FileCounters, FileMetadata, FileTotals, ListEntry, ListIter, ListOptions, ListStream,
+    PromoteMode, PromoteOutcome, PutOutcome, ScrubReport, Storage, StorageConfig, StorageError,
+    Upload,
+};
+use crate::util::blocking;
+
+struct StoredBlob {
+    data: Arc<[u8]>,
+    refs: usize,
+    created: DateTime<Utc>,
+    accessed: DateTime<Utc>,
+}
+
+/// Metadata of every file by its path, with empty and `.` components removed,
+/// which directories are derived from.
+#[derive(Default)]
+pub(super) struct FileTree(BTreeMap<String, FileMetadata>);
+
+impl Deref for FileTree {
+    type Target = BTreeMap<String, FileMetadata>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for FileTree {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+#[derive(Default)]
+struct Contents {
+    files: FileTree,
+    blobs: HashMap<([u8; 32], Compression), StoredBlob>,
+}
+
+pub(super) fn not_found(path: &str) -> StorageError {
+    StorageError::NotFound(format!("{path}: no such file or directory"))
+}
+
+impl FileTree {
+    /// The files under the directory `path`, with paths relative to it, in byte order.
+    pub(super) fn under<'a>(
+        &'a self,
+        path: &str,
+    ) -> impl Iterator<Item = (&'a str, &'a FileMetadata)> {
+        let prefix = match path {
+            "" => String::new(),
+            path => format!("{path}/"),
+        };
+        let len = prefix.len();
+        self.0
+            .range(prefix.clone()..)
+            .take_while(move |(file, _)| file.starts_with(&prefix))
+            .map(move |(file, metadata)| (&file[len..], metadata))
+    }
+
+    /// Fails like a filesystem would if one of the parents of `path` is a file.
+    fn check_parents(&self, path: &str) -> std::io::Result<()> {
+        match path
+            .match_indices('/')
+            .any(|(i, _)| self.0.contains_key(&path[..i]))
+        {
+            true => Err(std::io::ErrorKind::NotADirectory.into()),
+            false => Ok(()),
+        }
+    }
+
+    /// The file at `path`, failing like a filesystem would if it is a directory.
+    pub(super) fn file(&self, path: &str) -> std::io::Result<Option<&FileMetadata>> {
+        if let Some(metadata) = self.0.get(path) {
+            return Ok(Some(metadata));
+        }
+        if path.is_empty() || self.under(path).next().is_some() {
+            return Err(std::io::ErrorKind::IsADirectory.into());
+        }
+        self.check_parents(path)?;
+        Ok(None)
+    }
+
+    /// Checks that `path` is a directory. Directories only exist for as long as
+    /// there are files in them.
+    pub(super) fn directory(&self, path: &str) -> std::io::Result<()> {
+        match self.file(path) {
+            Err(e) if e.kind() == std::io::ErrorKind::IsADirectory => Ok(()),
+            Err(e) => Err(e),
+            Ok(Some(_)) => Err(std::io::ErrorKind::NotADirectory.into()),
+            Ok(None) => Err(std::io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    /// Lists the directory `directory`, as `path` was resolved, always in the order
+    /// of a sorted walk.
+    pub(super) fn entries(
+        &self,
+        path: &str,
+        directory: &str,
+        options: &ListOptions,
+    ) -> Result<Vec<(String, ListEntry)>, StorageError> {
+        self.directory(directory).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => not_found(path),
+            _ => explain_collision(path, e),
+        })?;
+
+        let visible = |depth: usize| options.max_depth.is_none_or(|max| depth <= max);
+        let mut directories = HashSet::new();
+        let mut entries = Vec::new();
+        for (file, metadata) in self.under(directory) {
+            if options.directories {
+                for (depth, (end, _)) in file.match_indices('/').enumerate() {
+                    if visible(depth + 1) {
+                        directories.insert(&file[..end]);
+                    }
+                }
+            }
+            if visible(file.split('/').count())
+                && metadata.version <= options.max_version
+                && !options.is_before_start(file)
+            {
+                entries.push((file.to_string(), ListEntry::File(metadata.clone())));
+            }
+        }
+        entries.extend(
+            directories
+                .into_iter()
+                .filter(|directory| !options.is_before_start(directory))
+                .map(|directory| (directory.to_string(), ListEntry::Directory)),
+        );
+        entries.sort_by(|(a, _), (b, _)| a.split('/').cmp(b.split('/')));
+        Ok(entries)
+    }
+}
+
+impl Contents {
+    /// Drops the reference `metadata` held to its blob.
+    fn release(&mut self, metadata: &FileMetadata, counters: &BlobCounters) {
+        let key = (metadata.checksum, metadata.compression);
+        let Some(blob) = self.blobs.get_mut(&key) else {
+            return;
+        };
+        blob.refs -= 1;
+        if blob.refs == 0 {
+            let blob = self.blobs.remove(&key).unwrap();
+            counters.record_removed(blob.data.len() as u64);
+        }
+    }
+}
+
+pub struct MemoryStorage {
+    contents: Mutex<Contents>,
+    blobs: BlobCounters,
+    files: FileCounters,
+    config: StorageConfig,
+}
+
+impl MemoryStorage {
+    pub fn new(config: StorageConfig) -> Self {
+        let result = Self {
+            contents: Mutex::default(),
+            blobs: BlobCounters::default(),
+            files: FileCounters::default(),
+            config,
+        };
+        // There's nothing to count at startup.
+        _ = result.blobs.initial.set(BlobTotals::default());
+        _ = result.files.initial.set(FileTotals::default());
+        result
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Contents> {
+        self.contents.lock().unwrap()
+    }
+
+    /// Normalizes a client supplied path into a key of `Contents::files`.
+    fn resolve(&self, path: &str) -> std::io::Result<String> {
+        self.config.path_limits.normalize(path)
+    }
+
+    /// The metadata of the file at `path`, which must exist.
+    fn read_meta_for(&self, contents: &Contents, path: &str) -> Result<FileMetadata, StorageError> {
+        contents
+            .files
+            .file(&self.resolve(path)?)
+            .map_err(|e| explain_collision(path, e))?
+            .cloned()
+            .ok_or_else(|| not_found(path))
+    }
+
+    /// Lists a directory from a snapshot of the files.
+    fn entries(
+        &self,
+        path: &str,
+        options: &ListOptions,
+    ) -> Result<Vec<(String, ListEntry)>, StorageError> {
+        let directory = self.resolve(path)?;
+        self.lock().files.entries(path, &directory, options)
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    fn config(&self) -> &StorageConfig {
+        &self.config
+    }
+
+    fn blob_counters(&self) -> &BlobCounters {
+        &self.blobs
+    }
+
+    fn file_counters(&self) -> &FileCounters {
+        &self.files
+    }
+
+    /// Everything is done under a single lock, so there are no lock maps.
+    fn lock_counts(&self) -> (usize, usize) {
+        (0, 0)
+    }
+
+    /// There's nothing to scrub, memory doesn't rot like disks do.
+    fn scrub_report(&self) -> Option<ScrubReport> {
+        None
+    }
+
+    async fn open(&self, path: &str) -> Result<(FileMetadata, Blob), StorageError> {
+        let mut contents = self.lock();
+        let metadata = self.read_meta_for(&contents, path)?;
+        check_zstd_dictionary(&self.config, &metadata)?;
+        // Blobs are only removed along with the last file referring to them.
+        let blob = contents
+            .blobs
+            .get_mut(&(metadata.checksum, metadata.compression))
+            .unwrap();
+        blob.accessed = Utc::now();
+        let data = std::io::Cursor::new(blob.data.clone());
+        Ok((metadata, Blob::Memory(data)))
+    }
+
+    async fn head(&self, path: &str) -> Result<(FileMetadata, BlobInfo), StorageError> {
+        let contents = self.lock();
+        let metadata = self.read_meta_for(&contents, path)?;
+        let blob = &contents.blobs[&(metadata.checksum, metadata.compression)];
+        let info = BlobInfo {
+            size: blob.data.len() as u64,
+            created: blob.created,
+            accessed: self.config.track_blob_access.then_some(blob.accessed),
+        };
+        Ok((metadata, info))
+    }
+
+    async fn metadata(&self, path: &str) -> Result<FileMetadata, StorageError> {
+        self.read_meta_for(&self.lock(), path)
+    }
+
+    async fn put(
+        &self,
+        path: &str,
+        version: DateTime<Utc>,
+        upload: Upload<'_>,
+    ) -> Result<PutOutcome, StorageError> {
+        let key = self.resolve(path)?;
+        let (decompressed_size, checksum, extra_digests, compression, mut compressed) =
+            blocking(|| {
+                let (decompressed_size, checksum, extra_digests) =
+                    inspect_content(&upload, &self.config)?;
+                let (compression, compressed) = match upload.compression {
+                    Some(compression) => (compression, None),
+                    None => choose_compression(
+                        &self.config,
+                        &upload,
+                        decompressed_size,
+                        |c| self.lock().blobs.contains_key(&(checksum, c)),
+                        &self.blobs,
+                    )?,
+                };
+                Result::<_, StorageError>::Ok((
+                    decompressed_size,
+                    checksum,
+                    extra_digests,
+                    compression,
+                    compressed,
+                ))
+            })?;
+
+        // The content is only read once the blob turns out to be missing, without
+        // holding the lock. Should it have been removed by the time the lock is
+        // taken again, the checks are simply repeated.
+        let mut data: Option<Arc<[u8]>> = None;
+        let mut contents = loop {
+            let contents = self.lock();
+            if data.is_some() || contents.blobs.contains_key(&(checksum, compression)) {
+                break contents;
+            }
+            drop(contents);
+            data = Some(blocking(|| {
+                let mut data = Vec::new();
+                content_reader(
+                    &upload,
+                    compression,
+                    compressed.take(),
+                    &self.config,
+                    &self.blobs,
+                )?
+                .read_to_end(&mut data)?;
+                std::io::Result::Ok(data.into())
+            })?);
+        };
+
+        let current = contents
+            .files
+            .file(&key)
+            .map_err(|e| explain_collision(path, e))?
+            .cloned();
+        if let Some(if_match) = &upload.if_match {
+            if !if_match.matches(current.as_ref().map(|meta| &meta.checksum)) {
+                return Ok(PutOutcome::PreconditionFailed);
+            }
+        }
+        if let Some(outcome) = current
+            .as_ref()
+            .and_then(|meta| refuse_older(self.config.write_policy, meta, version))
+        {
+            return Ok(outcome);
+        }
+
+        let created = match contents.blobs.get_mut(&(checksum, compression)) {
+            Some(blob) => {
+                blob.refs += 1;
+                self.blobs.deduplicated.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+            None => {
+                let data = data.unwrap();
+                self.blobs.record_created(data.len() as u64);
+                let now = Utc::now();
+                contents.blobs.insert(
+                    (checksum, compression),
+                    StoredBlob {
+                        data,
+                        refs: 1,
+                        created: now,
+                        accessed: now,
+                    },
+                );
+                true
+            }
+        };
+        let blob = &contents.blobs[&(checksum, compression)];
+        let zstd_dictionary = match compression {
+            Compression::Zstd => zstd_dictionary_of(&blob.data[..])?,
+            _ => None,
+        };
+
+        contents.files.insert(
+            key,
+            FileMetadata {
+                version,
+                checksum,
+                compression,
+                decompressed_size,
+                filename: upload.filename,
+                extra_digests,
+                zstd_dictionary,
+            },
+        );
+        self.files.record_stored(
+            decompressed_size,
+            current.as_ref().map(|meta| meta.decompressed_size),
+        );
+        if let Some(meta) = current {
+            contents.release(&meta, &self.blobs);
+        }
+
+        Ok(PutOutcome::Stored {
+            checksum,
+            deduplicated: !created,
+        })
+    }
+
+    async fn validate(&self, path: &str, upload: &Upload<'_>) -> Result<(), StorageError> {
+        self.resolve(path)?;
+        blocking(|| inspect_content(upload, &self.config))?;
+        Ok(())
+    }
+
+    async fn has_blob(&self, checksum: &[u8; 32]) -> Result<bool, StorageError> {
+        use clap::ValueEnum;
+
+        let contents = self.lock();
+        Ok(Compression::value_variants()
+            .iter()
+            .any(|&compression| contents.blobs.contains_key(&(*checksum, compression))))
+    }
+
+    async fn link(
+        &self,
+        path: &str,
+        version: DateTime<Utc>,
+        upload: Upload<'_>,
+    ) -> Result<PutOutcome, StorageError> {
+        use clap::ValueEnum;
+
+        let key = self.resolve(path)?;
+        let (checksum, decompressed_size) = link_target(&upload, &self.config)?;
+        let mut contents = self.lock();
+
+        let current = contents
+            .files
+            .file(&key)
+            .map_err(|e| explain_collision(path, e))?
+            .cloned();
+        if let Some(if_match) = &upload.if_match {
+            if !if_match.matches(current.as_ref().map(|meta| &meta.checksum)) {
+                return Ok(PutOutcome::PreconditionFailed);
+            }
+        }
+        if let Some(outcome) = current
+            .as_ref()
+            .and_then(|meta| refuse_older(self.config.write_policy, meta, version))
+        {
+            return Ok(outcome);
+        }
+
+        let is_stored = |compression| contents.blobs.contains_key(&(checksum, compression));
+        let compression = match upload.compression {
+            Some(compression) => Some(compression).filter(|&c| is_stored(c)),
+            None => Compression::value_variants()
+                .iter()
+                .copied()
+                .find(|&c| is_stored(c)),
+        }
+        .ok_or_else(|| {
+            StorageError::NotFound(format!(
+                "Content with checksum {} is not stored",
+                crate::util::bytes_to_hex(&checksum)
+            ))
+        })?;
+        let blob = contents.blobs.get_mut(&(checksum, compression)).unwrap();
+        check_blob_size(
+            Content::Memory(&blob.data),
+            compression,
+            decompressed_size,
+            &self.config,
+        )?;
+        let zstd_dictionary = match compression {
+            Compression::Zstd => zstd_dictionary_of(&blob.data[..])?,
+            _ => None,
+        };
+        blob.refs += 1;
+        self.blobs.deduplicated.fetch_add(1, Ordering::Relaxed);
+
+        // Extra digests can't be computed without reading the content.
+        contents.files.insert(
+            key,
+            FileMetadata {
+                version,
+                checksum,
+                compression,
+                decompressed_size,
+                filename: upload.filename,
+                extra_digests: ExtraDigests::default(),
+                zstd_dictionary,
+            },
+        );
+        self.files.record_stored(
+            decompressed_size,
+            current.as_ref().map(|meta| meta.decompressed_size),
+        );
+        if let Some(meta) = current {
+            contents.release(&meta, &self.blobs);
+        }
+
+        Ok(PutOutcome::Stored {
+            checksum,
+            deduplicated: true,
+        })
+    }
+
+    async fn delete(
+        &self,
+        path: &str,
+        max_version: Option<DateTime<Utc>>,
+    ) -> Result<DeleteOutcome, StorageError> {
+        let key = self.resolve(path)?;
+        let mut contents = self.lock();
+        let metadata = self.read_meta_for(&contents, path)?;
+        if max_version.is_some_and(|max_version| metadata.version > max_version) {
+            return Ok(DeleteOutcome::Superseded {
+                version: metadata.version,
+            });
+        }
+
+        contents.files.remove(&key);
+        contents.release(&metadata, &self.blobs);
+        self.files.record_removed(metadata.decompressed_size);
+        Ok(DeleteOutcome::Deleted)
+    }
+
+    async fn copy(
+        &self,
+        from: &str,
+        to: &str,
+        version: DateTime<Utc>,
+        remove_source: bool,
+    ) -> Result<PutOutcome, StorageError> {
+        let source_key = self.resolve(from)?;
+        let dest_key = self.resolve(to)?;
+        if source_key == dest_key {
+            return Err(StorageError::InvalidInput(
+                "The source and destination must be different files".into(),
+            ));
+        }
+
+        let mut contents = self.lock();
+        let source = self.read_meta_for(&contents, from)?;
+        let current = contents
+            .files
+            .file(&dest_key)
+            .map_err(|e| explain_collision(to, e))?
+            .cloned();
+        if let Some(outcome) = current
+            .as_ref()
+            .and_then(|meta| refuse_older(self.config.write_policy, meta, version))
+        {
+            return Ok(outcome);
+        }
+
+        if remove_source {
+            contents.files.remove(&source_key);
+        } else {
+            // The blob can't be missing while the source refers to it.
+            contents
+                .blobs
+                .get_mut(&(source.checksum, source.compression))
+                .unwrap()
+                .refs += 1;
+        }
+        contents.files.insert(
+            dest_key,
+            FileMetadata {
+                version,
+                ..source.clone()
+            },
+        );
+
+        self.files.record_stored(
+            source.decompressed_size,
+            current.as_ref().map(|meta| meta.decompressed_size),
+        );
+        if remove_source {
+            self.files.record_removed(source.decompressed_size);
+        }
+        if let Some(meta) = current {
+            contents.release(&meta, &self.blobs);
+        }
+
+        Ok(PutOutcome::Stored {
+            checksum: source.checksum,
+            deduplicated: true,
+        })
+    }
+
+    async fn promote(
+        &self,
+        from: &str,
+        to: &str,
+        mode: PromoteMode,
+    ) -> Result<PromoteOutcome, StorageError> {
+        let (from, to) = promotion_prefixes(from, to)?;
+        let (from_key, to_key) = (self.resolve(from)?, self.resolve(to)?);
+
+        let mut contents = self.lock();
+        contents
+            .files
+            .directory(&from_key)
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::NotFound => not_found(from),
+                _ => explain_collision(from, e),
+            })?;
+        let sources = contents
+            .files
+            .under(&from_key)
+            .map(|(path, _)| path.to_string())
+            .collect::<Vec<_>>();
+        let removed = match (mode, contents.files.directory(&to_key)) {
+            (_, Err(e)) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(explain_collision(to, e));
+            }
+            (PromoteMode::Replace, Ok(())) => {
+                let sources = sources.iter().map(String::as_str).collect::<HashSet<_>>();
+                contents
+                    .files
+                    .under(&to_key)
+                    .map(|(path, _)| path)
+                    .filter(|path| !sources.contains(path))
+                    .map(str::to_string)
+                    .collect()
+            }
+            _ => Vec::new(),
+        };
+
+        // Unless merging, whatever is in the way of a promoted file is among the removed
+        // ones. Checking beforehand makes sure the promotion isn't left halfway done.
+        if mode == PromoteMode::Merge {
+            for path in &sources {
+                contents
+                    .files
+                    .file(&format!("{to_key}/{path}"))
+                    .map_err(|e| explain_collision(&format!("{to}/{path}"), e))?;
+            }
+        }
+
+        for path in &removed {
+            let metadata = contents.files.remove(&format!("{to_key}/{path}")).unwrap();
+            contents.release(&metadata, &self.blobs);
+            self.files.record_removed(metadata.decompressed_size);
+        }
+
+        for path in &sources {
+            let metadata = contents
+                .files
+                .remove(&format!("{from_key}/{path}"))
+                .unwrap();
+            if let Some(replaced) = contents.files.insert(format!("{to_key}/{path}"), metadata) {
+                self.files.record_removed(replaced.decompressed_size);
+                contents.release(&replaced, &self.blobs);
+            }
+        }
+
+        Ok(PromoteOutcome {
+            moved: sources.len(),
+            removed: removed.len(),
+        })
+    }
+
+    async fn list(&self, path: &str, options: ListOptions) -> Result<ListIter, StorageError> {
+        Ok(Box::new(self.entries(path, &options)?.into_iter().map(Ok)))
+    }
+
+    async fn list_stream(
+        &self,
+        path: &str,
+        options: ListOptions,
+    ) -> Result<ListStream, StorageError> {
+        let entries = self.entries(path, &options)?;
+        Ok(futures_util::stream::iter(entries.into_iter().map(Ok)).boxed())
+    }
+}
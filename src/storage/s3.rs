@@ -0,0 +1,836 @@
+//! Storage in an S3 bucket, e.g. on MinIO, so that the server itself keeps no state.
+//! Like in the data directory, files are metadata objects under `files/` referring to
+//! deduplicated blobs under `blobs/`.
+//!
+//! NOTE: The bucket is indexed when the server starts and the index is only kept up
+//!       to date with the server's own changes, so a bucket mustn't be shared by
+//!       servers running at the same time.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::Read,
+    sync::{atomic::Ordering, Arc, Mutex, MutexGuard},
+};
+
+use async_trait::async_trait;
+use aws_sdk_s3::{error::DisplayErrorContext, primitives::ByteStream, types::Object, Client};
+use chrono::{DateTime, Utc};
+use futures_util::{StreamExt, TryStreamExt};
+
+use super::{
+    check_blob_size, check_zstd_dictionary, choose_compression, content_reader, explain_collision,
+    inspect_content, link_target,
+    memory::{not_found, FileTree},
+    promotion_prefixes, refuse_older, zstd_dictionary_of, Blob, BlobCounters, BlobInfo, BlobTotals,
+    Compression, Content, DeleteOutcome, ExtraDigests, FileCounters, FileMetadata, FileTotals,
+    ListIter, ListOptions, ListStream, PromoteMode, PromoteOutcome, PutOutcome, ScrubReport,
+    Storage, StorageConfig, StorageError, Upload,
+};
+use crate::util::{blocking, bytes_to_hex, hex_to_byte_array};
+
+#[derive(clap::Args)]
+pub struct S3Config {
+    /// The bucket to keep files in with `--backend s3`. Credentials and the region are
+    /// taken from the environment, like `AWS_ACCESS_KEY_ID` and `AWS_REGION`.
+    #[clap(long, required_if_eq("backend", "s3"))]
+    pub bucket: Option<String>,
+    /// URL of an S3 compatible service to use instead of AWS, e.g. MinIO. Buckets are
+    /// addressed by path rather than by host name there.
+    #[clap(long)]
+    pub s3_endpoint: Option<String>,
+}
+
+const FILES: &str = "files/";
+const BLOBS: &str = "blobs/";
+
+fn file_key(path: &str) -> String {
+    format!("{FILES}{path}")
+}
+
+fn blob_key(checksum: &[u8; 32], compression: Compression) -> String {
+    format!("{BLOBS}{}/{}", compression.coding(), bytes_to_hex(checksum))
+}
+
+/// The inverse of [`blob_key`], `None` for objects that aren't blobs.
+fn parse_blob_key(key: &str) -> Option<([u8; 32], Compression)> {
+    use clap::ValueEnum;
+
+    let (coding, checksum) = key.strip_prefix(BLOBS)?.split_once('/')?;
+    let compression = Compression::value_variants()
+        .iter()
+        .copied()
+        .find(|compression| compression.coding() == coding)?;
+    Some((hex_to_byte_array(checksum)?, compression))
+}
+
+/// Reports a failed request to S3 as an internal error.
+fn sdk_error(error: impl std::error::Error + Send + Sync + 'static) -> std::io::Error {
+    std::io::Error::other(DisplayErrorContext(error).to_string())
+}
+
+struct IndexedBlob {
+    size: u64,
+    refs: usize,
+    created: DateTime<Utc>,
+    zstd_dictionary: Option<u32>,
+}
+
+/// What is in the bucket, so that only reading blobs and changing files needs
+/// requests to it.
+#[derive(Default)]
+struct Index {
+    files: FileTree,
+    blobs: HashMap<([u8; 32], Compression), IndexedBlob>,
+}
+
+impl Index {
+    /// Stores `metadata` at `key`, referring to its blob, which must be indexed.
+    /// The reference of the file it replaces still has to be released.
+    fn insert(&mut self, key: String, metadata: FileMetadata) {
+        self.blobs
+            .get_mut(&(metadata.checksum, metadata.compression))
+            .unwrap()
+            .refs += 1;
+        self.files.insert(key, metadata);
+    }
+}
+
+pub struct S3Storage {
+    client: Client,
+    bucket: String,
+    index: Mutex<Index>,
+    /// Held while changing the bucket, so that its objects and the index change
+    /// in step. Reads only need the index.
+    writes: tokio::sync::Mutex<()>,
+    blobs: BlobCounters,
+    files: FileCounters,
+    config: StorageConfig,
+}
+
+impl S3Storage {
+    /// Connects to the bucket given by `config` and indexes it.
+    pub async fn open(config: StorageConfig) -> std::io::Result<Self> {
+        let Some(bucket) = config.s3.bucket.clone() else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--backend s3 requires --bucket",
+            ));
+        };
+        let shared = aws_config::load_from_env().await;
+        let mut s3 = aws_sdk_s3::config::Builder::from(&shared);
+        if let Some(endpoint) = &config.s3.s3_endpoint {
+            s3 = s3.endpoint_url(endpoint).force_path_style(true);
+        }
+
+        let storage = Self {
+            client: Client::from_conf(s3.build()),
+            bucket,
+            index: Mutex::default(),
+            writes: tokio::sync::Mutex::default(),
+            blobs: BlobCounters::default(),
+            files: FileCounters::default(),
+            config,
+        };
+        storage.load_index().await?;
+        Ok(storage)
+    }
+
+    /// Indexes the bucket, removing blobs left behind by writes that failed halfway.
+    async fn load_index(&self) -> std::io::Result<()> {
+        let mut blobs = HashMap::new();
+        for object in self.list_objects(BLOBS).await? {
+            let Some(key) = object.key().and_then(parse_blob_key) else {
+                continue;
+            };
+            let created = object
+                .last_modified()
+                .and_then(|time| DateTime::from_timestamp(time.secs(), time.subsec_nanos()))
+                .unwrap_or_default();
+            blobs.insert(
+                key,
+                IndexedBlob {
+                    size: object.size().unwrap_or(0) as u64,
+                    refs: 0,
+                    created,
+                    zstd_dictionary: None,
+                },
+            );
+        }
+
+        let paths = self
+            .list_objects(FILES)
+            .await?
+            .into_iter()
+            .filter_map(|object| Some(object.key()?[FILES.len()..].to_string()));
+        let files = futures_util::stream::iter(paths)
+            .map(|path| async move {
+                let metadata = self.get_metadata(&path).await?;
+                std::io::Result::Ok(metadata.map(|metadata| (path, metadata)))
+            })
+            .buffer_unordered(32)
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        let mut index = Index::default();
+        for (path, metadata) in files.into_iter().flatten() {
+            let Some(blob) = blobs.get_mut(&(metadata.checksum, metadata.compression)) else {
+                tracing::warn!(
+                    path,
+                    "the blob of a file is missing from the bucket, ignoring it"
+                );
+                continue;
+            };
+            blob.refs += 1;
+            blob.zstd_dictionary = metadata.zstd_dictionary;
+            index.files.insert(path, metadata);
+        }
+        let unused = blobs
+            .iter()
+            .filter(|(_, blob)| blob.refs == 0)
+            .map(|(&key, _)| key)
+            .collect::<Vec<_>>();
+        for (checksum, compression) in unused {
+            blobs.remove(&(checksum, compression));
+            self.delete_object(&blob_key(&checksum, compression))
+                .await?;
+        }
+        index.blobs = blobs;
+
+        _ = self.blobs.initial.set(BlobTotals {
+            blobs: index.blobs.len() as u64,
+            bytes: index.blobs.values().map(|blob| blob.size).sum(),
+        });
+        _ = self.files.initial.set(FileTotals {
+            files: index.files.len() as u64,
+            logical_bytes: index
+                .files
+                .values()
+                .map(|metadata| metadata.decompressed_size as u64)
+                .sum(),
+        });
+        *self.lock() = index;
+        Ok(())
+    }
+
+    async fn list_objects(&self, prefix: &str) -> std::io::Result<Vec<Object>> {
+        let mut pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.bucket)
+            .prefix(prefix)
+            .into_paginator()
+            .send();
+        let mut objects = Vec::new();
+        while let Some(page) = pages.next().await {
+            objects.extend(page.map_err(sdk_error)?.contents.unwrap_or_default());
+        }
+        Ok(objects)
+    }
+
+    /// The contents of an object, `None` if there's no such object.
+    async fn get_object(&self, key: &str) -> std::io::Result<Option<bytes::Bytes>> {
+        let output = match self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+        {
+            Ok(output) => output,
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_key()) => {
+                return Ok(None);
+            }
+            Err(e) => return Err(sdk_error(e)),
+        };
+        Ok(Some(
+            output.body.collect().await.map_err(sdk_error)?.into_bytes(),
+        ))
+    }
+
+    async fn put_object(&self, key: &str, data: Vec<u8>) -> std::io::Result<()> {
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .body(ByteStream::from(data))
+            .send()
+            .await
+            .map_err(sdk_error)?;
+        Ok(())
+    }
+
+    async fn delete_object(&self, key: &str) -> std::io::Result<()> {
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(sdk_error)?;
+        Ok(())
+    }
+
+    async fn get_metadata(&self, path: &str) -> std::io::Result<Option<FileMetadata>> {
+        let Some(data) = self.get_object(&file_key(path)).await? else {
+            return Ok(None);
+        };
+        serde_json::from_slice(&data).map(Some).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{}: {e}", file_key(path)),
+            )
+        })
+    }
+
+    async fn put_metadata(&self, path: &str, metadata: &FileMetadata) -> std::io::Result<()> {
+        self.put_object(&file_key(path), serde_json::to_vec(metadata)?)
+            .await
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Index> {
+        self.index.lock().unwrap()
+    }
+
+    /// Normalizes a client supplied path into a key of `Index::files`.
+    fn resolve(&self, path: &str) -> std::io::Result<String> {
+        self.config.path_limits.normalize(path)
+    }
+
+    /// The metadata of the file at `path`, which must exist.
+    fn read_meta(&self, path: &str) -> Result<FileMetadata, StorageError> {
+        self.lock()
+            .files
+            .file(&self.resolve(path)?)
+            .map_err(|e| explain_collision(path, e))?
+            .cloned()
+            .ok_or_else(|| not_found(path))
+    }
+
+    /// The metadata of the file at `key` if there is one, failing if it is a directory.
+    fn current(&self, key: &str, path: &str) -> Result<Option<FileMetadata>, StorageError> {
+        Ok(self
+            .lock()
+            .files
+            .file(key)
+            .map_err(|e| explain_collision(path, e))?
+            .cloned())
+    }
+
+    fn is_stored(&self, checksum: &[u8; 32], compression: Compression) -> bool {
+        self.lock().blobs.contains_key(&(*checksum, compression))
+    }
+
+    /// Reads an upload into memory, compressed as it is going to be stored.
+    fn read_content(
+        &self,
+        upload: &Upload<'_>,
+        compression: Compression,
+        compressed: Option<Vec<u8>>,
+    ) -> std::io::Result<Vec<u8>> {
+        blocking(|| {
+            let mut data = Vec::new();
+            content_reader(upload, compression, compressed, &self.config, &self.blobs)?
+                .read_to_end(&mut data)?;
+            Ok(data)
+        })
+    }
+
+    /// Drops the reference `metadata` held to its blob, removing the blob once
+    /// nothing refers to it.
+    async fn release(&self, metadata: &FileMetadata) {
+        let key = (metadata.checksum, metadata.compression);
+        {
+            let mut index = self.lock();
+            let Some(blob) = index.blobs.get_mut(&key) else {
+                return;
+            };
+            blob.refs -= 1;
+            if blob.refs > 0 {
+                return;
+            }
+            let blob = index.blobs.remove(&key).unwrap();
+            self.blobs.record_removed(blob.size);
+        }
+        if let Err(e) = self
+            .delete_object(&blob_key(&metadata.checksum, metadata.compression))
+            .await
+        {
+            // It's removed at the next startup instead.
+            tracing::warn!(error = %e, "failed to remove an unused blob");
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    fn config(&self) -> &StorageConfig {
+        &self.config
+    }
+
+    fn blob_counters(&self) -> &BlobCounters {
+        &self.blobs
+    }
+
+    fn file_counters(&self) -> &FileCounters {
+        &self.files
+    }
+
+    /// Writes are serialized by a single lock, so there are no lock maps.
+    fn lock_counts(&self) -> (usize, usize) {
+        (0, 0)
+    }
+
+    /// Keeping the blobs intact is up to S3.
+    fn scrub_report(&self) -> Option<ScrubReport> {
+        None
+    }
+
+    async fn open(&self, path: &str) -> Result<(FileMetadata, Blob), StorageError> {
+        let metadata = self.read_meta(path)?;
+        check_zstd_dictionary(&self.config, &metadata)?;
+        // The blob is only missing if the file has been changed in the meantime.
+        let data = self
+            .get_object(&blob_key(&metadata.checksum, metadata.compression))
+            .await?
+            .ok_or_else(|| not_found(path))?;
+        let data = std::io::Cursor::new(Arc::from(&data[..]));
+        Ok((metadata, Blob::Memory(data)))
+    }
+
+    async fn head(&self, path: &str) -> Result<(FileMetadata, BlobInfo), StorageError> {
+        let metadata = self.read_meta(path)?;
+        let index = self.lock();
+        let blob = index
+            .blobs
+            .get(&(metadata.checksum, metadata.compression))
+            .ok_or_else(|| not_found(path))?;
+        // Reads aren't recorded, as that would take a write to the bucket each.
+        let info = BlobInfo {
+            size: blob.size,
+            created: blob.created,
+            accessed: None,
+        };
+        Ok((metadata, info))
+    }
+
+    async fn metadata(&self, path: &str) -> Result<FileMetadata, StorageError> {
+        self.read_meta(path)
+    }
+
+    async fn put(
+        &self,
+        path: &str,
+        version: DateTime<Utc>,
+        upload: Upload<'_>,
+    ) -> Result<PutOutcome, StorageError> {
+        let key = self.resolve(path)?;
+        let (decompressed_size, checksum, extra_digests, compression, mut compressed) =
+            blocking(|| {
+                let (decompressed_size, checksum, extra_digests) =
+                    inspect_content(&upload, &self.config)?;
+                let (compression, compressed) = match upload.compression {
+                    Some(compression) => (compression, None),
+                    None => choose_compression(
+                        &self.config,
+                        &upload,
+                        decompressed_size,
+                        |c| self.is_stored(&checksum, c),
+                        &self.blobs,
+                    )?,
+                };
+                Result::<_, StorageError>::Ok((
+                    decompressed_size,
+                    checksum,
+                    extra_digests,
+                    compression,
+                    compressed,
+                ))
+            })?;
+
+        // The content is read before waiting for other writes, unless it is stored
+        // already. Should its blob be removed in the meantime, it's read afterwards.
+        let mut data = match self.is_stored(&checksum, compression) {
+            true => None,
+            false => Some(self.read_content(&upload, compression, compressed.take())?),
+        };
+        let _writes = self.writes.lock().await;
+
+        let current = self.current(&key, path)?;
+        if let Some(if_match) = &upload.if_match {
+            if !if_match.matches(current.as_ref().map(|meta| &meta.checksum)) {
+                return Ok(PutOutcome::PreconditionFailed);
+            }
+        }
+        if let Some(outcome) = current
+            .as_ref()
+            .and_then(|meta| refuse_older(self.config.write_policy, meta, version))
+        {
+            return Ok(outcome);
+        }
+
+        let created = !self.is_stored(&checksum, compression);
+        let zstd_dictionary = match created {
+            true => {
+                let data = match data.take() {
+                    Some(data) => data,
+                    None => self.read_content(&upload, compression, compressed.take())?,
+                };
+                let zstd_dictionary = match compression {
+                    Compression::Zstd => zstd_dictionary_of(&data[..])?,
+                    _ => None,
+                };
+                let size = data.len() as u64;
+                self.put_object(&blob_key(&checksum, compression), data)
+                    .await?;
+                self.blobs.record_created(size);
+                let now = Utc::now();
+                self.lock().blobs.insert(
+                    (checksum, compression),
+                    IndexedBlob {
+                        size,
+                        refs: 0,
+                        created: now,
+                        zstd_dictionary,
+                    },
+                );
+                zstd_dictionary
+            }
+            false => {
+                self.blobs.deduplicated.fetch_add(1, Ordering::Relaxed);
+                self.lock().blobs[&(checksum, compression)].zstd_dictionary
+            }
+        };
+
+        let metadata = FileMetadata {
+            version,
+            checksum,
+            compression,
+            decompressed_size,
+            filename: upload.filename,
+            extra_digests,
+            zstd_dictionary,
+        };
+        self.put_metadata(&key, &metadata).await?;
+        self.lock().insert(key, metadata);
+        self.files.record_stored(
+            decompressed_size,
+            current.as_ref().map(|meta| meta.decompressed_size),
+        );
+        if let Some(meta) = current {
+            self.release(&meta).await;
+        }
+
+        Ok(PutOutcome::Stored {
+            checksum,
+            deduplicated: !created,
+        })
+    }
+
+    async fn validate(&self, path: &str, upload: &Upload<'_>) -> Result<(), StorageError> {
+        self.resolve(path)?;
+        blocking(|| inspect_content(upload, &self.config))?;
+        Ok(())
+    }
+
+    async fn has_blob(&self, checksum: &[u8; 32]) -> Result<bool, StorageError> {
+        use clap::ValueEnum;
+
+        Ok(Compression::value_variants()
+            .iter()
+            .any(|&compression| self.is_stored(checksum, compression)))
+    }
+
+    async fn link(
+        &self,
+        path: &str,
+        version: DateTime<Utc>,
+        upload: Upload<'_>,
+    ) -> Result<PutOutcome, StorageError> {
+        use clap::ValueEnum;
+
+        let key = self.resolve(path)?;
+        let (checksum, decompressed_size) = link_target(&upload, &self.config)?;
+        let _writes = self.writes.lock().await;
+
+        let current = self.current(&key, path)?;
+        if let Some(if_match) = &upload.if_match {
+            if !if_match.matches(current.as_ref().map(|meta| &meta.checksum)) {
+                return Ok(PutOutcome::PreconditionFailed);
+            }
+        }
+        if let Some(outcome) = current
+            .as_ref()
+            .and_then(|meta| refuse_older(self.config.write_policy, meta, version))
+        {
+            return Ok(outcome);
+        }
+
+        let compression = match upload.compression {
+            Some(compression) => Some(compression).filter(|&c| self.is_stored(&checksum, c)),
+            None => Compression::value_variants()
+                .iter()
+                .copied()
+                .find(|&c| self.is_stored(&checksum, c)),
+        }
+        .ok_or_else(|| {
+            StorageError::NotFound(format!(
+                "Content with checksum {} is not stored",
+                bytes_to_hex(&checksum)
+            ))
+        })?;
+        let blob_key = blob_key(&checksum, compression);
+        let data = self.get_object(&blob_key).await?.ok_or_else(|| {
+            StorageError::Corrupt(format!("{blob_key} is missing from the bucket"))
+        })?;
+        check_blob_size(
+            Content::Memory(&data),
+            compression,
+            decompressed_size,
+            &self.config,
+        )?;
+        let zstd_dictionary = match compression {
+            Compression::Zstd => zstd_dictionary_of(&data[..])?,
+            _ => None,
+        };
+        self.blobs.deduplicated.fetch_add(1, Ordering::Relaxed);
+
+        // Extra digests can't be computed without reading the content.
+        let metadata = FileMetadata {
+            version,
+            checksum,
+            compression,
+            decompressed_size,
+            filename: upload.filename,
+            extra_digests: ExtraDigests::default(),
+            zstd_dictionary,
+        };
+        self.put_metadata(&key, &metadata).await?;
+        self.lock().insert(key, metadata);
+        self.files.record_stored(
+            decompressed_size,
+            current.as_ref().map(|meta| meta.decompressed_size),
+        );
+        if let Some(meta) = current {
+            self.release(&meta).await;
+        }
+
+        Ok(PutOutcome::Stored {
+            checksum,
+            deduplicated: true,
+        })
+    }
+
+    async fn delete(
+        &self,
+        path: &str,
+        max_version: Option<DateTime<Utc>>,
+    ) -> Result<DeleteOutcome, StorageError> {
+        let key = self.resolve(path)?;
+        let _writes = self.writes.lock().await;
+        let metadata = self.read_meta(path)?;
+        if max_version.is_some_and(|max_version| metadata.version > max_version) {
+            return Ok(DeleteOutcome::Superseded {
+                version: metadata.version,
+            });
+        }
+
+        self.delete_object(&file_key(&key)).await?;
+        self.lock().files.remove(&key);
+        self.files.record_removed(metadata.decompressed_size);
+        self.release(&metadata).await;
+        Ok(DeleteOutcome::Deleted)
+    }
+
+    async fn copy(
+        &self,
+        from: &str,
+        to: &str,
+        version: DateTime<Utc>,
+        remove_source: bool,
+    ) -> Result<PutOutcome, StorageError> {
+        let source_key = self.resolve(from)?;
+        let dest_key = self.resolve(to)?;
+        if source_key == dest_key {
+            return Err(StorageError::InvalidInput(
+                "The source and destination must be different files".into(),
+            ));
+        }
+
+        let _writes = self.writes.lock().await;
+        let source = self.read_meta(from)?;
+        let current = self.current(&dest_key, to)?;
+        if let Some(outcome) = current
+            .as_ref()
+            .and_then(|meta| refuse_older(self.config.write_policy, meta, version))
+        {
+            return Ok(outcome);
+        }
+
+        let metadata = FileMetadata {
+            version,
+            ..source.clone()
+        };
+        self.put_metadata(&dest_key, &metadata).await?;
+        self.lock().insert(dest_key, metadata);
+        self.files.record_stored(
+            source.decompressed_size,
+            current.as_ref().map(|meta| meta.decompressed_size),
+        );
+        if let Some(meta) = current {
+            self.release(&meta).await;
+        }
+        if remove_source {
+            self.delete_object(&file_key(&source_key)).await?;
+            self.lock().files.remove(&source_key);
+            self.files.record_removed(source.decompressed_size);
+            self.release(&source).await;
+        }
+
+        Ok(PutOutcome::Stored {
+            checksum: source.checksum,
+            deduplicated: true,
+        })
+    }
+
+    async fn promote(
+        &self,
+        from: &str,
+        to: &str,
+        mode: PromoteMode,
+    ) -> Result<PromoteOutcome, StorageError> {
+        let (from, to) = promotion_prefixes(from, to)?;
+        let (from_key, to_key) = (self.resolve(from)?, self.resolve(to)?);
+
+        let _writes = self.writes.lock().await;
+        let (sources, removed) = {
+            let index = self.lock();
+            index
+                .files
+                .directory(&from_key)
+                .map_err(|e| match e.kind() {
+                    std::io::ErrorKind::NotFound => not_found(from),
+                    _ => explain_collision(from, e),
+                })?;
+            let sources = index
+                .files
+                .under(&from_key)
+                .map(|(path, _)| path.to_string())
+                .collect::<Vec<_>>();
+            let removed = match (mode, index.files.directory(&to_key)) {
+                (_, Err(e)) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(explain_collision(to, e));
+                }
+                (PromoteMode::Replace, Ok(())) => {
+                    let sources = sources.iter().map(String::as_str).collect::<HashSet<_>>();
+                    index
+                        .files
+                        .under(&to_key)
+                        .map(|(path, _)| path)
+                        .filter(|path| !sources.contains(path))
+                        .map(str::to_string)
+                        .collect()
+                }
+                _ => Vec::new(),
+            };
+            (sources, removed)
+        };
+
+        // Unless merging, whatever is in the way of a promoted file is among the removed
+        // ones. Checking beforehand makes sure the promotion isn't left halfway done.
+        if mode == PromoteMode::Merge {
+            for path in &sources {
+                self.current(&format!("{to_key}/{path}"), &format!("{to}/{path}"))?;
+            }
+        }
+
+        // The bucket can't be changed atomically, so the promoted files are written
+        // first and the index, which readers go by, is switched over all at once.
+        // Should writing fail halfway, the files written so far are put back.
+        let (promoted, replaced) = {
+            let index = self.lock();
+            sources
+                .iter()
+                .map(|path| {
+                    (
+                        index.files[&format!("{from_key}/{path}")].clone(),
+                        index.files.get(&format!("{to_key}/{path}")).cloned(),
+                    )
+                })
+                .unzip::<_, _, Vec<_>, Vec<_>>()
+        };
+        for (written, (path, metadata)) in sources.iter().zip(&promoted).enumerate() {
+            let Err(e) = self
+                .put_metadata(&format!("{to_key}/{path}"), metadata)
+                .await
+            else {
+                continue;
+            };
+            for (path, replaced) in sources.iter().zip(&replaced).take(written) {
+                let dest = format!("{to_key}/{path}");
+                let undone = match replaced {
+                    Some(replaced) => self.put_metadata(&dest, replaced).await,
+                    None => self.delete_object(&file_key(&dest)).await,
+                };
+                if let Err(e) = undone {
+                    tracing::error!(
+                        error = %e,
+                        path = dest,
+                        "failed to undo a write of a failed promotion"
+                    );
+                }
+            }
+            return Err(e.into());
+        }
+
+        let mut dropped = Vec::new();
+        {
+            let mut index = self.lock();
+            for path in &removed {
+                dropped.extend(index.files.remove(&format!("{to_key}/{path}")));
+            }
+            // The promoted files keep the references their sources held.
+            for (path, metadata) in sources.iter().zip(promoted) {
+                index.files.remove(&format!("{from_key}/{path}"));
+                dropped.extend(index.files.insert(format!("{to_key}/{path}"), metadata));
+            }
+        }
+
+        let stale = sources
+            .iter()
+            .map(|path| format!("{from_key}/{path}"))
+            .chain(removed.iter().map(|path| format!("{to_key}/{path}")));
+        for path in stale {
+            if let Err(e) = self.delete_object(&file_key(&path)).await {
+                // NOTE: The file is gone from the index, but shows up again once the
+                //       bucket is indexed the next time.
+                tracing::error!(error = %e, path, "failed to remove a promoted file");
+            }
+        }
+        for metadata in &dropped {
+            self.files.record_removed(metadata.decompressed_size);
+            self.release(metadata).await;
+        }
+
+        Ok(PromoteOutcome {
+            moved: sources.len(),
+            removed: removed.len(),
+        })
+    }
+
+    async fn list(&self, path: &str, options: ListOptions) -> Result<ListIter, StorageError> {
+        let directory = self.resolve(path)?;
+        let entries = self.lock().files.entries(path, &directory, &options)?;
+        Ok(Box::new(entries.into_iter().map(Ok)))
+    }
+
+    async fn list_stream(
+        &self,
+        path: &str,
+        options: ListOptions,
+    ) -> Result<ListStream, StorageError> {
+        let directory = self.resolve(path)?;
+        let entries = self.lock().files.entries(path, &directory, &options)?;
+        Ok(futures_util::stream::iter(entries.into_iter().map(Ok)).boxed())
+    }
+}
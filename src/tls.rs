@@ -0,0 +1,143 @@
+//! Terminating TLS in the server itself, for deployments without a proxy in front of it.
+//! The certificate is reloaded on SIGHUP, so that renewing it doesn't need a restart.
+
+use std::{
+    future::Future,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use tokio_rustls::{
+    rustls::{
+        crypto::CryptoProvider,
+        server::{ClientHello, ResolvesServerCert},
+        sign::CertifiedKey,
+        ServerConfig,
+    },
+    TlsAcceptor,
+};
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct TlsConfig {
+    /// Serve HTTPS with the certificate chain in this PEM file. It's read again along
+    /// with the key when the server receives SIGHUP.
+    #[clap(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+    /// The private key of `--tls-cert`, in a PEM file.
+    #[clap(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+}
+
+fn invalid_data(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+/// Reads a certificate chain and its private key from PEM files.
+fn load_certified_key(
+    cert: &Path,
+    key: &Path,
+    provider: &CryptoProvider,
+) -> std::io::Result<CertifiedKey> {
+    let chain = rustls_pemfile::certs(&mut BufReader::new(std::fs::File::open(cert)?))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    if chain.is_empty() {
+        return Err(invalid_data(format!(
+            "{} contains no certificates",
+            cert.display()
+        )));
+    }
+    let private_key = rustls_pemfile::private_key(&mut BufReader::new(std::fs::File::open(key)?))?
+        .ok_or_else(|| invalid_data(format!("{} contains no private key", key.display())))?;
+    CertifiedKey::from_der(chain, private_key, provider)
+        .map_err(|e| invalid_data(format!("{}: {e}", key.display())))
+}
+
+/// Hands out the current certificate to every handshake, until it's reloaded.
+#[derive(Debug)]
+struct ReloadableCert {
+    cert: PathBuf,
+    key: PathBuf,
+    provider: Arc<CryptoProvider>,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ReloadableCert {
+    /// Reads the certificate again, keeping the current one should that fail.
+    fn reload(&self) -> std::io::Result<()> {
+        let certified_key = load_certified_key(&self.cert, &self.key, &self.provider)?;
+        *self.current.write().unwrap() = Arc::new(certified_key);
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for ReloadableCert {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// TLS as configured with `--tls-cert`.
+pub struct Tls {
+    pub acceptor: TlsAcceptor,
+    certificate: Arc<ReloadableCert>,
+}
+
+impl TlsConfig {
+    /// Loads the certificate, `None` if TLS isn't enabled. HTTP/2 is only offered
+    /// through ALPN with `http2`.
+    pub fn load(&self, http2: bool) -> std::io::Result<Option<Tls>> {
+        let (Some(cert), Some(key)) = (&self.tls_cert, &self.tls_key) else {
+            return Ok(None);
+        };
+
+        let provider = Arc::new(tokio_rustls::rustls::crypto::ring::default_provider());
+        let certificate = Arc::new(ReloadableCert {
+            current: RwLock::new(Arc::new(load_certified_key(cert, key, &provider)?)),
+            cert: cert.clone(),
+            key: key.clone(),
+            provider: provider.clone(),
+        });
+        let mut config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?
+            .with_no_client_auth()
+            .with_cert_resolver(certificate.clone());
+        if http2 {
+            config.alpn_protocols.push(b"h2".to_vec());
+        }
+        config.alpn_protocols.push(b"http/1.1".to_vec());
+
+        Ok(Some(Tls {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+            certificate,
+        }))
+    }
+}
+
+impl Tls {
+    /// Returns a task reloading the certificate whenever the server receives SIGHUP,
+    /// which is handled from now on instead of terminating the server.
+    pub fn reload_on_hangup(&self) -> std::io::Result<impl Future<Output = ()> + Send + 'static> {
+        #[cfg(target_family = "unix")]
+        let (mut hangup, certificate) = (
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?,
+            self.certificate.clone(),
+        );
+
+        Ok(async move {
+            #[cfg(target_family = "unix")]
+            while hangup.recv().await.is_some() {
+                match certificate.reload() {
+                    Ok(()) => tracing::info!("reloaded the TLS certificate"),
+                    Err(e) => tracing::error!(
+                        error = %e,
+                        "failed to reload the TLS certificate, keeping the current one"
+                    ),
+                }
+            }
+            #[cfg(not(target_family = "unix"))]
+            std::future::pending::<()>().await;
+        })
+    }
+}
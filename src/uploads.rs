@@ -0,0 +1,198 @@
+use std::{
+    collections::HashMap,
+    io::{Seek, Write},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use rand::RngCore;
+
+use crate::util::{blocking, bytes_to_hex, parse_seconds};
+
+#[derive(clap::Args)]
+pub struct UploadConfig {
+    /// Discard resumable uploads that have seen no activity for this many seconds.
+    #[clap(long = "upload-session-ttl", value_parser = parse_seconds, default_value = "3600")]
+    pub session_ttl: Duration,
+}
+
+struct Session {
+    path: String,
+    length: u64,
+    last_activity: Instant,
+}
+
+type SessionsArc = Arc<std::sync::Mutex<HashMap<String, Arc<tokio::sync::Mutex<Session>>>>>;
+
+/// Resumable uploads whose data is accumulated in temporary files until completed.
+pub struct UploadSessions {
+    directory: PathBuf,
+    sessions: SessionsArc,
+    expiry_worker: tokio::task::AbortHandle,
+}
+
+impl Drop for UploadSessions {
+    fn drop(&mut self) {
+        self.expiry_worker.abort();
+    }
+}
+
+/// The data of an upload being completed, see [`UploadSessions::open`].
+pub struct OpenedUpload {
+    pub file: std::fs::File,
+    session: tokio::sync::OwnedMutexGuard<Session>,
+}
+
+impl OpenedUpload {
+    /// Where the upload is to be stored.
+    pub fn path(&self) -> &str {
+        &self.session.path
+    }
+
+    /// The number of bytes received.
+    pub fn length(&self) -> u64 {
+        self.session.length
+    }
+}
+
+pub enum AppendOutcome {
+    Appended {
+        length: u64,
+    },
+    /// The chunk starts past the end of the data received so far.
+    Gap {
+        length: u64,
+    },
+}
+
+async fn expiry_worker(directory: PathBuf, sessions: SessionsArc, ttl: Duration) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60).min(ttl));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+    loop {
+        interval.tick().await;
+
+        sessions.lock().unwrap().retain(|id, session| {
+            // Sessions that are currently locked are in use and thus not expired.
+            let Ok(session) = session.try_lock() else {
+                return true;
+            };
+            if session.last_activity.elapsed() < ttl {
+                return true;
+            }
+            _ = std::fs::remove_file(directory.join(id));
+            false
+        });
+    }
+}
+
+fn unknown_session() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::NotFound, "No such upload session")
+}
+
+impl UploadSessions {
+    pub fn create(directory: PathBuf, config: &UploadConfig) -> std::io::Result<Self> {
+        // Sessions don't survive restarts, so anything left over is garbage.
+        if directory.exists() {
+            std::fs::remove_dir_all(&directory)?;
+        }
+        std::fs::create_dir_all(&directory)?;
+
+        let sessions = SessionsArc::default();
+        let expiry_worker = tokio::spawn(expiry_worker(
+            directory.clone(),
+            sessions.clone(),
+            config.session_ttl,
+        ))
+        .abort_handle();
+
+        Ok(Self {
+            directory,
+            sessions,
+            expiry_worker,
+        })
+    }
+
+    fn get(&self, id: &str) -> std::io::Result<Arc<tokio::sync::Mutex<Session>>> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .ok_or_else(unknown_session)
+    }
+
+    /// Starts a new upload that will be stored at `path` once completed, returns its id.
+    pub fn start(&self, path: String) -> std::io::Result<String> {
+        let mut bytes = [0; 16];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let id = bytes_to_hex(&bytes);
+
+        std::fs::File::create(self.directory.join(&id))?;
+        self.sessions.lock().unwrap().insert(
+            id.clone(),
+            Arc::new(tokio::sync::Mutex::new(Session {
+                path,
+                length: 0,
+                last_activity: Instant::now(),
+            })),
+        );
+
+        Ok(id)
+    }
+
+    /// Returns the number of bytes received so far.
+    pub async fn length(&self, id: &str) -> std::io::Result<u64> {
+        Ok(self.get(id)?.lock().await.length)
+    }
+
+    /// Writes `data` at `offset`, discarding anything previously received past that point.
+    pub async fn append(
+        &self,
+        id: &str,
+        offset: u64,
+        data: &[u8],
+    ) -> std::io::Result<AppendOutcome> {
+        let session = self.get(id)?;
+        let mut session = session.lock().await;
+        session.last_activity = Instant::now();
+
+        if offset > session.length {
+            return Ok(AppendOutcome::Gap {
+                length: session.length,
+            });
+        }
+
+        blocking(|| {
+            let mut file = std::fs::OpenOptions::new()
+                .write(true)
+                .open(self.directory.join(id))?;
+            file.set_len(offset)?;
+            file.seek(std::io::SeekFrom::Start(offset))?;
+            file.write_all(data)
+        })?;
+
+        session.length = offset + data.len() as u64;
+        Ok(AppendOutcome::Appended {
+            length: session.length,
+        })
+    }
+
+    /// Opens the data received for an upload to store it at the returned path.
+    /// Appending to the upload has to wait until the result is dropped.
+    pub async fn open(&self, id: &str) -> std::io::Result<OpenedUpload> {
+        let session = self.get(id)?.lock_owned().await;
+        let file = blocking(|| std::fs::File::open(self.directory.join(id)))?;
+        Ok(OpenedUpload { file, session })
+    }
+
+    pub fn remove(&self, id: &str) -> std::io::Result<()> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .remove(id)
+            .ok_or_else(unknown_session)?;
+        std::fs::remove_file(self.directory.join(id))
+    }
+}
@@ -22,3 +22,182 @@ pub fn hex_to_byte_array<const N: usize>(data: &str) -> Option<[u8; N]> {
 
     Some(result)
 }
+
+/// Parses a hex encoded SHA-256 checksum, in either case since it is turned into bytes
+/// anyway, explaining what is wrong with it if it's malformed.
+pub fn parse_checksum(data: &str) -> Result<[u8; 32], String> {
+    const EXPECTED: &str = "expected 64 hexadecimal characters";
+    if let Some(c) = data.chars().find(|c| !c.is_ascii_hexdigit()) {
+        return Err(format!("{EXPECTED}, found {c:?}"));
+    }
+    hex_to_byte_array(data).ok_or_else(|| format!("{EXPECTED}, got {}", data.len()))
+}
+
+/// Checks whether a content coding names gzip, accepting the `x-gzip` alias and
+/// ignoring case and surrounding whitespace as RFC 9110 asks.
+pub fn is_gzip_coding(coding: &[u8]) -> bool {
+    let coding = coding.trim_ascii();
+    coding.eq_ignore_ascii_case(b"gzip") || coding.eq_ignore_ascii_case(b"x-gzip")
+}
+
+/// Checks whether a content coding names zstd, like [`is_gzip_coding`] does for gzip.
+pub fn is_zstd_coding(coding: &[u8]) -> bool {
+    coding.trim_ascii().eq_ignore_ascii_case(b"zstd")
+}
+
+/// Parses a (possibly fractional) number of seconds, for use as a clap value parser.
+pub fn parse_seconds(value: &str) -> Result<std::time::Duration, String> {
+    value
+        .parse::<f64>()
+        .map_err(|e| e.to_string())
+        .and_then(|secs| std::time::Duration::try_from_secs_f64(secs).map_err(|e| e.to_string()))
+}
+
+fn percent_encode(data: &str, keep: impl Fn(u8) -> bool) -> String {
+    let mut result = String::with_capacity(data.len());
+    for byte in data.bytes() {
+        if keep(byte) {
+            result.push(byte as char);
+        } else {
+            result.push('%');
+            result.push_str(&bytes_to_hex(&[byte]).to_ascii_uppercase());
+        }
+    }
+    result
+}
+
+/// Percent-encodes everything outside of RFC 5987's `attr-char` set.
+pub fn percent_encode_attr(data: &str) -> String {
+    percent_encode(data, |byte| {
+        byte.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&byte)
+    })
+}
+
+/// Percent-encodes everything except unreserved characters, making the result safe
+/// to use as a query parameter value or, if `keep_slashes` is set, as a URL path.
+pub fn percent_encode_component(data: &str, keep_slashes: bool) -> String {
+    percent_encode(data, |byte| {
+        byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) || (keep_slashes && byte == b'/')
+    })
+}
+
+pub fn percent_decode(data: &str) -> Option<Vec<u8>> {
+    let mut result = Vec::with_capacity(data.len());
+    let mut it = data.bytes();
+    while let Some(byte) = it.next() {
+        if byte == b'%' {
+            let digits = [it.next()?, it.next()?];
+            result.push(hex_to_byte_array::<1>(std::str::from_utf8(&digits).ok()?)?[0]);
+        } else {
+            result.push(byte);
+        }
+    }
+    Some(result)
+}
+
+/// How hard we try to make writes durable before reporting success.
+///
+/// Syncing protects acknowledged uploads against power loss at the cost of
+/// considerably lower write throughput, especially on spinning disks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum FsyncPolicy {
+    /// Never fsync, leave flushing to the OS.
+    None,
+    /// Fsync blobs, metadata and refcounts before they are renamed into place.
+    Data,
+    /// Like `data`, but also fsync the containing directory afterwards, making the
+    /// rename itself durable.
+    #[value(name = "data+dir")]
+    DataAndDir,
+}
+
+impl FsyncPolicy {
+    pub fn sync_file(self, file: &std::fs::File) -> std::io::Result<()> {
+        match self {
+            FsyncPolicy::None => Ok(()),
+            FsyncPolicy::Data | FsyncPolicy::DataAndDir => file.sync_all(),
+        }
+    }
+
+    pub fn sync_parent(self, path: &std::path::Path) -> std::io::Result<()> {
+        match self {
+            FsyncPolicy::DataAndDir => std::fs::File::open(path.parent().unwrap())?.sync_all(),
+            FsyncPolicy::None | FsyncPolicy::Data => Ok(()),
+        }
+    }
+
+    /// Like [`std::fs::write`], but syncs according to the policy and goes through
+    /// `temp`, which is renamed over `path` once written. A crash thus leaves either
+    /// the old or the new contents behind, never a truncated file.
+    pub fn write(
+        self,
+        temp: &std::path::Path,
+        path: &std::path::Path,
+        data: impl AsRef<[u8]>,
+    ) -> std::io::Result<()> {
+        (|| {
+            let mut file = std::fs::File::create(temp)?;
+            std::io::Write::write_all(&mut file, data.as_ref())?;
+            self.sync_file(&file)?;
+            std::fs::rename(temp, path)
+        })()
+        .inspect_err(|_| _ = std::fs::remove_file(temp))?;
+        self.sync_parent(path)
+    }
+}
+
+/// Like [`std::io::copy`], but reads on a separate thread so that producing the data
+/// (usually compressing it) overlaps with writing it out.
+pub fn pipelined_copy(
+    reader: &mut (impl std::io::Read + Send),
+    writer: &mut impl std::io::Write,
+) -> std::io::Result<u64> {
+    use std::io::Read;
+
+    const CHUNK_SIZE: u64 = 256 * 1024;
+    const QUEUE_LENGTH: usize = 4;
+
+    let (sender, receiver) = std::sync::mpsc::sync_channel(QUEUE_LENGTH);
+    std::thread::scope(|scope| {
+        scope.spawn(move || loop {
+            let mut chunk = Vec::with_capacity(CHUNK_SIZE as usize);
+            match reader.by_ref().take(CHUNK_SIZE).read_to_end(&mut chunk) {
+                Ok(0) => break,
+                Ok(_) => {
+                    // The writer has failed and given up.
+                    if sender.send(Ok(chunk)).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    _ = sender.send(Err(e));
+                    break;
+                }
+            }
+        });
+
+        let mut written = 0;
+        for chunk in receiver {
+            let chunk = chunk?;
+            writer.write_all(&chunk)?;
+            written += chunk.len() as u64;
+        }
+        Ok(written)
+    })
+}
+
+/// Runs blocking filesystem work (or compression) from async code without stalling
+/// the other tasks on the same worker thread.
+///
+/// Unlike `spawn_blocking` this can borrow, which the storage layer relies on since
+/// its locks are held across the work anyway. That requires the multi-thread
+/// runtime the server runs on; on a current-thread one `f` would block the reactor
+/// (and deadlock on anything that needs it), so this panics instead. Outside of a
+/// runtime, e.g. on a plain thread, `f` simply runs.
+pub fn blocking<T>(f: impl FnOnce() -> T) -> T {
+    match tokio::runtime::Handle::try_current().map(|handle| handle.runtime_flavor()) {
+        Ok(tokio::runtime::RuntimeFlavor::MultiThread) => tokio::task::block_in_place(f),
+        Ok(flavor) => panic!("blocking work requires the multi-thread runtime, not {flavor:?}"),
+        Err(_) => f(),
+    }
+}
@@ -0,0 +1,80 @@
+mod common;
+
+use axum::{
+    body::Body,
+    http::{Request, Response},
+};
+use common::{body, TestServer};
+
+const ACCESS_FILE: &str = "\
+alpha  teamA/  rw
+alpha  teamB/  r
+beta   teamB/  rw
+";
+
+/// A server with the access file above, which has to outlive it.
+fn server() -> (TestServer, tempfile::NamedTempFile) {
+    let file = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(file.path(), ACCESS_FILE).unwrap();
+    let server = TestServer::new(&["--access-file", file.path().to_str().unwrap()]);
+    (server, file)
+}
+
+async fn send(server: &TestServer, method: &str, path: &str, token: &str) -> Response<Body> {
+    let body = match method {
+        "PUT" => Body::from(format!("written by {token}")),
+        _ => Body::empty(),
+    };
+    server
+        .send(
+            Request::builder()
+                .method(method)
+                .uri(format!("/files/{path}"))
+                .header("Authorization", format!("Bearer {token}"))
+                .body(body)
+                .unwrap(),
+        )
+        .await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn writes_are_limited_to_the_token_scope() {
+    let (server, _file) = server();
+
+    assert_eq!(
+        send(&server, "PUT", "teamA/file", "alpha").await.status(),
+        200
+    );
+    assert_eq!(
+        send(&server, "PUT", "teamB/file", "beta").await.status(),
+        200
+    );
+    for method in ["PUT", "DELETE"] {
+        let response = send(&server, method, "teamB/file", "alpha").await;
+        assert_eq!(response.status(), 403, "{method}");
+    }
+    // Paths are checked like storage normalizes them, which refuses `..`.
+    let response = send(&server, "PUT", "teamA/../teamB/file", "alpha").await;
+    assert_eq!(response.status(), 400);
+
+    let response = send(&server, "GET", "teamB/file", "beta").await;
+    assert_eq!(body(response).await, "written by beta");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reads_across_namespaces_depend_on_the_read_scope() {
+    let (server, _file) = server();
+    send(&server, "PUT", "teamA/file", "alpha").await;
+    send(&server, "PUT", "teamB/file", "beta").await;
+
+    // alpha may read teamB's files, but beta has no scope for teamA.
+    let response = send(&server, "GET", "teamB/file", "alpha").await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(body(response).await, "written by beta");
+    let response = send(&server, "GET", "teamA/file", "beta").await;
+    assert_eq!(response.status(), 403);
+
+    let response = send(&server, "GET", "teamA/file", "unknown").await;
+    assert_eq!(response.status(), 401);
+    assert_eq!(response.headers()["WWW-Authenticate"], "Bearer");
+}
@@ -0,0 +1,45 @@
+mod common;
+
+use axum::{body::Body, http::Request};
+use common::{sha256_hex, TestServer, OLD_VERSION};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn puts_are_audited_including_superseded_ones() {
+    let logs = tempfile::tempdir().unwrap();
+    let log = logs.path().join("audit.log");
+    let server = TestServer::new(&["--audit-log", log.to_str().unwrap()]);
+
+    assert_eq!(server.put("file", "new").await.status(), 200);
+    let response = server
+        .send(
+            Request::put(format!("/files/file?last_modified={OLD_VERSION}"))
+                .body(Body::from("old"))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), 200);
+    // Records are written in the background, all of them by the time it shuts down.
+    drop(server);
+
+    let records: Vec<serde_json::Value> = std::fs::read_to_string(&log)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(records.len(), 2, "{records:?}");
+
+    let stored = &records[0];
+    assert_eq!(stored["action"], "put");
+    assert_eq!(stored["path"], "file");
+    assert_eq!(stored["outcome"], "stored");
+    assert_eq!(stored["checksum"], sha256_hex(b"new"));
+    assert!(stored["timestamp"].is_string());
+    assert!(stored["version"].is_string());
+
+    let superseded = &records[1];
+    assert_eq!(superseded["action"], "put");
+    assert_eq!(superseded["path"], "file");
+    assert_eq!(superseded["outcome"], "superseded");
+    assert_eq!(superseded["version"], "2024-01-01T12:00:00Z");
+    assert!(superseded.get("checksum").is_none());
+}
@@ -0,0 +1,66 @@
+mod common;
+
+use chrono::{DateTime, Utc};
+use common::{sha256_hex, TestServer};
+
+fn date(response: &axum::http::Response<axum::body::Body>, name: &str) -> DateTime<Utc> {
+    let value = response.headers()[name].to_str().unwrap();
+    DateTime::parse_from_rfc2822(value).unwrap().to_utc()
+}
+
+fn blob_path(server: &TestServer, content: &[u8]) -> std::path::PathBuf {
+    let hex = sha256_hex(content);
+    server
+        .dir
+        .path()
+        .join("blobs/none")
+        .join(&hex[..2])
+        .join(&hex[2..])
+}
+
+async fn head(server: &TestServer, path: &str) -> axum::http::Response<axum::body::Body> {
+    server
+        .send(
+            axum::http::Request::head(format!("/files/{path}"))
+                .body(axum::body::Body::empty())
+                .unwrap(),
+        )
+        .await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn blob_creation_time_is_reported() {
+    let server = TestServer::new(&[]);
+    let before = Utc::now() - chrono::Duration::seconds(1);
+    server.put("file?compression=none", "data").await;
+
+    let response = head(&server, "file").await;
+    let created = date(&response, "Blob-Created");
+    assert!(before <= created && created <= Utc::now(), "{created}");
+    assert!(!response.headers().contains_key("Blob-Accessed"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn blob_access_time_is_updated_when_tracked() {
+    let server = TestServer::new(&["--track-blob-access"]);
+    server.put("file?compression=none", "data").await;
+
+    // Pretend the blob was last read long ago.
+    let long_ago = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1 << 30);
+    std::fs::File::options()
+        .write(true)
+        .open(blob_path(&server, b"data"))
+        .unwrap()
+        .set_times(std::fs::FileTimes::new().set_accessed(long_ago))
+        .unwrap();
+    let response = head(&server, "file").await;
+    assert_eq!(
+        date(&response, "Blob-Accessed"),
+        DateTime::<Utc>::from(long_ago)
+    );
+    let created = date(&response, "Blob-Created");
+
+    assert_eq!(server.get("/files/file").await.status(), 200);
+    let accessed = date(&head(&server, "file").await, "Blob-Accessed");
+    assert!(accessed >= created, "{accessed} < {created}");
+}
@@ -0,0 +1,65 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use chrono::{DateTime, Utc};
+use common::TestServer;
+use filetracker_rs::{
+    client::{ClientError, FiletrackerClient},
+    storage::Compression,
+};
+
+fn version(timestamp: i64) -> DateTime<Utc> {
+    DateTime::from_timestamp(timestamp, 0).unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn files_round_trip_through_the_client() {
+    let server = TestServer::new(&[]);
+    let client = FiletrackerClient::new(format!("http://{}/", server.listen().await));
+    let content = "client contents\n".repeat(100).into_bytes();
+
+    let stored = client
+        .put("dir/file", &content, Some(version(1_700_000_000)))
+        .await
+        .unwrap();
+    assert_eq!(stored, version(1_700_000_000));
+
+    let (metadata, received) = client.get("dir/file").await.unwrap();
+    assert_eq!(received, content);
+    assert_eq!(metadata.version, stored);
+    assert_eq!(metadata.decompressed_size, content.len());
+    assert_eq!(metadata.compression, Compression::Gzip);
+    assert_eq!(
+        filetracker_rs::util::bytes_to_hex(&metadata.checksum),
+        common::sha256_hex(&content)
+    );
+    let head = client.head("dir/file").await.unwrap();
+    assert_eq!(head.checksum, metadata.checksum);
+    assert_eq!(head.decompressed_size, metadata.decompressed_size);
+
+    client.put("dir/other", b"", None).await.unwrap();
+    let mut entries = client.list("dir", None).await.unwrap();
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    let paths: Vec<_> = entries.iter().map(|entry| entry.path.as_str()).collect();
+    assert_eq!(paths, ["file", "other"]);
+    assert_eq!(entries[0].version, stored);
+    assert_eq!(entries[0].decompressed_size, content.len());
+    assert_eq!(entries[1].decompressed_size, 0);
+
+    client.delete("dir/file", None).await.unwrap();
+    match client.get("dir/file").await {
+        Err(ClientError::Status(status, _)) => assert_eq!(status, 404),
+        other => panic!("expected a 404, got {other:?}"),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn paths_are_encoded_by_the_client() {
+    let server = TestServer::new(&[]);
+    let client = FiletrackerClient::new(format!("http://{}", server.listen().await));
+
+    let path = "with spaces/and?query#hash%.txt";
+    client.put(path, b"data", None).await.unwrap();
+    assert_eq!(client.get(path).await.unwrap().1, b"data");
+}
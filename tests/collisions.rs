@@ -0,0 +1,72 @@
+mod common;
+
+use common::{body, TestServer};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn files_cannot_be_stored_under_files() {
+    let server = TestServer::new(&[]);
+    server.put("foo/bar", "data").await;
+
+    let response = server.put("foo/bar/baz", "data").await;
+    assert_eq!(response.status(), 409);
+    assert_eq!(
+        body(response).await,
+        "foo/bar/baz: a file exists where a directory is needed"
+    );
+    assert_eq!(server.get("/files/foo/bar/baz").await.status(), 409);
+    assert_eq!(server.get("/list/foo/bar").await.status(), 409);
+    assert_eq!(body(server.get("/files/foo/bar").await).await, "data");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn files_cannot_replace_directories() {
+    let server = TestServer::new(&[]);
+    server.put("foo/bar/baz", "data").await;
+
+    let response = server.put("foo/bar", "data").await;
+    assert_eq!(response.status(), 409);
+    assert_eq!(
+        body(response).await,
+        "foo/bar: a directory exists where a file is needed"
+    );
+    assert_eq!(body(server.get("/files/foo/bar/baz").await).await, "data");
+}
+
+async fn request(
+    server: &TestServer,
+    method: &str,
+    uri: &str,
+) -> axum::http::Response<axum::body::Body> {
+    server
+        .send(
+            axum::http::Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(axum::body::Body::empty())
+                .unwrap(),
+        )
+        .await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reading_a_directory_points_to_its_listing() {
+    let server = TestServer::new(&[]);
+    server.put("dir/file", "data").await;
+
+    let response = request(&server, "GET", "/files/dir").await;
+    assert_eq!(response.status(), 404);
+    assert_eq!(
+        body(response).await,
+        "dir is a directory, its contents are listed at /list/dir"
+    );
+    let response = request(&server, "HEAD", "/files/dir").await;
+    assert_eq!(response.status(), 404);
+
+    let server = TestServer::new(&["--directory-response", "redirect"]);
+    server.put("dir/file", "data").await;
+    for method in ["GET", "HEAD"] {
+        let response = request(&server, method, "/files/dir").await;
+        assert_eq!(response.status(), 307, "{method}");
+        assert_eq!(response.headers()["Location"], "/list/dir", "{method}");
+    }
+}
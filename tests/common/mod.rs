@@ -0,0 +1,154 @@
+//! Runs the server in-process on a temporary data directory, with requests sent
+//! straight to the router.
+
+// Each test binary uses a different subset of these.
+#![allow(dead_code)]
+
+use axum::{
+    body::{Body, Bytes},
+    http::{Request, Response},
+    Router,
+};
+use clap::Parser;
+use http_body_util::BodyExt;
+use tower::ServiceExt;
+
+use filetracker_rs::{access, audit, capacity, server, spool, storage, uploads};
+
+/// The options of the server that apply to a running store, with the same names.
+#[derive(Parser)]
+struct TestOpts {
+    #[clap(flatten)]
+    storage: storage::StorageConfig,
+    #[clap(flatten)]
+    uploads: uploads::UploadConfig,
+    #[clap(flatten)]
+    http: server::HttpConfig,
+    #[clap(flatten)]
+    access: access::AccessConfig,
+    #[clap(flatten)]
+    audit: audit::AuditConfig,
+    #[clap(flatten)]
+    spool: spool::SpoolConfig,
+    #[clap(flatten)]
+    capacity: capacity::CapacityConfig,
+    #[clap(flatten)]
+    serve: server::ServeConfig,
+}
+
+/// Opens the store in `dir` like the server would with the given command line options.
+pub fn open_state(
+    dir: &std::path::Path,
+    args: &[&str],
+) -> std::io::Result<(server::AppState, server::ServeConfig)> {
+    let opts = TestOpts::parse_from(std::iter::once("filetracker").chain(args.iter().copied()));
+    let state = server::AppState::new(
+        dir,
+        opts.storage,
+        &opts.uploads,
+        opts.http,
+        &opts.access,
+        &opts.audit,
+        &opts.spool,
+        &opts.capacity,
+    )?;
+    Ok((state, opts.serve))
+}
+
+/// A version that is older than any file written with the current time.
+pub const OLD_VERSION: &str = "Mon,%2001%20Jan%202024%2012:00:00%20%2B0000";
+
+pub struct TestServer {
+    pub state: server::AppState,
+    pub app: Router,
+    serve: server::ServeConfig,
+    // Dropped last, once nothing uses the store anymore.
+    pub dir: tempfile::TempDir,
+}
+
+impl TestServer {
+    /// Starts a server with the given command line options.
+    pub fn new(args: &[&str]) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        Self::open(dir, args)
+    }
+
+    /// Starts a server on an existing data directory.
+    pub fn open(dir: tempfile::TempDir, args: &[&str]) -> Self {
+        let (state, serve) = open_state(dir.path(), args).unwrap();
+        let app = server::build_app(state.clone());
+        Self {
+            state,
+            app,
+            serve,
+            dir,
+        }
+    }
+
+    /// Stops the server and starts a new one on the same data directory.
+    pub fn restart(self, args: &[&str]) -> Self {
+        let Self {
+            state, app, dir, ..
+        } = self;
+        drop((state, app));
+        Self::open(dir, args)
+    }
+
+    /// Serves the app on a local port, for tests that need a real connection.
+    pub async fn listen(&self) -> std::net::SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let app = self.app.clone();
+        let serve = self.serve.clone();
+        tokio::spawn(async move { server::serve(listener, app, &serve).await });
+        address
+    }
+
+    pub async fn send(&self, request: Request<Body>) -> Response<Body> {
+        self.app.clone().oneshot(request).await.unwrap()
+    }
+
+    pub async fn get(&self, uri: &str) -> Response<Body> {
+        self.send(Request::get(uri).body(Body::empty()).unwrap())
+            .await
+    }
+
+    /// Uploads `data` as the file at `path`, versioned with the current time.
+    pub async fn put(&self, path: &str, data: impl Into<Bytes>) -> Response<Body> {
+        self.send(
+            Request::put(format!("/files/{path}"))
+                .body(Body::from(data.into()))
+                .unwrap(),
+        )
+        .await
+    }
+}
+
+pub async fn body(response: Response<Body>) -> Bytes {
+    response.into_body().collect().await.unwrap().to_bytes()
+}
+
+pub async fn json(response: Response<Body>) -> serde_json::Value {
+    serde_json::from_slice(&body(response).await).unwrap()
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    use sha2::Digest;
+    filetracker_rs::util::bytes_to_hex(&sha2::Sha256::digest(data))
+}
+
+pub fn gzip(data: &[u8]) -> Vec<u8> {
+    use std::io::Write;
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+pub fn gunzip(data: &[u8]) -> Vec<u8> {
+    use std::io::Read;
+    let mut result = Vec::new();
+    flate2::read::GzDecoder::new(data)
+        .read_to_end(&mut result)
+        .unwrap();
+    result
+}
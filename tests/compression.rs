@@ -0,0 +1,236 @@
+mod common;
+
+use axum::{body::Body, http::Request};
+use common::{body, gzip, json, sha256_hex, TestServer};
+
+fn compressible() -> Vec<u8> {
+    "compressible contents\n".repeat(1000).into_bytes()
+}
+
+/// Bytes that gzip can't make any smaller.
+fn incompressible() -> Vec<u8> {
+    let mut state = 0x2545f4914f6cdd1du64;
+    (0..16 * 1024)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+async fn stored_compression(server: &TestServer, path: &str) -> String {
+    let metadata = json(server.get(&format!("/meta/{path}")).await).await;
+    metadata["compression"].as_str().unwrap().to_string()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn uploads_can_override_the_compression() {
+    let server = TestServer::new(&[]);
+
+    server.put("default", compressible()).await;
+    assert_eq!(stored_compression(&server, "default").await, "Gzip");
+    server.put("none?compression=none", compressible()).await;
+    assert_eq!(stored_compression(&server, "none").await, "None");
+
+    server.put("incompressible", incompressible()).await;
+    assert_eq!(stored_compression(&server, "incompressible").await, "None");
+    server.put("gzip?compression=gzip", incompressible()).await;
+    assert_eq!(stored_compression(&server, "gzip").await, "Gzip");
+
+    for (path, content) in [("none", compressible()), ("gzip", incompressible())] {
+        let response = server.get(&format!("/files/{path}")).await;
+        assert!(!response.headers().contains_key("Content-Encoding"));
+        assert_eq!(body(response).await, content, "{path}");
+    }
+
+    let response = server.put("bogus?compression=lzma", "data").await;
+    assert_eq!(response.status(), 400);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn gzipped_uploads_stored_uncompressed_are_decompressed() {
+    let server = TestServer::new(&[]);
+    let response = server
+        .send(
+            Request::put("/files/raw?compression=none")
+                .header("Content-Encoding", "gzip")
+                .body(Body::from(gzip(&compressible())))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(stored_compression(&server, "raw").await, "None");
+
+    let response = server
+        .send(
+            Request::get("/files/raw")
+                .header("Accept-Encoding", "gzip")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert!(!response.headers().contains_key("Content-Encoding"));
+    assert_eq!(
+        response.headers()["SHA256-Checksum"],
+        sha256_hex(&compressible())
+    );
+    assert_eq!(body(response).await, compressible());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn pipelined_blob_writes_match_serial_compression() {
+    use std::io::Read;
+
+    // Several of the pipeline's chunks.
+    let content = compressible().repeat(64);
+    let server = TestServer::new(&[]);
+    let response = server.put("large?compression=gzip", content.clone()).await;
+    assert_eq!(response.status(), 200);
+
+    let mut expected = Vec::new();
+    flate2::read::GzEncoder::new(&content[..], flate2::Compression::new(9))
+        .read_to_end(&mut expected)
+        .unwrap();
+    let hex = sha256_hex(&content);
+    let blob = server
+        .dir
+        .path()
+        .join("blobs")
+        .join(&hex[..2])
+        .join(&hex[2..]);
+    assert_eq!(std::fs::read(blob).unwrap(), expected);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn gzip_content_encodings_are_normalized() {
+    let server = TestServer::new(&[]);
+    for (i, encoding) in ["gzip", "x-gzip", "GZIP", " X-Gzip "]
+        .into_iter()
+        .enumerate()
+    {
+        let response = server
+            .send(
+                Request::put(format!("/files/{i}"))
+                    .header("Content-Encoding", encoding)
+                    .body(Body::from(gzip(&compressible())))
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(response.status(), 200, "{encoding:?}");
+        assert_eq!(
+            body(server.get(&format!("/files/{i}")).await).await,
+            compressible()
+        );
+    }
+
+    for encoding in ["br", "deflate", "gzip, br"] {
+        let response = server
+            .send(
+                Request::put("/files/unsupported")
+                    .header("Content-Encoding", encoding)
+                    .body(Body::from("data"))
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(response.status(), 400, "{encoding:?}");
+    }
+    assert_eq!(server.get("/files/unsupported").await.status(), 404);
+
+    // Accept-Encoding is matched the same way.
+    for accept_encoding in ["x-gzip", "GZIP", "br, x-gzip;q=0.5"] {
+        let response = server
+            .send(
+                Request::get("/files/0")
+                    .header("Accept-Encoding", accept_encoding)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(
+            response.headers()["Content-Encoding"],
+            "gzip",
+            "{accept_encoding:?}"
+        );
+    }
+    let response = server
+        .send(
+            Request::get("/files/0")
+                .header("Accept-Encoding", "br")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert!(!response.headers().contains_key("Content-Encoding"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn min_ratio_decides_whether_to_store_compressed() {
+    // Half of it compresses well and half not at all.
+    let mut content = compressible()[..16 * 1024].to_vec();
+    content.extend(incompressible());
+    let ratio = gzip(&content).len() as f64 / content.len() as f64;
+    assert!((0.4..0.7).contains(&ratio), "{ratio}");
+
+    for (min_ratio, expected) in [("0.9", "Gzip"), ("0.3", "None")] {
+        let server = TestServer::new(&["--blob-compression-min-ratio", min_ratio]);
+        server.put("file", content.clone()).await;
+        assert_eq!(
+            stored_compression(&server, "file").await,
+            expected,
+            "{min_ratio}"
+        );
+        assert_eq!(body(server.get("/files/file").await).await, content);
+    }
+
+    // Inputs below the size floor aren't even tried.
+    let server = TestServer::new(&[
+        "--blob-compression-min-ratio",
+        "1",
+        "--blob-compression-min-size",
+        &(content.len() + 1).to_string(),
+    ]);
+    server.put("file", content.clone()).await;
+    assert_eq!(stored_compression(&server, "file").await, "None");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn no_compression_stores_uploads_raw() {
+    let server = TestServer::new(&["--no-compression"]);
+    server.put("identity", compressible()).await;
+    let response = server
+        .send(
+            Request::put("/files/gzipped")
+                .header("Content-Encoding", "gzip")
+                .body(Body::from(gzip(&compressible())))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), 200);
+
+    let hex = sha256_hex(&compressible());
+    let blob = server
+        .dir
+        .path()
+        .join("blobs/none")
+        .join(&hex[..2])
+        .join(&hex[2..]);
+    assert_eq!(std::fs::read(blob).unwrap(), compressible());
+    for path in ["identity", "gzipped"] {
+        assert_eq!(stored_compression(&server, path).await, "None");
+        let response = server
+            .send(
+                Request::get(format!("/files/{path}"))
+                    .header("Accept-Encoding", "gzip")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await;
+        assert!(
+            !response.headers().contains_key("Content-Encoding"),
+            "{path}"
+        );
+        assert_eq!(body(response).await, compressible(), "{path}");
+    }
+}
@@ -0,0 +1,117 @@
+mod common;
+
+use std::sync::atomic::Ordering;
+
+use axum::{body::Body, http::Request};
+use common::{body, sha256_hex, TestServer};
+use tower::ServiceExt;
+
+fn compressible() -> String {
+    "the same artifact uploaded by every shard\n".repeat(10_000)
+}
+
+/// Uploads from separate tasks, so that they really run at the same time.
+async fn upload_concurrently(server: &TestServer, data: &str, uploads: usize) {
+    let tasks: Vec<_> = (0..uploads)
+        .map(|i| {
+            let app = server.app.clone();
+            let request = Request::put(format!("/files/shard/{i}"))
+                .body(Body::from(data.to_string()))
+                .unwrap();
+            tokio::spawn(app.oneshot(request))
+        })
+        .collect();
+    for task in tasks {
+        assert_eq!(task.await.unwrap().unwrap().status(), 200);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn concurrent_identical_uploads_are_compressed_once() {
+    let server = TestServer::new(&[]);
+    let data = compressible();
+    upload_concurrently(&server, &data, 16).await;
+
+    let counters = server.state.storage.blob_counters();
+    assert_eq!(counters.compressions.load(Ordering::Relaxed), 1);
+    assert_eq!(counters.created.load(Ordering::Relaxed), 1);
+    assert_eq!(counters.deduplicated.load(Ordering::Relaxed), 15);
+    for i in 0..16 {
+        let response = server.get(&format!("/files/shard/{i}")).await;
+        assert_eq!(body(response).await, data);
+    }
+
+    // Later uploads reference the recently written blob directly.
+    server.put("late", data.clone()).await;
+    assert_eq!(counters.compressions.load(Ordering::Relaxed), 1);
+    assert_eq!(counters.deduplicated.load(Ordering::Relaxed), 16);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn uploads_are_compressed_once_without_the_recent_set() {
+    let server = TestServer::new(&["--recent-blobs", "0"]);
+    upload_concurrently(&server, &compressible(), 16).await;
+
+    let counters = server.state.storage.blob_counters();
+    assert_eq!(counters.compressions.load(Ordering::Relaxed), 1);
+    assert_eq!(counters.created.load(Ordering::Relaxed), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn removed_blobs_are_written_again() {
+    let server = TestServer::new(&[]);
+    let data = compressible();
+    server.put("file", data.clone()).await;
+    server
+        .send(Request::delete("/files/file").body(Body::empty()).unwrap())
+        .await;
+
+    // The blob is gone, so it can't be referenced even though it was just written.
+    server.put("file", data.clone()).await;
+    let counters = server.state.storage.blob_counters();
+    assert_eq!(counters.created.load(Ordering::Relaxed), 2);
+    assert_eq!(body(server.get("/files/file").await).await, data);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn concurrent_unique_blobs_are_all_stored() {
+    const UPLOADS: usize = 256;
+    let server = TestServer::new(&[]);
+    let content = |i: usize| format!("unique contents {i}\n").repeat(50);
+
+    let uploads: Vec<_> = (0..UPLOADS)
+        .map(|i| {
+            let request = Request::put(format!("/files/unique/{i}"))
+                .body(Body::from(content(i)))
+                .unwrap();
+            tokio::spawn(server.app.clone().oneshot(request))
+        })
+        .collect();
+    for upload in uploads {
+        assert_eq!(upload.await.unwrap().unwrap().status(), 200);
+    }
+
+    for i in 0..UPLOADS {
+        let response = server.get(&format!("/files/unique/{i}")).await;
+        assert_eq!(
+            response.headers()["SHA256-Checksum"],
+            sha256_hex(content(i).as_bytes())
+        );
+        assert_eq!(body(response).await, content(i));
+    }
+    let counters = server.state.storage.blob_counters();
+    assert_eq!(counters.created.load(Ordering::Relaxed), UPLOADS as u64);
+    assert_eq!(counters.deduplicated.load(Ordering::Relaxed), 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn uploads_report_whether_they_were_deduplicated() {
+    let server = TestServer::new(&[]);
+    let first = server.put("a", compressible()).await;
+    assert_eq!(first.headers()["X-Deduplicated"], "false");
+    let second = server.put("b", compressible()).await;
+    assert_eq!(second.headers()["X-Deduplicated"], "true");
+
+    let other = server.put("c", "other contents").await;
+    assert_eq!(other.headers()["X-Deduplicated"], "false");
+}
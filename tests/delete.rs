@@ -0,0 +1,118 @@
+mod common;
+
+use axum::{body::Body, http::Request};
+use common::TestServer;
+
+/// A version far enough in the future to be newer than the time of any request.
+const FUTURE_VERSION: &str = "Fri,%2001%20Jan%202100%2012:00:00%20%2B0000";
+
+async fn delete(server: &TestServer, uri: &str) -> axum::http::Response<Body> {
+    server
+        .send(Request::delete(uri).body(Body::empty()).unwrap())
+        .await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn keeping_a_newer_file_is_observable() {
+    let server = TestServer::new(&[]);
+    server
+        .put(&format!("future?last_modified={FUTURE_VERSION}"), "data")
+        .await;
+
+    let response = delete(&server, "/files/future").await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["X-Deleted"], "false");
+    assert_eq!(
+        response.headers()["Last-Modified"],
+        "Fri, 1 Jan 2100 12:00:00 +0000"
+    );
+    assert_eq!(server.get("/files/future").await.status(), 200);
+
+    server.put("present", "data").await;
+    let response = delete(&server, "/files/present").await;
+    assert_eq!(response.headers()["X-Deleted"], "true");
+    assert_eq!(server.get("/files/present").await.status(), 404);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn force_deletes_future_dated_files() {
+    let server = TestServer::new(&["--require-version"]);
+    server
+        .put(&format!("future?last_modified={FUTURE_VERSION}"), "data")
+        .await;
+
+    // Doesn't need a version even where one is otherwise required.
+    let response = delete(&server, "/files/future?force=true").await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["X-Deleted"], "true");
+    assert_eq!(server.get("/files/future").await.status(), 404);
+
+    assert_eq!(
+        delete(&server, "/files/future?force=true").await.status(),
+        404
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn batch_deletes_report_every_path() {
+    let server = TestServer::new(&[]);
+    server.put("present", "data").await;
+    server
+        .put(&format!("future?last_modified={FUTURE_VERSION}"), "data")
+        .await;
+    server.put("older", "data").await;
+
+    let paths = serde_json::json!([
+        "present",
+        "missing",
+        "future",
+        {"path": "older", "last_modified": "Mon, 01 Jan 2024 12:00:00 +0000"},
+        "../escape",
+    ]);
+    let response = server
+        .send(
+            Request::post("/batch-delete")
+                .header("Content-Type", "application/json")
+                .body(Body::from(paths.to_string()))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), 200);
+    let entries = common::json(response).await;
+    let statuses: Vec<_> = entries
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| {
+            (
+                entry["path"].as_str().unwrap(),
+                entry["status"].as_str().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        statuses,
+        [
+            ("present", "deleted"),
+            ("missing", "not_found"),
+            ("future", "superseded"),
+            ("older", "superseded"),
+            ("../escape", "error"),
+        ]
+    );
+    assert_eq!(entries[2]["version"], "Fri, 1 Jan 2100 12:00:00 +0000");
+    assert!(entries[4]["error"].is_string());
+
+    assert_eq!(server.get("/files/present").await.status(), 404);
+    assert_eq!(server.get("/files/future").await.status(), 200);
+    assert_eq!(server.get("/files/older").await.status(), 200);
+
+    let response = server
+        .send(
+            Request::post("/batch-delete")
+                .body(Body::from("not json"))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), 400);
+}
@@ -0,0 +1,57 @@
+mod common;
+
+use axum::{body::Body, http::Request};
+use common::TestServer;
+
+const CONTENT: &str = "The quick brown fox jumps over the lazy dog";
+const MD5: &str = "nhB9nTcrtoJr2B01QqQZ1g==";
+const CRC32: &str = "414fa339";
+
+#[tokio::test(flavor = "multi_thread")]
+async fn extra_digests_match_reference_values() {
+    let server = TestServer::new(&["--extra-digests", "md5,crc32"]);
+    assert_eq!(server.put("file", CONTENT).await.status(), 200);
+
+    for method in ["GET", "HEAD"] {
+        let response = server
+            .send(
+                Request::builder()
+                    .method(method)
+                    .uri("/files/file")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(response.headers()["Content-MD5"], MD5, "{method}");
+        assert_eq!(response.headers()["X-CRC32"], CRC32, "{method}");
+    }
+
+    // They are kept for gzipped uploads too, computed over the decompressed contents.
+    let response = server
+        .send(
+            Request::put("/files/gzipped")
+                .header("Content-Encoding", "gzip")
+                .body(Body::from(common::gzip(CONTENT.as_bytes())))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), 200);
+    let response = server.get("/files/gzipped").await;
+    assert_eq!(response.headers()["Content-MD5"], MD5);
+    assert_eq!(response.headers()["X-CRC32"], CRC32);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn extra_digests_are_only_computed_when_configured() {
+    let server = TestServer::new(&["--extra-digests", "crc32"]);
+    server.put("file", CONTENT).await;
+    let response = server.get("/files/file").await;
+    assert!(!response.headers().contains_key("Content-MD5"));
+    assert_eq!(response.headers()["X-CRC32"], CRC32);
+
+    let server = TestServer::new(&[]);
+    server.put("file", CONTENT).await;
+    let response = server.get("/files/file").await;
+    assert!(!response.headers().contains_key("Content-MD5"));
+    assert!(!response.headers().contains_key("X-CRC32"));
+}
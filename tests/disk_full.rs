@@ -0,0 +1,88 @@
+//! Simulates a full disk by planting links to `/dev/full`, on which every write
+//! fails with ENOSPC, where the server is going to write its temporary files.
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use std::path::{Path, PathBuf};
+
+use common::{body, sha256_hex, TestServer};
+
+/// Temporary files anywhere in the data directory.
+fn leftovers(dir: &Path) -> Vec<PathBuf> {
+    let mut result = Vec::new();
+    let mut stack = vec![dir.to_owned()];
+    while let Some(dir) = stack.pop() {
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.symlink_metadata().unwrap().is_dir() {
+                stack.push(path);
+            } else if path.extension().is_some_and(|extension| extension == "tmp")
+                || path.parent().unwrap().ends_with("tmp")
+            {
+                result.push(path);
+            }
+        }
+    }
+    result
+}
+
+fn plant_full_disk(path: &Path) {
+    std::os::unix::fs::symlink("/dev/full", path).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_full_disk_while_writing_a_blob_is_reported() {
+    let server = TestServer::new(&[]);
+    let hex = sha256_hex(b"data");
+    let blob = server
+        .dir
+        .path()
+        .join("blobs/none")
+        .join(&hex[..2])
+        .join(&hex[2..]);
+    plant_full_disk(&blob.with_extension("tmp"));
+
+    let response = server.put("file?compression=none", "data").await;
+    assert_eq!(response.status(), 507);
+    assert!(leftovers(server.dir.path()).is_empty());
+    assert!(!blob.exists());
+    assert_eq!(server.get("/files/file").await.status(), 404);
+
+    // Once there is space again, writing works as usual.
+    let response = server.put("file?compression=none", "data").await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(body(server.get("/files/file").await).await, "data");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_full_disk_while_writing_metadata_is_reported() {
+    let server = TestServer::new(&[]);
+    // The first temporary metadata file of the server.
+    let temp = server
+        .dir
+        .path()
+        .join("tmp")
+        .join(format!("{}-0", std::process::id()));
+    plant_full_disk(&temp);
+
+    let response = server.put("file", "data").await;
+    assert_eq!(response.status(), 507);
+    assert!(leftovers(server.dir.path()).is_empty());
+    assert_eq!(server.get("/files/file").await.status(), 404);
+
+    let response = server.put("file", "data").await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(body(server.get("/files/file").await).await, "data");
+
+    // The blob written before the failure isn't referenced twice.
+    let hex = sha256_hex(b"data");
+    let count = server
+        .dir
+        .path()
+        .join("blobs/none")
+        .join(&hex[..2])
+        .join(&hex[2..])
+        .with_extension("count");
+    assert_eq!(std::fs::read_to_string(count).unwrap(), "1");
+}
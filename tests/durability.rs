@@ -0,0 +1,83 @@
+mod common;
+
+use axum::{body::Body, http::Request};
+use common::{body, TestServer};
+
+/// Files left behind in the data directory by unfinished writes.
+fn leftovers(dir: &std::path::Path) -> Vec<std::path::PathBuf> {
+    let mut result = Vec::new();
+    let mut stack = vec![dir.to_owned()];
+    while let Some(dir) = stack.pop() {
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                stack.push(path);
+            } else if path.extension().is_some_and(|extension| extension == "tmp") {
+                result.push(path);
+            }
+        }
+    }
+    result
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn every_fsync_policy_stores_files() {
+    for policy in ["none", "data", "data+dir"] {
+        let server = TestServer::new(&["--fsync", policy]);
+        for compression in ["none", "gzip"] {
+            let path = format!("{compression}/file");
+            let response = server
+                .send(
+                    Request::put(format!("/files/{path}?compression={compression}"))
+                        .body(Body::from("durable contents"))
+                        .unwrap(),
+                )
+                .await;
+            assert_eq!(response.status(), 200, "{policy}");
+            // Written a second time to go through the refcount update as well.
+            server
+                .put(&format!("{path}.copy"), "durable contents")
+                .await;
+        }
+
+        // Everything is where it belongs once the server is gone.
+        let server = server.restart(&["--fsync", policy]);
+        for compression in ["none", "gzip"] {
+            let response = server.get(&format!("/files/{compression}/file")).await;
+            assert_eq!(body(response).await, "durable contents", "{policy}");
+        }
+        assert_eq!(
+            leftovers(server.dir.path()),
+            Vec::<std::path::PathBuf>::new()
+        );
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn compressed_and_plain_metadata_can_be_mixed() {
+    let server = TestServer::new(&[]);
+    server.put("plain", "legacy").await;
+    let mut server = server.restart(&["--metadata-compression", "gzip"]);
+    server.put("compressed", "new").await;
+
+    let metadata = server.dir.path().join("metadata");
+    let plain = std::fs::read(metadata.join("plain")).unwrap();
+    assert_eq!(plain.first(), Some(&b'{'));
+    let compressed = std::fs::read(metadata.join("compressed")).unwrap();
+    assert!(compressed.starts_with(&[0x1f, 0x8b]));
+    let json: serde_json::Value = serde_json::from_slice(&common::gunzip(&compressed)).unwrap();
+    assert_eq!(json["decompressed_size"], 3);
+
+    // Both are readable whichever way new metadata is written.
+    for args in [&["--metadata-compression", "gzip"][..], &[]] {
+        server = server.restart(args);
+        for (path, content) in [("plain", "legacy"), ("compressed", "new")] {
+            assert_eq!(
+                body(server.get(&format!("/files/{path}")).await).await,
+                content
+            );
+        }
+        let listing = body(server.get("/list/").await).await;
+        assert_eq!(listing.split(|&c| c == b'\n').count(), 2 * 3 + 1);
+    }
+}
@@ -0,0 +1,60 @@
+mod common;
+
+use axum::{body::Body, http::Request};
+use common::{body, json, sha256_hex, TestServer};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn errors_can_be_problem_details() {
+    let server = TestServer::new(&["--error-format", "problem"]);
+
+    let response = server.get("/files/missing").await;
+    assert_eq!(response.status(), 404);
+    assert_eq!(
+        response.headers()["Content-Type"],
+        "application/problem+json"
+    );
+    let problem = json(response).await;
+    assert_eq!(problem["type"], "about:blank");
+    assert_eq!(problem["title"], "Not Found");
+    assert_eq!(problem["status"], 404);
+    assert!(!problem["detail"].as_str().unwrap().is_empty());
+
+    let response = server
+        .send(
+            Request::put("/files/file")
+                .header("SHA256-Checksum", sha256_hex(b"other"))
+                .body(Body::from("data"))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), 422);
+    let problem = json(response).await;
+    assert_eq!(problem["title"], "Unprocessable Entity");
+    assert_eq!(problem["status"], 422);
+    assert!(problem["detail"].is_string());
+
+    let response = server
+        .send(
+            Request::put("/files/file")
+                .header("SHA256-Checksum", "not hex")
+                .body(Body::from("data"))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), 400);
+    assert_eq!(json(response).await["status"], 400);
+
+    // Successful responses are left alone.
+    let response = server.put("file", "data").await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(body(server.get("/files/file").await).await, "data");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn errors_are_plain_text_by_default() {
+    let server = TestServer::new(&[]);
+    let response = server.get("/files/missing").await;
+    assert_eq!(response.status(), 404);
+    assert!(response.headers().get("Content-Type").is_none());
+    assert!(!body(response).await.starts_with(b"{"));
+}
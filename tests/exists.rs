@@ -0,0 +1,69 @@
+mod common;
+
+use axum::{body::Body, http::Request};
+use common::{json, sha256_hex, TestServer};
+
+async fn exists(server: &TestServer, body: impl Into<Body>) -> serde_json::Value {
+    let response = server
+        .send(Request::post("/exists").body(body.into()).unwrap())
+        .await;
+    assert_eq!(response.status(), 200);
+    json(response).await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn exists_reports_present_and_missing_paths() {
+    let server = TestServer::new(&[]);
+    server.put("a/present", "data").await;
+    server.put("b/also", "other").await;
+
+    let expected = |entries: serde_json::Value| {
+        let entries = entries.as_array().unwrap().clone();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0]["path"], "a/present");
+        assert_eq!(entries[0]["exists"], true);
+        assert_eq!(entries[0]["checksum"], sha256_hex(b"data"));
+        assert!(entries[0]["version"].is_string());
+        assert_eq!(entries[1]["path"], "missing");
+        assert_eq!(entries[1]["exists"], false);
+        assert!(entries[1].get("checksum").is_none());
+        // A directory isn't a file.
+        assert_eq!(entries[2]["path"], "a");
+        assert_eq!(entries[2]["exists"], false);
+        assert_eq!(entries[3]["path"], "b/also");
+        assert_eq!(entries[3]["checksum"], sha256_hex(b"other"));
+    };
+
+    expected(exists(&server, r#"["a/present", "missing", "a", "b/also"]"#).await);
+    expected(exists(&server, "a/present\nmissing\na\nb/also\n").await);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn exists_rejects_invalid_lists() {
+    let server = TestServer::new(&[]);
+    let response = server
+        .send(Request::post("/exists").body(Body::from("[1, 2")).unwrap())
+        .await;
+    assert_eq!(response.status(), 400);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn exists_answers_large_path_lists() {
+    let server = TestServer::new(&[]);
+    server.put("present", "data").await;
+
+    // Far more paths than are looked up at once, in order.
+    let paths: Vec<String> = (0..200)
+        .map(|i| match i % 50 {
+            0 => "present".to_string(),
+            _ => format!("missing/{i}"),
+        })
+        .collect();
+    let entries = exists(&server, serde_json::to_vec(&paths).unwrap()).await;
+    let entries = entries.as_array().unwrap();
+    assert_eq!(entries.len(), paths.len());
+    for (entry, path) in entries.iter().zip(&paths) {
+        assert_eq!(entry["path"], *path);
+        assert_eq!(entry["exists"], path == "present");
+    }
+}
@@ -0,0 +1,44 @@
+mod common;
+
+use axum::http::{Request, Version};
+use common::TestServer;
+use http_body_util::{BodyExt, Empty};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+
+/// Sends a GET over cleartext HTTP/2 with prior knowledge.
+async fn h2_get(
+    address: std::net::SocketAddr,
+    path: &str,
+) -> hyper::Result<hyper::Response<hyper::body::Incoming>> {
+    let stream = tokio::net::TcpStream::connect(address).await.unwrap();
+    let (mut sender, connection) =
+        hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream)).await?;
+    tokio::spawn(connection);
+    sender
+        .send_request(
+            Request::get(format!("http://{address}{path}"))
+                .body(Empty::<bytes::Bytes>::new())
+                .unwrap(),
+        )
+        .await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn version_is_served_over_h2c() {
+    let server = TestServer::new(&["--http2", "--http2-max-concurrent-streams", "8"]);
+    let address = server.listen().await;
+
+    let response = h2_get(address, "/version").await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.version(), Version::HTTP_2);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let info: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(info["protocol_versions"], serde_json::json!([2]));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn h2c_is_refused_without_the_flag() {
+    let server = TestServer::new(&[]);
+    let address = server.listen().await;
+    assert!(h2_get(address, "/version").await.is_err());
+}
@@ -0,0 +1,62 @@
+//! Watches the directories the walk of a listing keeps open through `/proc`.
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use chrono::TimeZone;
+use common::TestServer;
+use filetracker_rs::storage::ListOptions;
+
+/// Number of file descriptors of this process open on something under `dir`.
+fn open_under(dir: &Path) -> usize {
+    std::fs::read_dir("/proc/self/fd")
+        .unwrap()
+        .filter_map(|entry| std::fs::read_link(entry.ok()?.path()).ok())
+        .filter(|target| target.starts_with(dir))
+        .count()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dropped_listings_stop_walking() {
+    let server = TestServer::new(&[]);
+    assert_eq!(server.put("tree/seed", "data").await.status(), 200);
+
+    // A tree in which every file is too new to be listed, so that the walk yields
+    // nothing and only notices the listing was dropped by checking for it.
+    let tree = server.dir.path().join("metadata/tree");
+    for i in 0..100 {
+        let dir = tree.join(format!("d{i}"));
+        std::fs::create_dir(&dir).unwrap();
+        for j in 0..50 {
+            std::fs::copy(tree.join("seed"), dir.join(format!("f{j}"))).unwrap();
+        }
+    }
+    let options = || ListOptions::recursive(chrono::Utc.timestamp_opt(0, 0).unwrap());
+
+    let start = Instant::now();
+    let entries = server.state.storage.list("tree", options()).await.unwrap();
+    assert_eq!(tokio::task::block_in_place(|| entries.count()), 0);
+    let full = start.elapsed();
+
+    let stream = server
+        .state
+        .storage
+        .list_stream("tree", options())
+        .await
+        .unwrap();
+    drop(stream);
+    let start = Instant::now();
+    while open_under(&tree) > 0 {
+        assert!(
+            start.elapsed() < full / 4,
+            "the walk went on for {:?}, a full one takes {full:?}",
+            start.elapsed()
+        );
+        tokio::time::sleep(Duration::from_micros(100)).await;
+    }
+}
@@ -0,0 +1,244 @@
+mod common;
+
+use common::{body, json, TestServer};
+use http_body_util::BodyExt;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn listings_are_truncated_at_the_cap() {
+    let server = TestServer::new(&["--list-max-entries", "3"]);
+    for i in 0..5 {
+        server.put(&format!("dir/{i}"), "data").await;
+    }
+
+    let response = server.get("/list/dir").await;
+    assert_eq!(response.status(), 206);
+    assert_eq!(response.headers()["X-Truncated"], "true");
+    assert_eq!(
+        body(response).await.split(|&c| c == b'\n').count(),
+        3 * 3 + 1
+    );
+
+    let response = server.get("/list/dir?format=html").await;
+    assert_eq!(response.status(), 206);
+    assert_eq!(response.headers()["X-Truncated"], "true");
+
+    // JSON listings continue on the next page instead.
+    let response = server.get("/list/dir?format=json").await;
+    assert_eq!(response.status(), 200);
+    let page = json(response).await;
+    assert_eq!(page["entries"].as_array().unwrap().len(), 3);
+    assert!(page["next_cursor"].is_string());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn listings_below_the_cap_are_complete() {
+    let server = TestServer::new(&["--list-max-entries", "5"]);
+    for i in 0..5 {
+        server.put(&format!("dir/{i}"), "data").await;
+    }
+
+    let response = server.get("/list/dir").await;
+    assert_eq!(response.status(), 200);
+    assert!(!response.headers().contains_key("X-Truncated"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn html_indexes_escape_entries_and_link_to_them() {
+    let server = TestServer::new(&[]);
+    server.put("dir/%3Cb%3E%26%22x%22.txt", "data").await;
+    server.put("dir/sub/deep/file", "data").await;
+
+    let response = server
+        .send(
+            axum::http::Request::get("/list/dir")
+                .header("Accept", "text/html")
+                .body(axum::body::Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        response.headers()["Content-Type"],
+        "text/html; charset=utf-8"
+    );
+    let html = String::from_utf8(body(response).await.to_vec()).unwrap();
+    assert!(!html.contains("<b>"));
+    assert!(html.contains(
+        "<a href=\"/files/dir/%3Cb%3E%26%22x%22.txt\">&lt;b&gt;&amp;&quot;x&quot;.txt</a>"
+    ));
+    // Only one level is shown, with links to browse further.
+    assert!(html.contains("<a href=\"/list/dir/sub/?format=html\">sub/</a>"));
+    assert!(!html.contains("deep"));
+    assert!(html.contains("<a href=\"/list/?format=html\">../</a>"));
+
+    let response = server.get("/list/dir?format=html").await;
+    assert_eq!(body(response).await, html);
+}
+
+/// The paths in a plain text listing, sorted.
+async fn listed_paths(server: &TestServer, uri: &str) -> Vec<String> {
+    let response = server.get(uri).await;
+    assert_eq!(response.status(), 200, "{uri}");
+    let listing = String::from_utf8(body(response).await.to_vec()).unwrap();
+    let mut paths: Vec<_> = listing.lines().step_by(3).map(String::from).collect();
+    paths.sort();
+    paths
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn listings_can_be_limited_in_depth() {
+    let server = TestServer::new(&[]);
+    for path in ["root/top", "root/a/middle", "root/a/b/bottom"] {
+        server.put(path, "data").await;
+    }
+
+    assert_eq!(listed_paths(&server, "/list/root?depth=1").await, ["top"]);
+    assert_eq!(
+        listed_paths(&server, "/list/root?depth=2").await,
+        ["a/middle", "top"]
+    );
+    for uri in ["/list/root?depth=3", "/list/root?depth=0", "/list/root"] {
+        assert_eq!(
+            listed_paths(&server, uri).await,
+            ["a/b/bottom", "a/middle", "top"],
+            "{uri}"
+        );
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn missing_nested_directories_are_not_found() {
+    let server = TestServer::new(&[]);
+    server.put("dir/file", "data").await;
+    for uri in ["/list/dir/missing/deeper", "/list/missing"] {
+        let response = server.get(uri).await;
+        assert_eq!(response.status(), 404, "{uri}");
+        assert!(!body(response).await.starts_with(b"file"));
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn entries_deleted_during_a_walk_are_skipped() {
+    let server = TestServer::new(&[]);
+    for path in ["dir/a/1", "dir/b/1", "dir/b/2", "dir/c/1", "dir/d"] {
+        server.put(path, "data").await;
+    }
+
+    let options = filetracker_rs::storage::ListOptions {
+        sorted: true,
+        ..filetracker_rs::storage::ListOptions::recursive(chrono::Utc::now())
+    };
+    let mut entries = server.state.storage.list("dir", options).await.unwrap();
+    assert_eq!(entries.next().unwrap().unwrap().0, "a/1");
+
+    // Both already read by the walk, but not visited yet.
+    let metadata = server.dir.path().join("metadata/dir");
+    std::fs::remove_dir_all(metadata.join("b")).unwrap();
+    std::fs::remove_file(metadata.join("d")).unwrap();
+
+    let rest: Vec<_> = entries.map(|entry| entry.unwrap().0).collect();
+    assert_eq!(rest, ["c/1"]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn listings_can_be_filtered_by_checksum() {
+    let server = TestServer::new(&[]);
+    server.put("one", "shared").await;
+    server.put("dir/two", "shared").await;
+    server.put("three", "different").await;
+
+    let uri = format!("/list/?checksum={}", common::sha256_hex(b"shared"));
+    assert_eq!(listed_paths(&server, &uri).await, ["dir/two", "one"]);
+
+    let response = server.get(&format!("{uri}&format=json")).await;
+    let page = json(response).await;
+    let mut paths: Vec<_> = page["entries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| entry["path"].as_str().unwrap().to_string())
+        .collect();
+    paths.sort();
+    assert_eq!(paths, ["dir/two", "one"]);
+
+    for checksum in ["abc", "zz"] {
+        let response = server.get(&format!("/list/?checksum={checksum}")).await;
+        assert_eq!(response.status(), 400, "{checksum}");
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn streamed_listings_match_synchronous_ones() {
+    use filetracker_rs::storage::{ListEntry, ListOptions};
+    use futures_util::TryStreamExt;
+
+    let server = TestServer::new(&[]);
+    for i in 0..50 {
+        server
+            .put(&format!("tree/{}/{i}", i % 7), format!("{i}"))
+            .await;
+    }
+    let summarize = |(path, entry): (String, ListEntry)| match entry {
+        ListEntry::File(metadata) => (path, Some((metadata.version, metadata.checksum))),
+        ListEntry::Directory | ListEntry::Corrupt => (path, None),
+    };
+    let options = || ListOptions {
+        directories: true,
+        ..ListOptions::recursive(chrono::Utc::now())
+    };
+
+    let storage = &server.state.storage;
+    let mut listed: Vec<_> = storage
+        .list("tree", options())
+        .await
+        .unwrap()
+        .map(|entry| summarize(entry.unwrap()))
+        .collect();
+    let mut streamed: Vec<_> = storage
+        .list_stream("tree", options())
+        .await
+        .unwrap()
+        .map_ok(summarize)
+        .try_collect()
+        .await
+        .unwrap();
+    listed.sort();
+    streamed.sort();
+    assert_eq!(listed.len(), 50 + 7);
+    assert_eq!(streamed, listed);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn corrupt_metadata_is_skipped_when_lenient() {
+    let server = TestServer::new(&[]);
+    for path in ["dir/a", "dir/b", "dir/c"] {
+        server.put(path, "data").await;
+    }
+    std::fs::write(server.dir.path().join("metadata/dir/b"), "{\"truncated").unwrap();
+
+    let response = server.get("/list/dir?format=json").await;
+    assert_eq!(response.status(), 500);
+    // A streamed listing fails halfway through unless the corrupt file comes first.
+    let streamed = server.get("/list/dir").await;
+    assert!(streamed.status() == 500 || streamed.into_body().collect().await.is_err());
+
+    assert_eq!(
+        listed_paths(&server, "/list/dir?skip_corrupt=true").await,
+        ["a", "c"]
+    );
+    let response = server.get("/list/dir?skip_corrupt=true").await;
+    let collected = response.into_body().collect().await.unwrap();
+    assert_eq!(collected.trailers().unwrap()["X-Skipped-Entries"], "1");
+
+    let response = server.get("/list/dir?format=json&skip_corrupt=true").await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["X-Skipped-Entries"], "1");
+    let listing = json(response).await;
+    let paths: Vec<_> = listing["entries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| entry["path"].as_str().unwrap())
+        .collect();
+    assert_eq!(paths.len(), 2, "{listing}");
+}
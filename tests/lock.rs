@@ -0,0 +1,17 @@
+mod common;
+
+use common::{open_state, TestServer};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_second_server_cannot_use_the_same_directory() {
+    let server = TestServer::new(&[]);
+    let error = open_state(server.dir.path(), &[]).err().unwrap();
+    assert!(error.to_string().contains("already in use"), "{error}");
+
+    // Unless locking is turned off, at the operator's own risk.
+    open_state(server.dir.path(), &["--data-dir-lock", "false"]).unwrap();
+
+    // The lock goes away along with the server holding it.
+    let dir = server.restart(&[]).dir;
+    open_state(dir.path(), &[]).unwrap();
+}
@@ -0,0 +1,58 @@
+mod common;
+
+use common::{body, gunzip, sha256_hex, TestServer};
+
+fn compressible() -> Vec<u8> {
+    "compressible contents\n".repeat(1000).into_bytes()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn the_memory_backend_stores_files() {
+    let server = TestServer::new(&["--backend", "memory"]);
+    assert_eq!(server.put("dir/a", compressible()).await.status(), 200);
+    let response = server.put("dir/b", compressible()).await;
+    assert_eq!(response.headers()["X-Deduplicated"], "true");
+
+    let response = server.get("/files/dir/a").await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        response.headers()["SHA256-Checksum"],
+        sha256_hex(&compressible())
+    );
+    assert_eq!(body(response).await, compressible());
+    let response = server
+        .send(
+            axum::http::Request::get("/files/dir/b")
+                .header("Accept-Encoding", "gzip")
+                .body(axum::body::Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.headers()["Content-Encoding"], "gzip");
+    assert_eq!(gunzip(&body(response).await), compressible());
+
+    let listing = body(server.get("/list/dir").await).await;
+    let mut paths: Vec<_> = std::str::from_utf8(&listing)
+        .unwrap()
+        .lines()
+        .step_by(3)
+        .collect();
+    paths.sort();
+    assert_eq!(paths, ["a", "b"]);
+
+    let response = server
+        .send(
+            axum::http::Request::delete("/files/dir/a")
+                .body(axum::body::Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(server.get("/files/dir/a").await.status(), 404);
+    assert_eq!(body(server.get("/files/dir/b").await).await, compressible());
+
+    // Nothing is stored in the data directory.
+    for stored in ["blobs", "metadata"] {
+        assert!(!server.dir.path().join(stored).exists(), "{stored}");
+    }
+}
@@ -0,0 +1,38 @@
+mod common;
+
+use axum::{body::Body, http::Request};
+use common::{json, sha256_hex, TestServer};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn metadata_is_served_as_json() {
+    let server = TestServer::new(&[]);
+    let content = "metadata contents\n".repeat(100);
+    let response = server
+        .send(
+            Request::put(format!(
+                "/files/dir/file?last_modified={}",
+                common::OLD_VERSION
+            ))
+            .header("X-Filename", "report.txt")
+            .body(Body::from(content.clone()))
+            .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), 200);
+
+    let response = server.get("/meta/dir/file").await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["Content-Type"], "application/json");
+    let metadata = json(response).await;
+    assert_eq!(metadata["version"], "2024-01-01T12:00:00Z");
+    let checksum: Vec<u8> = serde_json::from_value(metadata["checksum"].clone()).unwrap();
+    assert_eq!(
+        filetracker_rs::util::bytes_to_hex(&checksum),
+        sha256_hex(content.as_bytes())
+    );
+    assert_eq!(metadata["compression"], "Gzip");
+    assert_eq!(metadata["decompressed_size"], content.len());
+    assert_eq!(metadata["filename"], "report.txt");
+
+    assert_eq!(server.get("/meta/dir/missing").await.status(), 404);
+}
@@ -0,0 +1,84 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::path::Path;
+
+use clap::Parser;
+use common::{body, gzip, sha256_hex, TestServer};
+use filetracker_rs::storage;
+
+/// Compressible enough to stay gzipped once migrated.
+const SHARED: &[u8] = b"shared contents shared contents shared contents shared contents";
+
+#[derive(Parser)]
+struct Opts {
+    #[clap(flatten)]
+    storage: storage::StorageConfig,
+}
+
+/// Stores `content` in a legacy store the way the original filetracker does and
+/// links it at `path`.
+fn add_legacy_file(legacy: &Path, path: &str, content: &[u8]) {
+    let hex = sha256_hex(content);
+    let blob = legacy.join("blobs").join(&hex[..2]).join(&hex);
+    std::fs::create_dir_all(blob.parent().unwrap()).unwrap();
+    std::fs::write(&blob, gzip(content)).unwrap();
+
+    let link = legacy.join("links").join(path);
+    std::fs::create_dir_all(link.parent().unwrap()).unwrap();
+    let depth = path.matches('/').count() + 1;
+    let target = format!("{}blobs/{}/{hex}", "../".repeat(depth), &hex[..2]);
+    std::os::unix::fs::symlink(target, link).unwrap();
+}
+
+async fn migrate(legacy: &Path, directory: &Path) {
+    let opts = Opts::parse_from(["filetracker"]);
+    let storage = storage::LocalStorage::new(directory, opts.storage).unwrap();
+    filetracker_rs::migrate::migrate_from_legacy(legacy, &storage)
+        .await
+        .unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn legacy_stores_are_migrated() {
+    let legacy = tempfile::tempdir().unwrap();
+    add_legacy_file(legacy.path(), "a.txt", SHARED);
+    add_legacy_file(legacy.path(), "dir/b.txt", SHARED);
+    add_legacy_file(legacy.path(), "dir/deeper/c.txt", b"other contents");
+    let version = |path: &str| -> chrono::DateTime<chrono::Utc> {
+        let link = legacy.path().join("links").join(path);
+        link.symlink_metadata().unwrap().modified().unwrap().into()
+    };
+
+    let directory = tempfile::tempdir().unwrap();
+    migrate(legacy.path(), directory.path()).await;
+    // Already migrated files are skipped when resuming.
+    migrate(legacy.path(), directory.path()).await;
+
+    let server = TestServer::open(directory, &[]);
+    for (path, content) in [
+        ("a.txt", SHARED),
+        ("dir/b.txt", SHARED),
+        ("dir/deeper/c.txt", b"other contents"),
+    ] {
+        let metadata = server.state.storage.metadata(path).await.unwrap();
+        assert_eq!(metadata.version, version(path), "{path}");
+        assert_eq!(metadata.decompressed_size, content.len(), "{path}");
+        assert_eq!(
+            body(server.get(&format!("/files/{path}")).await).await,
+            content
+        );
+    }
+
+    // Both files share one blob.
+    let hex = sha256_hex(SHARED);
+    let count = server
+        .dir
+        .path()
+        .join("blobs")
+        .join(&hex[..2])
+        .join(&hex[2..])
+        .with_extension("count");
+    assert_eq!(std::fs::read_to_string(count).unwrap(), "2");
+}
@@ -0,0 +1,158 @@
+mod common;
+
+use axum::{
+    body::Body,
+    http::{Request, Response},
+};
+use common::{body, gunzip, sha256_hex, TestServer};
+
+fn contents() -> Vec<u8> {
+    "compressible contents\n".repeat(1000).into_bytes()
+}
+
+async fn put_gzipped(server: &TestServer, path: &str) {
+    let response = server
+        .send(
+            Request::put(format!("/files/{path}?compression=gzip"))
+                .body(Body::from(contents()))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), 200);
+}
+
+async fn request(
+    server: &TestServer,
+    method: &str,
+    path: &str,
+    accept_encoding: Option<&str>,
+) -> Response<Body> {
+    let mut request = Request::builder()
+        .method(method)
+        .uri(format!("/files/{path}"));
+    if let Some(accept_encoding) = accept_encoding {
+        request = request.header("Accept-Encoding", accept_encoding);
+    }
+    server.send(request.body(Body::empty()).unwrap()).await
+}
+
+fn content_length(response: &Response<Body>) -> usize {
+    response.headers()["Content-Length"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn responses_vary_by_accept_encoding() {
+    let server = TestServer::new(&[]);
+    put_gzipped(&server, "file").await;
+
+    let gzipped = request(&server, "GET", "file", Some("gzip")).await;
+    let identity = request(&server, "GET", "file", None).await;
+    assert_eq!(gzipped.headers()["Content-Encoding"], "gzip");
+    assert!(!identity.headers().contains_key("Content-Encoding"));
+    for response in [&gzipped, &identity] {
+        assert_eq!(response.headers()["Vary"], "Accept-Encoding");
+    }
+    assert_eq!(gunzip(&body(gzipped).await), contents());
+    assert_eq!(body(identity).await, contents());
+
+    let head = request(&server, "HEAD", "file", None).await;
+    assert_eq!(head.headers()["Vary"], "Accept-Encoding");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn vary_is_left_out_without_negotiation() {
+    let server = TestServer::new(&["--always-gzip"]);
+    put_gzipped(&server, "file").await;
+
+    for accept_encoding in [Some("gzip"), None] {
+        let response = request(&server, "GET", "file", accept_encoding).await;
+        assert_eq!(response.headers()["Content-Encoding"], "gzip");
+        assert!(!response.headers().contains_key("Vary"));
+    }
+
+    // Nor is there anything to negotiate for uncompressed files.
+    let server = TestServer::new(&[]);
+    server.put("plain?compression=none", contents()).await;
+    let response = request(&server, "GET", "plain", Some("gzip")).await;
+    assert!(!response.headers().contains_key("Vary"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn head_reports_the_length_of_the_negotiated_encoding() {
+    let server = TestServer::new(&[]);
+    put_gzipped(&server, "file").await;
+
+    for accept_encoding in [Some("gzip"), Some("identity"), None] {
+        let head = request(&server, "HEAD", "file", accept_encoding).await;
+        let get = request(&server, "GET", "file", accept_encoding).await;
+        assert_eq!(
+            head.headers().get("Content-Encoding"),
+            get.headers().get("Content-Encoding"),
+            "{accept_encoding:?}"
+        );
+        assert_eq!(
+            content_length(&head),
+            body(get).await.len(),
+            "{accept_encoding:?}"
+        );
+    }
+
+    let gzipped = request(&server, "HEAD", "file", Some("gzip")).await;
+    assert!(content_length(&gzipped) < contents().len());
+    let identity = request(&server, "HEAD", "file", None).await;
+    assert_eq!(content_length(&identity), contents().len());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn each_encoding_has_its_own_strong_tag() {
+    let server = TestServer::new(&[]);
+    put_gzipped(&server, "file").await;
+    let checksum = sha256_hex(&contents());
+    let gzip_tag = format!("\"{checksum}-gzip\"");
+    let identity_tag = format!("\"{checksum}\"");
+
+    let gzipped = request(&server, "GET", "file", Some("gzip")).await;
+    assert_eq!(gzipped.headers()["ETag"], gzip_tag.as_str());
+    let identity = request(&server, "GET", "file", None).await;
+    assert_eq!(identity.headers()["ETag"], identity_tag.as_str());
+
+    // A cached representation is only fresh for requests that would get it again.
+    let get = |accept_encoding: &'static str, if_none_match: &str| {
+        Request::get("/files/file")
+            .header("Accept-Encoding", accept_encoding)
+            .header("If-None-Match", if_none_match)
+            .body(Body::empty())
+            .unwrap()
+    };
+    let response = server.send(get("gzip", &gzip_tag)).await;
+    assert_eq!(response.status(), 304);
+    assert_eq!(response.headers()["ETag"], gzip_tag.as_str());
+    assert_eq!(response.headers()["Vary"], "Accept-Encoding");
+    assert_eq!(server.send(get("gzip", &identity_tag)).await.status(), 200);
+    assert_eq!(server.send(get("identity", &gzip_tag)).await.status(), 200);
+    assert_eq!(
+        server.send(get("identity", &identity_tag)).await.status(),
+        304
+    );
+
+    // Either tag names the stored file when writing it.
+    let response = server
+        .send(
+            Request::put("/files/file")
+                .header("If-Match", &gzip_tag)
+                .body(Body::from("new"))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), 200);
+
+    // Files always sent gzipped have just the one representation.
+    let server = TestServer::new(&["--always-gzip"]);
+    put_gzipped(&server, "file").await;
+    let response = request(&server, "GET", "file", None).await;
+    assert_eq!(response.headers()["ETag"], identity_tag.as_str());
+}
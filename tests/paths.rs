@@ -0,0 +1,42 @@
+mod common;
+
+use common::{body, TestServer};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn paths_over_the_length_limit_are_rejected() {
+    let server = TestServer::new(&["--max-path-length", "16"]);
+    assert_eq!(server.put("a/sixteen/bytes.", "data").await.status(), 200);
+
+    let response = server.put("a/seventeen/bytes", "data").await;
+    assert_eq!(response.status(), 400);
+    assert_eq!(
+        body(response).await,
+        "Path is longer than the maximum of 16 bytes"
+    );
+    assert_eq!(server.get("/files/a/seventeen/bytes").await.status(), 400);
+    assert!(!server.dir.path().join("metadata/a/seventeen").exists());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn paths_over_the_depth_limit_are_rejected() {
+    let server = TestServer::new(&["--max-path-depth", "3"]);
+    assert_eq!(server.put("a/b/c", "data").await.status(), 200);
+
+    let response = server.put("a/b/c/d", "data").await;
+    assert_eq!(response.status(), 400);
+    assert_eq!(
+        body(response).await,
+        "Path has more than the maximum of 3 components"
+    );
+    assert_eq!(server.get("/files/a/b/c/d").await.status(), 400);
+    assert_eq!(server.get("/list/a/b/c/d").await.status(), 400);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn paths_at_the_depth_limit_are_accepted() {
+    let server = TestServer::new(&["--max-path-depth", "3"]);
+    // `.` components aren't counted, as they don't nest the file any deeper.
+    assert_eq!(server.put("./a/./b/c", "data").await.status(), 200);
+    assert_eq!(body(server.get("/files/a/b/c").await).await, "data");
+    assert_eq!(server.get("/list/a/b/").await.status(), 200);
+}
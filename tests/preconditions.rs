@@ -0,0 +1,111 @@
+mod common;
+
+use axum::{body::Body, http::Request};
+use common::{body, sha256_hex, TestServer};
+
+fn put_if_match(if_match: &str, data: &'static str) -> Request<Body> {
+    Request::put("/files/file")
+        .header("If-Match", if_match)
+        .body(Body::from(data))
+        .unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn writes_matching_if_match_are_applied() {
+    let server = TestServer::new(&[]);
+    server.put("file", "first").await;
+
+    let tag = format!("\"{}\"", sha256_hex(b"first"));
+    let response = server.send(put_if_match(&tag, "second")).await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(body(server.get("/files/file").await).await, "second");
+
+    // Any of a list will do, as will any existing file.
+    let list = format!(
+        "\"{}\", \"{}\"",
+        sha256_hex(b"other"),
+        sha256_hex(b"second")
+    );
+    let response = server.send(put_if_match(&list, "third")).await;
+    assert_eq!(response.status(), 200);
+    let response = server.send(put_if_match("*", "fourth")).await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(body(server.get("/files/file").await).await, "fourth");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn writes_not_matching_if_match_fail() {
+    let server = TestServer::new(&[]);
+    let response = server.send(put_if_match("*", "data")).await;
+    assert_eq!(response.status(), 412);
+    assert_eq!(server.get("/files/file").await.status(), 404);
+
+    server.put("file", "first").await;
+    let stale = format!("\"{}\"", sha256_hex(b"stale"));
+    let weak = format!("W/\"{}\"", sha256_hex(b"first"));
+    for if_match in [stale.as_str(), weak.as_str()] {
+        let response = server.send(put_if_match(if_match, "second")).await;
+        assert_eq!(response.status(), 412, "{if_match}");
+    }
+    assert_eq!(body(server.get("/files/file").await).await, "first");
+
+    let response = server.send(put_if_match("not a tag", "second")).await;
+    assert_eq!(response.status(), 400);
+}
+
+async fn get_if_none_match(server: &TestServer, if_none_match: &str) -> axum::http::StatusCode {
+    server
+        .send(
+            Request::get("/files/file")
+                .header("If-None-Match", if_none_match)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .status()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn if_none_match_accepts_lists_and_weak_tags() {
+    let server = TestServer::new(&[]);
+    server.put("file", "data").await;
+    let etag = server.get("/files/file").await.headers()["ETag"]
+        .to_str()
+        .unwrap()
+        .to_string();
+    let other = format!("\"{}\"", sha256_hex(b"other"));
+
+    for matching in [
+        etag.clone(),
+        format!("{other}, {etag}"),
+        format!("{other},{etag}"),
+        format!("W/{etag}"),
+        format!("{other}, W/{etag}"),
+        "*".to_string(),
+    ] {
+        assert_eq!(
+            get_if_none_match(&server, &matching).await,
+            304,
+            "{matching}"
+        );
+    }
+
+    for not_matching in [other.clone(), format!("{other}, W/{other}")] {
+        assert_eq!(
+            get_if_none_match(&server, &not_matching).await,
+            200,
+            "{not_matching}"
+        );
+    }
+
+    // `*` only matches files that exist.
+    let response = server
+        .send(
+            Request::get("/files/missing")
+                .header("If-None-Match", "*")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), 404);
+}
@@ -0,0 +1,137 @@
+mod common;
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use axum::{body::Body, http::Request};
+use common::{body, json, TestServer};
+
+/// Enough files that a promotion moving them one at a time would be caught halfway.
+const FILES: usize = 64;
+
+/// The sizes of the files listed under `live`.
+async fn listed_sizes(server: &TestServer) -> Vec<usize> {
+    let response = server.get("/list/live").await;
+    assert_eq!(response.status(), 200);
+    let listing = String::from_utf8(body(response).await.to_vec()).unwrap();
+    listing
+        .lines()
+        .skip(2)
+        .step_by(3)
+        .map(|size| size.parse().unwrap())
+        .collect()
+}
+
+async fn promote(server: &TestServer, query: &str) -> serde_json::Value {
+    let response = server
+        .send(
+            Request::post(format!("/promote?{query}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), 200);
+    json(response).await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn readers_never_see_a_partial_promotion() {
+    let server = Arc::new(TestServer::new(&[]));
+    for file in 0..FILES {
+        let put = server.put(&format!("live/{file}"), "old").await;
+        assert_eq!(put.status(), 200);
+        let put = server.put(&format!("staging/{file}"), "newer").await;
+        assert_eq!(put.status(), 200);
+    }
+
+    // Lists the live files over and over, which must all be old or all be new.
+    let done = Arc::new(AtomicBool::new(false));
+    let reader = tokio::spawn({
+        let server = server.clone();
+        let done = done.clone();
+        async move {
+            let mut listings = 0;
+            loop {
+                let finished = done.load(Ordering::SeqCst);
+                let sizes = listed_sizes(&server).await;
+                assert_eq!(sizes.len(), FILES);
+                assert!(
+                    sizes.iter().all(|&size| size == sizes[0]),
+                    "old and new files listed together: {sizes:?}"
+                );
+                listings += 1;
+                if finished {
+                    return listings;
+                }
+            }
+        }
+    });
+
+    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    let outcome = promote(&server, "from=staging&to=live").await;
+    assert_eq!(outcome, serde_json::json!({ "moved": FILES, "removed": 0 }));
+    done.store(true, Ordering::SeqCst);
+    assert!(reader.await.unwrap() > 1);
+
+    assert_eq!(listed_sizes(&server).await, [5; FILES]);
+    for file in 0..FILES {
+        let staged = server.get(&format!("/files/staging/{file}")).await;
+        assert_eq!(staged.status(), 404);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_failed_promotion_is_rolled_back() {
+    let server = TestServer::new(&[]);
+    server.put("live/a", "old").await;
+    server.put("staging/a", "new").await;
+    server.put("staging/b/c", "new").await;
+    // `b` can't become a directory under `live` while it is a file it keeps.
+    server.put("live/b", "old").await;
+
+    let response = server
+        .send(
+            Request::post("/promote?from=staging&to=live&mode=merge")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), 409);
+
+    for (path, contents) in [
+        ("live/a", "old"),
+        ("live/b", "old"),
+        ("staging/a", "new"),
+        ("staging/b/c", "new"),
+    ] {
+        let response = server.get(&format!("/files/{path}")).await;
+        assert_eq!(body(response).await, contents, "{path}");
+    }
+    let stats = json(server.get("/stats").await).await;
+    assert_eq!(stats["storage"]["files"]["files"], 4);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn replace_removes_files_missing_from_the_source() {
+    for (mode, removed, stale) in [("replace", 1, 404), ("merge", 0, 200)] {
+        let server = TestServer::new(&[]);
+        server.put("live/a", "old").await;
+        server.put("live/stale", "old").await;
+        server.put("staging/a", "new").await;
+
+        let outcome = promote(&server, &format!("from=staging&to=live&mode={mode}")).await;
+        assert_eq!(
+            outcome,
+            serde_json::json!({ "moved": 1, "removed": removed }),
+            "{mode}"
+        );
+        assert_eq!(body(server.get("/files/live/a").await).await, "new");
+        assert_eq!(
+            server.get("/files/live/stale").await.status(),
+            stale,
+            "{mode}"
+        );
+    }
+}
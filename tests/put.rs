@@ -0,0 +1,319 @@
+mod common;
+
+use axum::{body::Body, http::Request};
+use common::{body, gzip, sha256_hex, TestServer};
+
+/// A body without a known length, sent chunked by a real client.
+fn chunked(chunks: &[&'static [u8]]) -> Body {
+    let chunks: Vec<_> = chunks
+        .iter()
+        .map(|&chunk| Ok::<_, std::io::Error>(axum::body::Bytes::from_static(chunk)))
+        .collect();
+    Body::from_stream(futures_util::stream::iter(chunks))
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn chunked_uploads_are_stored() {
+    let server = TestServer::new(&[]);
+    let response = server
+        .send(
+            Request::put("/files/chunked")
+                .body(chunked(&[b"hello ", b"chunked ", b"world"]))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), 200);
+
+    let response = server.get("/files/chunked").await;
+    assert_eq!(
+        response.headers()["SHA256-Checksum"],
+        sha256_hex(b"hello chunked world")
+    );
+    assert_eq!(response.headers()["Logical-Size"], "19");
+    assert_eq!(body(response).await, "hello chunked world");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn chunked_uploads_can_be_refused() {
+    let server = TestServer::new(&["--require-content-length"]);
+    let response = server
+        .send(
+            Request::put("/files/chunked")
+                .body(chunked(&[b"data"]))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), 411);
+    assert_eq!(server.get("/files/chunked").await.status(), 404);
+
+    let response = server
+        .send(
+            Request::put("/files/sized")
+                .header("Content-Length", 4)
+                .body(Body::from("data"))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), 200);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn logical_sizes_of_identity_bodies_must_match() {
+    let server = TestServer::new(&["--max-logical-size", "8"]);
+    let put = |size: usize| {
+        Request::put("/files/plain")
+            .header("Logical-Size", size)
+            .body(Body::from("data"))
+            .unwrap()
+    };
+
+    for size in [3, 5, 1000] {
+        assert_eq!(server.send(put(size)).await.status(), 400, "{size}");
+    }
+    assert_eq!(server.get("/files/plain").await.status(), 404);
+
+    assert_eq!(server.send(put(4)).await.status(), 200);
+    assert_eq!(
+        server.get("/files/plain").await.headers()["Logical-Size"],
+        "4"
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn implausible_logical_sizes_of_gzipped_bodies_are_rejected() {
+    let server = TestServer::new(&["--max-logical-size", "1000"]);
+    let put = |size: &str| {
+        Request::put("/files/gzipped")
+            .header("Content-Encoding", "gzip")
+            .header("Logical-Size", size)
+            .body(Body::from(gzip(b"data")))
+            .unwrap()
+    };
+
+    for size in ["-1", "18446744073709551616", "abc"] {
+        assert_eq!(server.send(put(size)).await.status(), 400, "{size}");
+    }
+    assert_eq!(server.send(put("1001")).await.status(), 400);
+    assert_eq!(server.send(put("4")).await.status(), 200);
+
+    // Above what deflate could possibly achieve for a body this small.
+    let server = TestServer::new(&[]);
+    assert_eq!(server.send(put("1000000")).await.status(), 400);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn validate_checks_without_storing() {
+    let server = TestServer::new(&[]);
+    let validate = |checksum: String| {
+        Request::put("/files/validated?validate=true")
+            .header("SHA256-Checksum", checksum)
+            .body(Body::from("data"))
+            .unwrap()
+    };
+
+    let response = server.send(validate(sha256_hex(b"data"))).await;
+    assert_eq!(response.status(), 204);
+    assert_eq!(server.get("/files/validated").await.status(), 404);
+
+    let response = server.send(validate(sha256_hex(b"other"))).await;
+    assert_eq!(response.status(), 422);
+    assert_eq!(server.get("/files/validated").await.status(), 404);
+
+    // Like other flags, `validate` may be given without a value.
+    for query in ["validate", "validate=1"] {
+        let response = server
+            .send(
+                Request::put(format!("/files/validated?{query}"))
+                    .body(Body::from("data"))
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(response.status(), 204, "{query}");
+        assert_eq!(server.get("/files/validated").await.status(), 404);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn declared_filenames_round_trip() {
+    let server = TestServer::new(&[]);
+    let response = server
+        .send(
+            Request::put("/files/report")
+                .header(
+                    "Content-Disposition",
+                    "attachment; filename=\"zazolc.txt\"; filename*=UTF-8''za%C5%BC%C3%B3%C5%82%C4%87.txt",
+                )
+                .body(Body::from("data"))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), 200);
+
+    let response = server.get("/files/report").await;
+    assert_eq!(
+        response.headers()["Content-Disposition"],
+        "attachment; filename=\"za____.txt\"; filename*=UTF-8''za%C5%BC%C3%B3%C5%82%C4%87.txt"
+    );
+    let metadata = common::json(server.get("/meta/report").await).await;
+    assert_eq!(metadata["filename"], "zażółć.txt");
+
+    // X-Filename is taken as raw UTF-8.
+    let response = server
+        .send(
+            Request::put("/files/other")
+                .header("X-Filename", "ąę.bin".as_bytes())
+                .body(Body::from("data"))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), 200);
+    let metadata = common::json(server.get("/meta/other").await).await;
+    assert_eq!(metadata["filename"], "ąę.bin");
+
+    // Without one, the last component of the path is used.
+    server.put("dir/plain.txt", "data").await;
+    assert_eq!(
+        server.get("/files/dir/plain.txt").await.headers()["Content-Disposition"],
+        "attachment; filename=\"plain.txt\""
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn gzip_uploads_describing_the_compressed_data_are_rejected() {
+    let data = "some contents\n".repeat(100);
+    let gzipped = gzip(data.as_bytes());
+    let put = |checksum: String, size: usize, content: Vec<u8>| {
+        Request::put("/files/gzipped")
+            .header("Content-Encoding", "gzip")
+            .header("SHA256-Checksum", checksum)
+            .header("Logical-Size", size)
+            .body(Body::from(content))
+            .unwrap()
+    };
+
+    // The checksum and size of the gzip stream rather than of its contents.
+    let server = TestServer::new(&[]);
+    let response = server
+        .send(put(sha256_hex(&gzipped), gzipped.len(), gzipped.clone()))
+        .await;
+    assert_eq!(response.status(), 400);
+    assert_eq!(
+        body(response).await,
+        "Logical-Size does not match the size recorded in the gzip trailer"
+    );
+    assert_eq!(server.get("/files/gzipped").await.status(), 404);
+
+    let response = server
+        .send(put(
+            sha256_hex(data.as_bytes()),
+            data.len(),
+            b"not gzip".repeat(4),
+        ))
+        .await;
+    assert_eq!(response.status(), 400);
+
+    let response = server
+        .send(put(
+            sha256_hex(data.as_bytes()),
+            data.len(),
+            gzipped.clone(),
+        ))
+        .await;
+    assert_eq!(response.status(), 200);
+
+    // The check can be turned off, trusting the client entirely.
+    let server = TestServer::new(&["--check-gzip-trailer", "false"]);
+    let response = server
+        .send(put(sha256_hex(&gzipped), gzipped.len(), gzipped.clone()))
+        .await;
+    assert_eq!(response.status(), 200);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn checksums_are_accepted_in_either_case() {
+    let server = TestServer::new(&[]);
+    let put = |path: &'static str, checksum: String| {
+        server.send(
+            Request::put(format!("/files/{path}"))
+                .header("SHA256-Checksum", checksum)
+                .body(Body::from("data"))
+                .unwrap(),
+        )
+    };
+
+    let checksum = sha256_hex(b"data");
+    assert_eq!(put("lower", checksum.clone()).await.status(), 200);
+    assert_eq!(put("upper", checksum.to_uppercase()).await.status(), 200);
+    // Stored checksums are always reported in lowercase.
+    for path in ["/files/lower", "/files/upper"] {
+        let response = server.get(path).await;
+        assert_eq!(response.headers()["SHA256-Checksum"], checksum);
+    }
+
+    for (invalid, message) in [
+        (
+            checksum[1..].to_string(),
+            "expected 64 hexadecimal characters, got 63",
+        ),
+        (
+            format!("{checksum}0"),
+            "expected 64 hexadecimal characters, got 65",
+        ),
+        (
+            format!("g{}", &checksum[1..]),
+            "expected 64 hexadecimal characters, found 'g'",
+        ),
+    ] {
+        let response = put("invalid", invalid.clone()).await;
+        assert_eq!(response.status(), 400, "{invalid}");
+        let text = String::from_utf8(body(response).await.to_vec()).unwrap();
+        assert!(text.contains(message), "{invalid}: {text}");
+    }
+    assert_eq!(server.get("/files/invalid").await.status(), 404);
+}
+
+/// Uploads `data` to `file` with the given URL-encoded version.
+async fn put_version(
+    server: &TestServer,
+    version: &str,
+    data: &'static str,
+) -> axum::http::Response<Body> {
+    server
+        .send(
+            Request::put(format!("/files/file?last_modified={version}"))
+                .body(Body::from(data))
+                .unwrap(),
+        )
+        .await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stale_writes_report_the_newer_version() {
+    let newer = "Tue,%2002%20Jan%202024%2012:00:00%20%2B0000";
+    let older = "Mon,%2001%20Jan%202024%2012:00:00%20%2B0000";
+
+    let server = TestServer::new(&[]);
+    assert_eq!(put_version(&server, newer, "newer").await.status(), 200);
+    let response = put_version(&server, older, "older").await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        response.headers()["X-Current-Version"],
+        "Tue, 2 Jan 2024 12:00:00 +0000"
+    );
+    let response = server.get("/files/file").await;
+    assert_eq!(
+        response.headers()["Last-Modified"],
+        "Tue, 2 Jan 2024 12:00:00 +0000"
+    );
+    assert_eq!(body(response).await, "newer");
+
+    let server = TestServer::new(&["--write-policy", "reject-older"]);
+    assert_eq!(put_version(&server, newer, "newer").await.status(), 200);
+    let response = put_version(&server, older, "older").await;
+    assert_eq!(response.status(), 409);
+    assert_eq!(
+        response.headers()["X-Current-Version"],
+        "Tue, 2 Jan 2024 12:00:00 +0000"
+    );
+    assert_eq!(body(server.get("/files/file").await).await, "newer");
+}
@@ -0,0 +1,132 @@
+mod common;
+
+use axum::{body::Body, http::Request};
+use common::{body, TestServer};
+
+fn contents() -> Vec<u8> {
+    (0..100_000u32)
+        .flat_map(|i| (i % 251).to_le_bytes())
+        .collect()
+}
+
+/// A server with the same contents stored gzipped and uncompressed.
+async fn server() -> TestServer {
+    let server = TestServer::new(&[]);
+    for (path, compression) in [("gzip", "gzip"), ("none", "none")] {
+        let response = server
+            .send(
+                Request::put(format!("/files/{path}?compression={compression}"))
+                    .body(Body::from(contents()))
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(response.status(), 200);
+    }
+    server
+}
+
+async fn get_range(
+    server: &TestServer,
+    path: &str,
+    range: &str,
+    accept_encoding: Option<&str>,
+) -> axum::http::Response<Body> {
+    let mut request = Request::get(format!("/files/{path}")).header("Range", range);
+    if let Some(accept_encoding) = accept_encoding {
+        request = request.header("Accept-Encoding", accept_encoding);
+    }
+    server.send(request.body(Body::empty()).unwrap()).await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn ranges_apply_to_the_logical_contents() {
+    let server = server().await;
+    let contents = contents();
+    let size = contents.len();
+
+    for path in ["gzip", "none"] {
+        for accept_encoding in [None, Some("gzip"), Some("identity"), Some("gzip, identity")] {
+            let response = get_range(&server, path, "bytes=1000-1999", accept_encoding).await;
+            assert_eq!(response.status(), 206, "{path} {accept_encoding:?}");
+            let headers = response.headers();
+            assert!(!headers.contains_key("Content-Encoding"));
+            assert_eq!(headers["Content-Range"], format!("bytes 1000-1999/{size}"));
+            assert_eq!(headers["Content-Length"], "1000");
+            assert_eq!(body(response).await, &contents[1000..2000]);
+        }
+    }
+
+    // Suffix and open-ended ranges.
+    let response = get_range(&server, "gzip", "bytes=-10", Some("gzip")).await;
+    assert_eq!(body(response).await, &contents[size - 10..]);
+    let response = get_range(&server, "gzip", "bytes=399990-", Some("gzip")).await;
+    assert_eq!(body(response).await, &contents[399_990..]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn ranges_of_gzipped_files_vary_by_encoding() {
+    let server = server().await;
+    let response = get_range(&server, "gzip", "bytes=0-9", Some("gzip")).await;
+    assert_eq!(response.headers()["Vary"], "Accept-Encoding");
+    // The checksum identifies the logical contents sent.
+    assert!(!response.headers()["ETag"]
+        .to_str()
+        .unwrap()
+        .starts_with("W/"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn ranges_are_ignored_for_clients_refusing_identity() {
+    let server = server().await;
+    let contents = contents();
+
+    for accept_encoding in ["gzip, identity;q=0", "gzip, *;q=0"] {
+        let response = get_range(&server, "gzip", "bytes=0-9", Some(accept_encoding)).await;
+        assert_eq!(response.status(), 200, "{accept_encoding}");
+        assert_eq!(response.headers()["Content-Encoding"], "gzip");
+        assert!(!response.headers().contains_key("Content-Range"));
+        assert_eq!(common::gunzip(&body(response).await), contents);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unsatisfiable_ranges() {
+    let server = server().await;
+    let size = contents().len();
+    for path in ["gzip", "none"] {
+        let response = get_range(&server, path, &format!("bytes={size}-"), Some("gzip")).await;
+        assert_eq!(response.status(), 416);
+        assert_eq!(
+            response.headers()["Content-Range"],
+            format!("bytes */{size}")
+        );
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stale_if_range_gets_the_whole_file() {
+    let server = server().await;
+    let response = server
+        .send(
+            Request::get("/files/none")
+                .header("Range", "bytes=0-9")
+                .header("If-Range", "\"0000\"")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(body(response).await, contents());
+
+    let etag = server.get("/files/none").await.headers()["ETag"].clone();
+    let response = server
+        .send(
+            Request::get("/files/none")
+                .header("Range", "bytes=0-9")
+                .header("If-Range", etag)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), 206);
+}
@@ -0,0 +1,23 @@
+mod common;
+
+use common::{body, sha256_hex, TestServer};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn blobs_are_staged_in_the_staging_directory() {
+    let staging = tempfile::tempdir().unwrap();
+    let staging_dir = staging.path().join("staging");
+    let server = TestServer::new(&["--staging-dir", staging_dir.to_str().unwrap()]);
+
+    assert_eq!(server.put("file", "data").await.status(), 200);
+    assert_eq!(server.put("copy", "data").await.status(), 200);
+    assert_eq!(body(server.get("/files/file").await).await, "data");
+    assert_eq!(
+        server.get("/files/copy").await.headers()["SHA256-Checksum"],
+        sha256_hex(b"data")
+    );
+    assert_eq!(std::fs::read_dir(&staging_dir).unwrap().count(), 0);
+
+    // The blob survives a restart, so it was moved into the store for real.
+    let server = server.restart(&["--staging-dir", staging_dir.to_str().unwrap()]);
+    assert_eq!(body(server.get("/files/file").await).await, "data");
+}
@@ -0,0 +1,38 @@
+mod common;
+
+use common::{json, TestServer};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn latency_percentiles_are_reported() {
+    let server = TestServer::new(&[]);
+    let stats = json(server.get("/stats").await).await;
+    assert!(stats["latency"]["get"].is_null());
+
+    for i in 0..10 {
+        assert!(server
+            .put(&format!("a/{i}"), "data")
+            .await
+            .status()
+            .is_success());
+        assert!(server
+            .get(&format!("/files/a/{i}"))
+            .await
+            .status()
+            .is_success());
+    }
+    server.get("/list/a").await;
+
+    let stats = json(server.get("/stats").await).await;
+    for (operation, count) in [("get", 10), ("put", 10), ("list", 1)] {
+        let percentiles = &stats["latency"][operation];
+        assert_eq!(percentiles["count"], count, "{operation}");
+        let p50 = percentiles["p50_ms"].as_f64().unwrap();
+        let p95 = percentiles["p95_ms"].as_f64().unwrap();
+        let p99 = percentiles["p99_ms"].as_f64().unwrap();
+        assert!(
+            0.0 < p50 && p50 <= p95 && p95 <= p99,
+            "{operation}: {percentiles}"
+        );
+    }
+    assert!(stats["latency"]["delete"].is_null());
+}
@@ -0,0 +1,46 @@
+#![cfg(unix)]
+
+mod common;
+
+use common::{body, sha256_hex, TestServer};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn listing_does_not_follow_symlinked_directories() {
+    let server = TestServer::new(&[]);
+    server.put("dir/sub/file", "data").await;
+
+    let metadata = server.dir.path().join("metadata");
+    std::os::unix::fs::symlink(metadata.join("dir"), metadata.join("dir/sub/loop")).unwrap();
+    std::os::unix::fs::symlink("/", metadata.join("dir/escape")).unwrap();
+
+    let response =
+        tokio::time::timeout(std::time::Duration::from_secs(10), server.get("/list/dir"))
+            .await
+            .expect("the walk terminates");
+    assert_eq!(response.status(), 200);
+    let listing = String::from_utf8(body(response).await.to_vec()).unwrap();
+    assert_eq!(listing.lines().next(), Some("sub/file"));
+    assert_eq!(listing.lines().count(), 3);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn symlinked_blobs_are_refused() {
+    let server = TestServer::new(&[]);
+    server.put("file?compression=none", "data").await;
+
+    let hex = sha256_hex(b"data");
+    let blob = server
+        .dir
+        .path()
+        .join("blobs/none")
+        .join(&hex[..2])
+        .join(&hex[2..]);
+    let target = server.dir.path().join("elsewhere");
+    std::fs::write(&target, "evil").unwrap();
+    std::fs::remove_file(&blob).unwrap();
+    std::os::unix::fs::symlink(&target, &blob).unwrap();
+
+    let response = server.get("/files/file").await;
+    assert!(response.status().is_server_error(), "{}", response.status());
+    assert_ne!(body(response).await, "evil");
+}
@@ -0,0 +1,164 @@
+mod common;
+
+use std::{path::PathBuf, sync::Arc};
+
+use axum::http::{Request, Version};
+use common::TestServer;
+use http_body_util::{BodyExt, Empty};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use tokio_rustls::{
+    client::TlsStream,
+    rustls::{pki_types::CertificateDer, ClientConfig, RootCertStore},
+    TlsConnector,
+};
+
+/// Certificates for `localhost` issued by `ca.pem`, valid for a century.
+fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/tls")
+        .join(name)
+}
+
+fn certificate(name: &str) -> CertificateDer<'static> {
+    let pem = std::fs::read(fixture(name)).unwrap();
+    let first = rustls_pemfile::certs(&mut &pem[..]).next().unwrap();
+    first.unwrap()
+}
+
+async fn connect(
+    address: std::net::SocketAddr,
+    alpn: &[&[u8]],
+) -> TlsStream<tokio::net::TcpStream> {
+    let mut roots = RootCertStore::empty();
+    roots.add(certificate("ca.pem")).unwrap();
+    let provider = Arc::new(tokio_rustls::rustls::crypto::ring::default_provider());
+    let mut config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+
+    let stream = tokio::net::TcpStream::connect(address).await.unwrap();
+    TlsConnector::from(Arc::new(config))
+        .connect("localhost".try_into().unwrap(), stream)
+        .await
+        .unwrap()
+}
+
+/// The certificate the server presented on `stream`.
+fn presented(stream: &TlsStream<tokio::net::TcpStream>) -> CertificateDer<'static> {
+    stream.get_ref().1.peer_certificates().unwrap()[0].clone()
+}
+
+async fn get(stream: TlsStream<tokio::net::TcpStream>, path: &str) -> (Version, bytes::Bytes) {
+    let request = Request::get(format!("https://localhost{path}"))
+        .body(Empty::<bytes::Bytes>::new())
+        .unwrap();
+    let response = match stream.get_ref().1.alpn_protocol() {
+        Some(b"h2") => {
+            let (mut sender, connection) =
+                hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+                    .await
+                    .unwrap();
+            tokio::spawn(connection);
+            sender.send_request(request).await.unwrap()
+        }
+        _ => {
+            let (mut sender, connection) =
+                hyper::client::conn::http1::handshake(TokioIo::new(stream))
+                    .await
+                    .unwrap();
+            tokio::spawn(connection);
+            sender.send_request(request).await.unwrap()
+        }
+    };
+    assert_eq!(response.status(), 200);
+    let version = response.version();
+    (
+        version,
+        response.into_body().collect().await.unwrap().to_bytes(),
+    )
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn files_are_served_over_https() {
+    let (cert, key) = (fixture("first.pem"), fixture("first.key"));
+    let server = TestServer::new(&[
+        "--tls-cert",
+        cert.to_str().unwrap(),
+        "--tls-key",
+        key.to_str().unwrap(),
+        "--http2",
+    ]);
+    server.put("file", "secret contents").await;
+    let address = server.listen().await;
+
+    let stream = connect(address, &[]).await;
+    assert_eq!(presented(&stream), certificate("first.pem"));
+    let (version, body) = get(stream, "/files/file").await;
+    assert_eq!(version, Version::HTTP_11);
+    assert_eq!(body, "secret contents");
+
+    // HTTP/2 is negotiated through ALPN.
+    let stream = connect(address, &[b"h2", b"http/1.1"]).await;
+    assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+    let (version, body) = get(stream, "/files/file").await;
+    assert_eq!(version, Version::HTTP_2);
+    assert_eq!(body, "secret contents");
+
+    // Cleartext requests get nowhere.
+    let stream = tokio::net::TcpStream::connect(address).await.unwrap();
+    let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .unwrap();
+    tokio::spawn(connection);
+    let request = Request::get("/files/file")
+        .body(Empty::<bytes::Bytes>::new())
+        .unwrap();
+    assert!(sender.send_request(request).await.is_err());
+}
+
+#[cfg(target_family = "unix")]
+#[tokio::test(flavor = "multi_thread")]
+async fn the_certificate_is_reloaded_on_sighup() {
+    let dir = tempfile::tempdir().unwrap();
+    let (cert, key) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+    std::fs::copy(fixture("first.pem"), &cert).unwrap();
+    std::fs::copy(fixture("first.key"), &key).unwrap();
+    let server = TestServer::new(&[
+        "--tls-cert",
+        cert.to_str().unwrap(),
+        "--tls-key",
+        key.to_str().unwrap(),
+    ]);
+    let address = server.listen().await;
+    // Once a connection is served, so is SIGHUP.
+    assert_eq!(
+        presented(&connect(address, &[]).await),
+        certificate("first.pem")
+    );
+
+    std::fs::copy(fixture("second.pem"), &cert).unwrap();
+    std::fs::copy(fixture("second.key"), &key).unwrap();
+    // SAFETY: Sending a signal to ourselves, which the server handles.
+    unsafe { libc::kill(libc::getpid(), libc::SIGHUP) };
+    let mut attempts = 0;
+    while presented(&connect(address, &[]).await) != certificate("second.pem") {
+        attempts += 1;
+        assert!(attempts < 100, "the certificate was not reloaded");
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    let (_, body) = get(connect(address, &[]).await, "/version").await;
+    assert!(body.starts_with(b"{"));
+
+    // A broken key is reported and ignored, the current certificate stays in use.
+    std::fs::write(&key, "not a key").unwrap();
+    // SAFETY: As above.
+    unsafe { libc::kill(libc::getpid(), libc::SIGHUP) };
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    assert_eq!(
+        presented(&connect(address, &[]).await),
+        certificate("second.pem")
+    );
+}
@@ -0,0 +1,154 @@
+mod common;
+
+use axum::{
+    body::Body,
+    http::{Request, Response},
+};
+use common::{body, json, sha256_hex, TestServer};
+
+async fn start(server: &TestServer, path: &str) -> String {
+    let response = server
+        .send(
+            Request::post(format!("/uploads?path={path}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), 201);
+    json(response).await["id"].as_str().unwrap().to_string()
+}
+
+async fn patch(server: &TestServer, id: &str, offset: u64, data: &'static str) -> Response<Body> {
+    server
+        .send(
+            Request::patch(format!("/uploads/{id}"))
+                .header("Upload-Offset", offset)
+                .body(Body::from(data))
+                .unwrap(),
+        )
+        .await
+}
+
+async fn offset(server: &TestServer, id: &str) -> u64 {
+    let response = server
+        .send(
+            Request::head(format!("/uploads/{id}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), 200);
+    response.headers()["Upload-Offset"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap()
+}
+
+async fn complete(server: &TestServer, id: &str, checksum: &str) -> Response<Body> {
+    server
+        .send(
+            Request::post(format!("/uploads/{id}/complete"))
+                .header("SHA256-Checksum", checksum)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn two_chunk_upload() {
+    let server = TestServer::new(&[]);
+    let id = start(&server, "a/file").await;
+
+    let response = patch(&server, &id, 0, "hello ").await;
+    assert_eq!(response.status(), 204);
+    assert_eq!(response.headers()["Upload-Offset"], "6");
+    let response = patch(&server, &id, 6, "world").await;
+    assert_eq!(response.status(), 204);
+    assert_eq!(response.headers()["Upload-Offset"], "11");
+
+    let response = complete(&server, &id, &sha256_hex(b"hello world")).await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(body(server.get("/files/a/file").await).await, "hello world");
+
+    // The session is gone once completed.
+    assert_eq!(patch(&server, &id, 11, "!").await.status(), 404);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn gaps_are_refused_and_resent_data_replaced() {
+    let server = TestServer::new(&[]);
+    let id = start(&server, "file").await;
+
+    assert_eq!(patch(&server, &id, 0, "hello").await.status(), 204);
+    // Out of order, past the end of what was received.
+    let response = patch(&server, &id, 8, "rld").await;
+    assert_eq!(response.status(), 409);
+    assert_eq!(response.headers()["Upload-Offset"], "5");
+
+    // Resending from an earlier offset discards everything after it.
+    assert_eq!(patch(&server, &id, 3, "p!!!").await.status(), 204);
+    assert_eq!(offset(&server, &id).await, 7);
+    assert_eq!(patch(&server, &id, 4, " world").await.status(), 204);
+
+    let response = complete(&server, &id, &sha256_hex(b"help world")).await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(body(server.get("/files/file").await).await, "help world");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn interrupted_upload_is_resumed() {
+    let server = TestServer::new(&[]);
+    let id = start(&server, "file").await;
+    assert_eq!(patch(&server, &id, 0, "first half, ").await.status(), 204);
+
+    // A client that lost track of what arrived asks before continuing.
+    let resume_at = offset(&server, &id).await;
+    assert_eq!(resume_at, 12);
+    assert_eq!(
+        patch(&server, &id, resume_at, "second half").await.status(),
+        204
+    );
+
+    let expected = b"first half, second half";
+    let response = complete(&server, &id, &sha256_hex(b"wrong")).await;
+    assert_eq!(response.status(), 422);
+    // A failed completion keeps the session around to be retried.
+    let response = complete(&server, &id, &sha256_hex(expected)).await;
+    assert_eq!(response.status(), 200);
+    let response = server.get("/files/file").await;
+    assert_eq!(response.headers()["Logical-Size"], "23");
+    assert_eq!(body(response).await, &expected[..]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn large_uploads_are_streamed_from_the_session() {
+    let server = TestServer::new(&[]);
+    let id = start(&server, "large").await;
+    let chunk = "0123456789abcdef".repeat(64 * 1024);
+    let chunk: &'static str = chunk.leak();
+    for i in 0..4 {
+        let response = patch(&server, &id, (i * chunk.len()) as u64, chunk).await;
+        assert_eq!(response.status(), 204);
+    }
+
+    let expected = chunk.repeat(4);
+    let response = complete(&server, &id, &sha256_hex(expected.as_bytes())).await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(body(server.get("/files/large").await).await, expected);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn idle_sessions_expire() {
+    let server = TestServer::new(&["--upload-session-ttl", "1"]);
+    let id = start(&server, "file").await;
+    assert_eq!(patch(&server, &id, 0, "data").await.status(), 204);
+
+    tokio::time::sleep(std::time::Duration::from_millis(2500)).await;
+    assert_eq!(patch(&server, &id, 4, "more").await.status(), 404);
+    assert!(std::fs::read_dir(server.dir.path().join("uploads"))
+        .unwrap()
+        .next()
+        .is_none());
+}
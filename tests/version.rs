@@ -0,0 +1,59 @@
+mod common;
+
+use common::{json, TestServer};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn version_is_served_without_a_socket() {
+    let server = TestServer::new(&[]);
+    for uri in ["/version", "/version/"] {
+        let response = server.get(uri).await;
+        assert_eq!(response.status(), 200, "{uri}");
+        assert_eq!(response.headers()["Content-Type"], "application/json");
+        let info = json(response).await;
+        assert_eq!(info["protocol_versions"], serde_json::json!([2]));
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn capabilities_reflect_the_configuration() {
+    let server = TestServer::new(&[]);
+    let capabilities = &json(server.get("/version").await).await["capabilities"];
+    assert_eq!(
+        capabilities["upload_encodings"],
+        serde_json::json!(["gzip", "zstd"])
+    );
+    assert!(capabilities["max_logical_size"].is_null());
+    assert!(capabilities["max_upload_size"].is_null());
+    assert_eq!(capabilities["versioning"], true);
+    assert_eq!(capabilities["write_policy"], "last-writer-wins");
+    assert_eq!(capabilities["require_version"], false);
+    assert_eq!(capabilities["require_content_length"], false);
+    assert_eq!(capabilities["access_control"], false);
+    assert_eq!(capabilities["content_negotiation"], true);
+    assert_eq!(capabilities["public_read"], true);
+    assert_eq!(capabilities["ranges"], true);
+
+    let server = TestServer::new(&[
+        "--max-logical-size",
+        "1000",
+        "--max-upload-size",
+        "500",
+        "--write-policy",
+        "always",
+        "--require-version",
+        "--require-content-length",
+        "--always-gzip",
+    ]);
+    let capabilities = &json(server.get("/version").await).await["capabilities"];
+    assert_eq!(capabilities["max_logical_size"], 1000);
+    assert_eq!(capabilities["max_upload_size"], 500);
+    assert_eq!(capabilities["versioning"], false);
+    assert_eq!(capabilities["write_policy"], "always");
+    assert_eq!(capabilities["require_version"], true);
+    assert_eq!(capabilities["require_content_length"], true);
+    assert_eq!(capabilities["content_negotiation"], false);
+
+    // Kept intact for the original client.
+    let info = json(server.get("/version").await).await;
+    assert_eq!(info["protocol_versions"], serde_json::json!([2]));
+}
@@ -0,0 +1,141 @@
+mod common;
+
+use axum::{body::Body, http::Request};
+use common::{body, TestServer};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn malformed_versions_are_explained() {
+    let server = TestServer::new(&[]);
+    server.put("file", "data").await;
+
+    for method in ["PUT", "DELETE"] {
+        let response = server
+            .send(
+                Request::builder()
+                    .method(method)
+                    .uri("/files/file?last_modified=yesterday")
+                    .body(Body::from("data"))
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(response.status(), 400, "{method}");
+        let message = String::from_utf8(body(response).await.to_vec()).unwrap();
+        assert!(
+            message.starts_with("Invalid last_modified query parameter, expected an RFC 2822"),
+            "{method}: {message}"
+        );
+    }
+
+    let response = server.get("/list/?last_modified=2024-01-01").await;
+    assert_eq!(response.status(), 400);
+    let message = String::from_utf8(body(response).await.to_vec()).unwrap();
+    assert!(message.contains("RFC 2822"), "{message}");
+
+    // Nothing was changed by the refused requests.
+    assert_eq!(body(server.get("/files/file").await).await, "data");
+}
+
+/// Whether `date` is an IMF-fixdate as defined by RFC 7231, e.g.
+/// `Sun, 06 Nov 1994 08:49:37 GMT`.
+fn is_imf_fixdate(date: &str) -> bool {
+    const DAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let digits = |s: &str, n: usize| s.len() == n && s.bytes().all(|c| c.is_ascii_digit());
+
+    let Some((day_name, rest)) = date.split_once(", ") else {
+        return false;
+    };
+    let parts: Vec<_> = rest.split(' ').collect();
+    let [day, month, year, time, "GMT"] = parts[..] else {
+        return false;
+    };
+    let time: Vec<_> = time.split(':').collect();
+    DAYS.contains(&day_name)
+        && digits(day, 2)
+        && MONTHS.contains(&month)
+        && digits(year, 4)
+        && time.len() == 3
+        && time.iter().all(|part| digits(part, 2))
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn last_modified_can_be_an_imf_fixdate() {
+    let server = TestServer::new(&["--last-modified-format", "imf-fixdate"]);
+    let response = server
+        .put(
+            &format!("file?last_modified={}", common::OLD_VERSION),
+            "data",
+        )
+        .await;
+    assert!(is_imf_fixdate(
+        response.headers()["Last-Modified"].to_str().unwrap()
+    ));
+
+    for method in ["GET", "HEAD"] {
+        let response = server
+            .send(
+                Request::builder()
+                    .method(method)
+                    .uri("/files/file")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await;
+        let date = response.headers()["Last-Modified"].to_str().unwrap();
+        assert_eq!(date, "Mon, 01 Jan 2024 12:00:00 GMT");
+        assert!(is_imf_fixdate(date), "{method}: {date}");
+    }
+
+    // The filetracker client's format stays the default.
+    let server = TestServer::new(&[]);
+    server
+        .put(
+            &format!("file?last_modified={}", common::OLD_VERSION),
+            "data",
+        )
+        .await;
+    let date = server.get("/files/file").await.headers()["Last-Modified"].clone();
+    assert_eq!(date, "Mon, 1 Jan 2024 12:00:00 +0000");
+    assert!(!is_imf_fixdate(date.to_str().unwrap()));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn versions_default_to_the_server_clock() {
+    let server = TestServer::new(&[]);
+    let before = chrono::Utc::now() - chrono::Duration::seconds(1);
+    let response = server.put("file", "data").await;
+    assert_eq!(response.status(), 200);
+    let version = response.headers()["Last-Modified"].to_str().unwrap();
+    let version = chrono::DateTime::parse_from_rfc2822(version).unwrap();
+    assert!(before <= version && version <= chrono::Utc::now());
+
+    let response = server
+        .send(Request::delete("/files/file").body(Body::empty()).unwrap())
+        .await;
+    assert_eq!(response.headers()["X-Deleted"], "true");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn versions_can_be_required() {
+    let server = TestServer::new(&["--require-version"]);
+    let response = server.put("file", "data").await;
+    assert_eq!(response.status(), 400);
+    assert_eq!(
+        body(response).await,
+        "This server requires an explicit last_modified query parameter"
+    );
+    assert_eq!(server.get("/files/file").await.status(), 404);
+
+    let versioned = format!("file?last_modified={}", common::OLD_VERSION);
+    assert_eq!(server.put(&versioned, "data").await.status(), 200);
+
+    let delete = |uri: String| Request::delete(uri).body(Body::empty()).unwrap();
+    let response = server.send(delete("/files/file".into())).await;
+    assert_eq!(response.status(), 400);
+    assert_eq!(server.get("/files/file").await.status(), 200);
+    let response = server.send(delete(format!("/files/{versioned}"))).await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(server.get("/files/file").await.status(), 404);
+}
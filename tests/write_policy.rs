@@ -0,0 +1,76 @@
+mod common;
+
+use common::{body, sha256_hex, TestServer, OLD_VERSION};
+
+/// Stores "new" at `file` and then uploads "old" to it with an older version.
+async fn put_older(server: &TestServer) -> axum::http::Response<axum::body::Body> {
+    assert_eq!(server.put("file", "new").await.status(), 200);
+    server
+        .send(
+            axum::http::Request::put(format!("/files/file?last_modified={OLD_VERSION}"))
+                .body(axum::body::Body::from("old"))
+                .unwrap(),
+        )
+        .await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn last_writer_wins_ignores_older_writes() {
+    let server = TestServer::new(&[]);
+    let response = put_older(&server).await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["X-Current-Checksum"], sha256_hex(b"new"));
+    assert_eq!(body(server.get("/files/file").await).await, "new");
+
+    let server = TestServer::new(&["--write-policy", "last-writer-wins"]);
+    assert_eq!(put_older(&server).await.status(), 200);
+    assert_eq!(body(server.get("/files/file").await).await, "new");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reject_older_reports_a_conflict() {
+    let server = TestServer::new(&["--write-policy", "reject-older"]);
+    let response = put_older(&server).await;
+    assert_eq!(response.status(), 409);
+    assert_eq!(response.headers()["X-Current-Checksum"], sha256_hex(b"new"));
+    assert_eq!(body(server.get("/files/file").await).await, "new");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn always_overwrites_newer_files() {
+    let server = TestServer::new(&["--write-policy", "always"]);
+    let response = put_older(&server).await;
+    assert_eq!(response.status(), 200);
+    assert!(!response.headers().contains_key("X-Current-Checksum"));
+
+    let response = server.get("/files/file").await;
+    assert_eq!(
+        response.headers()["Last-Modified"],
+        "Mon, 1 Jan 2024 12:00:00 +0000"
+    );
+    assert_eq!(body(response).await, "old");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn superseded_writes_report_the_current_file() {
+    let server = TestServer::new(&[]);
+    let response = put_older(&server).await;
+    assert_eq!(response.status(), 200);
+
+    let current = server.get("/files/file").await;
+    assert_eq!(
+        response.headers()["X-Current-Version"],
+        current.headers()["Last-Modified"]
+    );
+    assert_eq!(response.headers()["X-Current-Checksum"], sha256_hex(b"new"));
+    // Last-Modified is still the version of the ignored write.
+    assert_eq!(
+        response.headers()["Last-Modified"],
+        "Mon, 1 Jan 2024 12:00:00 +0000"
+    );
+
+    // Stored writes don't carry them.
+    let response = server.put("file", "newer").await;
+    assert!(!response.headers().contains_key("X-Current-Version"));
+    assert!(!response.headers().contains_key("X-Current-Checksum"));
+}
@@ -0,0 +1,360 @@
+mod common;
+
+use axum::{
+    body::Body,
+    http::{Request, Response},
+};
+use common::{body, gzip, json, sha256_hex, TestServer};
+
+fn compressible() -> Vec<u8> {
+    "compressible contents\n".repeat(1000).into_bytes()
+}
+
+async fn stored_compression(server: &TestServer, path: &str) -> String {
+    let metadata = json(server.get(&format!("/meta/{path}")).await).await;
+    metadata["compression"].as_str().unwrap().to_string()
+}
+
+fn zstd_blob(server: &TestServer, content: &[u8]) -> std::path::PathBuf {
+    let hex = sha256_hex(content);
+    server
+        .dir
+        .path()
+        .join("blobs/zstd")
+        .join(&hex[..2])
+        .join(&hex[2..])
+}
+
+async fn get(server: &TestServer, uri: &str, accept_encoding: &str) -> Response<Body> {
+    server
+        .send(
+            Request::get(uri)
+                .header("Accept-Encoding", accept_encoding)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn blobs_can_be_stored_with_zstd() {
+    let server = TestServer::new(&["--compression", "zstd"]);
+    assert_eq!(server.put("file", compressible()).await.status(), 200);
+    assert_eq!(stored_compression(&server, "file").await, "Zstd");
+
+    let blob = std::fs::read(zstd_blob(&server, &compressible())).unwrap();
+    assert!(blob.len() < compressible().len() / 10);
+    assert_eq!(zstd::decode_all(&blob[..]).unwrap(), compressible());
+
+    let response = get(&server, "/files/file", "gzip, zstd").await;
+    assert_eq!(response.headers()["Content-Encoding"], "zstd");
+    assert_eq!(response.headers()["Vary"], "Accept-Encoding");
+    assert_eq!(
+        response.headers()["ETag"],
+        format!("\"{}-zstd\"", sha256_hex(&compressible())).as_str()
+    );
+    assert_eq!(body(response).await, blob);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn zstd_is_decompressed_for_clients_without_it() {
+    let server = TestServer::new(&["--compression", "zstd:19"]);
+    server.put("file", compressible()).await;
+
+    for accept_encoding in ["gzip", "identity", "zstd;q=0"] {
+        let response = get(&server, "/files/file", accept_encoding).await;
+        assert!(
+            !response.headers().contains_key("Content-Encoding"),
+            "{accept_encoding}"
+        );
+        assert_eq!(
+            response.headers()["Content-Length"],
+            compressible().len().to_string()
+        );
+        assert_eq!(body(response).await, compressible(), "{accept_encoding}");
+    }
+    // Not even `--always-gzip` sends zstd to clients that can't handle it.
+    let server = server.restart(&["--always-gzip"]);
+    let response = server.get("/files/file").await;
+    assert!(!response.headers().contains_key("Content-Encoding"));
+    assert_eq!(body(response).await, compressible());
+
+    let response = server
+        .send(
+            Request::get("/files/file")
+                .header("Range", "bytes=22-43")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), 206);
+    assert_eq!(body(response).await, "compressible contents\n");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn uploads_can_ask_for_zstd() {
+    let server = TestServer::new(&[]);
+    server
+        .put("identity?compression=zstd", compressible())
+        .await;
+    let response = server
+        .send(
+            Request::put("/files/gzipped?compression=zstd")
+                .header("Content-Encoding", "gzip")
+                .body(Body::from(gzip(&compressible())))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), 200);
+
+    for path in ["identity", "gzipped"] {
+        assert_eq!(stored_compression(&server, path).await, "Zstd");
+        let response = get(&server, &format!("/files/{path}"), "zstd").await;
+        let compressed = body(response).await;
+        assert_eq!(zstd::decode_all(&compressed[..]).unwrap(), compressible());
+    }
+    // Both share one blob.
+    let count = zstd_blob(&server, &compressible()).with_extension("count");
+    assert_eq!(std::fs::read_to_string(count).unwrap(), "2");
+
+    // Other files are still stored with the default compression.
+    server.put("default", "other contents ".repeat(100)).await;
+    assert_eq!(stored_compression(&server, "default").await, "Gzip");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn metadata_can_be_compressed_with_zstd() {
+    let server = TestServer::new(&["--metadata-compression", "zstd"]);
+    server.put("file", "data").await;
+
+    let metadata = std::fs::read(server.dir.path().join("metadata/file")).unwrap();
+    let json: serde_json::Value =
+        serde_json::from_slice(&zstd::decode_all(&metadata[..]).unwrap()).unwrap();
+    assert_eq!(json["decompressed_size"], 4);
+
+    let server = server.restart(&[]);
+    assert_eq!(body(server.get("/files/file").await).await, "data");
+    let listing = body(server.get("/list/").await).await;
+    assert!(listing.starts_with(b"file\n"));
+}
+
+fn put_zstd(content: Vec<u8>, checksum: Option<String>, size: Option<usize>) -> Request<Body> {
+    let mut request = Request::put("/files/file").header("Content-Encoding", "zstd");
+    if let Some(checksum) = checksum {
+        request = request.header("SHA256-Checksum", checksum);
+    }
+    if let Some(size) = size {
+        request = request.header("Logical-Size", size);
+    }
+    request.body(Body::from(content)).unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn zstd_uploads_with_a_checksum_and_size_are_stored_as_they_are() {
+    let server = TestServer::new(&[]);
+    // Unlike the streaming encoder, this records the content size in the frame header.
+    let compressed = zstd::bulk::compress(&compressible(), 19).unwrap();
+    let response = server
+        .send(put_zstd(
+            compressed.clone(),
+            Some(sha256_hex(&compressible())),
+            Some(compressible().len()),
+        ))
+        .await;
+    assert_eq!(response.status(), 200);
+
+    assert_eq!(stored_compression(&server, "file").await, "Zstd");
+    let blob = std::fs::read(zstd_blob(&server, &compressible())).unwrap();
+    assert_eq!(blob, compressed);
+    let response = server.get("/files/file").await;
+    assert_eq!(response.headers()["Logical-Size"], "22000");
+    assert_eq!(body(response).await, compressible());
+
+    // The size is checked against the one recorded in the frame header.
+    let response = server
+        .send(put_zstd(
+            compressed.clone(),
+            Some(sha256_hex(&compressed)),
+            Some(compressed.len()),
+        ))
+        .await;
+    assert_eq!(response.status(), 400);
+    assert_eq!(
+        body(response).await,
+        "Logical-Size does not match the size recorded in the zstd frame header"
+    );
+    let response = server
+        .send(put_zstd(
+            b"not zstd".repeat(4),
+            Some(sha256_hex(&compressible())),
+            Some(compressible().len()),
+        ))
+        .await;
+    assert_eq!(response.status(), 400);
+    assert_eq!(body(response).await, "Content is not a valid zstd stream");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn zstd_uploads_are_decoded_to_count_them() {
+    let server = TestServer::new(&["--extra-digests", "crc32"]);
+    let compressed = zstd::encode_all(&compressible()[..], 3).unwrap();
+    let response = server.send(put_zstd(compressed.clone(), None, None)).await;
+    assert_eq!(response.status(), 200);
+
+    let response = server.get("/files/file").await;
+    assert_eq!(response.headers()["Logical-Size"], "22000");
+    assert_eq!(
+        response.headers()["SHA256-Checksum"],
+        sha256_hex(&compressible())
+    );
+    assert_eq!(
+        response.headers()["X-CRC32"],
+        format!("{:08x}", crc32fast::hash(&compressible()))
+    );
+    assert_eq!(body(response).await, compressible());
+    let blob = std::fs::read(zstd_blob(&server, &compressible())).unwrap();
+    assert_eq!(blob, compressed);
+
+    let response = server
+        .send(put_zstd(
+            compressed[..compressed.len() / 2].to_vec(),
+            None,
+            None,
+        ))
+        .await;
+    assert_eq!(response.status(), 400);
+    let message = String::from_utf8(body(response).await.to_vec()).unwrap();
+    assert!(
+        message.starts_with("Body is not a valid zstd stream"),
+        "{message}"
+    );
+    // Implausible sizes are rejected without even decoding.
+    let response = server
+        .send(put_zstd(compressed, None, Some(usize::MAX / 2)))
+        .await;
+    assert_eq!(response.status(), 400);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn zstd_uploads_can_be_stored_decompressed() {
+    let server = TestServer::new(&[]);
+    let compressed = zstd::encode_all(&compressible()[..], 3).unwrap();
+    let response = server
+        .send(
+            Request::put("/files/file?compression=none")
+                .header("Content-Encoding", "zstd")
+                .body(Body::from(compressed))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(stored_compression(&server, "file").await, "None");
+    assert_eq!(body(server.get("/files/file").await).await, compressible());
+}
+
+/// A small JSON artifact, of which there are many similar ones.
+fn artifact(id: usize) -> Vec<u8> {
+    format!(
+        r#"{{"id":{id},"name":"artifact-{id}","status":"passed","duration_ms":{},"tags":["build","linux","x86_64"]}}"#,
+        id * 7 % 1000
+    )
+    .into_bytes()
+}
+
+/// A dictionary trained on artifacts, written to a file for `--zstd-dict`.
+fn dictionary() -> (tempfile::NamedTempFile, Vec<u8>) {
+    let samples: Vec<_> = (0..1000).map(artifact).collect();
+    let dictionary = zstd::dict::from_samples(&samples, 4096).unwrap();
+    let file = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(file.path(), &dictionary).unwrap();
+    (file, dictionary)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_dictionary_shrinks_small_files() {
+    let (file, dictionary) = dictionary();
+    let id = zstd::zstd_safe::get_dict_id_from_dict(&dictionary).unwrap();
+    let plain = TestServer::new(&[]);
+    let server = TestServer::new(&["--zstd-dict", file.path().to_str().unwrap()]);
+    let content = artifact(123456);
+    for server in [&plain, &server] {
+        let response = server.put("file?compression=zstd", content.clone()).await;
+        assert_eq!(response.status(), 200);
+    }
+
+    let without = std::fs::read(zstd_blob(&plain, &content)).unwrap();
+    let with = std::fs::read(zstd_blob(&server, &content)).unwrap();
+    assert!(
+        with.len() * 2 < without.len(),
+        "{} {}",
+        with.len(),
+        without.len()
+    );
+    let metadata = json(server.get("/meta/file").await).await;
+    assert_eq!(metadata["zstd_dictionary"], id.get());
+    assert!(json(plain.get("/meta/file").await).await["zstd_dictionary"].is_null());
+
+    // Clients don't have the dictionary, so the blob is always decompressed for them.
+    let response = get(&server, "/files/file", "zstd").await;
+    assert!(!response.headers().contains_key("Content-Encoding"));
+    assert!(!response.headers().contains_key("Vary"));
+    assert_eq!(body(response).await, content);
+    let response = server
+        .send(
+            Request::get("/files/file")
+                .header("Range", "bytes=1-4")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(body(response).await, r#""id""#);
+
+    // Nor can they upload content compressed with one.
+    let compressed = zstd::bulk::Compressor::with_dictionary(3, &dictionary)
+        .unwrap()
+        .compress(&content)
+        .unwrap();
+    let response = server
+        .send(put_zstd(
+            compressed,
+            Some(sha256_hex(&content)),
+            Some(content.len()),
+        ))
+        .await;
+    assert_eq!(response.status(), 400);
+    assert_eq!(
+        body(response).await,
+        "zstd streams compressed with a dictionary are not supported"
+    );
+
+    // The blob can't be read without the dictionary.
+    let server = server.restart(&[]);
+    let response = server.get("/files/file").await;
+    assert_eq!(response.status(), 500);
+    assert_eq!(
+        body(response).await,
+        format!("The file is compressed with zstd dictionary {id}, which isn't loaded")
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn blobs_without_a_dictionary_stay_readable_with_one() {
+    let (file, _) = dictionary();
+    let server = TestServer::new(&[]);
+    let content = artifact(123456);
+    server.put("old?compression=zstd", content.clone()).await;
+
+    let server = server.restart(&["--zstd-dict", file.path().to_str().unwrap()]);
+    let response = get(&server, "/files/old", "zstd").await;
+    assert_eq!(response.headers()["Content-Encoding"], "zstd");
+    assert_eq!(
+        zstd::decode_all(&body(response).await[..]).unwrap(),
+        content
+    );
+
+    // New files sharing the blob don't use the dictionary either.
+    let response = server.put("new?compression=zstd", content.clone()).await;
+    assert_eq!(response.headers()["X-Deduplicated"], "true");
+    assert!(json(server.get("/meta/new").await).await["zstd_dictionary"].is_null());
+    assert_eq!(body(server.get("/files/new").await).await, content);
+}